@@ -0,0 +1,137 @@
+// benchmarks of the hot paths of insertion calling (phase 4) on synthetic alignments (see fixtures),
+// as a baseline for changes to the parsing and grouping of the alignments
+// run them with "cargo bench" (the reports are written to target/criterion)
+
+// the sx binary has no library target, so the modules that the benchmarks need are included here
+// (they only depend on each other), with the same lints as src/bin/sx/main.rs
+// (their tests are compiled too, but not run, so their imports are unused)
+#![allow(
+    dead_code,
+    unused_imports,
+    clippy::needless_return,
+    clippy::redundant_field_names,
+    clippy::ptr_arg,
+    clippy::len_zero,
+    clippy::legacy_numeric_constants,
+    clippy::needless_borrow,
+    clippy::needless_borrows_for_generic_args,
+    clippy::write_literal,
+    clippy::redundant_slicing,
+    clippy::iter_nth_zero,
+    clippy::needless_late_init,
+    clippy::manual_is_multiple_of,
+    clippy::manual_div_ceil,
+    clippy::op_ref,
+    clippy::char_lit_as_u8,
+    clippy::needless_range_loop,
+    clippy::partialeq_to_none,
+    clippy::redundant_pattern_matching,
+    clippy::same_item_push,
+    clippy::unnecessary_to_owned,
+    clippy::unused_io_amount
+)]
+
+#[path = "../src/bin/sx/errors.rs"]
+mod errors;
+#[path = "../src/bin/sx/regexes.rs"]
+mod regexes;
+#[path = "../src/bin/sx/tabular.rs"]
+mod tabular;
+#[path = "../src/bin/sx/te_mapper_utils/mod.rs"]
+mod te_mapper_utils;
+#[path = "../src/bin/sx/utils.rs"]
+mod utils;
+
+mod fixtures;
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+
+use std::collections::HashMap;
+
+use te_mapper_utils::diagnostics::Diagnostics;
+use te_mapper_utils::family_params::FamilyParams;
+use te_mapper_utils::genome_alignment::GenomeAlignment;
+use te_mapper_utils::second_sam_file;
+
+// the numbers of insertions on the synthetic chromosome (about 10 alignments each)
+const SIZES: [usize; 3] = [1_000, 10_000, 50_000];
+
+fn bench_calling(c: &mut Criterion) {
+    let chrom = fixtures::CHROM.to_owned();
+    let chrom_lengths: HashMap<String, u64> = HashMap::new();
+    let te_lengths = fixtures::te_lengths();
+    let mut group = c.benchmark_group("calling");
+    group.sample_size(10);
+    for size in SIZES.iter() {
+        let records = fixtures::genome_aligned_records(*size, 42);
+        group.throughput(Throughput::Elements(records.len() as u64));
+        // the heaps are consumed, so each iteration gets a fresh copy (which isn't timed)
+        group.bench_function(BenchmarkId::new("make_3d_vector", size), |b| {
+            b.iter_batched(
+                || fixtures::alignment_heaps(&records).0,
+                |mut heap| GenomeAlignment::make_3d_vector(&mut heap),
+                BatchSize::LargeInput,
+            );
+        });
+        group.bench_function(BenchmarkId::new("get_non_ref_tes", size), |b| {
+            b.iter_batched(
+                || fixtures::alignment_heaps(&records).0,
+                |mut heap| {
+                    GenomeAlignment::get_non_ref_tes(
+                        &mut heap,
+                        0,
+                        100,
+                        &FamilyParams::default(),
+                        &chrom,
+                        &chrom_lengths,
+                        false,
+                        0,
+                        &Diagnostics::default(),
+                    )
+                },
+                BatchSize::LargeInput,
+            );
+        });
+        group.bench_function(BenchmarkId::new("get_ref_tes", size), |b| {
+            b.iter_batched(
+                || fixtures::alignment_heaps(&records).1,
+                |mut heap| {
+                    GenomeAlignment::get_ref_tes(
+                        &mut heap,
+                        0.1,
+                        1.5,
+                        &FamilyParams::default(),
+                        &te_lengths,
+                        &chrom,
+                        &chrom_lengths,
+                        false,
+                        0,
+                        &Diagnostics::default(),
+                    )
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn bench_metadata_read(c: &mut Criterion) {
+    let metadata = second_sam_file::genome_alignment_metadata(&HashMap::new());
+    let records = fixtures::genome_aligned_records(10_000, 42);
+    let mut group = c.benchmark_group("tabular");
+    group.throughput(Throughput::Elements(records.len() as u64));
+    group.bench_function("Metadata::read", |b| {
+        b.iter(|| {
+            for record in &records {
+                black_box(metadata.read(record.clone()));
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_calling, bench_metadata_read);
+criterion_main!(benches);
@@ -0,0 +1,191 @@
+// synthetic genome alignments for the benchmarks, shaped like the output of phase 3:
+// insertions of a few transposon families spread along a chromosome, each with a handful of
+// split-reads on either end (at various split points), plus unsupported reads scattered in between
+
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::te_mapper_utils::genome_alignment::{GenomeAlignment, SplitReadGenome};
+use crate::te_mapper_utils::second_sam_file;
+
+pub const CHROM: &str = "2L";
+
+// the read length of the split-reads
+const READ_LENGTH: u64 = 150;
+
+// the transposon families and their lengths (the lengths of the Drosophila consensus sequences)
+pub const FAMILIES: [(&str, u64); 6] = [
+    ("roo", 9092),
+    ("jockey", 5020),
+    ("blood", 7410),
+    ("297", 6995),
+    ("412", 7567),
+    ("copia", 5143),
+];
+
+// a small xorshift generator, so that the fixtures are the same on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return self.0;
+    }
+
+    // a number in [low, high)
+    fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low)
+    }
+
+    // the number of split-reads at an end of an insertion: mostly a few, sometimes many
+    fn read_count(&mut self) -> u64 {
+        if self.range(0, 10) == 0 {
+            return self.range(10, 40);
+        }
+        return self.range(1, 8);
+    }
+}
+
+// a genome-aligned split-read (see select_reads for the read name)
+// the TE part of the read is te_length nucleotides, the rest is aligned to the genome at pos
+fn split_read(
+    read_num: &mut u64,
+    te_name: &str,
+    te_length: u64,
+    is_start: bool,
+    pos: u64,
+    cigar: String,
+) -> String {
+    *read_num += 1;
+    let (sm, start) = if is_start {
+        ("SM", "start")
+    } else {
+        ("MS", "end")
+    };
+    return format!(
+        "r{}|{}|{}|{}|{}|{}\t0\t{}\t{}\t60\t{}\t*\t0\t0\t*\t*",
+        read_num,
+        te_name,
+        te_length,
+        READ_LENGTH - te_length,
+        sm,
+        start,
+        CHROM,
+        pos,
+        cigar
+    );
+}
+
+// the SAM records of num_insertions insertions, half of them non-reference and half reference
+pub fn genome_aligned_records(num_insertions: usize, seed: u64) -> Vec<String> {
+    let mut rng = Rng(seed.max(1));
+    let mut records: Vec<String> = Vec::new();
+    let mut read_num: u64 = 0;
+    let mut pos: u64 = 10_000;
+    for insertion_num in 0..num_insertions {
+        let (te_name, te_length) = FAMILIES[rng.range(0, FAMILIES.len() as u64) as usize];
+        if insertion_num % 2 == 0 {
+            // a non-reference insertion: the reads at the start of the transposon end at the upstream
+            // end of the TSD, and the reads at its end start at the downstream end
+            let downstream_pos = pos;
+            let upstream_pos = pos + rng.range(4, 9);
+            for _ in 0..rng.read_count() {
+                let te_part = rng.range(30, 120);
+                let genome_part = READ_LENGTH - te_part;
+                records.push(split_read(
+                    &mut read_num,
+                    te_name,
+                    te_part,
+                    true,
+                    upstream_pos + 1 - genome_part,
+                    format!("{}M{}S", genome_part, te_part),
+                ));
+            }
+            for _ in 0..rng.read_count() {
+                let te_part = rng.range(30, 120);
+                let genome_part = READ_LENGTH - te_part;
+                records.push(split_read(
+                    &mut read_num,
+                    te_name,
+                    te_part,
+                    false,
+                    downstream_pos,
+                    format!("{}S{}M", te_part, genome_part),
+                ));
+            }
+            pos += rng.range(2_000, 20_000);
+        } else {
+            // a reference insertion: the whole read aligns to the genome, across the ends of the transposon
+            let upstream_pos = pos;
+            let downstream_pos = pos + te_length + rng.range(0, 40) - 20;
+            for _ in 0..rng.read_count() {
+                let te_part = rng.range(30, 120);
+                records.push(split_read(
+                    &mut read_num,
+                    te_name,
+                    te_part,
+                    true,
+                    upstream_pos - (READ_LENGTH - te_part),
+                    format!("{}M", READ_LENGTH),
+                ));
+            }
+            for _ in 0..rng.read_count() {
+                let te_part = rng.range(30, 120);
+                records.push(split_read(
+                    &mut read_num,
+                    te_name,
+                    te_part,
+                    false,
+                    downstream_pos + 1 - te_part,
+                    format!("{}M", READ_LENGTH),
+                ));
+            }
+            pos = downstream_pos + rng.range(2_000, 20_000);
+        }
+        // an unsupported split-read between insertions
+        let te_part = rng.range(30, 120);
+        records.push(split_read(
+            &mut read_num,
+            te_name,
+            te_part,
+            true,
+            pos - rng.range(500, 1_500),
+            format!("{}M{}S", READ_LENGTH - te_part, te_part),
+        ));
+    }
+    return records;
+}
+
+// the non-reference and reference alignment heaps of the records (as in phase 4)
+pub fn alignment_heaps(
+    records: &[String],
+) -> (BinaryHeap<GenomeAlignment>, BinaryHeap<GenomeAlignment>) {
+    let metadata = second_sam_file::genome_alignment_metadata(&HashMap::new());
+    let chroms = vec![CHROM.to_owned()];
+    let mut non_ref_heap = BinaryHeap::new();
+    let mut ref_heap = BinaryHeap::new();
+    for record in records {
+        let (_, alignment) = second_sam_file::read_genome_alignment(
+            record.clone(),
+            &metadata,
+            &chroms,
+            &HashMap::new(),
+            0,
+        )
+        .unwrap();
+        match alignment.split_read_genome {
+            SplitReadGenome::M(_) => ref_heap.push(alignment),
+            _ => non_ref_heap.push(alignment),
+        }
+    }
+    return (non_ref_heap, ref_heap);
+}
+
+// the lengths of the transposons, for the reference insertions
+pub fn te_lengths() -> HashMap<String, u64> {
+    FAMILIES
+        .iter()
+        .map(|(te_name, length)| (te_name.to_string(), *length))
+        .collect()
+}
@@ -0,0 +1,56 @@
+use thiserror::Error;
+
+// the errors returned while parsing the alignment files and calling insertions
+// (each variant keeps the offending value so that callers can match on it and report it)
+// anyhow is only used at the command-line boundary
+#[derive(Debug, Error)]
+pub enum StanexError {
+    #[error("CIGAR string \"{cigar}\" is not {expected}")]
+    MalformedCigar {
+        cigar: String,
+        expected: &'static str,
+    },
+    #[error("unable to find transposon \"{0}\" in transposon list")]
+    UnknownTransposon(String),
+    #[error("read with CIGAR string \"{cigar}\" does not align to the start or end of transposon \"{te_name}\"")]
+    NotAtTransposonEnd { cigar: String, te_name: String },
+    #[error("CIGAR string \"{cigar}\" has a clip shorter than {min_clip} nucleotides")]
+    ClipTooShort { cigar: String, min_clip: u64 },
+    #[error("read \"{read}\" has {count} ambiguous bases (N), more than {max_read_n}")]
+    TooManyAmbiguousBases {
+        read: String,
+        count: u64,
+        max_read_n: u64,
+    },
+    #[error("read \"{0}\" is unmapped")]
+    UnmappedRead(String),
+    #[error("read \"{0}\" has no position (\"*\" in RNAME or CIGAR)")]
+    UnplacedRead(String),
+    #[error("read \"{0}\" is a secondary or supplementary alignment")]
+    NotPrimaryAlignment(String),
+    #[error("read \"{0}\" does not span an insertion")]
+    NotSpanning(String),
+    #[error("chromosome \"{0}\" is not in the list of chromosomes")]
+    InvalidChromosome(String),
+    #[error("field {0} is invalid")]
+    MissingField(String),
+    #[error("field {field} has an invalid value \"{value}\"")]
+    InvalidValue { field: String, value: String },
+    #[error("record at {chrom}:{pos} is out of order, so the output cannot be indexed")]
+    UnsortedRecord { chrom: String, pos: u64 },
+    #[error("FASTQ file \"{0}\" ends in the middle of a record")]
+    TruncatedFastq(String),
+    #[error("the paired-end reads files do not match: {0}")]
+    MismatchedReadPairs(String),
+    #[error("the SAM header {0}")]
+    InvalidSamHeader(&'static str),
+    #[error("{step} failed ({status})")]
+    SubprocessFailed {
+        step: String,
+        status: std::process::ExitStatus,
+    },
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, StanexError>;
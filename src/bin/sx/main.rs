@@ -1,93 +1,553 @@
+// lints of the style that the existing code was written in (explicit returns and field names,
+// borrowed String/Vec parameters, ...), which clippy -D warnings would otherwise reject
+#![allow(
+    clippy::needless_return,
+    clippy::redundant_field_names,
+    clippy::ptr_arg,
+    clippy::len_zero,
+    clippy::legacy_numeric_constants,
+    clippy::needless_borrow,
+    clippy::needless_borrows_for_generic_args,
+    clippy::write_literal,
+    clippy::redundant_slicing,
+    clippy::iter_nth_zero,
+    clippy::needless_late_init,
+    clippy::manual_is_multiple_of,
+    clippy::manual_div_ceil,
+    clippy::op_ref,
+    clippy::char_lit_as_u8,
+    clippy::needless_range_loop,
+    clippy::partialeq_to_none,
+    clippy::redundant_pattern_matching,
+    clippy::same_item_push,
+    clippy::unnecessary_to_owned,
+    clippy::unused_io_amount
+)]
+
+mod errors;
 mod regexes;
 mod sg_utils;
 mod sx_app;
+mod sx_catalog;
+mod sx_check_library;
+mod sx_chrom_sizes;
 mod sx_download;
 mod sx_map;
 mod sx_variants;
+mod sx_versions;
 mod tabular;
 mod te_mapper_utils;
 mod utils;
 
+use clap::ArgMatches;
+
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::errors::StanexError;
+
+use crate::te_mapper_utils::family_map::FamilyMap;
+use crate::te_mapper_utils::family_params::FamilyParams;
+use crate::te_mapper_utils::first_sam_file;
+use crate::te_mapper_utils::select_alignments;
+use crate::te_mapper_utils::select_alignments::{
+    HeaderStyle, InsertionTypes, OutputFormat, OverlapResolution, Region, STDOUT_PATH,
+};
+use crate::te_mapper_utils::select_reads::SelectedReadsFormat;
+use crate::te_mapper_utils::te_filter::TeFilter;
+use crate::te_mapper_utils::te_lengths;
+use crate::te_mapper_utils::zygosity::ZygosityThresholds;
+use crate::utils::{LogFormat, Reads};
+
+// the number of threads of the "BWA Threads" argument (default_threads if it is left out),
+// capped to the available CPUs unless --allow-oversubscribe is given
+fn bwa_threads(matches: &ArgMatches, default_threads: u16) -> u16 {
+    let requested = match matches
+        .value_of("BWA Threads")
+        .map(|num| num.parse::<u16>())
+    {
+        Some(Ok(num)) if num > 0 => num,
+        Some(_) => {
+            eprintln!(
+                "Please enter a number of BWA threads between 1 and {} or omit the argument",
+                u16::MAX
+            );
+            std::process::exit(2);
+        }
+        None => return default_threads,
+    };
+    return utils::checked_thread_count(
+        requested as usize,
+        utils::available_cpus(),
+        matches.is_present("Allow Oversubscribe"),
+        "BWA",
+    ) as u16;
+}
+
+// the number of threads of the "Mapper Threads" argument (None picks it automatically),
+// capped to the available CPUs unless --allow-oversubscribe is given
+fn mapper_threads(matches: &ArgMatches) -> Option<usize> {
+    // 0 picks the number of threads automatically, like leaving the argument out
+    return matches
+        .value_of("Mapper Threads")
+        .map(|num| {
+            num.parse::<usize>()
+                .expect("Please enter a positive number of mapper threads or omit the argument")
+        })
+        .filter(|num| *num > 0)
+        .map(|num| {
+            utils::checked_thread_count(
+                num,
+                utils::available_cpus(),
+                matches.is_present("Allow Oversubscribe"),
+                "mapper",
+            )
+        });
+}
 
-use crate::utils::Reads;
+// the extra "bwa mem" arguments of the --bwa-k, --bwa-T, --bwa-L and --bwa-extra options
+fn bwa_options(matches: &ArgMatches) -> Vec<String> {
+    let mut bwa_options: Vec<String> = Vec::new();
+    for (arg, option) in &[("BWA k", "-k"), ("BWA T", "-T"), ("BWA L", "-L")] {
+        if let Some(value) = matches.value_of(arg) {
+            bwa_options.push(option.to_string());
+            bwa_options.push(value.to_owned());
+        }
+    }
+    if let Some(extra) = matches.value_of("BWA Extra") {
+        bwa_options.extend(extra.split_whitespace().map(|arg| arg.to_owned()));
+    }
+    if let Err(e) = utils::check_bwa_mem_args(&bwa_options) {
+        eprintln!(
+            "Invalid BWA MEM options \"{}\": {}",
+            bwa_options.join(" "),
+            e
+        );
+        std::process::exit(2);
+    }
+    return bwa_options;
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let app = sx_app::app();
     let app_matches = app.get_matches();
+    // the global --log-format is read from the subcommand too, since it can come after it
+    let log_format = app_matches.value_of("Log Format").or_else(|| {
+        app_matches
+            .subcommand()
+            .1
+            .and_then(|matches| matches.value_of("Log Format"))
+    });
+    if let Some(log_format) = log_format {
+        utils::set_log_format(LogFormat::parse(log_format).unwrap());
+    }
 
     // handle "download" subcommand
     if let Some(matches) = app_matches.subcommand_matches("download") {
         let url_arg = matches.value_of("URL").unwrap();
         let output_arg = matches.value_of("Output File").unwrap();
-        sx_download::download(url_arg, output_arg);
+        let cache_key = sx_download::CacheKey {
+            sha256: matches
+                .value_of("SHA-256")
+                .map(|checksum| checksum.to_owned()),
+            size: matches.value_of("Size").map(|size| {
+                size.parse::<u64>()
+                    .expect("Please enter a positive file size in bytes or omit the argument")
+            }),
+        };
+        let force = matches.is_present("Force");
+        let retries = matches
+            .value_of("Retries")
+            .unwrap_or("3")
+            .parse::<u32>()
+            .expect("Please enter a positive number of retries or omit the argument");
+        sx_download::download(url_arg, output_arg, &cache_key, force, retries);
     }
 
     // handle "variants" subcommand
     if let Some(matches) = app_matches.subcommand_matches("variants") {
         let reference = matches.value_of("Reference").unwrap();
         let result_dir = matches.value_of("Result Directory").unwrap();
-        let bwa_threads = match matches.value_of("BWA Threads") {
-            Some(num) => num
-                .to_owned()
-                .parse::<u16>()
-                .expect("Please enter a positive number of BWA threads or omit the argument"),
-            None => 1,
-        };
-        let paired_ends = matches.is_present("Paired-Ends");
-        if paired_ends {
+        let bwa_threads = bwa_threads(matches, 1);
+        let input = if let Some(bam) = matches.value_of("BAM") {
+            sx_variants::VariantsInput::SortedBam(bam.to_owned())
+        } else if matches.is_present("Paired-Ends") {
             let reads1 = matches.value_of("Reads1").unwrap();
             let reads2 = matches.value_of("Reads2").unwrap();
-            let reads_struct = Reads::PairedEnds(reads1.to_owned(), reads2.to_owned());
-            sx_variants::run_variant_calling_pipeline(
-                reference,
-                reads_struct,
-                result_dir,
-                bwa_threads,
-            );
+            if matches.is_present("Validate Pairs") {
+                if let Err(e) = utils::validate_read_pairs(reads1, reads2) {
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                }
+            }
+            sx_variants::VariantsInput::Reads(Reads::PairedEnds(
+                reads1.to_owned(),
+                reads2.to_owned(),
+            ))
         } else {
-            let reads = matches.value_of("Reads").unwrap();
-            let reads_struct = Reads::SingleEnd(reads.to_owned());
-            sx_variants::run_variant_calling_pipeline(
-                reference,
-                reads_struct,
-                result_dir,
-                bwa_threads,
-            );
-        }
+            let reads = match matches.value_of("Reads") {
+                Some(reads_path) => reads_path,
+                None => {
+                    eprintln!("Please provide a value to the command-line argument \"reads\" (or \"bam\")");
+                    std::process::exit(2);
+                }
+            };
+            if matches.is_present("Interleaved") {
+                sx_variants::VariantsInput::Reads(Reads::Interleaved(reads.to_owned()))
+            } else {
+                sx_variants::VariantsInput::Reads(Reads::SingleEnd(reads.to_owned()))
+            }
+        };
+        sx_variants::run_variant_calling_pipeline(
+            reference,
+            input,
+            result_dir,
+            bwa_threads,
+            &bwa_options(matches),
+            matches.is_present("Keep Temp SAM"),
+            matches.is_present("Overwrite"),
+        );
     }
 
     // handle "map" subcommand
     if let Some(matches) = app_matches.subcommand_matches("map") {
-        let reference = matches.value_of("Reference").unwrap();
+        let references: Vec<&str> = matches.values_of("Reference").unwrap().collect();
+        if matches.is_present("List Chromosomes") {
+            sx_map::list_chromosomes(&references, matches.value_of("Result Directory"));
+            return Ok(());
+        }
         let paired_ends = matches.is_present("Paired-Ends");
-        let json_output = matches.is_present("JSON");
         let transposons = matches.value_of("Transposons File").unwrap();
         let result_dir = matches.value_of("Result Directory").unwrap();
-        let phase = matches
-            .value_of("phase")
-            .unwrap_or("1")
-            .parse::<u32>()
-            .expect("Please enter a positive phase or omit the argument");
-        let bwa_threads = match matches.value_of("BWA Threads") {
-            Some(num) => num
-                .to_owned()
-                .parse::<u16>()
-                .expect("Please enter a positive number of BWA threads or omit the argument"),
-            None => 8,
+        let options = sx_map::MapOptions {
+            bwa_threads: bwa_threads(matches, 8),
+            mapper_threads: mapper_threads(matches),
+            bwa_options: bwa_options(matches),
+            output_format: if matches.is_present("JSON") {
+                OutputFormat::Json
+            } else {
+                OutputFormat::parse(matches.value_of("Format").unwrap_or("tsv")).unwrap()
+            },
+            header_style: HeaderStyle::parse(matches.value_of("Header Style").unwrap_or("bergman"))
+                .unwrap(),
+            phase: matches
+                .value_of("phase")
+                .unwrap_or("1")
+                .parse::<u32>()
+                .expect("Please enter a positive phase or omit the argument"),
+            te_aligned: matches.value_of("TE Aligned").map(|te_aligned| {
+                let validation =
+                    File::open(te_aligned)
+                        .map_err(StanexError::from)
+                        .and_then(|te_aligned_file| {
+                            first_sam_file::validate_te_aligned(&mut BufReader::new(
+                                te_aligned_file,
+                            ))
+                        });
+                if let Err(e) = validation {
+                    eprintln!("Invalid TE alignment {}: {}", te_aligned, e);
+                    std::process::exit(2);
+                }
+                te_aligned.to_owned()
+            }),
+            cache_alignments: matches.is_present("Cache Alignments"),
+            // --recall re-runs the calling step from the cache
+            from_cache: matches.is_present("From Cache") || matches.is_present("Recall"),
+            stream: matches.is_present("Stream"),
+            recall: matches.is_present("Recall"),
+            reference_length_window: match matches.value_of("Reference Length Window") {
+                Some(window) => match select_alignments::parse_length_window(window) {
+                    Some(window) => window,
+                    None => {
+                        eprintln!(
+                            "Invalid reference length window (expected MIN,MAX with 0 <= MIN <= MAX, e.g. 0.1,1.5): {}",
+                            window
+                        );
+                        std::process::exit(2);
+                    }
+                },
+                None => (0.1, 1.5),
+            },
+            sam_layout: match matches.value_of("SAM Layout") {
+                Some(layout) => {
+                    let sam_layout = match tabular::parse_layout(layout) {
+                        Ok(sam_layout) => sam_layout,
+                        Err(e) => {
+                            eprintln!("Invalid SAM layout: {}", e);
+                            std::process::exit(2);
+                        }
+                    };
+                    if let Some(field) = sam_layout
+                        .keys()
+                        .find(|field| !sx_map::SAM_LAYOUT_FIELDS.contains(&&field[..]))
+                    {
+                        eprintln!(
+                            "Invalid SAM layout: unknown field {} (expected one of {})",
+                            field,
+                            sx_map::SAM_LAYOUT_FIELDS.join(", ")
+                        );
+                        std::process::exit(2);
+                    }
+                    sam_layout
+                }
+                None => HashMap::new(),
+            },
+            contig_renames: match matches.values_of("Rename Contigs") {
+                Some(values) => {
+                    let values: Vec<String> = values.map(|value| value.to_owned()).collect();
+                    match utils::parse_contig_renames(&values) {
+                        Ok(contig_renames) => contig_renames,
+                        Err(e) => {
+                            eprintln!("Invalid contig renames: {}", e);
+                            std::process::exit(2);
+                        }
+                    }
+                }
+                None => HashMap::new(),
+            },
+            chromosomes: if matches.is_present("Chromosomes")
+                || matches.is_present("Chromosomes File")
+            {
+                let inline_chromosomes: Vec<String> = match matches.values_of("Chromosomes") {
+                    Some(values) => values.map(|value| value.to_owned()).collect(),
+                    None => Vec::new(),
+                };
+                match utils::parse_chromosome_list(
+                    &inline_chromosomes,
+                    matches.value_of("Chromosomes File"),
+                ) {
+                    Ok(chromosomes) => Some(chromosomes),
+                    Err(e) => {
+                        eprintln!("Invalid chromosome list: {}", e);
+                        std::process::exit(2);
+                    }
+                }
+            } else {
+                None
+            },
+            min_contig_length: matches
+                .value_of("Min Contig Length")
+                .unwrap_or("0")
+                .parse::<u64>()
+                .expect("Please enter a positive minimum contig length or omit the argument"),
+            region: matches
+                .value_of("Region")
+                .map(|region| match Region::parse(region) {
+                    Ok(region) => region,
+                    Err(e) => {
+                        eprintln!("Invalid region (expected CHROM:START-END): {}", e);
+                        std::process::exit(2);
+                    }
+                }),
+            sorted: matches.is_present("Sorted"),
+            chrom_order_file: matches
+                .value_of("Chromosome Order")
+                .map(|chrom_order_file| chrom_order_file.to_owned()),
+            bgzip: matches.is_present("Bgzip"),
+            gzip_output: matches.is_present("Gzip Output"),
+            min_clip: matches
+                .value_of("Min Clip")
+                .unwrap_or("0")
+                .parse::<u64>()
+                .expect("Please enter a positive minimum clip length or omit the argument"),
+            min_genome_overlap: matches
+                .value_of("Min Genome Overlap")
+                .unwrap_or("0")
+                .parse::<u64>()
+                .expect("Please enter a positive minimum genome overlap or omit the argument"),
+            max_read_n: matches.value_of("Max Read N").map(|max_read_n| {
+                max_read_n
+                    .parse::<u64>()
+                    .expect("Please enter a positive maximum number of Ns or omit the argument")
+            }),
+            long_reads: matches.is_present("Long Reads"),
+            genome_first: matches.is_present("Genome First"),
+            max_tsd_length: matches.value_of("Max TSD Length").map(|max_tsd_length| {
+                max_tsd_length
+                    .parse::<u64>()
+                    .expect("Please enter a positive maximum TSD length or omit the argument")
+            }),
+            selected_reads_format: SelectedReadsFormat::parse(
+                matches.value_of("Selected Reads Format").unwrap_or("fasta"),
+            )
+            .unwrap(),
+            min_element_length: matches
+                .value_of("Min Element Length")
+                .unwrap_or("0")
+                .parse::<u64>()
+                .expect("Please enter a positive minimum element length or omit the argument"),
+            sample: matches.value_of("Sample").map(|sample| sample.to_owned()),
+            te_filter: {
+                let patterns = |arg: &str| -> Vec<String> {
+                    match matches.values_of(arg) {
+                        Some(values) => values.map(|pattern| pattern.to_owned()).collect(),
+                        None => Vec::new(),
+                    }
+                };
+                TeFilter::new(&patterns("TE Include"), &patterns("TE Exclude"))
+            },
+            family_map: match matches.value_of("Family Map") {
+                Some(family_map_file) => match FamilyMap::read(family_map_file) {
+                    Ok(family_map) => family_map,
+                    Err(e) => {
+                        eprintln!("Invalid family map: {}", e);
+                        std::process::exit(2);
+                    }
+                },
+                None => FamilyMap::default(),
+            },
+            family_params: match matches.value_of("Family Params") {
+                Some(family_params_file) => match FamilyParams::read(family_params_file) {
+                    Ok(family_params) => family_params,
+                    Err(e) => {
+                        eprintln!("Invalid family parameters: {}", e);
+                        std::process::exit(2);
+                    }
+                },
+                None => FamilyParams::default(),
+            },
+            te_lengths: matches.value_of("TE Lengths").map(|te_lengths_file| {
+                match te_lengths::read_te_lengths(te_lengths_file) {
+                    Ok(te_lengths) => te_lengths,
+                    Err(e) => {
+                        eprintln!("Invalid transposon lengths: {}", e);
+                        std::process::exit(2);
+                    }
+                }
+            }),
+            case_insensitive_te_names: matches.is_present("Case-Insensitive TE Names"),
+            report_one_sided: matches.is_present("Report One-Sided"),
+            count_fragments: matches.is_present("Count Fragments"),
+            breakpoint_ranges: matches.is_present("Breakpoint Ranges"),
+            annotate_neighbors: matches.is_present("Annotate Neighbors"),
+            vcf_read_evidence: matches.is_present("VCF Read Evidence"),
+            compact_json: matches.is_present("Compact JSON"),
+            tsd_sequence: matches.is_present("TSD Sequence"),
+            reconcile_ltr: matches.is_present("Reconcile LTR"),
+            reconcile_overlaps: if matches.is_present("Reconcile Overlaps") {
+                Some(
+                    OverlapResolution::parse(
+                        matches.value_of("Overlap Resolution").unwrap_or("annotate"),
+                    )
+                    .unwrap(),
+                )
+            } else {
+                None
+            },
+            overlap_window: matches
+                .value_of("Overlap Window")
+                .unwrap_or("50")
+                .parse::<u64>()
+                .expect("Please enter a positive overlap window or omit the argument"),
+            annotation: matches
+                .value_of("Annotation")
+                .map(|annotation| annotation.to_owned()),
+            coverage: matches
+                .value_of("Coverage")
+                .map(|coverage| coverage.to_owned()),
+            zygosity_thresholds: match matches.value_of("Zygosity Thresholds") {
+                Some(thresholds) => match ZygosityThresholds::parse(thresholds) {
+                    Some(thresholds) => thresholds,
+                    None => {
+                        eprintln!(
+                            "Invalid zygosity thresholds (expected HET,HOM with 0 < HET <= HOM): {}",
+                            thresholds
+                        );
+                        std::process::exit(2);
+                    }
+                },
+                None => ZygosityThresholds::DEFAULT,
+            },
+            diagnostics_json: matches
+                .value_of("Diagnostics JSON")
+                .map(|diagnostics_json| diagnostics_json.to_owned()),
+            support_reads_cap: if matches.is_present("Emit Support Reads") {
+                Some(
+                    matches
+                        .value_of("Max Support Reads")
+                        .unwrap_or("20")
+                        .parse::<usize>()
+                        .expect("Please enter a positive number of supporting reads or omit the argument"),
+                )
+            } else {
+                None
+            },
+            emit_boundary_positions: matches.is_present("Emit Boundary Positions"),
+            buffer_output: matches.is_present("Buffer Output"),
+            split_by_chromosome: matches.is_present("Split By Chromosome"),
+            output: matches.value_of("Output").map(|output| output.to_owned()),
+            strict: matches.is_present("Strict"),
+            abort_on_empty_output: matches.is_present("Abort On Empty Output"),
+            profile: matches.is_present("Profile"),
+            overwrite: matches.is_present("Overwrite"),
+            insertion_types: match matches.value_of("Recall") {
+                Some(recall) => InsertionTypes::parse_recall(recall).unwrap(),
+                None => InsertionTypes {
+                    non_reference: !matches.is_present("No Non-Reference TEs"),
+                    // a long read doesn't show the ends of a reference insertion as split-reads do
+                    // (and the genome alignment of a read in a reference insertion isn't clipped)
+                    reference: !matches.is_present("No Reference TEs")
+                        && !matches.is_present("Long Reads")
+                        && !matches.is_present("Genome First"),
+                },
+            },
         };
+        if options.bgzip && options.output_format.tabix_columns().is_none() {
+            eprintln!("--bgzip only works with the sorted TSV, GFF3, VCF and McClintock outputs");
+            std::process::exit(2);
+        }
+        if options.stream && options.phase > 3 {
+            eprintln!("--stream aligns the reads to the genome in phase 4, so phase 4 can't be re-run on its own with it (genome_aligned.sam is only written without --stream)");
+            std::process::exit(2);
+        }
+        if options.genome_first && references.len() > 1 {
+            eprintln!("--genome-first only works with a single reference (--ref)");
+            std::process::exit(2);
+        }
+        if options.vcf_read_evidence && options.output_format != OutputFormat::Vcf {
+            eprintln!("--vcf-read-evidence only works with the VCF output (--format vcf)");
+            std::process::exit(2);
+        }
+        if options.compact_json
+            && options.output_format != OutputFormat::Json
+            && options.output_format != OutputFormat::Ndjson
+        {
+            eprintln!("--compact-json only works with the JSON and NDJSON outputs (--format json or ndjson)");
+            std::process::exit(2);
+        }
+        if options.breakpoint_ranges
+            && options.output_format != OutputFormat::Tsv
+            && options.output_format != OutputFormat::Json
+            && options.output_format != OutputFormat::Ndjson
+        {
+            eprintln!("--breakpoint-ranges only works with the TSV, JSON and NDJSON outputs (--vcf-read-evidence adds the same ranges to the VCF output)");
+            std::process::exit(2);
+        }
+        if options.output.as_deref() == Some(STDOUT_PATH) {
+            if options.bgzip {
+                eprintln!("--bgzip needs an output file, not stdout");
+                std::process::exit(2);
+            }
+            utils::progress_to_stderr();
+        }
         if paired_ends {
             let reads1 = matches.value_of("Reads1").unwrap();
             let reads2 = matches.value_of("Reads2").unwrap();
+            if matches.is_present("Validate Pairs") {
+                if let Err(e) = utils::validate_read_pairs(reads1, reads2) {
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                }
+            }
             let reads_struct = Reads::PairedEnds(reads1.to_owned(), reads2.to_owned());
             sx_map::map(
-                reference,
+                &references,
                 &reads_struct,
                 transposons,
                 result_dir,
-                bwa_threads,
-                json_output,
-                phase,
+                &options,
             );
         } else {
             let reads = match matches.value_of("Reads") {
@@ -97,15 +557,17 @@ fn main() -> Result<(), Box<dyn Error>> {
                     std::process::exit(2);
                 }
             };
-            let reads_struct = Reads::SingleEnd(reads.to_owned());
+            let reads_struct = if matches.is_present("Interleaved") {
+                Reads::Interleaved(reads.to_owned())
+            } else {
+                Reads::SingleEnd(reads.to_owned())
+            };
             sx_map::map(
-                reference,
+                &references,
                 &reads_struct,
                 transposons,
                 result_dir,
-                bwa_threads,
-                json_output,
-                phase,
+                &options,
             );
         }
     }
@@ -115,7 +577,91 @@ fn main() -> Result<(), Box<dyn Error>> {
         let reference = matches.value_of("Reference").unwrap();
         // let transposons = matches.value_of("Transposons File").unwrap();
         let result_dir = matches.value_of("Result Directory").unwrap();
-        sg_utils::tile_ref::tile_ref(reference, result_dir);
+        sg_utils::tile_ref::tile_ref(reference, result_dir, matches.is_present("Overwrite"));
+    }
+
+    // handle "catalog" subcommand
+    if let Some(matches) = app_matches.subcommand_matches("catalog") {
+        let reference = matches.value_of("Reference").unwrap();
+        let transposons = matches.value_of("Transposons File").unwrap();
+        let result_dir = matches.value_of("Result Directory").unwrap();
+        let options = sx_map::MapOptions {
+            bwa_threads: bwa_threads(matches, 8),
+            bwa_options: bwa_options(matches),
+            chromosomes: matches
+                .values_of("Chromosomes")
+                .map(|values| values.map(|value| value.trim().to_owned()).collect()),
+            overwrite: matches.is_present("Overwrite"),
+            ..Default::default()
+        };
+        sx_catalog::catalog(reference, transposons, result_dir, options);
+    }
+
+    // handle "index" subcommand
+    if let Some(matches) = app_matches.subcommand_matches("index") {
+        let mut fasta_names: Vec<&str> = matches.values_of("Reference").unwrap().collect();
+        if let Some(transposons) = matches.value_of("Transposons File") {
+            fasta_names.push(transposons);
+        }
+        for fasta_name in fasta_names {
+            utils::bwa_index_if_required(fasta_name);
+            println!("BWA index of {}:", fasta_name);
+            for index_file in utils::bwa_index_files(fasta_name) {
+                println!("{}", index_file);
+            }
+        }
+    }
+
+    // handle "check-library" subcommand
+    if let Some(matches) = app_matches.subcommand_matches("check-library") {
+        let transposons = matches.value_of("Transposons File").unwrap();
+        let min_length = match matches.value_of("Min Length") {
+            Some(num) => num
+                .parse::<u64>()
+                .expect("Please enter a positive minimum length or omit the argument"),
+            None => sx_check_library::DEFAULT_MIN_LENGTH,
+        };
+        sx_check_library::check_library_file(
+            transposons,
+            min_length,
+            matches.is_present("Families"),
+        );
+    }
+
+    // handle "chrom-sizes" subcommand
+    if let Some(matches) = app_matches.subcommand_matches("chrom-sizes") {
+        sx_chrom_sizes::chrom_sizes(
+            matches.value_of("Reference").unwrap(),
+            matches.value_of("Output"),
+        );
+    }
+
+    // handle "versions" subcommand
+    if app_matches.subcommand_matches("versions").is_some() {
+        sx_versions::print_versions();
+    }
+
+    // handle (hidden) "debug-cigar" subcommand
+    if let Some(matches) = app_matches.subcommand_matches("debug-cigar") {
+        let cigar = matches.value_of("CIGAR").unwrap();
+        let pos = matches
+            .value_of("Position")
+            .unwrap()
+            .parse::<u64>()
+            .expect("Please enter a positive position");
+        let old_m = matches
+            .value_of("Old M")
+            .unwrap_or("0")
+            .parse::<u64>()
+            .expect("Please enter a positive old M or omit the argument");
+        let old_s = matches
+            .value_of("Old S")
+            .unwrap_or("0")
+            .parse::<u64>()
+            .expect("Please enter a positive old S or omit the argument");
+        let is_start = !matches.is_present("TE End");
+        let new_plus = !matches.is_present("Reverse");
+        te_mapper_utils::debug_cigar::debug_cigar(cigar, pos, old_m, old_s, is_start, new_plus);
     }
 
     return Ok(());
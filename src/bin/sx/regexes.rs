@@ -8,6 +8,14 @@ lazy_static! {
     pub static ref HM_REGEX: Regex = Regex::new(r"^(\d+)H(\d+)M$").unwrap();
     pub static ref MH_REGEX: Regex = Regex::new(r"^(\d+)M(\d+)H$").unwrap();
     pub static ref M_REGEX: Regex = Regex::new(r"^\d+M$").unwrap();
+    // any CIGAR string, and its operations (for long reads)
+    pub static ref CIGAR_REGEX: Regex = Regex::new(r"^(\d+[MIDNSHP=X])+$").unwrap();
+    pub static ref CIGAR_OP_REGEX: Regex = Regex::new(r"(\d+)([MIDNSHP=X])").unwrap();
+    // BWA MEM progress lines (printed to stderr for every batch of reads)
+    pub static ref BWA_READ_BATCH_REGEX: Regex =
+        Regex::new(r"^\[M::process\] read (\d+) sequences \((\d+) bp\)").unwrap();
+    pub static ref BWA_PROCESSED_BATCH_REGEX: Regex =
+        Regex::new(r"^\[M::mem_process_seqs\] Processed (\d+) reads").unwrap();
 }
 
 // get a numeric capture from a regex less verbosely
@@ -27,3 +35,13 @@ pub fn get_capture<'t>(captures: Option<regex::Captures<'t>>, which: usize) -> u
         .parse()
         .unwrap()
 }
+
+// get the 2 lengths of a CIGAR string with 2 operations (e.g. 10S140M), in order
+// unlike get_capture, this doesn't assume anything about the string: it returns None if
+// the CIGAR string doesn't match the regex or if a length doesn't fit in a u64
+pub fn get_cigar_lengths(regex: &Regex, cigar: &str) -> Option<(u64, u64)> {
+    let captures = regex.captures(cigar)?;
+    let first = captures.get(1)?.as_str().parse().ok()?;
+    let second = captures.get(2)?.as_str().parse().ok()?;
+    Some((first, second))
+}
@@ -1,5 +1,6 @@
 use path_abs::PathFile;
 
+use std::fmt;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -12,6 +13,10 @@ pub struct ILoc {
     pub chrom: String,
     pub upstream_pos: u64,
     pub downstream_pos: u64,
+    // the transposon and its orientation (columns 4 and 5 of the TE mapper output, e.g. "roo" and "+/+"),
+    // to report which element was removed at each excision
+    pub te_name: String,
+    pub orientation: String,
 }
 
 impl ILoc {
@@ -25,6 +30,8 @@ impl ILoc {
                 chrom: fields[0].to_owned(),
                 upstream_pos: FromStr::from_str(fields[1]).unwrap(),
                 downstream_pos: FromStr::from_str(fields[2]).unwrap(),
+                te_name: fields[4].to_owned(),
+                orientation: fields[3].to_owned(),
             });
         } else {
             return None;
@@ -53,3 +60,42 @@ impl ILoc {
         pos >= self.upstream_pos && pos <= self.downstream_pos && chrom == &self.chrom
     }
 }
+
+// e.g. "roo (+/+) at 2L:1000-10091", for logging the excised elements
+impl fmt::Display for ILoc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) at {}:{}-{}",
+            self.te_name, self.orientation, self.chrom, self.upstream_pos, self.downstream_pos
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ILoc;
+
+    use path_abs::PathFile;
+
+    #[test]
+    fn test_read_file() {
+        let tsv_name =
+            std::env::temp_dir().join(format!("sx_test_iloc_{}.tsv", std::process::id()));
+        std::fs::write(
+            &tsv_name,
+            "#CHROM\tSTART\tEND\tSTRAND\tTE_NAME\tUP_READS\tDOWN_READS\tTYPE\n\
+             2L\t1000\t1005\t+/+\troo\t2\t1\tnon-reference\n\
+             3R\t4990\t10000\t+/-\tjockey\t1\t1\treference\n",
+        )
+        .unwrap();
+        let ilocs = ILoc::read_file(PathFile::new(&tsv_name).unwrap());
+        std::fs::remove_file(&tsv_name).unwrap();
+        // only the reference insertions are kept, with their transposon
+        assert_eq!(ilocs.len(), 1);
+        assert_eq!(ilocs[0].te_name, "jockey");
+        assert_eq!(ilocs[0].orientation, "+/-");
+        assert_eq!(ilocs[0].length(), 5011);
+        assert_eq!(ilocs[0].to_string(), "jockey (+/-) at 3R:4990-10000");
+    }
+}
@@ -1,3 +1,6 @@
+// coordinate shifting is not wired into the CLI yet
+#[allow(dead_code)]
 pub mod coord_shift;
+#[allow(dead_code)]
 pub mod iloc;
 pub mod tile_ref;
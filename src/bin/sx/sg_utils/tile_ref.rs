@@ -1,6 +1,6 @@
-use path_abs::PathDir;
-
+use std::collections::VecDeque;
 use std::fs::File;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
@@ -8,32 +8,53 @@ use std::io::Write;
 use std::str::FromStr;
 
 use crate::utils;
+use crate::utils::{log_error, IO_BUFFER_CAPACITY};
+
+// the length of the artificial reads
+const READ_LENGTH: usize = 150;
+
+// the tiled reads in the output directory
+pub const TILED_READS_FILE: &str = "tiled_ref.fastq";
 
 // tile a set of artificial 150-bp-wide artificial "reads" across the reference genome
 // this will then be used by the TE mapper to look for transposons to find
 // all possible transposons within the reference genome
 // (from the list of possible transposon sequences that we are looking for)
 
-pub fn tile_ref(ref_path: &str, output_dir: &str) {
+pub fn tile_ref(ref_path: &str, output_dir: &str, overwrite: bool) {
     let _ref_path_checked = utils::absolute_filepath_checked(ref_path);
-    let _output_dir_unchecked = PathDir::create(output_dir);
-    let output_path = format!("{}/{}", output_dir, "tiled_ref.fastq");
-    let lines = BufReader::new(File::open(ref_path).unwrap())
-        .lines()
-        .map(|l| l.unwrap());
-    let mut writer = BufWriter::new(File::create(output_path).unwrap());
+    utils::create_result_dir(output_dir);
+    utils::check_overwrite(output_dir, &[TILED_READS_FILE.to_owned()], overwrite);
+    let output_path = format!("{}/{}", output_dir, TILED_READS_FILE);
+    let reader = BufReader::with_capacity(IO_BUFFER_CAPACITY, File::open(ref_path).unwrap());
+    let mut writer =
+        BufWriter::with_capacity(IO_BUFFER_CAPACITY, File::create(&output_path).unwrap());
+    // the reads are flushed explicitly, since a write error would be lost if this were left to drop
+    if let Err(e) = tile_reads(reader, &mut writer).and_then(|_| writer.flush()) {
+        log_error!("Unable to write the tiled reads {}: {}", output_path, e);
+        std::process::exit(1);
+    }
+}
+
+// write the tiled reads of a FASTA file to a FASTQ writer, and return the number of reads
+// the window is a ring buffer, so that sequences on a single (unwrapped) line of a whole
+// chromosome take linear time
+fn tile_reads<R: BufRead, W: Write>(reader: R, writer: &mut W) -> io::Result<u64> {
     // store current state information
     // such as chromosome name & length, original position,
-    // current read number & name, and char buffer
+    // current read number, and the window of the last READ_LENGTH nucleotides
     let mut chrom = String::new();
     let mut chrom_length: u64 = 0;
     let mut original_pos: u64 = 1;
     let mut read_num: u64 = 1;
-    let mut read_name;
-    let mut buffer = String::new();
-    for line in lines {
+    let mut num_reads: u64 = 0;
+    let mut buffer: VecDeque<u8> = VecDeque::with_capacity(READ_LENGTH + 1);
+    // use ~ for quality score to indicate highest quality
+    let quality_bytes: Vec<u8> = vec![0x7e; READ_LENGTH];
+    for line in reader.lines() {
+        let line = line?;
         // FASTA header line
-        if line.chars().nth(0) == Some('>') {
+        if line.starts_with('>') {
             let fields: Vec<&str> = (&line[..]).split(" ").collect();
             chrom = fields[0][1..].to_owned();
             chrom_length = FromStr::from_str(
@@ -48,10 +69,10 @@ pub fn tile_ref(ref_path: &str, output_dir: &str) {
             .unwrap();
             original_pos = 1;
             read_num = 1;
-            buffer = String::new();
+            buffer.clear();
         } else {
-            // process the line char by char
-            for nt in line.chars() {
+            // process the line nucleotide by nucleotide
+            for nt in line.bytes() {
                 // print out status every 1,000,000 nts processed
                 if original_pos % 1_000_000 == 0 {
                     println!("Processing chromosome {}: position {}", chrom, original_pos);
@@ -60,29 +81,90 @@ pub fn tile_ref(ref_path: &str, output_dir: &str) {
                 if original_pos == chrom_length {
                     println!("Done processing chromosome {}", chrom);
                 }
-                // add the last character to the buffer
-                buffer.push(nt);
-                // if the buffer is over-full, remove the first character
-                if buffer.len() == 151 {
-                    buffer.remove(0);
+                // add the last nucleotide to the buffer
+                buffer.push_back(nt);
+                // if the buffer is over-full, remove the first nucleotide
+                if buffer.len() > READ_LENGTH {
+                    buffer.pop_front();
                 }
                 // generate fastq read if the buffer is full
-                // (use ~ for quality score to indicate highest quality)
-                if buffer.len() == 150 {
-                    read_name = format!("{}_Read_{}", chrom, read_num);
-                    let mut quality_bytes: Vec<u8> = Vec::with_capacity(buffer.len());
-                    for _ in 0..buffer.len() {
-                        quality_bytes.push(0x7e);
-                    }
-                    let quality_str = String::from_utf8(quality_bytes).unwrap();
-                    let fastq = format!("@{}\n{}\n+\n{}\n", read_name, buffer, quality_str);
-                    writer.write(fastq.as_bytes()).unwrap();
-                    // update counter and buffer
+                if buffer.len() == READ_LENGTH {
+                    writeln!(writer, "@{}_Read_{}", chrom, read_num)?;
+                    let (front, back) = buffer.as_slices();
+                    writer.write_all(front)?;
+                    writer.write_all(back)?;
+                    writer.write_all(b"\n+\n")?;
+                    writer.write_all(&quality_bytes)?;
+                    writer.write_all(b"\n")?;
+                    // update counters
                     read_num += 1;
+                    num_reads += 1;
                 }
                 // update counter
                 original_pos += 1;
             }
         }
     }
+    return Ok(num_reads);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tile_reads, READ_LENGTH};
+
+    use std::io::Cursor;
+    use std::time::Instant;
+
+    // a FlyBase-style header (the chromosome length is in the "loc" field)
+    fn header(chrom: &str, length: usize) -> String {
+        format!(
+            ">{} type=golden_path_region; loc={}:1..{}; ID={};\n",
+            chrom, chrom, length, chrom
+        )
+    }
+
+    #[test]
+    fn test_tile_reads() {
+        // a wrapped chromosome and an unwrapped one (the window restarts at every header)
+        let sequence_2l: String = "ACGT".repeat(40);
+        let wrapped_2l: Vec<&str> = vec![
+            &sequence_2l[..60],
+            &sequence_2l[60..120],
+            &sequence_2l[120..],
+        ];
+        let sequence_4: String = "T".repeat(149) + "G";
+        let fasta = format!(
+            "{}{}\n{}{}\n",
+            header("2L", 160),
+            wrapped_2l.join("\n"),
+            header("4", 150),
+            sequence_4
+        );
+        let mut fastq: Vec<u8> = Vec::new();
+        let num_reads = tile_reads(Cursor::new(fasta), &mut fastq).unwrap();
+        assert_eq!(num_reads, 11 + 1);
+        let fastq = String::from_utf8(fastq).unwrap();
+        let lines: Vec<&str> = fastq.lines().collect();
+        assert_eq!(lines.len(), 4 * 12);
+        assert_eq!(lines[0], "@2L_Read_1");
+        assert_eq!(lines[1], &sequence_2l[..READ_LENGTH]);
+        assert_eq!(lines[2], "+");
+        assert_eq!(lines[3], "~".repeat(READ_LENGTH));
+        assert_eq!(lines[40], "@2L_Read_11");
+        assert_eq!(lines[41], &sequence_2l[10..]);
+        assert_eq!(lines[44], "@4_Read_1");
+        assert_eq!(lines[45], sequence_4);
+    }
+
+    // a benchmark of a 10 MB chromosome on a single line (run it with "cargo test --release -- --ignored")
+    #[test]
+    #[ignore]
+    fn bench_tile_unwrapped_line() {
+        let length = 10_000_000;
+        let fasta = header("2L", length) + &"ACGT".repeat(length / 4) + "\n";
+        let start_time = Instant::now();
+        let num_reads = tile_reads(Cursor::new(fasta), &mut std::io::sink()).unwrap();
+        println!("tiled a {} bp line in {:?}", length, start_time.elapsed());
+        assert_eq!(num_reads, (length - READ_LENGTH + 1) as u64);
+    }
 }
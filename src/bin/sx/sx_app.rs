@@ -2,6 +2,55 @@
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
+// allow a run to replace the output files of an earlier run (shared by the subcommands with a result directory)
+fn overwrite_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Overwrite")
+        .long("overwrite")
+        .takes_value(false)
+        .help("use this argument to replace the output files of an earlier run in the result directory (by default, the run stops before replacing any of them)")
+        .required(false)
+}
+
+// lets the BWA (and mapper) threads exceed the available CPUs, which are capped to them by default
+fn allow_oversubscribe_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Allow Oversubscribe")
+        .long("allow-oversubscribe")
+        .takes_value(false)
+        .help("use this argument to run with more threads than the available CPUs (by default, --threads, --bwa-threads and --mapper-threads are capped to the number of CPUs, with a warning)")
+        .required(false)
+}
+
+// the options that tune the BWA MEM alignments (shared by the subcommands that run BWA)
+fn bwa_option_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("BWA k")
+            .long("bwa-k")
+            .takes_value(true)
+            .value_name("INT")
+            .help("the minimum seed length of BWA MEM (bwa mem -k; default 19), lower it to find split-reads with shorter flanks")
+            .required(false),
+        Arg::with_name("BWA T")
+            .long("bwa-T")
+            .takes_value(true)
+            .value_name("INT")
+            .help("the minimum score of the alignments that BWA MEM outputs (bwa mem -T; default 30)")
+            .required(false),
+        Arg::with_name("BWA L")
+            .long("bwa-L")
+            .takes_value(true)
+            .value_name("INT[,INT]")
+            .help("the 5' and 3' clipping penalties of BWA MEM (bwa mem -L; default 5,5)")
+            .required(false),
+        Arg::with_name("BWA Extra")
+            .long("bwa-extra")
+            .takes_value(true)
+            .value_name("ARGS")
+            .help("other bwa mem options, e.g. \"-A 2 -B 6\" (only bwa mem options are allowed, and not -t, -o or -p)")
+            .allow_hyphen_values(true)
+            .required(false),
+    ]
+}
+
 // the download subcommand
 fn download_sc() -> App<'static, 'static> {
     SubCommand::with_name("download")
@@ -21,9 +70,40 @@ fn download_sc() -> App<'static, 'static> {
                 .long("output")
                 .takes_value(true)
                 .value_name("FILE")
-                .help("the path to your output file (relative or absolute)")
+                .help("the path to your output file (relative or absolute); the download is written to <FILE>.partial and only renamed to <FILE> once it is complete, so an existing non-empty <FILE> is reused (checked against --sha256 and --size if they are given)")
                 .required(true)
         )
+        .arg(
+            Arg::with_name("SHA-256")
+                .long("sha256")
+                .takes_value(true)
+                .value_name("CHECKSUM")
+                .help("the expected SHA-256 checksum of the file (an existing output file is only reused if it matches)")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("Size")
+                .long("size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("the expected size of the file in bytes (an existing output file is only reused if it matches)")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("Force")
+                .long("force")
+                .takes_value(false)
+                .help("use this argument to download the file again even if a complete copy already exists")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("Retries")
+                .long("retries")
+                .takes_value(true)
+                .value_name("NUM")
+                .help("the number of times to retry a failed download, with an exponential backoff, resuming from the partial file (default: 3)")
+                .required(false)
+        )
 }
 
 // the variants subcommand
@@ -47,12 +127,21 @@ fn variants_sc() -> App<'static, 'static> {
                 .requires_all(&["Reads1", "Reads2"]),
                 
         )
+        .arg(
+            Arg::with_name("Interleaved")
+                .long("interleaved")
+                .takes_value(false)
+                .help("use this argument if the reads file given with --reads contains interleaved paired-end reads")
+                .required(false)
+                .conflicts_with("Paired-Ends")
+                .requires("Reads"),
+        )
         .arg(
             Arg::with_name("Reads")
                 .long("reads")
                 .takes_value(true)
                 .value_name("FILE")
-                .help("the path to the single-end reads FASTQ file (relative or absolute)")
+                .help("the path to the single-end (or interleaved paired-end) reads FASTQ file (relative or absolute)")
                 .conflicts_with("Paired-Ends"),
         )
         .arg(
@@ -69,6 +158,22 @@ fn variants_sc() -> App<'static, 'static> {
                 .value_name("FILE")
                 .help("the path to the 2nd of 2 reads FASTQ files for paired-ends (relative or absolute)")
         )
+        .arg(
+            Arg::with_name("Validate Pairs")
+                .long("validate-pairs")
+                .takes_value(false)
+                .help("use this argument to check that the 2 paired-end reads files have the same number of reads and that their first and last reads are mates before aligning (this reads both files once)")
+                .required(false)
+                .requires("Paired-Ends"),
+        )
+        .arg(
+            Arg::with_name("BAM")
+                .long("bam")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path to an already-aligned BAM file sorted by coordinate (relative or absolute); if present, alignment is skipped and variants are called directly from this file")
+                .conflicts_with_all(&["Reads", "Paired-Ends", "Interleaved"]),
+        )
         .arg(
             Arg::with_name("Result Directory")
             .long("result")
@@ -86,6 +191,17 @@ fn variants_sc() -> App<'static, 'static> {
                 .help("the number of threads to run BWA with (default value 1; choose 1 if you want a deterministic output; choose higher numbers to run faster while taking up more memory)")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("Keep Temp SAM")
+                .long("keep-temp-sam")
+                .takes_value(false)
+                .help("use this argument to write the raw BWA alignments to raw_alignments.sam (e.g. for debugging) before fixing them with samtools fixmate; by default they are piped straight into samtools fixmate, which saves a lot of disk space")
+                .required(false)
+                .conflicts_with("BAM"),
+        )
+        .arg(overwrite_arg())
+        .arg(allow_oversubscribe_arg())
+        .args(&bwa_option_args())
 }
 
 // the TE mapper subcommand
@@ -97,7 +213,9 @@ fn mapper_sc() -> App<'static, 'static> {
                 .long("ref")
                 .takes_value(true)
                 .value_name("FILE")
-                .help("the path to the reference sequence FASTA file (relative or absolute)")
+                .multiple(true)
+                .number_of_values(1)
+                .help("the path to the reference sequence FASTA file (relative or absolute); repeat this argument to map against several references (e.g. the sub-genomes of a hybrid), in which case each insertion is labeled with the reference it was found in")
                 .required(true),
         )
         .arg(
@@ -114,9 +232,75 @@ fn mapper_sc() -> App<'static, 'static> {
                 .short("j")
                 .long("json")
                 .takes_value(false)
-                .help("use this argument if you want results to be printed in JSON (useful when passing output as input to other programs, or just for convenience)")
+                .help("use this argument if you want results to be printed in JSON (useful when passing output as input to other programs, or just for convenience); same as --format json")
+                .required(false)
+                .conflicts_with("Format"),
+        )
+        .arg(
+            Arg::with_name("Format")
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["tsv", "json", "gff3", "vcf", "ndjson", "mcclintock"])
+                .help("the format of the output file (default: tsv); gff3 writes one mobile_genetic_element feature per insertion, e.g. for JBrowse/Apollo annotation tracks; vcf writes one <INS:ME> record per insertion with a single sample column; ndjson writes one JSON object per line and insertion, with a kind field (reference or non-reference); mcclintock writes the BED6 of the McClintock TE caller benchmarks (te_mapper_output.bed, no header, 0-based half-open coordinates: the TSD of a non-reference insertion and the element of a reference insertion, named <family>_non-reference_<N> or <family>_reference_<N> with the transposon name without its #class/family suffix and N the number of the insertion in the file, score 0)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Compact JSON")
+                .long("compact-json")
+                .takes_value(false)
+                .help("use this argument to write the JSON output (--json or --format json) on a single line instead of pretty-printing it, which makes the output of a genome-wide run with its split-reads much smaller; the NDJSON output is always one compact object per line")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Sample")
+                .long("sample")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("the sample name used as the VCF sample column and recorded in te_mapper_manifest.json (default: the reads file name without its extensions)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Header Style")
+                .long("header-style")
+                .takes_value(true)
+                .value_name("STYLE")
+                .possible_values(&["bergman", "generic"])
+                .help("the column names of the TSV output (default: bergman); bergman uses the descriptive names (e.g. \"TSD Upstream\"), generic uses short names for pipelines (e.g. chrom, start, end)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Sorted")
+                .long("sorted")
+                .takes_value(false)
+                .help("use this argument to sort the TSV, GFF3 or VCF output by genomic coordinate across all chromosomes, so that it can be compressed with bgzip and indexed with tabix")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("Chromosome Order")
+                .long("chrom-order")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("a .fai or .genome file with the chromosome order used by --sorted (default: the order of the reference genome's .fai index)")
+                .required(false)
+                .requires("Sorted"),
+        )
+        .arg(
+            Arg::with_name("Bgzip")
+                .long("bgzip")
+                .takes_value(false)
+                .help("use this argument to compress the sorted TSV, GFF3, VCF or McClintock output with bgzip (replacing the original file) and index it with tabix (.tbi)")
+                .required(false)
+                .requires("Sorted"),
+        )
+        .arg(
+            Arg::with_name("Gzip Output")
+                .long("gzip-output")
+                .takes_value(false)
+                .help("use this argument to compress the output with gzip (te_mapper_output.<format>.gz)")
+                .required(false)
+                .conflicts_with("Bgzip"),
+        )
         .arg(
             Arg::with_name("phase")
             .long("phase")
@@ -124,12 +308,438 @@ fn mapper_sc() -> App<'static, 'static> {
             .help("the TE mapper phase to start on (default: 1)")
             .required(false)
         )
+        .arg(
+            Arg::with_name("TE Aligned")
+            .long("te-aligned")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("an existing alignment of the reads to the transposons (te_aligned.sam from phase 1, relative or absolute) to use instead of running phase 1")
+            .required(false)
+            .conflicts_with("From Cache")
+        )
+        .arg(
+            Arg::with_name("Cache Alignments")
+            .long("cache-alignments")
+            .takes_value(false)
+            .help("use this argument to save the parsed genome alignments to alignments.bin in the result directory during phase 4, so that phase 4 can later be re-run with --from-cache")
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("From Cache")
+            .long("from-cache")
+            .takes_value(false)
+            .help("use this argument to re-run only phase 4 using the genome alignments saved by --cache-alignments instead of genome_aligned.sam")
+            .required(false)
+            .conflicts_with("Cache Alignments")
+        )
+        .arg(
+            Arg::with_name("Recall")
+            .long("recall")
+            .takes_value(true)
+            .value_name("TYPE")
+            .possible_values(&["reference", "nonreference"])
+            .help("re-call only the reference or only the non-reference insertions from the genome alignments saved by --cache-alignments (implies --from-cache), e.g. to tune --reference-length-window or --max-tsd-length; the output and manifest get the type in their names (e.g. te_mapper_output.reference.tsv), so the outputs of the full run are kept")
+            .required(false)
+            .conflicts_with_all(&["Cache Alignments", "No Reference TEs", "No Non-Reference TEs", "Long Reads"])
+        )
+        .arg(
+            Arg::with_name("Stream")
+            .long("stream")
+            .takes_value(false)
+            .help("use this argument to parse the genome alignments in phase 4 while bwa mem writes them instead of writing genome_aligned.sam in phase 3 and reading it back, which saves the disk space and I/O of the largest intermediate file (tens of GB for a whole genome); the alignments are parsed on a single thread (at the pace of bwa mem), and phase 4 can't be re-run on its own afterwards (--phase 4 needs genome_aligned.sam from a run without --stream)")
+            .required(false)
+            .conflicts_with_all(&["Cache Alignments", "From Cache", "Recall", "Emit Support Reads"])
+        )
+        .arg(
+            Arg::with_name("Reference Length Window")
+            .long("reference-length-window")
+            .takes_value(true)
+            .value_name("MIN,MAX")
+            .help("the shortest and longest reference insertion, as fractions of the length of the transposon in the library (default: 0.1,1.5)")
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("Interleaved")
+                .long("interleaved")
+                .takes_value(false)
+                .help("use this argument if the reads file given with --reads contains interleaved paired-end reads")
+                .required(false)
+                .conflicts_with("Paired-Ends")
+                .requires("Reads"),
+        )
+        .arg(
+            Arg::with_name("SAM Layout")
+                .long("sam-layout")
+                .takes_value(true)
+                .value_name("LAYOUT")
+                .help("the columns of the SAM fields if the alignment files don't use the standard SAM column order, e.g. QNAME=1,FLAG=2,RNAME=3,POS=4,CIGAR=6,SEQ=10 (fields that are left out keep their standard column)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Rename Contigs")
+                .long("rename-contigs")
+                .takes_value(true)
+                .value_name("OLD=NEW")
+                .multiple(true)
+                .use_delimiter(true)
+                .number_of_values(1)
+                .help("rename the contigs of the reference genomes before they are matched against the chromosome list, e.g. chr2L=2L,chrX=X (a value without \"=\" is a tab-separated file with the old and new names); can be repeated or comma-separated")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Chromosomes")
+                .long("chromosomes")
+                .takes_value(true)
+                .value_name("CHROM")
+                .multiple(true)
+                .use_delimiter(true)
+                .number_of_values(1)
+                .help("the chromosomes (contigs) to call insertions on, after --rename-contigs, e.g. 2L,2R,3L,3R (default: the Drosophila Melanogaster chromosomes 2L, 2R, 3L, 3R, 4, X and Y); can be repeated or comma-separated, and is merged with --chromosomes-file")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Chromosomes File")
+                .long("chromosomes-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("a file with one chromosome (contig) to call insertions on per line, for genomes with many scaffolds (blank lines and \"#\" comments are ignored); merged with --chromosomes")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Min Contig Length")
+                .long("min-contig-length")
+                .takes_value(true)
+                .value_name("BP")
+                .help("skip the chromosomes (contigs) shorter than this in the reference's \".fai\" index (default: 0), e.g. the tiny scaffolds of a draft assembly, whose calls are usually unreliable; the number of skipped contigs and their share of the genome length are reported")
+                .conflicts_with("Region")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Region")
+                .long("region")
+                .takes_value(true)
+                .value_name("CHROM:START-END")
+                .help("only call insertions at a single locus, e.g. 2L:1000000-1050000 (one-based, after --rename-contigs); only the split-reads with a breakpoint in the region are used, so reference insertions that extend past it are only reported with --report-one-sided")
+                .conflicts_with_all(&["Chromosomes", "Chromosomes File"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("TE Include")
+                .long("te-include")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .number_of_values(1)
+                .value_name("PATTERN")
+                .help("only map the transposons that match one of these names or glob patterns (matched against the transposon name, its #class/family suffix, its class and its family, e.g. \"LTR\" or \"LTR/Gypsy\"); can be repeated or comma-separated")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("TE Exclude")
+                .long("te-exclude")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .number_of_values(1)
+                .value_name("PATTERN")
+                .help("do not map the transposons that match one of these names or glob patterns (same matching as --te-include, and applied after it)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Family Map")
+                .long("family-map")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("a 2-column (transposon, family) tab-separated file that collapses the sub-families of a redundant transposon library into families: the split-reads of all the sub-families of a family are pooled and its insertions are called under the family name (the transposons that aren't in the file keep their own names); only the transposons in the file are collapsed: names are matched in full, so transposons that only share the part before a \"#class/family\" suffix (e.g. \"roo#LTR/Bel-Pao\" and \"roo\") are kept apart unless the file maps them to the same family; the length of a family, e.g. for the window of reference insertion lengths, is the length of its longest transposon, so that a full-length copy of any of them is still called; it is applied when the reads are selected in phase 2")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Family Params")
+                .long("family-params")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("a 5-column (family, min TSD, max TSD, min length, max length) tab-separated file of the TSD windows of the non-reference insertions and the length windows of the reference insertions (as in --reference-length-window) of some families, e.g. 7 to 9 bp for the P-element; a family is the name that its insertions are called under (with or without its #class/family suffix), \".\" keeps the global window, and the families that aren't in the file use the global windows")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("TE Lengths")
+                .long("te-lengths")
+                .takes_value(true)
+                .value_name("TSV")
+                .help("a 2-column (transposon, length) tab-separated file with the lengths of the transposons (e.g. from the library), used instead of the lengths of the @SQ lines of the TE alignment; the transposons of the alignment that aren't in the file keep their @SQ lengths, with a warning")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Case-Insensitive TE Names")
+                .long("case-insensitive-te-names")
+                .takes_value(false)
+                .help("use this argument to match the transposon names of the TE alignments (and of --te-lengths) to the @SQ lines of the TE alignment whatever their case (e.g. Roo and roo); the insertions are named as in the @SQ lines")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Report One-Sided")
+                .long("report-one-sided")
+                .takes_value(false)
+                .help("use this argument to keep the insertions with split-reads on only one end (e.g. near a contig edge or at low coverage) instead of discarding them; they are marked in a one-sided column (TSV), a one_sided attribute (GFF3) or the OneSided filter (VCF)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Count Fragments")
+                .long("count-fragments")
+                .takes_value(false)
+                .help("use this argument to also count the distinct fragments (reads or read pairs, by read name) that support each end of an insertion, since both mates of a pair can be split at the same end; the fragment counts are written besides the read counts (as upstream/downstream fragment columns in the TSV, attributes in the GFF3 and INFO fields in the VCF)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Breakpoint Ranges")
+                .long("breakpoint-ranges")
+                .takes_value(false)
+                .help("use this argument to also report how precisely the split-reads of each end of an insertion agree on its breakpoint: the first and last boundaries of the split-reads (as MIN-MAX) and the number of nucleotides between them (the spread) are written as upstream/downstream breakpoint range and spread columns in the TSV and fields in the JSON, and are left empty (\".\") for an end without split-reads")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Annotate Neighbors")
+                .long("annotate-neighbors")
+                .takes_value(false)
+                .help("use this argument to annotate every insertion with the nearest other insertion (of either type) on its chromosome, e.g. to find the insertion hotspots and the nested elements; the distance to it (0 if they overlap) and the call itself (as NAME@START-END) are written as neighbor columns in the TSV, attributes in the GFF3 and INFO fields in the VCF, and are left empty (\".\") for the only insertion of a chromosome")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("VCF Read Evidence")
+                .long("vcf-read-evidence")
+                .takes_value(false)
+                .help("use this argument to add the split-reads of every insertion to the sample column of the VCF output (--format vcf), as FORMAT fields for bcftools: the number of split-reads of the upstream and downstream ends (UR, DR), the first and last of their boundaries (UBR, DBR) and every distinct boundary, i.e. the candidate positions of each end (UBP, DBP); the position of the record is still the consensus position")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("TSD Sequence")
+                .long("tsd-sequence")
+                .takes_value(false)
+                .help("use this argument to add the target site duplication sequence of every non-reference insertion, read from the reference genome (reverse-complemented for +/- insertions, so that it is on the strand of the transposon)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Reconcile LTR")
+                .long("reconcile-ltr")
+                .takes_value(false)
+                .help("use this argument to merge overlapping reference calls of LTR retrotransposons (transposon names with the #LTR class, e.g. roo#LTR/Bel-Pao), which are usually a full element and the solo LTR of a related family: only the longer call is kept, and every merge is printed")
+                .required(false)
+                .conflicts_with_all(&["No Reference TEs", "Long Reads"]),
+        )
+        .arg(
+            Arg::with_name("Reconcile Overlaps")
+                .long("reconcile-overlaps")
+                .takes_value(false)
+                .help("use this argument to look for non-reference and reference calls of the same family (see --family-map) that overlap (a new insertion next to a copy of its family in the reference can be called both ways); by default both are kept and each one names the other in an extra overlapping calls column (NAME@START-END), see --overlap-resolution")
+                .required(false)
+                .conflicts_with_all(&["No Reference TEs", "No Non-Reference TEs", "Recall", "Long Reads"]),
+        )
+        .arg(
+            Arg::with_name("Overlap Resolution")
+                .long("overlap-resolution")
+                .takes_value(true)
+                .value_name("RESOLUTION")
+                .possible_values(&["annotate", "reference", "non-reference"])
+                .help("what --reconcile-overlaps does with overlapping calls: annotate them (default), or keep only the reference or only the non-reference call (every dropped call is printed)")
+                .requires("Reconcile Overlaps")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Overlap Window")
+                .long("overlap-window")
+                .takes_value(true)
+                .value_name("BP")
+                .help("how far apart (in bp) a non-reference call's TSD and a reference call can be for --reconcile-overlaps to count them as overlapping (default: 50)")
+                .requires("Reconcile Overlaps")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Annotation")
+                .long("annotation")
+                .takes_value(true)
+                .value_name("GFF3")
+                .help("a GFF3 annotation of the reference genome (e.g. from FlyBase): every insertion gets the feature it falls in (exon, intron, gene for genes without annotated exons, or intergenic) and the IDs of the genes it overlaps")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Coverage")
+                .long("coverage")
+                .takes_value(true)
+                .value_name("BEDGRAPH")
+                .help("a bedGraph of the read depth of the sample (e.g. from bedtools genomecov -bga): every non-reference insertion gets a HEURISTIC zygosity hint (heterozygous, homozygous or uncertain) from its split-reads per end over the depth at its TSD; only meaningful for a single diploid individual, not for pools")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Zygosity Thresholds")
+                .long("zygosity-thresholds")
+                .takes_value(true)
+                .value_name("HET,HOM")
+                .help("the support fractions from which an insertion is hinted heterozygous and homozygous (default: 0.2,0.75)")
+                .requires("Coverage")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Diagnostics JSON")
+                .long("diagnostics-json")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("also write the number of records that were skipped at each stage (and why) to this JSON file; the summary is always printed to stderr at the end of the run")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Strict")
+                .long("strict")
+                .takes_value(false)
+                .help("use this argument to stop at the first record that points to a misconfigured run (an alignment to a contig that isn't in the chromosome list, to a transposon that isn't in the TE alignment's header, or a line that can't be parsed) instead of skipping it; unmapped reads and reads that aren't split-reads are still skipped")
+                .required(false)
+                .conflicts_with("Region"),
+        )
+        .arg(
+            Arg::with_name("Abort On Empty Output")
+                .long("abort-on-empty-output")
+                .takes_value(false)
+                .help("use this argument to exit with an error (status 1) if no insertions were called, e.g. so that a workflow manager stops at a misconfigured run (the wrong chromosome names or transposon library) instead of carrying an empty output forward; the number of reads kept and skipped at each stage is printed first to show where they were lost (the empty output is still written)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Profile")
+                .long("profile")
+                .takes_value(false)
+                .help("use this argument to print the wall-clock time of each phase (TE alignment, read selection, genome alignment and alignment selection) at the end of the run, and add it to the manifest, e.g. to see whether the alignments or the calling take the most time")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Emit Support Reads")
+                .long("emit-support-reads")
+                .help("write the genome alignments of the split-reads that support each insertion to result_dir/supporting_reads.bam (sorted and indexed, for IGV), with the ID of the insertion (as in the GFF3 and VCF outputs) in the XI tag; needs the genome-aligned SAM file and samtools")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Max Support Reads")
+                .long("max-support-reads")
+                .takes_value(true)
+                .value_name("N")
+                .requires("Emit Support Reads")
+                .help("the most supporting reads written per insertion with --emit-support-reads, taken from both ends of the insertion (default: 20)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Emit Boundary Positions")
+                .long("emit-boundary-positions")
+                .takes_value(false)
+                .help("use this argument to write the boundary position of every split-read of every insertion to result_dir/boundary_positions.tsv (insertion_id, side and read_boundary columns, with the insertion IDs of the GFF3 and VCF outputs), e.g. to plot how far the reads of an insertion are spread around its breakpoints")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Output")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path of the output file instead of result_dir/te_mapper_output.<format> (compressed with gzip if it ends in .gz); \"-\" writes the output to stdout for piping into other tools, and the progress messages to stderr (the intermediate files are still written to the result directory)")
+                .conflicts_with("Gzip Output")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Split By Chromosome")
+                .long("split-by-chromosome")
+                .takes_value(false)
+                .help("use this argument to write the insertions of every chromosome to their own file, result_dir/te_mapper_output.<chrom>.<format>, instead of a single output file (e.g. to process the chromosomes in parallel); the files are listed in result_dir/te_mapper_output.chromosomes.tsv")
+                .conflicts_with_all(&["Output", "Emit Support Reads"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Buffer Output")
+                .long("buffer-output")
+                .takes_value(false)
+                .help("use this argument to write the TSV or NDJSON output once every insertion is called, instead of one chromosome at a time (which keeps less in memory); the JSON, GFF3 and VCF outputs, and the --sorted outputs, are always written at the end")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("No Reference TEs")
+                .long("no-reference-tes")
+                .takes_value(false)
+                .help("use this argument to only call non-reference insertions (the alignments of reference insertions are not kept in memory)")
+                .required(false)
+                .conflicts_with("No Non-Reference TEs"),
+        )
+        .arg(
+            Arg::with_name("No Non-Reference TEs")
+                .long("no-nonreference-tes")
+                .takes_value(false)
+                .help("use this argument to only call reference insertions (the alignments of non-reference insertions are not kept in memory)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Min Clip")
+                .long("min-clip")
+                .takes_value(true)
+                .value_name("N")
+                .help("the minimum number of clipped nucleotides for a split-read to be used (default: 0); filters out reads like 1S149M that carry almost no evidence of a transposon junction")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Min Genome Overlap")
+                .long("min-genome-overlap")
+                .takes_value(true)
+                .value_name("N")
+                .help("the minimum number of nucleotides a split-read must match the genome past the insertion breakpoint to count towards an insertion (default: 0); filters out reads like 145S5M whose genome anchor is too short to place confidently")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Max Read N")
+                .long("max-read-n")
+                .takes_value(true)
+                .value_name("N")
+                .help("the most ambiguous bases (N) that a read can have to be used as a split-read (default: no limit); reads with N-rich ends, e.g. from low-quality sequencing cycles, can align to the genome spuriously")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Max TSD Length")
+                .long("max-tsd-length")
+                .takes_value(true)
+                .value_name("BP")
+                .help("the longest target site duplication of a non-reference insertion (default: 100, or the most common read length minus --min-clip for shorter reads, which is printed in phase 4)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Long Reads")
+                .long("long-reads")
+                .takes_value(false)
+                .help("use this argument for long reads (e.g. Nanopore or PacBio) that span whole insertions: an insertion is called from the primary and supplementary genome alignments (SA tag) of a read on either side of its transposon part; only non-reference insertions are called, and bwa should get the read type, e.g. --bwa-extra \"-x ont2d\" or --bwa-extra \"-x pacbio\"")
+                .required(false)
+                .conflicts_with("No Non-Reference TEs"),
+        )
+        .arg(
+            Arg::with_name("Genome First")
+                .long("genome-first")
+                .takes_value(false)
+                .help("use this argument to align the reads to the genome first in phase 1, and then only the soft-clipped parts of the reads to the transposons (genome_first.sam, genome_clips.fasta and te_clips.sam in the result directory): with a large transposon library, this aligns far fewer sequences to the transposons, but it takes a whole genome alignment, a split-read is only found if its genome part is the primary alignment, and only non-reference insertions are called (a reference insertion has no clip in the genome); it needs a single --ref")
+                .required(false)
+                .conflicts_with_all(&["TE Aligned", "Long Reads", "No Non-Reference TEs", "Recall"]),
+        )
+        .arg(
+            Arg::with_name("Min Element Length")
+                .long("min-element-length")
+                .takes_value(true)
+                .value_name("BP")
+                .help("the minimum length of a transposon in the library for it to be called as a reference insertion (default: 0); shorter entries, e.g. fragments, are still used for non-reference insertions")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Selected Reads Format")
+                .long("selected-reads-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["fasta", "fastq"])
+                .help("the format of the split-reads selected in phase 2 and aligned to the genome in phase 3 (default: fasta); fastq keeps the base qualities of the reads (the QUAL column of the TE alignment), which BWA MEM uses in phase 3")
+                .required(false),
+        )
         .arg(
             Arg::with_name("Reads")
                 .long("reads")
                 .takes_value(true)
                 .value_name("FILE")
-                .help("the path to the single-end reads FASTQ file (relative or absolute)")
+                .help("the path to the single-end (or interleaved paired-end) reads FASTQ file (relative or absolute)")
                 .conflicts_with("Paired-Ends"),
         )
         .arg(
@@ -146,13 +756,21 @@ fn mapper_sc() -> App<'static, 'static> {
                 .value_name("FILE")
                 .help("the path to the 2nd of 2 reads FASTQ files for paired-ends (relative or absolute)")
         )
+        .arg(
+            Arg::with_name("Validate Pairs")
+                .long("validate-pairs")
+                .takes_value(false)
+                .help("use this argument to check that the 2 paired-end reads files have the same number of reads and that their first and last reads are mates before aligning (this reads both files once)")
+                .required(false)
+                .requires("Paired-Ends"),
+        )
         .arg(
             Arg::with_name("Transposons File")
                 .long("transposons")
                 .takes_value(true)
                 .value_name("FILE")
                 .help("the path to the file containing the transposons that the TE mapper should look for (relative or absolute)")
-                .required(true)
+                .required_unless("List Chromosomes")
         )
         .arg(
             Arg::with_name("Result Directory")
@@ -160,7 +778,14 @@ fn mapper_sc() -> App<'static, 'static> {
             .takes_value(true)
             .value_name("DIR")
             .help("the path to the directory where results (a TSV file containing the found transposons as well as some intermediate files) will be stored (relative or absolute)")
-            .required(true),
+            .required_unless("List Chromosomes"),
+        )
+        .arg(
+            Arg::with_name("List Chromosomes")
+                .long("list-chromosomes")
+                .takes_value(false)
+                .help("use this argument to print the name and length of every chromosome of the reference(s) and exit, e.g. to check whether the aligner uses \"2L\" or \"chr2L\"; the names are read from the header of the genome-aligned SAM file in the result directory if there is one, or else from the reference's .fai index")
+                .required(false),
         )
         .arg(
             Arg::with_name("BWA Threads")
@@ -170,6 +795,17 @@ fn mapper_sc() -> App<'static, 'static> {
                 .help("the number of threads to run BWA with (default value 8; choose 1 if you want a deterministic output; choose higher numbers to run faster while taking up more memory)")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("Mapper Threads")
+                .long("mapper-threads")
+                .takes_value(true)
+                .value_name("NUM_THREADS")
+                .help("the number of threads that select the reads and parse the alignments (default: the number of available CPUs, up to 32)")
+                .required(false),
+        )
+        .arg(overwrite_arg())
+        .arg(allow_oversubscribe_arg())
+        .args(&bwa_option_args())
 }
 
 // the sg (synthetic genome) subcommand
@@ -199,16 +835,224 @@ fn sg_sc() -> App<'static, 'static> {
             .help("the path to the directory where results (a TSV file containing the found transposons as well as some intermediate files) will be stored (relative or absolute)")
             .required(true),
         )
+        .arg(overwrite_arg())
+}
+
+// the catalog subcommand
+fn catalog_sc() -> App<'static, 'static> {
+    SubCommand::with_name("catalog")
+        .about("Catalog the transposons of a reference genome in one step, by tiling it into artificial reads (like sg) and mapping them back with the TE mapper; only the reference insertions are called, since they are the only meaningful calls from tiled reads")
+        .arg(
+            Arg::with_name("Reference")
+                .long("ref")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path to the reference sequence FASTA file (relative or absolute)")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("Transposons File")
+                .long("transposons")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path to the file containing the transposons to catalog (relative or absolute)")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("Result Directory")
+                .long("result")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("the path to the directory where the tiled reads, the intermediate files of the TE mapper and the reference insertions (te_mapper_output.tsv) will be stored (relative or absolute)")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("BWA Threads")
+                .long("bwa-threads")
+                .takes_value(true)
+                .value_name("NUM_THREADS")
+                .help("the number of threads to run BWA with (default value 8)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Chromosomes")
+                .long("chromosomes")
+                .takes_value(true)
+                .value_name("CHROM")
+                .multiple(true)
+                .use_delimiter(true)
+                .number_of_values(1)
+                .help("the chromosomes (contigs) to catalog, e.g. 2L,2R,3L,3R (default: the Drosophila Melanogaster chromosomes 2L, 2R, 3L, 3R, 4, X and Y); can be repeated or comma-separated")
+                .required(false),
+        )
+        .arg(overwrite_arg())
+        .arg(allow_oversubscribe_arg())
+        .args(&bwa_option_args())
+}
+
+// the (hidden) debug-cigar subcommand
+fn debug_cigar_sc() -> App<'static, 'static> {
+    SubCommand::with_name("debug-cigar")
+        .about("Print how a CIGAR string is interpreted by the TE mapper's split-read parsing (for troubleshooting)")
+        .setting(AppSettings::Hidden)
+        .arg(
+            Arg::with_name("CIGAR")
+                .long("cigar")
+                .takes_value(true)
+                .value_name("CIGAR")
+                .help("the CIGAR string to interpret (e.g. 10S140M)")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("Position")
+                .long("pos")
+                .takes_value(true)
+                .value_name("POS")
+                .help("the 1-based POS field of the alignment")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("Old M")
+                .long("old-m")
+                .takes_value(true)
+                .value_name("NUM")
+                .help("the size of the match in the TE alignment (only used for full matches to the genome; default 0)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Old S")
+                .long("old-s")
+                .takes_value(true)
+                .value_name("NUM")
+                .help("the size of the soft-clip in the TE alignment (only used for full matches to the genome; default 0)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("TE End")
+                .long("te-end")
+                .takes_value(false)
+                .help("use this argument if the TE alignment was at the end (3' side) of the transposon instead of the start")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Reverse")
+                .long("reverse")
+                .takes_value(false)
+                .help("use this argument if the genome alignment is +/- (SAM flag 16)")
+                .required(false),
+        )
+}
+
+// the index subcommand
+fn index_sc() -> App<'static, 'static> {
+    SubCommand::with_name("index")
+        .about("Build the BWA indexes of a reference and a transposon library ahead of time (e.g. as a separate cluster job), so that later runs of the TE mapper skip the indexing")
+        .arg(
+            Arg::with_name("Reference")
+                .long("ref")
+                .takes_value(true)
+                .value_name("FILE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("the path to the reference sequence FASTA file (relative or absolute); can be repeated")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("Transposons File")
+                .long("transposons")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path to the transposon library FASTA file (relative or absolute)")
+                .required(false),
+        )
+}
+
+// the check-library subcommand
+fn check_library_sc() -> App<'static, 'static> {
+    SubCommand::with_name("check-library")
+        .about("Check a transposon library FASTA file before a run of the TE mapper: print the length of every element and flag the duplicate names, the headers without a name and the empty sequences (which stop the run with a non-zero exit status), the names without a #class/family suffix and the short elements")
+        .arg(
+            Arg::with_name("Transposons File")
+                .long("transposons")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path to the transposon library FASTA file (relative or absolute)")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("Min Length")
+                .long("min-length")
+                .takes_value(true)
+                .value_name("NUM")
+                .help("flag the elements shorter than this many nucleotides, which rarely have split-reads (default value 100)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("Families")
+                .long("families")
+                .takes_value(false)
+                .help("use this argument to flag every name without a #class/family suffix, e.g. if the families of the output are used (by default, they are only flagged if some of the names have one)")
+                .required(false),
+        )
+}
+
+// the chrom-sizes subcommand
+fn chrom_sizes_sc() -> App<'static, 'static> {
+    SubCommand::with_name("chrom-sizes")
+        .about("Write the chrom.sizes file of a reference (the name and length of every contig, tab-separated), as bedtools and deeptools expect it")
+        .arg(
+            Arg::with_name("Reference")
+                .long("ref")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path to the reference sequence FASTA file (relative or absolute); its .fai index is created if necessary")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("Output")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("the path to the chrom.sizes file to write (default: stdout)")
+                .required(false),
+        )
+}
+
+// the versions subcommand
+fn versions_sc() -> App<'static, 'static> {
+    SubCommand::with_name("versions")
+        .about("Print the version of this tool and of the external tools it runs (bwa, samtools and freebayes), e.g. for a methods section or a bug report")
 }
 
 // the entire CLI app
 pub fn app() -> App<'static, 'static> {
     App::new("Stan-X Tools")
         .author("Sanath Govindarajan")
-        .version("1.0")
+        .version(env!("CARGO_PKG_VERSION"))
         .about(
             "Miscellaneous tools used for Whole-Genome Sequencing analysis in the Stan-X project",
         )
         .setting(AppSettings::SubcommandRequiredElseHelp)
-        .subcommands(vec![download_sc(), variants_sc(), mapper_sc(), sg_sc()])
+        .arg(
+            Arg::with_name("Log Format")
+                .long("log-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .global(true)
+                .help("the format of the progress and error messages (default: text); json writes every message to stderr as a JSON object on its own line (level, phase, message, timestamp and optional counts) for pipeline orchestrators such as Nextflow or Cromwell (the command-line errors and the messages of samtools stay plain text)")
+                .required(false),
+        )
+        .subcommands(vec![
+            download_sc(),
+            variants_sc(),
+            mapper_sc(),
+            sg_sc(),
+            catalog_sc(),
+            index_sc(),
+            check_library_sc(),
+            chrom_sizes_sc(),
+            versions_sc(),
+            debug_cigar_sc(),
+        ])
 }
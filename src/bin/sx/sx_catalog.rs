@@ -0,0 +1,37 @@
+// catalog the transposons of a reference genome in one step: tile the reference into artificial reads
+// (see sg_utils::tile_ref) and map them back to the reference with the TE mapper
+// the tiled reads are the reference itself, so every transposon of the reference shows up as a
+// reference insertion, and only the reference insertions are called (a non-reference insertion
+// from the tiled reads could only be an alignment artifact)
+
+use crate::sg_utils::tile_ref;
+use crate::sx_map::{self, MapOptions};
+use crate::te_mapper_utils::select_alignments::InsertionTypes;
+use crate::utils;
+use crate::utils::{progress, Reads};
+
+pub fn catalog(ref_name: &str, transposons_name: &str, result_dir: &str, options: MapOptions) {
+    let options = MapOptions {
+        insertion_types: InsertionTypes {
+            non_reference: false,
+            reference: true,
+        },
+        ..options
+    };
+    // stop before tiling the whole reference if the TE mapper would stop on the files of an earlier run
+    utils::check_overwrite(
+        result_dir,
+        &sx_map::run_output_files(&[ref_name], &options, options.phase),
+        options.overwrite,
+    );
+    progress!("Tiling the reference {}", ref_name);
+    tile_ref::tile_ref(ref_name, result_dir, options.overwrite);
+    let tiled_reads = Reads::SingleEnd(format!("{}/{}", result_dir, tile_ref::TILED_READS_FILE));
+    sx_map::map(
+        &[ref_name],
+        &tiled_reads,
+        transposons_name,
+        result_dir,
+        &options,
+    );
+}
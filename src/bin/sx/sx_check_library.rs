@@ -0,0 +1,199 @@
+// check a transposon library FASTA file before a long run of the TE mapper
+// the names of the library are the reference sequences ("@SQ" lines) of the TE alignment, so a
+// duplicate name or an empty sequence only shows up in phase 2 (as a missing or ambiguous transposon)
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::errors::{Result, StanexError};
+use crate::te_mapper_utils::te_name::TeName;
+use crate::utils;
+use crate::utils::log_error;
+
+// the default length below which an element is flagged (see --min-length): the split-reads of an
+// element need a clip that aligns to one of its ends, so a much shorter element is rarely found
+pub const DEFAULT_MIN_LENGTH: u64 = 100;
+
+// the name (the first word of the FASTA header, as in BWA and samtools) and length of every element,
+// in the order of the library
+pub fn read_library<R: BufRead>(reader: &mut R) -> Result<Vec<(String, u64)>> {
+    let mut elements: Vec<(String, u64)> = Vec::new();
+    let mut line_num = 0;
+    while let Some(line) = utils::read_line_trimmed(reader)? {
+        line_num += 1;
+        if let Some(header) = line.strip_prefix('>') {
+            let name = header.split_whitespace().next().unwrap_or("").to_owned();
+            elements.push((name, 0));
+            continue;
+        }
+        let length = line.bytes().filter(|b| !b.is_ascii_whitespace()).count() as u64;
+        match elements.last_mut() {
+            Some((_, element_length)) => *element_length += length,
+            // a sequence before the first header
+            None if length > 0 => {
+                return Err(StanexError::InvalidValue {
+                    field: format!("FASTA line {}", line_num),
+                    value: line,
+                })
+            }
+            None => {}
+        }
+    }
+    return Ok(elements);
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct LibraryIssues {
+    // fatal: the transposons of the TE alignment are looked up by name
+    pub duplicate_names: Vec<String>,
+    // fatal: the number of headers without a name
+    pub unnamed: u64,
+    // fatal: the names of the elements without a sequence
+    pub empty: Vec<String>,
+    // the names without the "#class/family" suffix, if they are checked
+    pub without_family: Vec<String>,
+    // the names of the elements shorter than the minimum length
+    pub short: Vec<String>,
+}
+
+impl LibraryIssues {
+    pub fn is_fatal(&self) -> bool {
+        !self.duplicate_names.is_empty() || self.unnamed > 0 || !self.empty.is_empty()
+    }
+}
+
+// the names without a "#class/family" suffix are only checked with check_families, or if some of the
+// names have one (a library that mixes both puts the elements without one in families of their own)
+pub fn check_library(
+    elements: &[(String, u64)],
+    min_length: u64,
+    check_families: bool,
+) -> LibraryIssues {
+    let mut issues = LibraryIssues::default();
+    let check_families = check_families
+        || elements
+            .iter()
+            .any(|(name, _)| TeName::parse(name).class_family.is_some());
+    let mut names: Vec<&String> = Vec::new();
+    for (name, length) in elements {
+        if name.is_empty() {
+            issues.unnamed += 1;
+            continue;
+        }
+        if names.contains(&name) {
+            if !issues.duplicate_names.contains(name) {
+                issues.duplicate_names.push(name.clone());
+            }
+            continue;
+        }
+        names.push(name);
+        if *length == 0 {
+            issues.empty.push(name.clone());
+        } else if *length < min_length {
+            issues.short.push(name.clone());
+        }
+        if check_families && TeName::parse(name).class_family.is_none() {
+            issues.without_family.push(name.clone());
+        }
+    }
+    return issues;
+}
+
+// print the length of every element and the issues of the library, and exit with 1 on a fatal issue
+pub fn check_library_file(transposons_name: &str, min_length: u64, check_families: bool) {
+    let elements = match File::open(transposons_name)
+        .map_err(StanexError::from)
+        .and_then(|file| read_library(&mut BufReader::new(file)))
+    {
+        Ok(elements) => elements,
+        Err(e) => {
+            log_error!("Unable to read the library {}: {}", transposons_name, e);
+            std::process::exit(1);
+        }
+    };
+    let issues = check_library(&elements, min_length, check_families);
+
+    println!("name\tlength");
+    for (name, length) in &elements {
+        println!("{}\t{}", name, length);
+    }
+    println!("\n{} elements in {}", elements.len(), transposons_name);
+    let report = |issue: &str, names: &[String]| {
+        if !names.is_empty() {
+            println!("{} {}: {}", names.len(), issue, names.join(", "));
+        }
+    };
+    report("duplicate names", &issues.duplicate_names);
+    if issues.unnamed > 0 {
+        println!("{} headers without a name", issues.unnamed);
+    }
+    report("elements without a sequence", &issues.empty);
+    report(
+        "names without a #class/family suffix",
+        &issues.without_family,
+    );
+    report(
+        &format!("elements shorter than {} nt", min_length),
+        &issues.short,
+    );
+
+    if elements.is_empty() {
+        log_error!("The library {} has no elements", transposons_name);
+        std::process::exit(1);
+    }
+    if issues.is_fatal() {
+        log_error!(
+            "The library {} can't be used by the TE mapper: please fix the duplicate names, unnamed headers and empty sequences",
+            transposons_name
+        );
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_library, read_library, LibraryIssues};
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_check_library() {
+        let library = ">roo#LTR/Bel-Pao roo element\nACGTACGT\nACGT\r\n\
+                       >jockey#LINE/Jockey\nACGTACGTACGTACGT\n\
+                       >roo#LTR/Bel-Pao\nACGT\n\
+                       >INE-1\n\n\
+                       >\nACGT\n";
+        let elements = read_library(&mut Cursor::new(library)).unwrap();
+        assert_eq!(
+            elements,
+            vec![
+                ("roo#LTR/Bel-Pao".to_owned(), 12),
+                ("jockey#LINE/Jockey".to_owned(), 16),
+                ("roo#LTR/Bel-Pao".to_owned(), 4),
+                ("INE-1".to_owned(), 0),
+                ("".to_owned(), 4),
+            ]
+        );
+        let issues = check_library(&elements, 15, false);
+        assert_eq!(
+            issues,
+            LibraryIssues {
+                duplicate_names: vec!["roo#LTR/Bel-Pao".to_owned()],
+                unnamed: 1,
+                empty: vec!["INE-1".to_owned()],
+                without_family: vec!["INE-1".to_owned()],
+                short: vec!["roo#LTR/Bel-Pao".to_owned()],
+            }
+        );
+        assert!(issues.is_fatal());
+
+        // a library of names without families is only checked for them on request
+        let elements = vec![("roo".to_owned(), 9092), ("jockey".to_owned(), 5020)];
+        assert!(!check_library(&elements, 100, false).is_fatal());
+        assert_eq!(check_library(&elements, 100, false).without_family.len(), 0);
+        assert_eq!(check_library(&elements, 100, true).without_family.len(), 2);
+
+        // a sequence before the first header
+        assert!(read_library(&mut Cursor::new("ACGT\n>roo\nACGT\n")).is_err());
+    }
+}
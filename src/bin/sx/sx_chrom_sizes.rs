@@ -0,0 +1,69 @@
+// write the chrom.sizes (".genome") file of a reference, as bedtools and deeptools expect it:
+// the name and length of every contig, in the order of the reference
+// (the lengths are read from the reference's ".fai" index, which is created if necessary)
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::errors::{Result, StanexError};
+use crate::utils;
+use crate::utils::{log_error, IO_BUFFER_CAPACITY};
+
+// write a "chrom<TAB>length" line for every contig (a contig without a length is an error)
+pub fn write_chrom_sizes<W: Write>(contigs: &[(String, u64)], writer: &mut W) -> Result<()> {
+    for (chrom, length) in contigs {
+        if *length == 0 {
+            return Err(StanexError::InvalidValue {
+                field: format!("length of {}", chrom),
+                value: length.to_string(),
+            });
+        }
+        writeln!(writer, "{}\t{}", chrom, length)?;
+    }
+    writer.flush()?;
+    return Ok(());
+}
+
+// write the chrom.sizes file of ref_name to output_name (stdout if it isn't given)
+pub fn chrom_sizes(ref_name: &str, output_name: Option<&str>) {
+    let fai_name = utils::samtools_faidx_if_required(ref_name);
+    let contigs = utils::read_fai(&fai_name);
+    if contigs.is_empty() {
+        log_error!("The reference {} has no contigs", ref_name);
+        std::process::exit(1);
+    }
+    let written = match output_name {
+        Some(output_name) => File::create(output_name)
+            .map_err(StanexError::from)
+            .and_then(|output| {
+                write_chrom_sizes(
+                    &contigs,
+                    &mut BufWriter::with_capacity(IO_BUFFER_CAPACITY, output),
+                )
+            }),
+        None => write_chrom_sizes(&contigs, &mut io::stdout().lock()),
+    };
+    if let Err(e) = written {
+        log_error!("Unable to write the chrom.sizes of {}: {}", ref_name, e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_chrom_sizes;
+
+    #[test]
+    fn test_write_chrom_sizes() {
+        let contigs = vec![("2L".to_owned(), 23513712), ("4".to_owned(), 1348131)];
+        let mut chrom_sizes: Vec<u8> = Vec::new();
+        write_chrom_sizes(&contigs, &mut chrom_sizes).unwrap();
+        assert_eq!(
+            String::from_utf8(chrom_sizes).unwrap(),
+            "2L\t23513712\n4\t1348131\n"
+        );
+
+        let contigs = vec![("2L".to_owned(), 23513712), ("empty".to_owned(), 0)];
+        assert!(write_chrom_sizes(&contigs, &mut Vec::new()).is_err());
+    }
+}
@@ -1,22 +1,222 @@
 use path_abs::PathFile;
 use std::ffi::OsStr;
+use std::fs;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 
 use crate::utils;
 
-pub fn download(url: &str, output_file: &str) {
-    // first, create the absolute filepath from the relative filepath (create it if it doesn't exist)
-    let output_path: PathFile = utils::absolute_filepath_unchecked(output_file);
-    let output_path_os_str: &OsStr = output_path.as_ref();
-    let output_path_str: &str = output_path_os_str.to_str().unwrap();
+// what an already-downloaded file is expected to look like
+// (both checks are optional; if neither is given, any non-empty file counts as complete,
+// since a download is only renamed to its output file once it is complete)
+pub struct CacheKey {
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+}
+
+// the wait before the first retry (doubled after every failed attempt)
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+// compute the SHA-256 checksum of a file with the "sha256sum" command
+fn sha256sum(file: &str) -> String {
+    let output = match Command::new("sha256sum").arg(file).output() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Aborting: unable to run sha256sum: {}", e);
+            std::process::exit(1);
+        }
+    };
+    utils::exit_if_failed("sha256sum", output.status);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    stdout.split_whitespace().nth(0).unwrap_or("").to_owned()
+}
+
+// is the output file already present and complete?
+fn is_cache_hit(output_file: &str, cache_key: &CacheKey) -> bool {
+    let file_size = match fs::metadata(output_file) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return false,
+    };
+    if file_size == 0 {
+        return false;
+    }
+    if let Some(expected_size) = cache_key.size {
+        if file_size != expected_size {
+            println!(
+                "Existing file has size {} (expected {}): downloading again",
+                file_size, expected_size
+            );
+            return false;
+        }
+    }
+    if let Some(expected_sha256) = &cache_key.sha256 {
+        if !sha256sum(output_file).eq_ignore_ascii_case(expected_sha256) {
+            println!("Existing file does not match the expected SHA-256: downloading again");
+            return false;
+        }
+    }
+    return true;
+}
+
+// the file that a download is written to until it is complete, e.g. "dm6.fa.gz.partial"
+// (so that an interrupted download is never mistaken for the complete file, and can be resumed)
+fn partial_file_name(output_file: &str) -> String {
+    format!("{}.partial", output_file)
+}
+
+// run curl once, resuming from the bytes that are already in the partial file
+// returns a description of the failure and curl's exit code if the download didn't complete
+fn try_download(url: &str, partial_path_str: &str) -> Result<(), (String, i32)> {
     // curl:
     // -L argument is the location
     // -o argument is the output file
-    println!("Waiting for cURL command to download file...");
-    let mut child_proc = Command::new("curl")
-        .args(&["-L", url, "-o", output_path_str])
-        .spawn()
-        .unwrap();
-    let _result = child_proc.wait().unwrap();
-    println!("Reference sequence downloaded");
+    // -C - resumes the transfer from the end of the output file
+    // -f makes HTTP errors fail instead of saving the error page
+    let status = Command::new("curl")
+        .args(&["-f", "-L", "-C", "-", url, "-o", partial_path_str])
+        .status()
+        .map_err(|e| (format!("unable to run curl: {}", e), 1))?;
+    return utils::check_step_status("curl", status)
+        .map_err(|e| (e.to_string(), status.code().unwrap_or(1)));
+}
+
+// retries is the number of times a failed download is retried
+// (with an exponential backoff between attempts, and each attempt resuming the previous one)
+// the download is written to the partial file, which is only renamed to output_file once it is complete;
+// after the last failed attempt, the partial file is kept so that the next run can resume it
+pub fn download(url: &str, output_file: &str, cache_key: &CacheKey, force: bool, retries: u32) {
+    // skip the download if the file is already there (unless we are forced to download it again)
+    if !force && is_cache_hit(output_file, cache_key) {
+        println!(
+            "{} already exists and is complete: skipping download (use --force to download it again)",
+            output_file
+        );
+        return;
+    }
+    let partial_file = partial_file_name(output_file);
+    // a partial file is only resumed if it can be the start of the file
+    // (shorter than the expected size), otherwise it is downloaded again from scratch
+    if let Ok(metadata) = fs::metadata(&partial_file) {
+        let resumable = match cache_key.size {
+            Some(expected_size) => metadata.len() < expected_size,
+            None => true,
+        };
+        if metadata.is_file() && (force || !resumable) {
+            remove_or_exit(&partial_file);
+        }
+    }
+    // first, create the absolute filepath from the relative filepath (create it if it doesn't exist)
+    let partial_path: PathFile = utils::absolute_filepath_unchecked(&partial_file);
+    let partial_path_os_str: &OsStr = partial_path.as_ref();
+    let partial_path_str: &str = partial_path_os_str.to_str().unwrap();
+
+    let mut failures: Vec<String> = Vec::new();
+    let mut exit_code = 1;
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=(retries + 1) {
+        if attempt == 1 {
+            println!("Waiting for cURL command to download file...");
+        } else {
+            println!(
+                "Retrying in {} s (attempt {} of {})...",
+                backoff.as_secs(),
+                attempt,
+                retries + 1
+            );
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+        match try_download(url, partial_path_str) {
+            Ok(()) => {
+                // a completed transfer can still be the wrong file (or a corrupted resume)
+                if (cache_key.sha256.is_some() || cache_key.size.is_some())
+                    && !is_cache_hit(&partial_file, cache_key)
+                {
+                    eprintln!(
+                        "Downloaded file does not match the expected size/SHA-256: {}",
+                        output_file
+                    );
+                    remove_or_exit(&partial_file);
+                    std::process::exit(1);
+                }
+                if let Err(e) = fs::rename(&partial_file, output_file) {
+                    eprintln!(
+                        "Unable to rename {} to {}: {}",
+                        partial_file, output_file, e
+                    );
+                    std::process::exit(1);
+                }
+                println!("Reference sequence downloaded");
+                return;
+            }
+            Err((failure, failure_exit_code)) => {
+                eprintln!("Download attempt {} failed: {}", attempt, failure);
+                failures.push(failure);
+                exit_code = failure_exit_code;
+            }
+        }
+    }
+    eprintln!(
+        "Unable to download {} after {} attempts:",
+        url,
+        failures.len()
+    );
+    for (attempt, failure) in failures.iter().enumerate() {
+        eprintln!("  attempt {}: {}", attempt + 1, failure);
+    }
+    // an empty partial file has nothing to resume
+    match fs::metadata(&partial_file) {
+        Ok(metadata) if metadata.len() > 0 => eprintln!(
+            "The partial download {} is resumed by the next run",
+            partial_file
+        ),
+        Ok(_) => remove_or_exit(&partial_file),
+        Err(_) => {}
+    }
+    // exit with curl's exit code for the last attempt
+    std::process::exit(exit_code);
+}
+
+fn remove_or_exit(file: &str) {
+    if let Err(e) = fs::remove_file(file) {
+        eprintln!("Unable to remove {}: {}", file, e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_cache_hit, CacheKey};
+
+    use std::fs;
+
+    #[test]
+    fn test_is_cache_hit() {
+        let output_file = std::env::temp_dir()
+            .join(format!("sx_test_is_cache_hit_{}.fa", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let no_key = CacheKey {
+            sha256: None,
+            size: None,
+        };
+        assert!(!is_cache_hit(&output_file, &no_key));
+        // an empty file is never complete
+        fs::write(&output_file, "").unwrap();
+        assert!(!is_cache_hit(&output_file, &no_key));
+
+        // any other file is, unless it doesn't have the expected size
+        fs::write(&output_file, ">2L\nACGT").unwrap();
+        assert!(is_cache_hit(&output_file, &no_key));
+        let size_key = |size: u64| CacheKey {
+            sha256: None,
+            size: Some(size),
+        };
+        assert!(is_cache_hit(&output_file, &size_key(8)));
+        assert!(!is_cache_hit(&output_file, &size_key(100)));
+
+        fs::remove_file(&output_file).unwrap();
+    }
 }
@@ -1,53 +1,807 @@
-use path_abs::{PathDir, PathFile, PathOps};
+use path_abs::{PathFile, PathOps};
+use serde::Serialize;
 
-use crate::te_mapper_utils::{select_alignments, select_reads};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::errors::Result;
+use crate::sx_versions;
+use crate::te_mapper_utils::alignment_cache::{AlignmentSource, BwaStream};
+use crate::te_mapper_utils::annotation::Annotation;
+use crate::te_mapper_utils::diagnostics::{Diagnostics, Stage};
+use crate::te_mapper_utils::family_map::FamilyMap;
+use crate::te_mapper_utils::family_params::FamilyParams;
+use crate::te_mapper_utils::indexed_fasta::IndexedFasta;
+use crate::te_mapper_utils::output_data_types::OutputInsertions;
+use crate::te_mapper_utils::select_alignments::{
+    DistinctCounts, HeaderStyle, InsertionTypes, OutputColumns, OutputFormat, OutputStream,
+    OverlapResolution, Region,
+};
+use crate::te_mapper_utils::select_reads::SelectedReadsFormat;
+use crate::te_mapper_utils::te_filter::TeFilter;
+use crate::te_mapper_utils::zygosity::{Coverage, ZygosityThresholds};
+use crate::te_mapper_utils::{
+    bgzf, first_sam_file, genome_first, select_alignments, select_reads, support_reads,
+};
 use crate::utils;
 use crate::utils::Reads;
+use crate::utils::{log_error, progress, IO_BUFFER_CAPACITY};
+
+// the settings of a TE mapper run (everything besides the input and output paths)
+pub struct MapOptions {
+    pub bwa_threads: u16,
+    // the number of worker threads that parse the alignments (see default_mapper_threads if not given)
+    pub mapper_threads: Option<usize>,
+    // extra "bwa mem" arguments for both alignments (see utils::check_bwa_mem_args)
+    pub bwa_options: Vec<String>,
+    pub output_format: OutputFormat,
+    pub header_style: HeaderStyle,
+    // the phase to start on
+    pub phase: u32,
+    // an existing TE alignment (the output of phase 1) to use instead of result_dir/te_aligned.sam,
+    // which skips phase 1
+    pub te_aligned: Option<String>,
+    // save the parsed genome alignments to result_dir/alignments.bin in phase 4
+    pub cache_alignments: bool,
+    // load the parsed genome alignments from result_dir/alignments.bin instead of
+    // genome_aligned.sam (this skips phases 1 to 3)
+    pub from_cache: bool,
+    // align the selected reads to the genome in phase 4 and parse the alignments from bwa mem's output
+    // instead of writing genome_aligned.sam in phase 3 (see alignment_cache::AlignmentSource::Bwa)
+    pub stream: bool,
+    // call only the insertion types of insertion_types again from the cache (see --recall),
+    // into output files named after them so that the full run's outputs are kept
+    pub recall: bool,
+    // the shortest and longest reference insertion, as fractions of the transposon's length
+    pub reference_length_window: (f64, f64),
+    // a map between SAM fields and their (1-indexed) columns, for alignment files that
+    // don't use the standard SAM column order (empty for the standard order)
+    pub sam_layout: HashMap<String, usize>,
+    // new names for the contigs of the reference genomes (e.g. "chr2L" to "2L"),
+    // applied to the genome alignments, the chromosome lengths and the chromosome order
+    pub contig_renames: HashMap<String, String>,
+    // the chromosomes to call insertions on (--chromosomes/--chromosomes-file, after the renames)
+    // (the chromosomes of Drosophila Melanogaster if not given)
+    pub chromosomes: Option<Vec<String>>,
+    // contigs shorter than this are removed from the chromosomes (0 keeps all of them)
+    pub min_contig_length: u64,
+    // a single locus to call insertions on (--region), instead of whole chromosomes
+    pub region: Option<Region>,
+    // sort the insertions by genomic coordinate across all chromosomes (for tabix)
+    pub sorted: bool,
+    // a ".fai" or ".genome" file with the chromosome order for sorting
+    // (the order of the reference genomes' ".fai" indexes if not given)
+    pub chrom_order_file: Option<String>,
+    // compress the sorted output with bgzip and index it with tabix
+    pub bgzip: bool,
+    // compress the output with gzip (te_mapper_output.<format>.gz)
+    pub gzip_output: bool,
+    // the minimum length of the clipped part of a split-read (in both the TE and genome alignments)
+    pub min_clip: u64,
+    // the minimum number of nucleotides a split-read matches the genome past the breakpoint
+    // for it to count towards an insertion (see SplitReadGenome::genome_anchor)
+    pub min_genome_overlap: u64,
+    // reads with more ambiguous bases (N) than this are not selected in phase 2 (None keeps all of them)
+    pub max_read_n: Option<u64>,
+    // call insertions from long reads that span them (see long_read), instead of from split-reads
+    // (only non-reference insertions are called)
+    pub long_reads: bool,
+    // align the reads to the genome in phase 1, and only their soft-clips to the transposons (see genome_first)
+    // (only non-reference insertions are called)
+    pub genome_first: bool,
+    // the longest TSD of a non-reference insertion (see select_alignments::max_tsd_length if not given)
+    pub max_tsd_length: Option<u64>,
+    // transposons shorter than this are not called as reference insertions (0 keeps all of them)
+    pub min_element_length: u64,
+    // the format of the split-reads selected in phase 2 (result_dir/selected_reads.fasta or .fastq)
+    pub selected_reads_format: SelectedReadsFormat,
+    // the sample column of the VCF output and the sample in the manifest
+    // (the reads file name without its extensions if not given)
+    pub sample: Option<String>,
+    // the transposon families to map (--te-include/--te-exclude)
+    pub te_filter: TeFilter,
+    // the canonical families of the transposons, to pool the support of their sub-families
+    // (see family_map::FamilyMap; empty to call every transposon under its own name)
+    pub family_map: FamilyMap,
+    // the TSD and length windows of some families, in place of the global ones
+    // (see family_params::FamilyParams; empty to use the global windows for every family)
+    pub family_params: FamilyParams,
+    // the lengths of the transposons from a library TSV, instead of the TE alignment's header
+    // (see te_lengths; None to use the header)
+    pub te_lengths: Option<HashMap<String, u64>>,
+    // match the transposon names of the TE alignments to the header's (and --te-lengths') whatever their case
+    // (the insertions are named as in the header)
+    pub case_insensitive_te_names: bool,
+    // keep the insertions with split-reads on only one end (marked as one-sided in the output)
+    pub report_one_sided: bool,
+    // count the distinct fragments of the split-reads of every insertion besides the split-reads
+    // (see select_alignments::count_fragments)
+    pub count_fragments: bool,
+    // report the range of the boundaries of the split-reads of each end of every insertion
+    // (see select_alignments::add_breakpoint_ranges)
+    pub breakpoint_ranges: bool,
+    // annotate every insertion with the nearest other insertion on its chromosome and the distance to it
+    // (see select_alignments::annotate_neighbors)
+    pub annotate_neighbors: bool,
+    // add the split-reads of the ends of every insertion to the sample column of the VCF output
+    // (see output_data_types::Insertion::vcf_read_evidence)
+    pub vcf_read_evidence: bool,
+    // write the JSON output without the pretty-printing (the NDJSON output is always compact)
+    pub compact_json: bool,
+    // add the sequence of the TSD of every non-reference insertion (read from the reference genome)
+    pub tsd_sequence: bool,
+    // keep only the full element of overlapping reference calls of LTR transposons,
+    // merging away the solo LTRs of related families (see select_alignments::reconcile_ltr_calls)
+    pub reconcile_ltr: bool,
+    // what to do with the non-reference and reference calls of the same family that overlap
+    // (see select_alignments::reconcile_overlapping_calls; None leaves them alone)
+    pub reconcile_overlaps: Option<OverlapResolution>,
+    // how far apart 2 calls can be and still overlap (in bp)
+    pub overlap_window: u64,
+    // a GFF3 annotation to report the genes that the insertions overlap (exon, intron or intergenic)
+    pub annotation: Option<String>,
+    // a bedGraph of the read depth of the sample to hint at the zygosity of the non-reference
+    // insertions of a single diploid individual (a heuristic, see zygosity)
+    pub coverage: Option<String>,
+    pub zygosity_thresholds: ZygosityThresholds,
+    // a JSON file for the tallies of the skipped records (they are always summarized on stderr)
+    pub diagnostics_json: Option<String>,
+    // stop at the first record that points to a misconfigured run (e.g. a contig that isn't
+    // in the chromosome list) instead of skipping it
+    pub strict: bool,
+    // exit with 1 (after the number of reads kept at each stage) if no insertions were called,
+    // which is usually a misconfigured run (e.g. the wrong chromosome names or transposon library)
+    pub abort_on_empty_output: bool,
+    // print the wall-clock time of each phase at the end (and add it to the manifest)
+    pub profile: bool,
+    // write the genome alignments of the split-reads of every insertion to result_dir/supporting_reads.bam
+    // (with at most this many reads per insertion)
+    pub support_reads_cap: Option<usize>,
+    // write the boundary position of every split-read of every insertion to result_dir/boundary_positions.tsv
+    pub emit_boundary_positions: bool,
+    // write the TSV and NDJSON outputs once all the insertions are called, instead of one chromosome
+    // at a time (the output is always written at the end when it is sorted or the supporting reads are written)
+    pub buffer_output: bool,
+    // write the output of every chromosome to its own file in result_dir
+    // (te_mapper_output.<chrom>.<format>, listed in te_mapper_output.chromosomes.tsv)
+    pub split_by_chromosome: bool,
+    // the path of the final output instead of result_dir/te_mapper_output.<format>
+    // ("-" is stdout, see select_alignments::STDOUT_PATH)
+    pub output: Option<String>,
+    // replace the output files of an earlier run in result_dir (see utils::check_overwrite)
+    pub overwrite: bool,
+    // the types of insertions to call (both by default)
+    pub insertion_types: InsertionTypes,
+}
+
+// the defaults of the map subcommand's arguments (for the subcommands that run the TE mapper themselves)
+impl Default for MapOptions {
+    fn default() -> MapOptions {
+        MapOptions {
+            bwa_threads: 8,
+            mapper_threads: None,
+            bwa_options: Vec::new(),
+            output_format: OutputFormat::Tsv,
+            header_style: HeaderStyle::Bergman,
+            phase: 1,
+            te_aligned: None,
+            cache_alignments: false,
+            from_cache: false,
+            stream: false,
+            recall: false,
+            reference_length_window: (0.1, 1.5),
+            sam_layout: HashMap::new(),
+            contig_renames: HashMap::new(),
+            chromosomes: None,
+            min_contig_length: 0,
+            region: None,
+            sorted: false,
+            chrom_order_file: None,
+            bgzip: false,
+            gzip_output: false,
+            min_clip: 0,
+            min_genome_overlap: 0,
+            max_read_n: None,
+            long_reads: false,
+            genome_first: false,
+            max_tsd_length: None,
+            min_element_length: 0,
+            selected_reads_format: SelectedReadsFormat::Fasta,
+            sample: None,
+            te_filter: TeFilter::new(&[], &[]),
+            family_map: FamilyMap::default(),
+            family_params: FamilyParams::default(),
+            te_lengths: None,
+            case_insensitive_te_names: false,
+            report_one_sided: false,
+            count_fragments: false,
+            breakpoint_ranges: false,
+            annotate_neighbors: false,
+            vcf_read_evidence: false,
+            compact_json: false,
+            tsd_sequence: false,
+            reconcile_ltr: false,
+            reconcile_overlaps: None,
+            overlap_window: 50,
+            annotation: None,
+            coverage: None,
+            zygosity_thresholds: ZygosityThresholds::DEFAULT,
+            diagnostics_json: None,
+            strict: false,
+            abort_on_empty_output: false,
+            profile: false,
+            support_reads_cap: None,
+            emit_boundary_positions: false,
+            buffer_output: false,
+            split_by_chromosome: false,
+            output: None,
+            overwrite: false,
+            insertion_types: InsertionTypes::ALL,
+        }
+    }
+}
+
+// a record of the inputs and outputs of a run, written to result_dir/te_mapper_manifest.json
+// (e.g. to keep track of which VCF belongs to which sample before merging them)
+#[derive(Serialize)]
+struct Manifest<'a> {
+    version: &'static str,
+    // the versions of the aligner and samtools (null if they are not installed)
+    bwa_version: Option<String>,
+    samtools_version: Option<String>,
+    sample: &'a str,
+    references: &'a [&'a str],
+    reads: Vec<&'a String>,
+    transposons: &'a str,
+    output: String,
+    output_format: &'static str,
+    non_reference_insertions: usize,
+    reference_insertions: usize,
+    // the number of transposon families ("class/family") and elements with at least 1 insertion
+    distinct_families: DistinctCounts,
+    distinct_elements: DistinctCounts,
+    // only with --profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase_seconds: Option<&'a PhaseTimings>,
+}
+
+fn write_manifest(manifest_name: &str, manifest: &Manifest) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(manifest_name)?);
+    serde_json::to_writer_pretty(&mut writer, manifest).map_err(std::io::Error::from)?;
+    writer.flush()?;
+    return Ok(());
+}
+
+// the wall-clock time of each phase in seconds (see --profile)
+// phases 3 and 4 add up the time of every reference, phase 4 includes writing the output,
+// and the phases that weren't run are null
+#[derive(Default, Serialize)]
+struct PhaseTimings {
+    te_alignment: Option<f64>,
+    select_reads: Option<f64>,
+    genome_alignment: Option<f64>,
+    select_alignments: Option<f64>,
+}
+
+impl PhaseTimings {
+    fn add(phase_seconds: &mut Option<f64>, start: Instant) {
+        *phase_seconds = Some(phase_seconds.unwrap_or(0.0) + start.elapsed().as_secs_f64());
+    }
+
+    fn phases(&self) -> [(&'static str, Option<f64>); 4] {
+        [
+            ("phase 1 (TE alignment)", self.te_alignment),
+            ("phase 2 (select reads)", self.select_reads),
+            ("phase 3 (genome alignment)", self.genome_alignment),
+            ("phase 4 (select alignments)", self.select_alignments),
+        ]
+    }
+
+    // a row per phase with its time and share of the total ("-" for the phases that weren't run)
+    fn table(&self) -> String {
+        let total: f64 = self
+            .phases()
+            .iter()
+            .filter_map(|(_, seconds)| *seconds)
+            .sum();
+        let mut table = String::new();
+        for (phase, seconds) in self.phases().iter() {
+            match seconds {
+                Some(seconds) => table.push_str(&format!(
+                    "{:<28}{:>10.2} s{:>7.1}%\n",
+                    phase,
+                    seconds,
+                    if total > 0.0 {
+                        100.0 * seconds / total
+                    } else {
+                        0.0
+                    }
+                )),
+                None => table.push_str(&format!("{:<28}{:>12}\n", phase, "-")),
+            }
+        }
+        table.push_str(&format!("{:<28}{:>10.2} s", "total", total));
+        return table;
+    }
+}
+
+// the SAM fields whose columns can be changed with MapOptions::sam_layout
+pub const SAM_LAYOUT_FIELDS: [&str; 7] = ["QNAME", "FLAG", "RNAME", "POS", "CIGAR", "SEQ", "QUAL"];
+
+// a short name for each reference genome (the file name up to the first ".")
+// used to label the insertions and the per-reference intermediate files
+// when mapping against more than 1 reference genome (e.g. the sub-genomes of a hybrid)
+fn reference_labels(ref_names: &[&str]) -> Vec<String> {
+    let mut labels: Vec<String> = Vec::new();
+    for (index, ref_name) in ref_names.iter().enumerate() {
+        let file_name = Path::new(ref_name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(ref_name);
+        let mut label = file_name.split('.').nth(0).unwrap().to_owned();
+        // make sure that 2 references with the same file name still get different labels
+        if labels.contains(&label) {
+            label = format!("{}_{}", label, index + 1);
+        }
+        labels.push(label);
+    }
+    return labels;
+}
+
+// which of the reference genomes have the chromosome of the region (--region)?
+// (with several references, it is normally in only one of them, and the others are skipped)
+// exits if none of them has it, or if the region ends past the end of the chromosome
+fn references_with_region(
+    ref_names: &[&str],
+    region: &Region,
+    contig_renames: &HashMap<String, String>,
+) -> Vec<bool> {
+    let mut has_region: Vec<bool> = Vec::new();
+    for ref_name in ref_names {
+        let length = utils::read_chrom_lengths(ref_name)
+            .into_iter()
+            .find(|(chrom, _)| utils::rename_contig(chrom, contig_renames) == region.chrom)
+            .map(|(_, length)| length);
+        if let Some(length) = length {
+            if region.end > length {
+                log_error!(
+                    "The region {}:{}-{} ends past the length of {} ({}) in {}",
+                    region.chrom,
+                    region.start,
+                    region.end,
+                    region.chrom,
+                    length,
+                    ref_name
+                );
+                std::process::exit(2);
+            }
+        }
+        has_region.push(length.is_some());
+    }
+    if !has_region.contains(&true) {
+        log_error!(
+            "The region's chromosome {} is not in {}",
+            region.chrom,
+            ref_names.join(", ")
+        );
+        std::process::exit(2);
+    }
+    return has_region;
+}
+
+// print the name and length of every chromosome (contig) of each reference genome
+// (from the header of the genome-aligned SAM file if phase 3 has already been run,
+// so that the names are exactly the ones that the aligner used, or else from the reference's ".fai" index)
+pub fn list_chromosomes(ref_names: &[&str], result_dir: Option<&str>) {
+    let multiple_refs = ref_names.len() > 1;
+    for (ref_name, label) in ref_names.iter().zip(reference_labels(ref_names)) {
+        let file_suffix = if multiple_refs {
+            format!(".{}", label)
+        } else {
+            String::new()
+        };
+        let genome_aligned_name = result_dir
+            .map(|result_dir| format!("{}/genome_aligned{}.sam", result_dir, file_suffix))
+            .filter(|genome_aligned_name| Path::new(genome_aligned_name).exists());
+        let contigs = match genome_aligned_name {
+            Some(genome_aligned_name) => {
+                println!("# {} (from {})", ref_name, genome_aligned_name);
+                // only the header is read
+                let mut genome_aligned_reader =
+                    BufReader::new(File::open(&genome_aligned_name).unwrap());
+                match first_sam_file::read_sq_header(&mut genome_aligned_reader) {
+                    Ok(contigs) => contigs,
+                    Err(e) => {
+                        log_error!(
+                            "Unable to read the header of {}: {}",
+                            genome_aligned_name,
+                            e
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => {
+                let fai_name = utils::samtools_faidx_if_required(ref_name);
+                println!("# {} (from {})", ref_name, fai_name);
+                utils::read_fai(&fai_name)
+            }
+        };
+        for (name, length) in contigs {
+            println!("{}\t{}", name, length);
+        }
+    }
+}
+
+// the number of families in the histogram printed at the end of the run
+const HISTOGRAM_FAMILIES: usize = 20;
+// the length of the longest bar of the histogram
+const HISTOGRAM_WIDTH: usize = 40;
+
+// print the number of insertions of the most common transposon families to stderr,
+// as a quick check of the run (e.g. that roo dominates in Drosophila)
+// the counts are sorted from the most to the least common family
+// (in the JSON log format, the counts of every family are a single log event instead)
+fn print_family_histogram(counts: &[(&str, usize)]) {
+    if utils::log_json() {
+        let counts: Vec<(&str, u64)> = counts
+            .iter()
+            .map(|&(family, count)| (family, count as u64))
+            .collect();
+        utils::print_json_event("info", "Insertions per transposon family", &counts);
+        return;
+    }
+    if counts.is_empty() {
+        eprintln!("\nNo insertions found");
+        return;
+    }
+    eprintln!(
+        "\nInsertions per transposon family (top {} of {}):",
+        HISTOGRAM_FAMILIES.min(counts.len()),
+        counts.len()
+    );
+    let max_count = counts[0].1;
+    let name_width = counts
+        .iter()
+        .take(HISTOGRAM_FAMILIES)
+        .map(|(family, _)| family.len())
+        .max()
+        .unwrap();
+    for (family, count) in counts.iter().take(HISTOGRAM_FAMILIES) {
+        // every family with an insertion gets at least 1 "#"
+        let bar_length = (count * HISTOGRAM_WIDTH / max_count).max(1);
+        eprintln!(
+            "{:<name_width$}  {:>6}  {}",
+            family,
+            count,
+            "#".repeat(bar_length),
+            name_width = name_width
+        );
+    }
+}
+
+// copy the genome alignments of the reads that support the insertions into a SAM file (see --emit-support-reads)
+fn write_support_sam(
+    genome_aligned_name: &str,
+    support_sam_name: &str,
+    read_names: &HashMap<String, Vec<String>>,
+    sam_layout: &HashMap<String, usize>,
+) -> Result<u64> {
+    let reader = BufReader::with_capacity(IO_BUFFER_CAPACITY, File::open(genome_aligned_name)?);
+    let mut writer = BufWriter::with_capacity(IO_BUFFER_CAPACITY, File::create(support_sam_name)?);
+    let num_records =
+        support_reads::extract_support_reads(reader, &mut writer, read_names, sam_layout)?;
+    writer.flush()?;
+    return Ok(num_records);
+}
+
+// write the boundary positions of the split-reads of the insertions (see --emit-boundary-positions)
+fn write_boundary_positions(
+    boundary_positions_name: &str,
+    output: &Vec<OutputInsertions>,
+    chrom_order: Option<&Vec<String>>,
+) -> Result<u64> {
+    let mut writer =
+        BufWriter::with_capacity(IO_BUFFER_CAPACITY, File::create(boundary_positions_name)?);
+    let num_reads = support_reads::write_boundary_positions(&mut writer, output, chrom_order)?;
+    writer.flush()?;
+    return Ok(num_reads);
+}
+
+// the suffix of the output files of a --recall run
+// e.g. ".reference" for --recall reference
+fn recall_suffix(options: &MapOptions) -> String {
+    if options.recall {
+        return format!(".{}", options.insertion_types.name());
+    }
+    return String::new();
+}
+
+// the name of the final output file in result_dir
+// (the output of a single chromosome with --split-by-chromosome, e.g. "te_mapper_output.2L.tsv")
+fn output_name(options: &MapOptions, chrom: Option<&str>) -> String {
+    // the characters of a contig name that don't belong in a file name are replaced with "_"
+    let chrom_part = match chrom {
+        Some(chrom) => format!(
+            ".{}",
+            chrom.replace(
+                |c: char| !(c.is_ascii_alphanumeric() || "._-".contains(c)),
+                "_"
+            )
+        ),
+        None => String::new(),
+    };
+    let mut output_name = format!(
+        "te_mapper_output{}{}.{}",
+        recall_suffix(options),
+        chrom_part,
+        options.output_format.extension()
+    );
+    if options.gzip_output {
+        output_name.push_str(".gz");
+    }
+    return output_name;
+}
+
+// the index of the per-chromosome output files (see --split-by-chromosome)
+fn chrom_index_name(options: &MapOptions) -> String {
+    format!("te_mapper_output{}.chromosomes.tsv", recall_suffix(options))
+}
+
+// write the insertions of every chromosome to their own output file in result_dir, and an index
+// of the files with the number of insertions in each (one line per chromosome with insertions)
+// returns the insertions (now grouped by chromosome) for the counts at the end of the run
+fn write_chrom_outputs(
+    output: Vec<OutputInsertions>,
+    result_dir: &Path,
+    options: &MapOptions,
+    output_columns: &OutputColumns,
+    chrom_lengths: &HashMap<String, u64>,
+    chrom_order: Option<&Vec<String>>,
+    sample: &str,
+) -> Result<Vec<OutputInsertions>> {
+    let mut index = BufWriter::new(File::create(result_dir.join(chrom_index_name(options)))?);
+    writeln!(index, "chrom\tfile\tnon_reference\treference")?;
+    let mut grouped_output: Vec<OutputInsertions> = Vec::new();
+    for (chrom, chrom_output) in select_alignments::group_by_chromosome(output, chrom_order) {
+        let mut chrom_output_name = output_name(options, Some(&chrom));
+        let chrom_output_path = result_dir.join(&chrom_output_name);
+        select_alignments::write_output(
+            &chrom_output,
+            &chrom_output_path,
+            options.output_format,
+            options.header_style,
+            output_columns,
+            chrom_lengths,
+            chrom_order,
+            sample,
+            options.vcf_read_evidence,
+            options.compact_json,
+        )?;
+        if options.bgzip {
+            let columns = options
+                .output_format
+                .tabix_columns()
+                .expect("Only the TSV, GFF3, VCF and McClintock outputs can be indexed");
+            bgzf::bgzip_and_index(&chrom_output_path, columns)?;
+            chrom_output_name.push_str(".gz");
+        }
+        writeln!(
+            index,
+            "{}\t{}\t{}\t{}",
+            chrom,
+            chrom_output_name,
+            chrom_output
+                .iter()
+                .map(|chrom| chrom.non_reference.len())
+                .sum::<usize>(),
+            chrom_output
+                .iter()
+                .map(|chrom| chrom.reference.len())
+                .sum::<usize>()
+        )?;
+        grouped_output.extend(chrom_output);
+    }
+    index.flush()?;
+    return Ok(grouped_output);
+}
+
+// the files in result_dir that a run writes, starting from phase
+// (the files of the earlier phases are the inputs of the run, so they are not included)
+pub fn run_output_files(ref_names: &[&str], options: &MapOptions, phase: u32) -> Vec<String> {
+    let mut file_names: Vec<String> = Vec::new();
+    if phase <= 1 && options.te_aligned.is_none() {
+        if options.genome_first {
+            file_names.push("genome_first.sam".to_owned());
+            file_names.push("genome_clips.fasta".to_owned());
+            file_names.push("te_clips.sam".to_owned());
+        }
+        file_names.push("te_aligned.sam".to_owned());
+    }
+    if phase <= 2 {
+        file_names.push(format!(
+            "selected_reads.{}",
+            options.selected_reads_format.extension()
+        ));
+    }
+    let file_suffixes: Vec<String> = if ref_names.len() > 1 {
+        reference_labels(ref_names)
+            .iter()
+            .map(|label| format!(".{}", label))
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    for file_suffix in &file_suffixes {
+        if phase <= 3 {
+            file_names.push(format!("genome_aligned{}.sam", file_suffix));
+        }
+        if options.cache_alignments && !options.from_cache {
+            file_names.push(format!("alignments{}.bin", file_suffix));
+        }
+        if options.support_reads_cap.is_some() {
+            file_names.push(format!("supporting_reads{}.bam", file_suffix));
+        }
+    }
+    if options.emit_boundary_positions {
+        file_names.push(format!("boundary_positions{}.tsv", recall_suffix(options)));
+    }
+    // an output given with --output is outside result_dir
+    // (the names of the per-chromosome outputs aren't known until the insertions are called)
+    if options.split_by_chromosome {
+        file_names.push(chrom_index_name(options));
+    } else if options.output.is_none() {
+        let output_name = output_name(options, None);
+        if options.bgzip {
+            file_names.push(format!("{}.gz", output_name));
+        }
+        file_names.push(output_name);
+    }
+    file_names.push(format!("te_mapper_manifest{}.json", recall_suffix(options)));
+    return file_names;
+}
+
+// the most worker threads that are used by default
+// (parsing the alignments is limited by the disk long before this)
+const MAX_DEFAULT_MAPPER_THREADS: usize = 32;
+
+// the number of worker threads if --mapper-threads isn't given: one per available CPU,
+// so that small laptops aren't oversubscribed and big servers aren't underused
+fn default_mapper_threads() -> usize {
+    utils::available_cpus().min(MAX_DEFAULT_MAPPER_THREADS)
+}
 
 pub fn map(
-    ref_name: &str,
+    ref_names: &[&str],
     reads: &Reads,
     transposons_name: &str,
     result_dir: &str,
-    bwa_threads: u16,
-    output_should_be_json: bool,
-    phase: u32,
+    options: &MapOptions,
 ) {
-    // create the result directory if it's not already there
-    match PathDir::create(result_dir) {
-        Ok(_) => (),
-        Err(e) => panic!("Unable to create result directory: {}", e),
+    let bwa_threads = options.bwa_threads;
+    // select_reads and the parsing of the genome alignments run on the global rayon thread pool
+    let mapper_threads = options
+        .mapper_threads
+        .unwrap_or_else(default_mapper_threads);
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(mapper_threads)
+        .build_global()
+    {
+        utils::print_warning(format_args!(
+            "Unable to start {} mapper threads: {}",
+            mapper_threads, e
+        ));
+    }
+    progress!("Mapper threads: {}", rayon::current_num_threads());
+    // the cached alignments are only useful in phase 4
+    let phase = if options.from_cache {
+        4
+    } else if options.te_aligned.is_some() {
+        options.phase.max(2)
+    } else {
+        options.phase
     };
+
+    let result_dir_path = utils::create_result_dir(result_dir);
+    utils::check_overwrite(
+        result_dir,
+        &run_output_files(ref_names, options, phase),
+        options.overwrite,
+    );
     // make sure that the reference and transposons files are present
-    match PathFile::new(ref_name) {
-        Ok(_) => (),
-        Err(e) => panic!("Reference genome not present: {}", e),
-    };
+    for ref_name in ref_names {
+        match PathFile::new(ref_name) {
+            Ok(_) => (),
+            Err(e) => panic!("Reference genome not present: {}", e),
+        };
+    }
     match PathFile::new(transposons_name) {
         Ok(_) => (),
         Err(e) => panic!("Transposons file not present: {}", e),
     };
+    // read the annotation before the alignments, so that a bad file doesn't waste a whole run
+    let annotation = options.annotation.as_ref().map(|annotation_name| {
+        match Annotation::open(annotation_name, &options.contig_renames) {
+            Ok(annotation) => annotation,
+            Err(e) => {
+                log_error!("Unable to read the annotation {}: {}", annotation_name, e);
+                std::process::exit(1);
+            }
+        }
+    });
+    let coverage = options.coverage.as_ref().map(|coverage_name| {
+        match Coverage::open(coverage_name, &options.contig_renames) {
+            Ok(coverage) => coverage,
+            Err(e) => {
+                log_error!("Unable to read the coverage {}: {}", coverage_name, e);
+                std::process::exit(1);
+            }
+        }
+    });
 
-    // index the transposons file and reference sequence if necessary
+    // index the transposons file and reference sequences if necessary
     if phase <= 1 {
         utils::bwa_index_if_required(transposons_name);
     }
     if phase <= 3 {
-        utils::bwa_index_if_required(ref_name);
+        for ref_name in ref_names {
+            utils::bwa_index_if_required(ref_name);
+        }
     }
 
-    let te_aligned_name = format!("{}/te_aligned.sam", result_dir);
-    let genome_aligned_name = format!("{}/genome_aligned.sam", result_dir);
-    let result_dir_path = PathDir::new(result_dir).unwrap();
+    let te_aligned_name = match &options.te_aligned {
+        Some(te_aligned_name) => te_aligned_name.clone(),
+        None => format!("{}/te_aligned.sam", result_dir),
+    };
+    let selected_reads_file = format!(
+        "selected_reads.{}",
+        options.selected_reads_format.extension()
+    );
     let selected_reads_path =
-        PathFile::create(result_dir_path.concat("selected_reads.fasta").unwrap()).unwrap();
+        PathFile::create(result_dir_path.concat(&selected_reads_file).unwrap()).unwrap();
 
     let mut transposons_map = None;
+    // the most common read length of the TE alignment, which bounds the TSD length
+    let mut modal_read_length = None;
+    let mut max_tsd_length = options.max_tsd_length;
+    // the records skipped in phases 2 and 4, summarized at the end
+    let diagnostics = Diagnostics::new(options.strict);
+    let mut reference_te_lengths = None;
+    let mut timings = PhaseTimings::default();
+
+    // phases 1 and 2 don't depend on the reference genome,
+    // so they are only run once even if there are multiple references
 
     // phase 1: align the reads to the transposons
+    // (or to the genome first, and then only their soft-clips to the transposons)
     if phase <= 1 {
-        println!("\n\nPHASE 1\n");
-        utils::bwa_mem_align(transposons_name, reads, &te_aligned_name[..], bwa_threads);
+        utils::set_log_phase(1);
+        progress!("\n\nPHASE 1\n");
+        let phase_start = Instant::now();
+        if options.genome_first {
+            genome_first::align_genome_first(
+                ref_names[0],
+                reads,
+                transposons_name,
+                &te_aligned_name,
+                result_dir,
+                options.min_clip,
+                bwa_threads,
+                &options.bwa_options,
+            );
+        } else {
+            utils::bwa_mem_align(
+                transposons_name,
+                reads,
+                utils::BwaOutput::File(&te_aligned_name[..]),
+                bwa_threads,
+                &options.bwa_options,
+            );
+        }
+        PhaseTimings::add(&mut timings.te_alignment, phase_start);
     }
 
     let te_aligned_path = PathFile::new(te_aligned_name.clone()).unwrap();
@@ -55,69 +809,647 @@ pub fn map(
     // phase 2: look for split-reads (reads that go off one end of the transposon)
     // in order to be safe, only perfect matches are used
     if phase <= 2 {
-        println!("\n\nPHASE 2\n");
-        transposons_map = Some(select_reads::select_reads(
+        utils::set_log_phase(2);
+        progress!("\n\nPHASE 2\n");
+        let phase_start = Instant::now();
+        let (transposons, read_length) = select_reads::select_reads(
             &te_aligned_path,
             &selected_reads_path,
             false,
-        ));
+            &options.sam_layout,
+            options.min_clip,
+            options.max_read_n,
+            options.long_reads,
+            &options.te_filter,
+            &options.family_map,
+            options.te_lengths.as_ref(),
+            options.case_insensitive_te_names,
+            options.selected_reads_format,
+            &diagnostics,
+        );
+        transposons_map = Some(transposons);
+        modal_read_length = read_length;
+        PhaseTimings::add(&mut timings.select_reads, phase_start);
     }
-    // phase 3: align the potential split-reads to the genome and make sure that
-    // the other half of the split-read is a perfect match as well
-    if phase <= 3 {
-        println!("\n\nPHASE 3\n");
-        let selected_reads_name = format!("{}/selected_reads.fasta", result_dir);
-        utils::bwa_mem_align(
-            ref_name,
-            &Reads::SingleEnd(selected_reads_name),
-            &genome_aligned_name[..],
-            bwa_threads,
+
+    // phases 3 and 4 are run once per reference genome
+    // (with a single reference, the intermediate files keep their usual names)
+    let multiple_refs = ref_names.len() > 1;
+    // the final output is result_dir/output_name unless --output is given
+    let mut output_name = match &options.output {
+        Some(output) => output.clone(),
+        None => output_name(options, None),
+    };
+    let output_path = match &options.output {
+        Some(output) => PathBuf::from(output),
+        None => result_dir_path.as_path().join(&output_name),
+    };
+    // the optional columns of the output, whether it is streamed or not
+    let output_columns = OutputColumns {
+        reference_source: multiple_refs,
+        tsd_sequence: options.tsd_sequence,
+        zygosity: coverage.is_some(),
+        annotation: annotation.is_some(),
+        overlapping_calls: options.reconcile_overlaps == Some(OverlapResolution::Annotate),
+        one_sided: options.report_one_sided,
+        fragments: options.count_fragments,
+        breakpoint_ranges: options.breakpoint_ranges,
+        neighbors: options.annotate_neighbors,
+    };
+    // the output is written as each chromosome is called if it doesn't have to be sorted
+    // (the insertions are still kept without their split-reads for the counts at the end,
+    // so it isn't when the split-reads are written too)
+    let mut output_stream = if phase <= 4
+        && options.output_format.can_stream()
+        && !options.sorted
+        && options.support_reads_cap.is_none()
+        && !options.emit_boundary_positions
+        && !options.buffer_output
+        && !options.split_by_chromosome
+    {
+        let output_stream = OutputStream::create(
+            &output_path,
+            options.output_format,
+            options.header_style,
+            &output_columns,
         );
+        match output_stream {
+            Ok(output_stream) => Some(output_stream),
+            Err(e) => {
+                log_error!("Unable to write the output: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let labels = reference_labels(ref_names);
+    let has_region = match &options.region {
+        Some(region) if phase <= 4 => {
+            references_with_region(ref_names, region, &options.contig_renames)
+        }
+        _ => vec![true; ref_names.len()],
+    };
+    let mut output = Vec::new();
+    let mut all_chrom_lengths = HashMap::new();
+    // the reference source of the insertions of each reference, its genome alignment and its file suffix
+    let mut genome_alignments: Vec<(Option<String>, String, String)> = Vec::new();
+    for ((ref_name, label), has_region) in ref_names.iter().zip(labels).zip(has_region) {
+        if !has_region {
+            progress!(
+                "\n\nSkipping {}: the region's chromosome is not in it",
+                ref_name
+            );
+            continue;
+        }
+        let file_suffix = if multiple_refs {
+            format!(".{}", label)
+        } else {
+            String::new()
+        };
+        let genome_aligned_name = format!("{}/genome_aligned{}.sam", result_dir, file_suffix);
+        genome_alignments.push((
+            if multiple_refs {
+                Some(label.clone())
+            } else {
+                None
+            },
+            genome_aligned_name.clone(),
+            file_suffix.clone(),
+        ));
+
+        let selected_reads = Reads::SingleEnd(format!("{}/{}", result_dir, selected_reads_file));
+        // phase 3: align the potential split-reads to the genome and make sure that
+        // the other half of the split-read is a perfect match as well
+        // (with --stream, the alignment is parsed in phase 4 as it is written)
+        if phase <= 3 && !options.stream {
+            utils::set_log_phase(3);
+            progress!("\n\nPHASE 3 ({})\n", ref_name);
+            let phase_start = Instant::now();
+            utils::bwa_mem_align(
+                ref_name,
+                &selected_reads,
+                utils::BwaOutput::File(&genome_aligned_name[..]),
+                bwa_threads,
+                &options.bwa_options,
+            );
+            PhaseTimings::add(&mut timings.genome_alignment, phase_start);
+        }
+        // phase 4: select the alignments that are properly positioned on a break-point
+        // between a transposon and the genome (down to the exact nucleotide)
+        if phase <= 4 {
+            utils::set_log_phase(4);
+            progress!("\n\nPHASE 4 ({})\n", ref_name);
+            let phase_start = Instant::now();
+            let alignment_cache_name = format!("{}/alignments{}.bin", result_dir, file_suffix);
+            let alignment_cache_path;
+            let genome_aligned_path;
+            let alignment_source = if options.from_cache {
+                alignment_cache_path = match PathFile::new(&alignment_cache_name) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        log_error!(
+                            "Unable to read the alignment cache {} (run phase 4 with --cache-alignments first): {}",
+                            alignment_cache_name,
+                            e
+                        );
+                        std::process::exit(1);
+                    }
+                };
+                AlignmentSource::Cache(&alignment_cache_path)
+            } else if options.stream {
+                AlignmentSource::Bwa(BwaStream {
+                    ref_name,
+                    reads: &selected_reads,
+                    bwa_threads,
+                    bwa_options: &options.bwa_options,
+                })
+            } else if options.cache_alignments {
+                genome_aligned_path = PathFile::new(genome_aligned_name).unwrap();
+                alignment_cache_path = PathFile::create(alignment_cache_name).unwrap();
+                AlignmentSource::SamWithCache(&genome_aligned_path, &alignment_cache_path)
+            } else {
+                genome_aligned_path = PathFile::new(genome_aligned_name).unwrap();
+                AlignmentSource::Sam(&genome_aligned_path)
+            };
+
+            if transposons_map.is_none() {
+                let (transposons, read_length) = select_reads::select_reads(
+                    &te_aligned_path,
+                    &selected_reads_path,
+                    true,
+                    &options.sam_layout,
+                    options.min_clip,
+                    options.max_read_n,
+                    options.long_reads,
+                    &options.te_filter,
+                    &options.family_map,
+                    options.te_lengths.as_ref(),
+                    options.case_insensitive_te_names,
+                    options.selected_reads_format,
+                    &diagnostics,
+                );
+                transposons_map = Some(transposons);
+                modal_read_length = read_length;
+            }
+
+            if max_tsd_length.is_none() {
+                let derived =
+                    select_alignments::max_tsd_length(modal_read_length, options.min_clip);
+                match modal_read_length {
+                    Some(read_length) => progress!(
+                        "Max TSD length: {} bp (read length {} bp, minimum clip {} bp; use --max-tsd-length to override)",
+                        derived, read_length, options.min_clip
+                    ),
+                    None => progress!(
+                        "Max TSD length: {} bp (the read length is unknown; use --max-tsd-length to override)",
+                        derived
+                    ),
+                }
+                max_tsd_length = Some(derived);
+            }
+
+            if reference_te_lengths.is_none() {
+                let (lengths, excluded) = select_alignments::reference_te_lengths(
+                    transposons_map.as_ref().unwrap(),
+                    options.min_element_length,
+                );
+                if !excluded.is_empty() {
+                    progress!(
+                        "Excluded from the reference insertions (shorter than {} bp): {}",
+                        options.min_element_length,
+                        excluded.join(", ")
+                    );
+                }
+                reference_te_lengths = Some(lengths);
+            }
+
+            // Drosophila Melanogaster has these 7 chromosomes (use --chromosomes for a different organism)
+            let chroms = match (&options.region, &options.chromosomes) {
+                (Some(region), _) => vec![region.chrom.clone()],
+                (None, Some(chromosomes)) => chromosomes.clone(),
+                (None, None) => vec![
+                    "2L".to_owned(),
+                    "2R".to_owned(),
+                    "3L".to_owned(),
+                    "3R".to_owned(),
+                    "4".to_owned(),
+                    "X".to_owned(),
+                    "Y".to_owned(),
+                ],
+            };
+
+            // reads whose boundary falls past the end of a chromosome are skipped
+            let chrom_lengths: HashMap<String, u64> = utils::read_chrom_lengths(ref_name)
+                .into_iter()
+                .map(|(chrom, length)| {
+                    (
+                        utils::rename_contig(&chrom, &options.contig_renames).to_owned(),
+                        length,
+                    )
+                })
+                .collect();
+            let (chroms, excluded) =
+                utils::exclude_short_contigs(chroms, &chrom_lengths, options.min_contig_length);
+            if !excluded.is_empty() {
+                let genome_length: u64 = chrom_lengths.values().sum();
+                let excluded_length: u64 = excluded.iter().map(|chrom| chrom_lengths[chrom]).sum();
+                progress!(
+                    "Excluded {} contigs shorter than {} bp ({:.2}% of the genome length of {})",
+                    excluded.len(),
+                    options.min_contig_length,
+                    100.0 * excluded_length as f64 / genome_length as f64,
+                    ref_name
+                );
+            }
+
+            // params (you can change these depending on the situation)
+            // min TSD length: 0
+            // max TSD length: see max_tsd_length above
+            // min TE length (for reference TE's): 0.1 * the original length (see --reference-length-window)
+            // max TE length (for reference TE's): 1.5 * the original length
+            all_chrom_lengths.extend(chrom_lengths.clone());
+            let mut reference_fasta = if options.tsd_sequence {
+                let fai_name = utils::samtools_faidx_if_required(ref_name);
+                match IndexedFasta::open(ref_name, &fai_name, &options.contig_renames) {
+                    Ok(reference_fasta) => Some(reference_fasta),
+                    Err(e) => {
+                        log_error!(
+                            "Unable to extract the TSD sequences from {}: {}",
+                            ref_name,
+                            e
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            select_alignments::select_alignments(
+                chroms,
+                0,
+                max_tsd_length.unwrap(),
+                options.reference_length_window.0,
+                options.reference_length_window.1,
+                &options.family_params,
+                alignment_source,
+                transposons_map.as_ref().unwrap(),
+                reference_te_lengths.as_ref().unwrap(),
+                &chrom_lengths,
+                &options.sam_layout,
+                &options.contig_renames,
+                options.min_clip,
+                options.report_one_sided,
+                options.min_genome_overlap,
+                options.insertion_types,
+                if multiple_refs { Some(label) } else { None },
+                options.region.as_ref(),
+                &diagnostics,
+                |mut chrom_output| {
+                    if options.count_fragments {
+                        select_alignments::count_fragments(&mut chrom_output);
+                    }
+                    if options.reconcile_ltr {
+                        let merges = select_alignments::reconcile_ltr_calls(&mut chrom_output);
+                        for merge in &merges {
+                            progress!("{}", merge);
+                        }
+                        diagnostics.record_many(
+                            Stage::InsertionCalling,
+                            "merged into an overlapping LTR call",
+                            merges.len() as u64,
+                        );
+                    }
+                    if let Some(resolution) = options.reconcile_overlaps {
+                        let drops = select_alignments::reconcile_overlapping_calls(
+                            &mut chrom_output,
+                            options.overlap_window,
+                            resolution,
+                        );
+                        for drop in &drops {
+                            progress!("{}", drop);
+                        }
+                        diagnostics.record_many(
+                            Stage::InsertionCalling,
+                            "dropped for an overlapping call of the other insertion type",
+                            drops.len() as u64,
+                        );
+                    }
+                    if let Some(reference_fasta) = &mut reference_fasta {
+                        if let Err(e) =
+                            select_alignments::add_tsd_sequences(&mut chrom_output, reference_fasta)
+                        {
+                            log_error!(
+                                "Unable to extract the TSD sequences from {}: {}",
+                                ref_name,
+                                e
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                    if let Some(annotation) = &annotation {
+                        select_alignments::add_annotations(&mut chrom_output, annotation);
+                    }
+                    if let Some(coverage) = &coverage {
+                        select_alignments::add_zygosity_hints(
+                            &mut chrom_output,
+                            coverage,
+                            &options.zygosity_thresholds,
+                        );
+                    }
+                    if options.breakpoint_ranges {
+                        select_alignments::add_breakpoint_ranges(&mut chrom_output);
+                    }
+                    if options.annotate_neighbors {
+                        select_alignments::annotate_neighbors(&mut chrom_output);
+                    }
+                    if let Some(stream) = &mut output_stream {
+                        if let Err(e) = stream.write_chrom(&chrom_output) {
+                            log_error!("Unable to write the output: {}", e);
+                            if let Some(stream) = output_stream.take() {
+                                stream.discard();
+                            }
+                            std::process::exit(1);
+                        }
+                        chrom_output.drop_reads();
+                    }
+                    output.push(chrom_output);
+                },
+            );
+            PhaseTimings::add(&mut timings.select_alignments, phase_start);
+        }
     }
-    // phase 4: select the alignments that are properly positioned on a break-point
-    // between a transposon and the genome (down to the exact nucleotide)
+
     if phase <= 4 {
-        println!("\n\nPHASE 4\n");
-        let genome_aligned_path = PathFile::new(genome_aligned_name).unwrap();
+        let output_start = Instant::now();
+        let sample = options
+            .sample
+            .clone()
+            .unwrap_or_else(|| reads.sample_name());
+        let chrom_order = if options.sorted {
+            match &options.chrom_order_file {
+                Some(chrom_order_file) => Some(utils::read_chrom_order(chrom_order_file)),
+                None => {
+                    let mut chrom_order: Vec<String> = Vec::new();
+                    for ref_name in ref_names {
+                        let fai_name = utils::samtools_faidx_if_required(ref_name);
+                        for chrom in utils::read_chrom_order(&fai_name) {
+                            let chrom =
+                                utils::rename_contig(&chrom, &options.contig_renames).to_owned();
+                            if !chrom_order.contains(&chrom) {
+                                chrom_order.push(chrom);
+                            }
+                        }
+                    }
+                    Some(chrom_order)
+                }
+            }
+        } else {
+            None
+        };
+        if options.split_by_chromosome {
+            let chrom_outputs = write_chrom_outputs(
+                output,
+                result_dir_path.as_path(),
+                options,
+                &output_columns,
+                &all_chrom_lengths,
+                chrom_order.as_ref(),
+                &sample,
+            );
+            output = match chrom_outputs {
+                Ok(chrom_outputs) => chrom_outputs,
+                Err(e) => {
+                    log_error!("Unable to write the per-chromosome outputs: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            output_name = chrom_index_name(options);
+            progress!("Per-chromosome outputs listed in {}", output_name);
+        } else {
+            let written = match output_stream {
+                Some(output_stream) => output_stream.finish(),
+                None => select_alignments::write_output(
+                    &output,
+                    &output_path,
+                    options.output_format,
+                    options.header_style,
+                    &output_columns,
+                    &all_chrom_lengths,
+                    chrom_order.as_ref(),
+                    &sample,
+                    options.vcf_read_evidence,
+                    options.compact_json,
+                ),
+            };
+            if let Err(e) = written {
+                log_error!(
+                    "Unable to write the output {}: {}",
+                    output_path.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+            if options.bgzip {
+                let columns = options
+                    .output_format
+                    .tabix_columns()
+                    .expect("Only the TSV, GFF3, VCF and McClintock outputs can be indexed");
+                if let Err(e) = bgzf::bgzip_and_index(&output_path, columns) {
+                    log_error!("Unable to compress and index the output: {}", e);
+                    std::process::exit(1);
+                }
+                output_name.push_str(".gz");
+                progress!("Output compressed and indexed");
+            }
+        }
+
+        if options.emit_boundary_positions {
+            let boundary_positions_name = format!(
+                "{}/boundary_positions{}.tsv",
+                result_dir,
+                recall_suffix(options)
+            );
+            match write_boundary_positions(&boundary_positions_name, &output, chrom_order.as_ref())
+            {
+                Ok(num_reads) => progress!(
+                    "{} split-read boundaries written to {}",
+                    num_reads,
+                    boundary_positions_name
+                ),
+                Err(e) => {
+                    log_error!(
+                        "Unable to write the boundary positions to {}: {}",
+                        boundary_positions_name,
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
 
-        let output_path;
-        if output_should_be_json {
-            output_path =
-                PathFile::create(result_dir_path.concat("te_mapper_output.json").unwrap()).unwrap();
+        if let Some(support_reads_cap) = options.support_reads_cap {
+            for (reference_source, genome_aligned_name, file_suffix) in &genome_alignments {
+                let read_names = support_reads::support_read_names(
+                    &output,
+                    chrom_order.as_ref(),
+                    reference_source,
+                    support_reads_cap,
+                );
+                let support_sam_name =
+                    format!("{}/supporting_reads{}.sam", result_dir, file_suffix);
+                let extracted = write_support_sam(
+                    genome_aligned_name,
+                    &support_sam_name,
+                    &read_names,
+                    &options.sam_layout,
+                );
+                let num_records = match extracted {
+                    Ok(num_records) => num_records,
+                    Err(e) => {
+                        log_error!(
+                            "Unable to extract the supporting reads from {}: {}",
+                            genome_aligned_name,
+                            e
+                        );
+                        std::process::exit(1);
+                    }
+                };
+                let support_bam_name =
+                    format!("{}/supporting_reads{}.bam", result_dir, file_suffix);
+                utils::samtools_sort_and_index(&support_sam_name, &support_bam_name);
+                if let Err(e) = std::fs::remove_file(&support_sam_name) {
+                    log_error!("Unable to remove {}: {}", support_sam_name, e);
+                    std::process::exit(1);
+                }
+                progress!(
+                    "{} supporting alignments written to {}",
+                    num_records,
+                    support_bam_name
+                );
+            }
+        }
+
+        PhaseTimings::add(&mut timings.select_alignments, output_start);
+        let (distinct_families, distinct_elements) = select_alignments::distinct_te_counts(&output);
+        let manifest = Manifest {
+            version: env!("CARGO_PKG_VERSION"),
+            bwa_version: sx_versions::tool_version("bwa"),
+            samtools_version: sx_versions::tool_version("samtools"),
+            sample: &sample,
+            references: ref_names,
+            reads: reads.files(),
+            transposons: transposons_name,
+            output: output_name,
+            output_format: options.output_format.extension(),
+            non_reference_insertions: output.iter().map(|chrom| chrom.non_reference.len()).sum(),
+            reference_insertions: output.iter().map(|chrom| chrom.reference.len()).sum(),
+            distinct_families: distinct_families,
+            distinct_elements: distinct_elements,
+            phase_seconds: if options.profile {
+                Some(&timings)
+            } else {
+                None
+            },
+        };
+        let manifest_name = format!(
+            "{}/te_mapper_manifest{}.json",
+            result_dir,
+            recall_suffix(options)
+        );
+        if let Err(e) = write_manifest(&manifest_name, &manifest) {
+            log_error!("Unable to write the manifest {}: {}", manifest_name, e);
+            std::process::exit(1);
+        }
+
+        if utils::log_json() {
+            utils::print_json_event(
+                "info",
+                "Insertions called",
+                &[
+                    (
+                        "non_reference_insertions",
+                        manifest.non_reference_insertions as u64,
+                    ),
+                    ("reference_insertions", manifest.reference_insertions as u64),
+                ],
+            );
+        }
+        print_family_histogram(&select_alignments::family_counts(&output));
+    }
+    if utils::log_json() {
+        let skipped = diagnostics.skipped_counts();
+        let counts: Vec<(&str, u64)> = skipped
+            .iter()
+            .map(|(reason, count)| (&reason[..], *count))
+            .collect();
+        if counts.is_empty() {
+            utils::print_json_event("info", "No records were skipped", &[]);
         } else {
-            output_path =
-                PathFile::create(result_dir_path.concat("te_mapper_output.tsv").unwrap()).unwrap();
-        }
-
-        // Drosophila Melanogaster has these 7 chromosomes (change them for a different organism)
-        let chroms = vec![
-            "2L".to_owned(),
-            "2R".to_owned(),
-            "3L".to_owned(),
-            "3R".to_owned(),
-            "4".to_owned(),
-            "X".to_owned(),
-            "Y".to_owned(),
-        ];
-
-        // params (you can change these depending on the situation)
-        // min TSD length: 0
-        // max TSD length: 100
-        // min TE length (for reference TE's): 0.1 * the original length
-        // max TE length (for reference TE's): 1.5 * the original length
-        select_alignments::select_alignments(
-            chroms,
-            0,
-            100,
-            0.1,
-            1.5,
-            &genome_aligned_path,
-            &output_path,
-            &transposons_map.unwrap_or_else(|| {
-                select_reads::select_reads(&te_aligned_path, &selected_reads_path, true)
-            }),
-            output_should_be_json,
+            utils::print_json_event("warning", "Skipped records", &counts);
+        }
+    } else {
+        match diagnostics.summary() {
+            Some(summary) => eprintln!("\nSkipped records:\n{}", summary),
+            None => eprintln!("\nNo records were skipped"),
+        }
+    }
+    if let Some(diagnostics_json) = &options.diagnostics_json {
+        if let Err(e) = diagnostics.write_json(diagnostics_json) {
+            log_error!(
+                "Unable to write the diagnostics to {}: {}",
+                diagnostics_json,
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+    if options.profile {
+        if utils::log_json() {
+            let counts: Vec<(&str, u64)> = [
+                ("phase_1_ms", timings.te_alignment),
+                ("phase_2_ms", timings.select_reads),
+                ("phase_3_ms", timings.genome_alignment),
+                ("phase_4_ms", timings.select_alignments),
+            ]
+            .iter()
+            .filter_map(|(phase, seconds)| {
+                seconds.map(|seconds| (*phase, (seconds * 1000.0) as u64))
+            })
+            .collect();
+            utils::print_json_event("info", "Phase timings", &counts);
+        } else {
+            eprintln!("\nPhase timings:\n{}", timings.table());
+        }
+    }
+    if options.abort_on_empty_output
+        && output
+            .iter()
+            .all(|chrom| chrom.non_reference.is_empty() && chrom.reference.is_empty())
+    {
+        if utils::log_json() {
+            let funnel: Vec<(String, u64)> = diagnostics
+                .funnel()
+                .into_iter()
+                .flat_map(|(stage, kept, skipped)| {
+                    vec![
+                        (format!("{}: kept", stage.description()), kept),
+                        (format!("{}: skipped", stage.description()), skipped),
+                    ]
+                })
+                .collect();
+            let counts: Vec<(&str, u64)> = funnel
+                .iter()
+                .map(|(stage, count)| (&stage[..], *count))
+                .collect();
+            utils::print_json_event("info", "Read funnel", &counts);
+        } else {
+            eprintln!("\nRead funnel:\n{}", diagnostics.funnel_summary());
+        }
+        log_error!(
+            "No insertions were called (--abort-on-empty-output): please check the chromosome names (--chromosomes), the transposon library and the reads"
         );
+        std::process::exit(1);
     }
-    println!("\n\nTE mapping done\n");
+    progress!("\n\nTE mapping done\n");
 }
@@ -1,21 +1,63 @@
+use std::collections::HashMap;
+use std::io::Cursor;
 use std::process::Command;
 
+use crate::errors::{Result, StanexError};
+use crate::te_mapper_utils::first_sam_file;
 use crate::utils;
-use crate::utils::Reads;
+use crate::utils::{log_error, Reads};
+
+// the variant caller can either start from raw reads (and align them first)
+// or from a BAM file that has already been aligned and sorted by coordinate
+pub enum VariantsInput {
+    Reads(Reads),
+    SortedBam(String),
+}
 
 // "fix" alignments by cleaning up read pairing information and flags
-// also compress from SAM format to BAM format to save space and
-// put the output in result_dir/fixed_alignments.sam
+// also compress from SAM format to BAM format to save space
+// (an input_file of "-" reads the SAM alignments from stdin)
+fn samtools_fixmate_command(input_file: &str, output_file: &str) -> Command {
+    let mut command = Command::new("samtools");
+    command.args(&["fixmate", "-O", "bam", input_file, output_file]);
+    return command;
+}
+
+// fix the alignments in result_dir/raw_alignments.sam and
+// put the output in result_dir/fixed_alignments.bam
 fn samtools_fixmate(result_dir: &str) {
     let input_file = format!("{}/raw_alignments.sam", result_dir);
     let output_file = format!("{}/fixed_alignments.bam", result_dir);
 
     println!("Waiting for samtools fixmate...");
-    let mut child_proc = Command::new("samtools")
-        .args(&["fixmate", "-O", "bam", &input_file[..], &output_file[..]])
-        .spawn()
-        .unwrap();
-    let _result = child_proc.wait().unwrap();
+    let mut child_proc = utils::spawn_step(
+        "samtools fixmate",
+        &mut samtools_fixmate_command(&input_file[..], &output_file[..]),
+    );
+    utils::wait_for_step("samtools fixmate", &mut child_proc);
+    println!("Alignment fixing complete");
+}
+
+// align the reads and fix the alignments at the same time, piping the SAM output of BWA
+// straight into samtools fixmate so that the (uncompressed, and often huge) SAM file is never written
+fn bwa_mem_align_and_fixmate(
+    ref_name: &str,
+    reads_names: &Reads,
+    result_dir: &str,
+    bwa_threads: u16,
+    bwa_options: &[String],
+) {
+    let output_file = format!("{}/fixed_alignments.bam", result_dir);
+    utils::bwa_mem_align(
+        ref_name,
+        reads_names,
+        utils::BwaOutput::Pipe(
+            "samtools fixmate",
+            &mut samtools_fixmate_command("-", &output_file[..]),
+        ),
+        bwa_threads,
+        bwa_options,
+    );
     println!("Alignment fixing complete");
 }
 
@@ -26,53 +68,302 @@ fn samtools_sort(result_dir: &str) {
     let output_file = format!("{}/sorted_alignments.bam", result_dir);
 
     println!("Waiting for samtools sort...");
-    let mut child_proc = Command::new("samtools")
-        .args(&["sort", "-O", "bam", &input_file[..], "-o", &output_file[..]])
-        .spawn()
-        .unwrap();
-    let _result = child_proc.wait().unwrap();
+    let mut child_proc = utils::spawn_step(
+        "samtools sort",
+        Command::new("samtools").args(&[
+            "sort",
+            "-O",
+            "bam",
+            &input_file[..],
+            "-o",
+            &output_file[..],
+        ]),
+    );
+    utils::wait_for_step("samtools sort", &mut child_proc);
     println!("Alignment sorting complete");
 }
 
+// the SAM header of a BAM file
+fn read_bam_header(bam_file: &str) -> String {
+    let output = match Command::new("samtools")
+        .args(&["view", "-H", bam_file])
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Aborting: unable to run samtools view: {}", e);
+            std::process::exit(1);
+        }
+    };
+    utils::exit_if_failed("samtools view", output.status);
+    return String::from_utf8_lossy(&output.stdout).into_owned();
+}
+
+// make sure that a user-supplied BAM file is sorted by coordinate
+// (Freebayes does not work unless alignments are in numerical order)
+// by checking for "SO:coordinate" in the @HD header line
+fn validate_sorted_bam(bam_file: &str) {
+    utils::absolute_filepath_checked(bam_file);
+    let header = read_bam_header(bam_file);
+    let is_sorted = header
+        .lines()
+        .filter(|line| line.starts_with("@HD"))
+        .any(|line| line.split('\t').any(|field| field == "SO:coordinate"));
+    if !is_sorted {
+        eprintln!(
+            "The BAM file {} is not sorted by coordinate (its @HD header line has no \"SO:coordinate\"): please sort it with samtools sort first",
+            bam_file
+        );
+        std::process::exit(2);
+    }
+}
+
+// the contigs of the alignments that aren't in the reference genome with the same length
+// (e.g. "chr2L" in the alignments and "2L" in the reference, or another assembly of the genome)
+fn mismatched_contigs(
+    bam_contigs: &[(String, u64)],
+    ref_contigs: &[(String, u64)],
+) -> Vec<(String, u64)> {
+    let ref_lengths: HashMap<&String, u64> = ref_contigs
+        .iter()
+        .map(|(name, length)| (name, *length))
+        .collect();
+    return bam_contigs
+        .iter()
+        .filter(|(name, length)| ref_lengths.get(name) != Some(length))
+        .cloned()
+        .collect();
+}
+
+// the most mismatched contigs that are listed in the error
+const MAX_LISTED_CONTIGS: usize = 10;
+
+// make sure that the contigs of the BAM file (its "@SQ" header lines) are the contigs of the reference
+// FASTA file (its ".fai" index): Freebayes only calls variants on the contigs that it finds in both,
+// so a reference with other contig names gives an empty VCF instead of an error
+fn validate_bam_contigs(bam_file: &str, ref_name: &str) {
+    let header = read_bam_header(bam_file);
+    let bam_contigs = match first_sam_file::read_sq_header(&mut Cursor::new(header)) {
+        Ok(bam_contigs) => bam_contigs,
+        Err(e) => {
+            eprintln!("Aborting: unable to read the header of {}: {}", bam_file, e);
+            std::process::exit(1);
+        }
+    };
+    let ref_contigs = utils::read_fai(&utils::samtools_faidx_if_required(ref_name));
+    let mismatched = mismatched_contigs(&bam_contigs, &ref_contigs);
+    if mismatched.is_empty() {
+        return;
+    }
+    let describe = |contigs: &[(String, u64)]| {
+        let mut names: Vec<String> = contigs
+            .iter()
+            .take(MAX_LISTED_CONTIGS)
+            .map(|(name, length)| format!("{} ({} bp)", name, length))
+            .collect();
+        if contigs.len() > MAX_LISTED_CONTIGS {
+            names.push(format!("and {} more", contigs.len() - MAX_LISTED_CONTIGS));
+        }
+        names.join(", ")
+    };
+    eprintln!(
+        "The contigs of the BAM file {} don't match the reference {}: {} of its {} contigs are not in the reference with the same length: {}\n\
+         the reference's contigs are: {}",
+        bam_file,
+        ref_name,
+        mismatched.len(),
+        bam_contigs.len(),
+        describe(&mismatched),
+        describe(&ref_contigs)
+    );
+    std::process::exit(2);
+}
+
+// run a pipeline step that writes partial_file, and rename partial_file to final_file once it has succeeded
+// if the step can't be run or fails, partial_file is removed so that no truncated output is left behind
+fn run_into_partial_file(
+    step: &str,
+    command: &mut Command,
+    partial_file: &str,
+    final_file: &str,
+) -> Result<()> {
+    let result = command
+        .status()
+        .map_err(StanexError::from)
+        .and_then(|status| utils::check_step_status(step, status));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(partial_file);
+        return Err(e);
+    }
+    std::fs::rename(partial_file, final_file)?;
+    return Ok(());
+}
+
 // do variant calling with Freebayes
 // use the --pooled-continuous flag since we are using more than 1 fly in our sample
-fn freebayes_variant_call(ref_name: &str, result_dir: &str) {
-    let input_file = format!("{}/sorted_alignments.bam", result_dir);
-    let output_file = format!("{}/variants.vcf", result_dir);
+// the VCF is written as variants.vcf.partial and only renamed once Freebayes has finished,
+// so that an interrupted or failed run doesn't leave a truncated variants.vcf
+fn freebayes_variant_call(ref_name: &str, input_file: &str, result_dir: &str) {
+    let final_file = format!("{}/variants.vcf", result_dir);
+    let output_file = format!("{}.partial", final_file);
 
     println!("Waiting for Freebayes...");
-    let mut child_proc = Command::new("freebayes")
-        .args(&[
+    let result = run_into_partial_file(
+        "freebayes",
+        Command::new("freebayes").args(&[
             "--pooled-continuous",
             "--fasta-reference",
             ref_name,
             "--bam",
-            &input_file[..],
+            input_file,
             "--vcf",
             &output_file[..],
-        ])
-        .spawn()
-        .unwrap();
-    let _result = child_proc.wait().unwrap();
+        ]),
+        &output_file,
+        &final_file,
+    );
+    if let Err(e) = result {
+        log_error!("Aborting: unable to write {}: {}", final_file, e);
+        std::process::exit(1);
+    }
     println!("Variant calling complete");
 }
 
 // run the entire pipeline, one step after another
 // everything must be blocking since each step depends on the previous step's output
+// if a sorted BAM file is supplied, the alignment steps are skipped entirely
+// the raw SAM alignments are only written to disk with keep_temp_sam (see --keep-temp-sam),
+// otherwise they are piped from BWA into samtools fixmate
 pub fn run_variant_calling_pipeline(
     ref_name: &str,
-    reads_names: Reads,
+    input: VariantsInput,
     result_dir: &str,
     bwa_threads: u16,
+    bwa_options: &[String],
+    keep_temp_sam: bool,
+    overwrite: bool,
 ) {
-    utils::bwa_index_if_required(ref_name);
-    utils::bwa_mem_align(
-        ref_name,
-        &reads_names.clone(),
-        &format!("{}/raw_alignments.sam", result_dir)[..],
-        bwa_threads,
-    );
-    samtools_fixmate(result_dir);
-    samtools_sort(result_dir);
-    freebayes_variant_call(ref_name, result_dir);
+    utils::create_result_dir(result_dir);
+    // the alignments are only written if they aren't given
+    let output_files: &[&str] = match input {
+        VariantsInput::Reads(_) if keep_temp_sam => &[
+            "raw_alignments.sam",
+            "fixed_alignments.bam",
+            "sorted_alignments.bam",
+            "variants.vcf",
+        ],
+        VariantsInput::Reads(_) => &[
+            "fixed_alignments.bam",
+            "sorted_alignments.bam",
+            "variants.vcf",
+        ],
+        VariantsInput::SortedBam(_) => &["variants.vcf"],
+    };
+    let output_files: Vec<String> = output_files.iter().map(|file| file.to_string()).collect();
+    utils::check_overwrite(result_dir, &output_files, overwrite);
+    match input {
+        VariantsInput::Reads(reads_names) => {
+            utils::bwa_index_if_required(ref_name);
+            if keep_temp_sam {
+                utils::bwa_mem_align(
+                    ref_name,
+                    &reads_names,
+                    utils::BwaOutput::File(&format!("{}/raw_alignments.sam", result_dir)[..]),
+                    bwa_threads,
+                    bwa_options,
+                );
+                samtools_fixmate(result_dir);
+            } else {
+                bwa_mem_align_and_fixmate(
+                    ref_name,
+                    &reads_names,
+                    result_dir,
+                    bwa_threads,
+                    bwa_options,
+                );
+            }
+            samtools_sort(result_dir);
+            freebayes_variant_call(
+                ref_name,
+                &format!("{}/sorted_alignments.bam", result_dir)[..],
+                result_dir,
+            );
+        }
+        VariantsInput::SortedBam(bam_file) => {
+            validate_sorted_bam(&bam_file[..]);
+            validate_bam_contigs(&bam_file[..], ref_name);
+            freebayes_variant_call(ref_name, &bam_file[..], result_dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mismatched_contigs, run_into_partial_file};
+    use std::process::Command;
+
+    fn contigs(contigs: &[(&str, u64)]) -> Vec<(String, u64)> {
+        contigs
+            .iter()
+            .map(|(name, length)| (name.to_string(), *length))
+            .collect()
+    }
+
+    #[test]
+    fn test_mismatched_contigs() {
+        let reference = contigs(&[("2L", 23513712), ("2R", 25286936), ("X", 23542271)]);
+        assert!(mismatched_contigs(&contigs(&[("2L", 23513712)]), &reference).is_empty());
+        // another naming convention, and another assembly
+        assert_eq!(
+            mismatched_contigs(
+                &contigs(&[("chr2L", 23513712), ("2R", 25286936), ("X", 22422827)]),
+                &reference
+            ),
+            contigs(&[("chr2L", 23513712), ("X", 22422827)])
+        );
+    }
+
+    #[test]
+    fn test_run_into_partial_file() {
+        let dir = std::env::temp_dir().join(format!("sx_test_partial_vcf_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let partial_file = dir
+            .join("variants.vcf.partial")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let final_file = dir.join("variants.vcf").to_str().unwrap().to_owned();
+        let write_vcf = |exit_code: i32| {
+            let script = format!("echo '##fileformat=VCFv4.2' > \"$0\"; exit {}", exit_code);
+            let mut command = Command::new("sh");
+            command.args(&["-c", &script[..], &partial_file[..]]);
+            command
+        };
+
+        // a step that fails after writing part of its output leaves neither file
+        assert!(
+            run_into_partial_file("freebayes", &mut write_vcf(1), &partial_file, &final_file)
+                .is_err()
+        );
+        assert!(!dir.join("variants.vcf.partial").exists());
+        assert!(!dir.join("variants.vcf").exists());
+        // and neither does a step that can't be run
+        assert!(run_into_partial_file(
+            "freebayes",
+            &mut Command::new("sx_test_missing_freebayes"),
+            &partial_file,
+            &final_file
+        )
+        .is_err());
+        assert!(!dir.join("variants.vcf").exists());
+
+        // a step that succeeds is renamed to the final file
+        run_into_partial_file("freebayes", &mut write_vcf(0), &partial_file, &final_file).unwrap();
+        assert!(!dir.join("variants.vcf.partial").exists());
+        assert_eq!(
+            std::fs::read_to_string(&final_file).unwrap(),
+            "##fileformat=VCFv4.2\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,106 @@
+// the versions of this tool and of the external tools that it runs
+// (the results depend on the versions of the aligner and the variant caller,
+// so they belong in methods sections and bug reports)
+
+use lazy_static::lazy_static;
+
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::Mutex;
+
+// the external tools, and the arguments that make them print their version
+pub const EXTERNAL_TOOLS: [(&str, &[&str]); 3] = [
+    // bwa has no version option, it prints the version in its usage
+    ("bwa", &[]),
+    ("samtools", &["--version"]),
+    ("freebayes", &["--version"]),
+];
+
+lazy_static! {
+    // the versions that have already been queried (None if the tool is not installed)
+    static ref TOOL_VERSIONS: Mutex<HashMap<&'static str, Option<String>>> =
+        Mutex::new(HashMap::new());
+}
+
+// find the version in the output of a tool
+// e.g. "Version: 0.7.17-r1188" (bwa), "samtools 1.10" (samtools) and "version:  v1.3.2" (freebayes)
+fn parse_tool_version(tool: &str, output: &str) -> Option<String> {
+    for line in output.lines() {
+        let line = line.trim();
+        let version = if line.to_lowercase().starts_with("version:") {
+            line["version:".len()..].trim()
+        } else if line.starts_with(tool) && line[tool.len()..].starts_with(' ') {
+            line[tool.len()..].trim()
+        } else {
+            continue;
+        };
+        let version = version.trim_start_matches('v');
+        if !version.is_empty() {
+            return Some(version.to_owned());
+        }
+    }
+    return None;
+}
+
+// the version of an external tool, or None if it can't be run
+// (each tool is only queried once per run)
+pub fn tool_version(tool: &'static str) -> Option<String> {
+    let mut tool_versions = TOOL_VERSIONS.lock().unwrap();
+    if let Some(version) = tool_versions.get(tool) {
+        return version.clone();
+    }
+    let args: &[&str] = EXTERNAL_TOOLS
+        .iter()
+        .find(|(name, _)| *name == tool)
+        .map(|(_, args)| *args)
+        .unwrap_or(&["--version"]);
+    // the version can be on stdout or stderr, and the exit status is not checked
+    // because bwa exits with 1 after printing its usage
+    let version = Command::new(tool)
+        .args(args)
+        .output()
+        .ok()
+        .and_then(|output| {
+            let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+            text.push('\n');
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+            parse_tool_version(tool, &text)
+        });
+    tool_versions.insert(tool, version.clone());
+    return version;
+}
+
+// print the version of this tool and of every external tool
+pub fn print_versions() {
+    println!("sx {}", env!("CARGO_PKG_VERSION"));
+    for (tool, _) in EXTERNAL_TOOLS.iter() {
+        match tool_version(tool) {
+            Some(version) => println!("{} {}", tool, version),
+            None => println!("{} not found", tool),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_tool_version;
+
+    #[test]
+    fn test_parse_tool_version() {
+        let bwa_usage = "\nProgram: bwa (alignment via Burrows-Wheeler transformation)\n\
+                         Version: 0.7.17-r1188\nContact: Heng Li <lh3@sanger.ac.uk>\n";
+        assert_eq!(
+            parse_tool_version("bwa", bwa_usage),
+            Some("0.7.17-r1188".to_owned())
+        );
+        assert_eq!(
+            parse_tool_version("samtools", "samtools 1.10\nUsing htslib 1.10.2\n"),
+            Some("1.10".to_owned())
+        );
+        assert_eq!(
+            parse_tool_version("freebayes", "version:  v1.3.2\n"),
+            Some("1.3.2".to_owned())
+        );
+        assert_eq!(parse_tool_version("samtools", "command not found\n"), None);
+    }
+}
@@ -1,6 +1,9 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context};
 
 use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::errors::{Result, StanexError};
 
 pub struct Metadata {
     pub delimiter: String,
@@ -19,31 +22,187 @@ pub struct Data {
 }
 
 impl Metadata {
+    // (only used by the tests and benchmarks since the "@SQ" lines are parsed by their tags)
+    #[allow(dead_code)]
     pub fn read(&self, row: String) -> Data {
+        match self.try_read(&row) {
+            Ok(data) => data,
+            Err(_) => panic!(
+                "error reading tabular data: a position is greater than the number of columns ({}) ... string: \"{}\"",
+                row.split(&self.delimiter[..]).count(),
+                row
+            ),
+        }
+    }
+
+    // like read, but a row with too few columns is an error instead of a panic
+    // (for rows that come straight from the input files)
+    pub fn try_read(&self, row: &str) -> Result<Data> {
         let split_str: Vec<&str> = row.split(&self.delimiter[..]).collect();
         let mut res = Data {
             fields: HashMap::new(),
         };
         for (position, heading) in &self.headings {
             if position > &split_str.len() {
-                panic!(
-                    "error reading tabular data: position {} is greater than the number of columns ({}) ... string: \"{}\"",
-                    position, split_str.len(), row
-                );
+                return Err(StanexError::MissingField(heading.clone()));
             }
             res.fields
                 .insert(heading.clone(), split_str[position - 1].to_string());
         }
-        return res;
+        return Ok(res);
+    }
+
+    // the inverse of read: write the fields of a row in the order of their positions
+    // (headings that are missing from the data are left empty)
+    pub fn write(&self, data: &Data) -> String {
+        let mut positions: Vec<&usize> = self.headings.keys().collect();
+        positions.sort();
+        let values: Vec<String> = positions
+            .into_iter()
+            .map(|position| data.get(&self.headings[position]).unwrap_or_default())
+            .collect();
+        return values.join(&self.delimiter[..]);
+    }
+
+    // build a copy of the metadata with some headings moved to different (1-indexed) positions
+    // (used when a file does not follow the standard column order)
+    // headings that are not in the metadata are ignored
+    pub fn with_positions(&self, positions: &HashMap<String, usize>) -> Metadata {
+        let mut headings: HashMap<usize, String> = self
+            .headings
+            .iter()
+            .filter(|(_, heading)| !positions.contains_key(*heading))
+            .map(|(position, heading)| (*position, heading.clone()))
+            .collect();
+        for heading in self.headings.values() {
+            if let Some(new_position) = positions.get(heading) {
+                if let Some(other_heading) = headings.get(new_position) {
+                    panic!(
+                        "error building tabular metadata: {} and {} are both in column {}",
+                        heading, other_heading, new_position
+                    );
+                }
+                headings.insert(*new_position, heading.clone());
+            }
+        }
+        return Metadata {
+            delimiter: self.delimiter.clone(),
+            headings,
+        };
+    }
+}
+
+// parse a column layout such as "QNAME=1,FLAG=2,CIGAR=6"
+// into a map between headings and their (1-indexed) positions
+pub fn parse_layout(layout: &str) -> anyhow::Result<HashMap<String, usize>> {
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for field in layout.split(',') {
+        let field = field.trim();
+        if field.is_empty() {
+            continue;
+        }
+        let mut parts = field.splitn(2, '=');
+        let heading = parts.next().unwrap().trim();
+        let position: usize = parts
+            .next()
+            .context(format!(
+                "field {} has no column (expected NAME=COLUMN)",
+                field
+            ))?
+            .trim()
+            .parse()
+            .context(format!("field {} has an invalid column", field))?;
+        if position == 0 {
+            bail!("field {} has an invalid column (columns start at 1)", field);
+        }
+        if positions.values().any(|other| *other == position) {
+            bail!("column {} is used more than once", position);
+        }
+        positions.insert(heading.to_owned(), position);
     }
+    Ok(positions)
 }
 
 impl Data {
+    pub fn new() -> Data {
+        Data {
+            fields: HashMap::new(),
+        }
+    }
+    pub fn insert(&mut self, heading: &str, value: String) {
+        self.fields.insert(heading.to_owned(), value);
+    }
     pub fn get(&self, heading: &str) -> Result<String> {
         let value = self
             .fields
             .get(&heading.to_string())
-            .context(format!("field {} is invalid", heading))?;
+            .ok_or_else(|| StanexError::MissingField(heading.to_owned()))?;
         Ok(value.clone())
     }
+    // get a field and parse it (e.g. into a number)
+    pub fn get_parsed<T: FromStr>(&self, heading: &str) -> Result<T> {
+        let value = self.get(heading)?;
+        value.parse().map_err(|_| StanexError::InvalidValue {
+            field: heading.to_owned(),
+            value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sam_metadata() -> Metadata {
+        let mut headings = HashMap::new();
+        headings.insert(1, "QNAME".to_string());
+        headings.insert(2, "FLAG".to_string());
+        headings.insert(6, "CIGAR".to_string());
+        Metadata {
+            delimiter: "\t".to_string(),
+            headings: headings,
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let metadata = sam_metadata();
+        let data = metadata.read("read1\t0\t2L\t100\t60\t10M5S".to_string());
+        assert_eq!(metadata.write(&data), "read1\t0\t10M5S");
+        // missing fields are left empty
+        let mut data = Data::new();
+        data.insert("CIGAR", "10M5S".to_string());
+        assert_eq!(metadata.write(&data), "\t\t10M5S");
+    }
+
+    #[test]
+    fn test_parse_layout() {
+        let positions = parse_layout("QNAME=2, FLAG=1,CIGAR=3").unwrap();
+        assert_eq!(positions.get("QNAME"), Some(&2));
+        assert_eq!(positions.get("FLAG"), Some(&1));
+        assert_eq!(positions.get("CIGAR"), Some(&3));
+        assert!(parse_layout("QNAME").is_err());
+        assert!(parse_layout("QNAME=x").is_err());
+        assert!(parse_layout("QNAME=0").is_err());
+        assert!(parse_layout("QNAME=1,FLAG=1").is_err());
+    }
+
+    #[test]
+    fn test_with_positions() {
+        // swap QNAME and FLAG, and move CIGAR to the 3rd column
+        let positions = parse_layout("QNAME=2,FLAG=1,CIGAR=3").unwrap();
+        let data = sam_metadata()
+            .with_positions(&positions)
+            .read("0\tread1\t10M5S".to_string());
+        assert_eq!(data.get("QNAME").unwrap(), "read1");
+        assert_eq!(data.get("FLAG").unwrap(), "0");
+        assert_eq!(data.get("CIGAR").unwrap(), "10M5S");
+        // headings that are not moved keep their position
+        let positions = parse_layout("CIGAR=3").unwrap();
+        let data = sam_metadata()
+            .with_positions(&positions)
+            .read("read1\t0\t10M5S".to_string());
+        assert_eq!(data.get("QNAME").unwrap(), "read1");
+        assert_eq!(data.get("CIGAR").unwrap(), "10M5S");
+    }
 }
@@ -0,0 +1,222 @@
+// cache the structured genome alignments (the per-chromosome binary heaps) on disk
+// so that the calling step can be re-run with different parameters
+// without re-reading and re-parsing the genome-aligned SAM file
+
+use path_abs::{PathFile, PathInfo};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use super::second_sam_file::BinHeaps;
+use crate::utils::{log_error, progress, Reads, IO_BUFFER_CAPACITY};
+
+// bump this whenever the layout of GenomeAlignment (or anything inside it) changes
+// so that stale caches are rejected instead of being misread
+const CACHE_VERSION: u32 = 3;
+
+// where phase 4 gets the genome alignments from
+pub enum AlignmentSource<'a> {
+    // parse the genome-aligned SAM file
+    Sam(&'a PathFile),
+    // parse the genome-aligned SAM file and save the parsed alignments to the cache (2nd path)
+    SamWithCache(&'a PathFile, &'a PathFile),
+    // load the parsed alignments from the cache instead of the genome-aligned SAM file
+    Cache(&'a PathFile),
+    // align the selected reads to the genome and parse the alignments while BWA writes them
+    // (see --stream), without a genome-aligned SAM file
+    Bwa(BwaStream<'a>),
+}
+
+// the alignment of the selected reads to a reference (see utils::bwa_mem_align)
+pub struct BwaStream<'a> {
+    pub ref_name: &'a str,
+    pub reads: &'a Reads,
+    pub bwa_threads: u16,
+    pub bwa_options: &'a [String],
+}
+
+// the settings that the alignments of a cache were parsed with: the alignments of other chromosomes,
+// clips, contig names or SAM columns were never parsed into it, so a run with other settings can't use it
+// (the cache covers every position of its chromosomes, so a --region only needs its chromosome in the cache)
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheSettings {
+    pub chroms: Vec<String>,
+    pub min_clip: u64,
+    pub contig_renames: BTreeMap<String, String>,
+    pub sam_layout: BTreeMap<String, usize>,
+}
+
+impl CacheSettings {
+    pub fn new(
+        chroms: &[String],
+        min_clip: u64,
+        contig_renames: &HashMap<String, String>,
+        sam_layout: &HashMap<String, usize>,
+    ) -> CacheSettings {
+        CacheSettings {
+            chroms: chroms.to_vec(),
+            min_clip,
+            contig_renames: contig_renames.clone().into_iter().collect(),
+            sam_layout: sam_layout.clone().into_iter().collect(),
+        }
+    }
+
+    // the first setting of a run that the cache wasn't written with (None if the run can use the cache)
+    fn mismatch(&self, run: &CacheSettings) -> Option<String> {
+        if let Some(chrom) = run.chroms.iter().find(|chrom| !self.chroms.contains(chrom)) {
+            return Some(format!("the chromosome {} isn't in the cache", chrom));
+        }
+        if run.min_clip != self.min_clip {
+            return Some(format!(
+                "--min-clip {} instead of {}",
+                run.min_clip, self.min_clip
+            ));
+        }
+        if run.contig_renames != self.contig_renames {
+            return Some("other contig renames".to_owned());
+        }
+        if run.sam_layout != self.sam_layout {
+            return Some("another SAM layout".to_owned());
+        }
+        return None;
+    }
+}
+
+// why a cache can't be read
+#[derive(Debug)]
+enum CacheError {
+    // e.g. a truncated or corrupt cache
+    Unreadable(bincode::Error),
+    // the version of the cache, if it has one
+    Version(u32),
+    // see CacheSettings::mismatch
+    Mismatch(String),
+}
+
+fn try_write_cache(
+    cache_path: &Path,
+    bin_heaps: &BinHeaps,
+    settings: &CacheSettings,
+) -> bincode::Result<()> {
+    let mut writer = BufWriter::with_capacity(IO_BUFFER_CAPACITY, File::create(cache_path)?);
+    bincode::serialize_into(&mut writer, &CACHE_VERSION)?;
+    bincode::serialize_into(&mut writer, settings)?;
+    bincode::serialize_into(&mut writer, bin_heaps)?;
+    writer.flush()?;
+    return Ok(());
+}
+
+pub fn write_cache(cache_path: &PathFile, bin_heaps: &BinHeaps, settings: &CacheSettings) {
+    if let Err(e) = try_write_cache(cache_path.as_ref(), bin_heaps, settings) {
+        log_error!(
+            "Unable to write the alignment cache {}: {}",
+            cache_path.display(),
+            e
+        );
+        std::process::exit(1);
+    }
+    progress!("Alignments cached in {}", cache_path.display());
+}
+
+fn try_read_cache(
+    cache_path: &Path,
+    settings: &CacheSettings,
+) -> std::result::Result<BinHeaps, CacheError> {
+    let file = File::open(cache_path).map_err(|e| CacheError::Unreadable(e.into()))?;
+    let mut reader = BufReader::with_capacity(IO_BUFFER_CAPACITY, file);
+    let version: u32 = bincode::deserialize_from(&mut reader).unwrap_or(0);
+    if version != CACHE_VERSION {
+        return Err(CacheError::Version(version));
+    }
+    let cached_settings: CacheSettings =
+        bincode::deserialize_from(&mut reader).map_err(CacheError::Unreadable)?;
+    if let Some(mismatch) = cached_settings.mismatch(settings) {
+        return Err(CacheError::Mismatch(mismatch));
+    }
+    return bincode::deserialize_from(&mut reader).map_err(CacheError::Unreadable);
+}
+
+pub fn read_cache(cache_path: &PathFile, settings: &CacheSettings) -> BinHeaps {
+    let bin_heaps = match try_read_cache(cache_path.as_ref(), settings) {
+        Ok(bin_heaps) => bin_heaps,
+        Err(CacheError::Unreadable(e)) => {
+            log_error!(
+                "Unable to read the alignment cache {} (re-run phase 4 with --cache-alignments if it is truncated): {}",
+                cache_path.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+        Err(CacheError::Version(version)) => {
+            log_error!(
+                "The alignment cache {} was written by an incompatible version of this tool (cache version {}, expected {}): please re-run phase 4 without --from-cache",
+                cache_path.display(),
+                version,
+                CACHE_VERSION
+            );
+            std::process::exit(2);
+        }
+        Err(CacheError::Mismatch(mismatch)) => {
+            log_error!(
+                "The alignment cache {} was written with other settings ({}): please re-run phase 4 with --cache-alignments and these settings",
+                cache_path.display(),
+                mismatch
+            );
+            std::process::exit(2);
+        }
+    };
+    progress!("Alignments loaded from {}", cache_path.display());
+    return bin_heaps;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{try_read_cache, try_write_cache, CacheError, CacheSettings};
+    use crate::te_mapper_utils::second_sam_file::BinHeaps;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_cache_settings() {
+        let cache_path =
+            std::env::temp_dir().join(format!("sx_test_cache_settings_{}.bin", std::process::id()));
+        let chroms = vec!["2L".to_owned(), "3R".to_owned()];
+        let renames: HashMap<String, String> = vec![("chr2L".to_owned(), "2L".to_owned())]
+            .into_iter()
+            .collect();
+        let settings = CacheSettings::new(&chroms, 20, &renames, &HashMap::new());
+        let mut bin_heaps = BinHeaps::new();
+        for chrom in &chroms {
+            bin_heaps.insert(chrom.clone(), Default::default());
+        }
+        try_write_cache(&cache_path, &bin_heaps, &settings).unwrap();
+
+        assert_eq!(try_read_cache(&cache_path, &settings).unwrap().len(), 2);
+        // a subset of the chromosomes, e.g. the chromosome of a --region
+        let region_settings = CacheSettings::new(&chroms[1..], 20, &renames, &HashMap::new());
+        assert!(try_read_cache(&cache_path, &region_settings).is_ok());
+        // a chromosome that wasn't in the cached run, another clip and no renames
+        for other_settings in &[
+            CacheSettings::new(&["X".to_owned()], 20, &renames, &HashMap::new()),
+            CacheSettings::new(&chroms, 25, &renames, &HashMap::new()),
+            CacheSettings::new(&chroms, 20, &HashMap::new(), &HashMap::new()),
+        ] {
+            assert!(matches!(
+                try_read_cache(&cache_path, other_settings),
+                Err(CacheError::Mismatch(_))
+            ));
+        }
+
+        // a truncated cache
+        let cache = std::fs::read(&cache_path).unwrap();
+        std::fs::write(&cache_path, &cache[..cache.len() - 1]).unwrap();
+        assert!(matches!(
+            try_read_cache(&cache_path, &settings),
+            Err(CacheError::Unreadable(_))
+        ));
+        std::fs::remove_file(&cache_path).unwrap();
+    }
+}
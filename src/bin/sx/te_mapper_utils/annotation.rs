@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::errors::{Result, StanexError};
+
+// where an insertion is relative to the genes of a GFF3 annotation
+// (feature is "exon", "intron", "gene" for genes without annotated exons, or "intergenic")
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationOverlap {
+    pub feature: String,
+    pub gene_ids: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FeatureKind {
+    Gene,
+    Exon,
+}
+
+// a gene or an exon (one-based fully closed, like GFF3)
+#[derive(Debug)]
+struct Feature {
+    start: u64,
+    end: u64,
+    kind: FeatureKind,
+    gene_id: String,
+}
+
+// the features of a chromosome, sorted by start
+// max_end[i] is the largest end of features[..=i], so that the search for overlapping features
+// can stop as soon as no earlier feature reaches the query
+struct ChromFeatures {
+    features: Vec<Feature>,
+    max_end: Vec<u64>,
+}
+
+pub struct Annotation {
+    chroms: HashMap<String, ChromFeatures>,
+    // the genes with at least one exon (the rest of a gene is an intron only if it has exons)
+    genes_with_exons: HashSet<String>,
+}
+
+// the value of a GFF3 attribute (e.g. "ID" in "ID=FBgn0000001;Name=roo")
+fn attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes
+        .split(';')
+        .filter_map(|attribute| attribute.trim().split_once('='))
+        .find(|(attribute_key, _)| *attribute_key == key)
+        .map(|(_, value)| value)
+}
+
+impl Annotation {
+    pub fn open(gff3_name: &str, contig_renames: &HashMap<String, String>) -> Result<Annotation> {
+        Annotation::read(&mut BufReader::new(File::open(gff3_name)?), contig_renames)
+    }
+
+    // read the genes and exons of a GFF3 file
+    // exons are linked to their gene through their transcript (e.g. exon -> mRNA -> gene)
+    // contig_renames are applied to the chromosome names (see --rename-contigs)
+    pub fn read<R: BufRead>(
+        reader: &mut R,
+        contig_renames: &HashMap<String, String>,
+    ) -> Result<Annotation> {
+        let mut genes: Vec<(String, Feature)> = Vec::new();
+        // exons are kept with the ID of their parent until all the transcripts are known
+        let mut exons: Vec<(String, u64, u64, String)> = Vec::new();
+        let mut gene_ids: HashSet<String> = HashSet::new();
+        let mut transcript_genes: HashMap<String, String> = HashMap::new();
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            // the sequences at the end of the file are not features
+            if line.starts_with("##FASTA") {
+                break;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let invalid_line = || StanexError::InvalidValue {
+                field: format!("GFF3 line {}", line_num + 1),
+                value: line.clone(),
+            };
+            if fields.len() != 9 {
+                return Err(invalid_line());
+            }
+            let chrom = contig_renames
+                .get(fields[0])
+                .cloned()
+                .unwrap_or_else(|| fields[0].to_owned());
+            let start: u64 = fields[3].parse().map_err(|_| invalid_line())?;
+            let end: u64 = fields[4].parse().map_err(|_| invalid_line())?;
+            let feature_type = fields[2];
+            let id = attribute(fields[8], "ID");
+            // only the first parent is used for features with several parents
+            let parent = attribute(fields[8], "Parent").and_then(|parent| parent.split(',').next());
+            if feature_type == "exon" {
+                if let Some(parent) = parent {
+                    exons.push((chrom, start, end, parent.to_owned()));
+                }
+            } else if feature_type.ends_with("gene") {
+                // e.g. gene, pseudogene and ncRNA_gene
+                if let Some(id) = id {
+                    gene_ids.insert(id.to_owned());
+                    genes.push((
+                        chrom,
+                        Feature {
+                            start,
+                            end,
+                            kind: FeatureKind::Gene,
+                            gene_id: id.to_owned(),
+                        },
+                    ));
+                }
+            } else if let (Some(id), Some(parent)) = (id, parent) {
+                // e.g. mRNA, ncRNA and transcript
+                transcript_genes.insert(id.to_owned(), parent.to_owned());
+            }
+        }
+
+        let mut features: HashMap<String, Vec<Feature>> = HashMap::new();
+        for (chrom, gene) in genes {
+            features.entry(chrom).or_default().push(gene);
+        }
+        let mut genes_with_exons: HashSet<String> = HashSet::new();
+        for (chrom, start, end, parent) in exons {
+            // an exon's parent is a transcript, or the gene itself in some annotations
+            let gene_id = match transcript_genes.get(&parent) {
+                Some(gene_id) => gene_id.clone(),
+                None if gene_ids.contains(&parent) => parent,
+                None => continue,
+            };
+            genes_with_exons.insert(gene_id.clone());
+            features.entry(chrom).or_default().push(Feature {
+                start,
+                end,
+                kind: FeatureKind::Exon,
+                gene_id,
+            });
+        }
+
+        let chroms = features
+            .into_iter()
+            .map(|(chrom, mut features)| {
+                features.sort_by_key(|feature| (feature.start, feature.end));
+                let mut max_end: Vec<u64> = Vec::with_capacity(features.len());
+                for feature in &features {
+                    let previous_max_end = max_end.last().copied().unwrap_or(0);
+                    max_end.push(previous_max_end.max(feature.end));
+                }
+                (chrom, ChromFeatures { features, max_end })
+            })
+            .collect();
+        return Ok(Annotation {
+            chroms,
+            genes_with_exons,
+        });
+    }
+
+    // the features that overlap start..=end (binary search for the last feature that starts
+    // at or before the end, then walk back while an earlier feature can still reach the start)
+    fn overlapping(&self, chrom: &str, start: u64, end: u64) -> Vec<&Feature> {
+        let mut overlapping: Vec<&Feature> = Vec::new();
+        let chrom_features = match self.chroms.get(chrom) {
+            Some(chrom_features) => chrom_features,
+            None => return overlapping,
+        };
+        let num_candidates = chrom_features
+            .features
+            .partition_point(|feature| feature.start <= end);
+        for index in (0..num_candidates).rev() {
+            if chrom_features.max_end[index] < start {
+                break;
+            }
+            let feature = &chrom_features.features[index];
+            if feature.end >= start {
+                overlapping.push(feature);
+            }
+        }
+        overlapping.reverse();
+        return overlapping;
+    }
+
+    // where an insertion (one-based fully closed) is relative to the genes
+    // an exon wins over an intron when the insertion overlaps both (e.g. on an exon boundary)
+    pub fn annotate(&self, chrom: &str, start: u64, end: u64) -> AnnotationOverlap {
+        let (start, end) = (start.min(end), start.max(end));
+        let overlapping = self.overlapping(chrom, start, end);
+        let mut gene_ids: Vec<String> = Vec::new();
+        let exons: Vec<&&Feature> = overlapping
+            .iter()
+            .filter(|feature| feature.kind == FeatureKind::Exon)
+            .collect();
+        let feature = if !exons.is_empty() {
+            for exon in exons {
+                if !gene_ids.contains(&exon.gene_id) {
+                    gene_ids.push(exon.gene_id.clone());
+                }
+            }
+            "exon"
+        } else {
+            let mut has_exons = false;
+            for gene in &overlapping {
+                has_exons |= self.genes_with_exons.contains(&gene.gene_id);
+                if !gene_ids.contains(&gene.gene_id) {
+                    gene_ids.push(gene.gene_id.clone());
+                }
+            }
+            if gene_ids.is_empty() {
+                "intergenic"
+            } else if has_exons {
+                "intron"
+            } else {
+                "gene"
+            }
+        };
+        return AnnotationOverlap {
+            feature: feature.to_owned(),
+            gene_ids,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Annotation, AnnotationOverlap};
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    fn overlap(feature: &str, gene_ids: &[&str]) -> AnnotationOverlap {
+        AnnotationOverlap {
+            feature: feature.to_owned(),
+            gene_ids: gene_ids.iter().map(|gene_id| gene_id.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_annotate() {
+        let gff3 = "##gff-version 3\n\
+                    chr2L\tFlyBase\tgene\t1000\t5000\t.\t+\t.\tID=FBgn01;Name=a\n\
+                    chr2L\tFlyBase\tmRNA\t1000\t5000\t.\t+\t.\tID=FBtr01;Parent=FBgn01\n\
+                    chr2L\tFlyBase\texon\t1000\t1200\t.\t+\t.\tParent=FBtr01\n\
+                    chr2L\tFlyBase\texon\t4000\t5000\t.\t+\t.\tParent=FBtr01\n\
+                    chr2L\tFlyBase\tgene\t4500\t9000\t.\t-\t.\tID=FBgn02\n\
+                    chr2L\tFlyBase\tpseudogene\t20000\t21000\t.\t+\t.\tID=FBgn03\n\
+                    ##FASTA\n\
+                    >chr2L\n";
+        let contig_renames: HashMap<String, String> = vec![("chr2L".to_owned(), "2L".to_owned())]
+            .into_iter()
+            .collect();
+        let annotation = Annotation::read(&mut Cursor::new(gff3), &contig_renames).unwrap();
+        assert_eq!(
+            annotation.annotate("2L", 1100, 1105),
+            overlap("exon", &["FBgn01"])
+        );
+        assert_eq!(
+            annotation.annotate("2L", 2000, 2005),
+            overlap("intron", &["FBgn01"])
+        );
+        // overlapping genes (an exon of one gene inside the other gene)
+        assert_eq!(
+            annotation.annotate("2L", 4600, 4605),
+            overlap("exon", &["FBgn01"])
+        );
+        assert_eq!(
+            annotation.annotate("2L", 8000, 8000),
+            overlap("gene", &["FBgn02"])
+        );
+        // an insertion across the start of an exon, and an empty TSD (start after end)
+        assert_eq!(
+            annotation.annotate("2L", 3998, 4001),
+            overlap("exon", &["FBgn01"])
+        );
+        assert_eq!(
+            annotation.annotate("2L", 1201, 1200),
+            overlap("exon", &["FBgn01"])
+        );
+        assert_eq!(
+            annotation.annotate("2L", 15000, 15005),
+            overlap("intergenic", &[])
+        );
+        assert_eq!(
+            annotation.annotate("2L", 20500, 20505),
+            overlap("gene", &["FBgn03"])
+        );
+        assert_eq!(
+            annotation.annotate("3R", 1100, 1105),
+            overlap("intergenic", &[])
+        );
+        // a line that is not a GFF3 feature
+        assert!(Annotation::read(&mut Cursor::new("2L\tgene\t1\t10\n"), &HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::errors::{Result, StanexError};
+use crate::utils::IO_BUFFER_CAPACITY;
+
+// BGZF compression and tabix indexing of the sorted output
+// a BGZF file is a series of small gzip members, so it can still be read with gunzip,
+// but tabix can seek straight to the block that contains a region
+
+// uncompressed bytes per block (the same as htslib, so that a block never exceeds 64 KiB once compressed)
+const MAX_BLOCK_SIZE: usize = 0xff00;
+
+// the empty block that marks the end of a BGZF file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    compressed_offset: u64, // the number of compressed bytes written so far
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner: inner,
+            buffer: Vec::with_capacity(MAX_BLOCK_SIZE),
+            compressed_offset: 0,
+        }
+    }
+
+    // the position of the next byte: the offset of its block in the compressed file
+    // in the upper 48 bits and its offset within the uncompressed block in the lower 16 bits
+    pub fn virtual_offset(&self) -> u64 {
+        (self.compressed_offset << 16) | self.buffer.len() as u64
+    }
+
+    fn write_block(&mut self) -> std::io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buffer)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+
+        // gzip header with the "BC" extra field, which holds the total block size minus 1
+        let block_size = compressed.len() + 26;
+        let mut block: Vec<u8> = Vec::with_capacity(block_size);
+        block.extend_from_slice(&[
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00,
+        ]);
+        block.extend_from_slice(&((block_size - 1) as u16).to_le_bytes());
+        block.extend_from_slice(&compressed);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(self.buffer.len() as u32).to_le_bytes());
+        self.inner.write_all(&block)?;
+
+        self.compressed_offset += block_size as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // write the last block and the EOF marker
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if !self.buffer.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(MAX_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == MAX_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.flush()
+    }
+}
+
+// which columns tabix reads the coordinates from (1-based, as in "tabix -s -b -e")
+pub struct TabixColumns {
+    pub seq: i32,
+    pub beg: i32,
+    pub end: i32,
+    pub meta: u8,  // lines starting with this character are skipped
+    pub skip: i32, // the number of header lines to skip
+    // the start is 0-based and the end exclusive (the UCSC format of "tabix -p bed"),
+    // instead of 1-based and inclusive
+    pub zero_based: bool,
+}
+
+// the TSV has a single header line and the chromosome, start and end in the first 3 columns
+pub const TSV_COLUMNS: TabixColumns = TabixColumns {
+    seq: 1,
+    beg: 2,
+    end: 3,
+    meta: b'#',
+    skip: 1,
+    zero_based: false,
+};
+
+// the same as "tabix -p gff"
+pub const GFF3_COLUMNS: TabixColumns = TabixColumns {
+    seq: 1,
+    beg: 4,
+    end: 5,
+    meta: b'#',
+    skip: 0,
+    zero_based: false,
+};
+
+// the VCF output: every record is a symbolic allele at a single position
+pub const VCF_COLUMNS: TabixColumns = TabixColumns {
+    seq: 1,
+    beg: 2,
+    end: 2,
+    meta: b'#',
+    skip: 0,
+    zero_based: false,
+};
+
+// the same as "tabix -p bed" (the McClintock output)
+pub const BED_COLUMNS: TabixColumns = TabixColumns {
+    seq: 1,
+    beg: 2,
+    end: 3,
+    meta: b'#',
+    skip: 0,
+    zero_based: true,
+};
+
+// the smallest bin that contains the 0-based, half-open interval [beg, end)
+fn reg2bin(beg: u64, end: u64) -> u32 {
+    let end = end - 1;
+    if beg >> 14 == end >> 14 {
+        return (4681 + (beg >> 14)) as u32;
+    }
+    if beg >> 17 == end >> 17 {
+        return (585 + (beg >> 17)) as u32;
+    }
+    if beg >> 20 == end >> 20 {
+        return (73 + (beg >> 20)) as u32;
+    }
+    if beg >> 23 == end >> 23 {
+        return (9 + (beg >> 23)) as u32;
+    }
+    if beg >> 26 == end >> 26 {
+        return (1 + (beg >> 26)) as u32;
+    }
+    return 0;
+}
+
+// the index of one chromosome
+// bins: the chunks of the compressed file (start and end virtual offsets) that hold the records in each bin
+// linear: the virtual offset of the first record that overlaps each 16 kb window
+#[derive(Default)]
+struct ReferenceIndex {
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    linear: Vec<u64>,
+}
+
+impl ReferenceIndex {
+    fn push(&mut self, beg: u64, end: u64, chunk_start: u64, chunk_end: u64) {
+        let chunks = self.bins.entry(reg2bin(beg, end)).or_default();
+        match chunks.last_mut() {
+            // merge with the previous chunk if the records are adjacent in the file
+            Some(last_chunk) if last_chunk.1 == chunk_start => last_chunk.1 = chunk_end,
+            _ => chunks.push((chunk_start, chunk_end)),
+        }
+        let last_window = ((end - 1) >> 14) as usize;
+        if self.linear.len() <= last_window {
+            self.linear.resize(last_window + 1, u64::MAX);
+        }
+        for window in (beg >> 14) as usize..=last_window {
+            if self.linear[window] == u64::MAX {
+                self.linear[window] = chunk_start;
+            }
+        }
+    }
+
+    // windows without records point to the previous record (or the first one if there is none)
+    fn fill_linear_index(&mut self) {
+        let first_offset = self
+            .bins
+            .values()
+            .flat_map(|chunks| chunks.iter().map(|chunk| chunk.0))
+            .min()
+            .unwrap_or(0);
+        let mut previous_offset = first_offset;
+        for offset in self.linear.iter_mut() {
+            if *offset == u64::MAX {
+                *offset = previous_offset;
+            }
+            previous_offset = *offset;
+        }
+    }
+}
+
+pub struct TabixIndex {
+    columns: &'static TabixColumns,
+    names: Vec<String>,
+    references: Vec<ReferenceIndex>,
+}
+
+impl TabixIndex {
+    // write the index in the ".tbi" format (BGZF-compressed)
+    pub fn write<W: Write>(&self, writer: W) -> std::io::Result<W> {
+        let mut index = BgzfWriter::new(writer);
+        let mut names: Vec<u8> = Vec::new();
+        for name in &self.names {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        index.write_all(b"TBI\x01")?;
+        for value in &[
+            self.references.len() as i32,
+            // generic format, with the UCSC flag for 0-based coordinates
+            if self.columns.zero_based { 0x10000 } else { 0 },
+            self.columns.seq,
+            self.columns.beg,
+            self.columns.end,
+            self.columns.meta as i32,
+            self.columns.skip,
+            names.len() as i32,
+        ] {
+            index.write_all(&value.to_le_bytes())?;
+        }
+        index.write_all(&names)?;
+        for reference in &self.references {
+            index.write_all(&(reference.bins.len() as i32).to_le_bytes())?;
+            for (bin, chunks) in &reference.bins {
+                index.write_all(&bin.to_le_bytes())?;
+                index.write_all(&(chunks.len() as i32).to_le_bytes())?;
+                for (chunk_start, chunk_end) in chunks {
+                    index.write_all(&chunk_start.to_le_bytes())?;
+                    index.write_all(&chunk_end.to_le_bytes())?;
+                }
+            }
+            index.write_all(&(reference.linear.len() as i32).to_le_bytes())?;
+            for offset in &reference.linear {
+                index.write_all(&offset.to_le_bytes())?;
+            }
+        }
+        index.finish()
+    }
+}
+
+// copy the lines of a sorted file into a BGZF writer and index them
+// a chromosome must not reappear after another one has started, and the starts must not decrease
+pub fn compress_and_index<R: BufRead, W: Write>(
+    reader: R,
+    writer: &mut BgzfWriter<W>,
+    columns: &'static TabixColumns,
+) -> Result<TabixIndex> {
+    let mut index = TabixIndex {
+        columns: columns,
+        names: Vec::new(),
+        references: Vec::new(),
+    };
+    let mut last_beg = 0;
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let chunk_start = writer.virtual_offset();
+        writer.write_all(format!("{}\n", line).as_bytes())?;
+        let chunk_end = writer.virtual_offset();
+        if (line_num as i32) < columns.skip || line.as_bytes().first() == Some(&columns.meta) {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let field = |column: i32, name: &str| {
+            fields
+                .get(column as usize - 1)
+                .ok_or_else(|| StanexError::MissingField(name.to_owned()))
+        };
+        let chrom = field(columns.seq, "CHROM")?;
+        let parse_coord = |column: i32, name: &str| -> Result<u64> {
+            let value = field(column, name)?;
+            value.parse().map_err(|_| StanexError::InvalidValue {
+                field: name.to_owned(),
+                value: value.to_string(),
+            })
+        };
+        // convert to a 0-based, half-open interval (which must not be empty)
+        let beg = if columns.zero_based {
+            parse_coord(columns.beg, "START")?
+        } else {
+            parse_coord(columns.beg, "START")?.saturating_sub(1)
+        };
+        let end = parse_coord(columns.end, "END")?.max(beg + 1);
+
+        if index.names.last().map(|name| &name[..]) != Some(*chrom) {
+            if index.names.iter().any(|name| name == chrom) {
+                return Err(StanexError::UnsortedRecord {
+                    chrom: chrom.to_string(),
+                    pos: beg + 1,
+                });
+            }
+            index.names.push(chrom.to_string());
+            index.references.push(ReferenceIndex::default());
+            last_beg = 0;
+        }
+        if beg < last_beg {
+            return Err(StanexError::UnsortedRecord {
+                chrom: chrom.to_string(),
+                pos: beg + 1,
+            });
+        }
+        last_beg = beg;
+        index
+            .references
+            .last_mut()
+            .unwrap()
+            .push(beg, end, chunk_start, chunk_end);
+    }
+    for reference in index.references.iter_mut() {
+        reference.fill_linear_index();
+    }
+    Ok(index)
+}
+
+// replace a sorted output file with "<file>.gz" and write its tabix index to "<file>.gz.tbi"
+pub fn bgzip_and_index(path: &Path, columns: &'static TabixColumns) -> Result<()> {
+    let gz_path = format!("{}.gz", path.display());
+    let tbi_path = format!("{}.gz.tbi", path.display());
+
+    let reader = BufReader::with_capacity(IO_BUFFER_CAPACITY, File::open(path)?);
+    let mut writer = BgzfWriter::new(BufWriter::with_capacity(
+        IO_BUFFER_CAPACITY,
+        File::create(&gz_path)?,
+    ));
+    let index = compress_and_index(reader, &mut writer, columns)?;
+    writer.finish()?;
+    index.write(BufWriter::new(File::create(&tbi_path)?))?;
+
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::{Cursor, Read};
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(compressed)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn test_bgzf_round_trip() {
+        // large enough to span several blocks
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&data).unwrap();
+        assert_eq!(
+            writer.virtual_offset() & 0xffff,
+            (200_000 % MAX_BLOCK_SIZE) as u64
+        );
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.ends_with(&EOF_BLOCK));
+        assert_eq!(decompress(&compressed), data);
+    }
+
+    #[test]
+    fn test_reg2bin() {
+        assert_eq!(reg2bin(0, 1), 4681);
+        assert_eq!(reg2bin(16383, 16384), 4681);
+        assert_eq!(reg2bin(16383, 16385), 585);
+        assert_eq!(reg2bin(0, 1 << 26), 1);
+        assert_eq!(reg2bin(0, (1 << 26) + 1), 0);
+    }
+
+    #[test]
+    fn test_compress_and_index() {
+        let tsv = "Chromosome\tStart\tEnd\n\
+                   2L\t1000\t1005\n\
+                   2L\t20000\t20010\n\
+                   3R\t4990\t10000\n";
+        let mut writer = BgzfWriter::new(Vec::new());
+        let index = compress_and_index(Cursor::new(tsv), &mut writer, &TSV_COLUMNS).unwrap();
+        assert_eq!(decompress(&writer.finish().unwrap()), tsv.as_bytes());
+
+        assert_eq!(index.names, vec!["2L", "3R"]);
+        let chrom_2l = &index.references[0];
+        assert_eq!(chrom_2l.bins.keys().collect::<Vec<_>>(), vec![&4681, &4682]);
+        // both records are in the first block, after the 21-byte header line
+        assert_eq!(chrom_2l.linear, vec![21, 34]);
+        assert_eq!(chrom_2l.bins[&4681], vec![(21, 34)]);
+
+        let tbi = decompress(&index.write(Vec::new()).unwrap());
+        assert_eq!(&tbi[..4], b"TBI\x01");
+        assert_eq!(i32::from_le_bytes([tbi[4], tbi[5], tbi[6], tbi[7]]), 2);
+        assert_eq!(&tbi[36..42], b"2L\x003R\x00");
+    }
+
+    #[test]
+    fn test_compress_and_index_bed() {
+        let bed = "2L\t999\t1005\troo_non-reference_1\t0\t+\n\
+                   2L\t16384\t16390\tjockey_reference_2\t0\t-\n";
+        let mut writer = BgzfWriter::new(Vec::new());
+        let index = compress_and_index(Cursor::new(bed), &mut writer, &BED_COLUMNS).unwrap();
+        // the starts are already 0-based, so the second record starts the second 16 kb bin
+        // (instead of straddling the first 2)
+        assert_eq!(
+            index.references[0].bins.keys().collect::<Vec<_>>(),
+            vec![&4681, &4682]
+        );
+
+        let tbi = decompress(&index.write(Vec::new()).unwrap());
+        assert_eq!(
+            i32::from_le_bytes([tbi[8], tbi[9], tbi[10], tbi[11]]),
+            0x10000
+        );
+    }
+
+    #[test]
+    fn test_unsorted_records() {
+        let tsv = "Chromosome\tStart\tEnd\n\
+                   2L\t1000\t1005\n\
+                   3R\t4990\t10000\n\
+                   2L\t20000\t20010\n";
+        let mut writer = BgzfWriter::new(Vec::new());
+        let result = compress_and_index(Cursor::new(tsv), &mut writer, &TSV_COLUMNS);
+        assert!(matches!(
+            result,
+            Err(StanexError::UnsortedRecord { ref chrom, pos: 20000 }) if chrom == "2L"
+        ));
+    }
+}
@@ -0,0 +1,71 @@
+// print how a single CIGAR string is interpreted by the split-read parsers
+// (useful for troubleshooting calls that look wrong without building whole SAM files)
+
+use super::genome_alignment::SplitReadGenome;
+use super::te_alignment::SplitReadTE;
+
+pub fn debug_cigar(cigar: &str, pos: u64, old_m: u64, old_s: u64, is_start: bool, new_plus: bool) {
+    println!("CIGAR string {} at position {}\n", cigar, pos);
+
+    // phase 2: the read was aligned to a transposon
+    // (SM reads must start at the first nucleotide of the transposon,
+    // MS reads must end at the last nucleotide of the transposon)
+    println!("As a TE alignment (phase 2):");
+    match SplitReadTE::parse(cigar.to_owned(), pos) {
+        Ok(SplitReadTE::SM(alignment)) => {
+            println!("  SM read: s = {}, m = {}", alignment.s, alignment.m);
+            println!(
+                "  first matching nucleotide (get_first_m): {} (must be 1 to be selected)",
+                alignment.get_first_m()
+            );
+        }
+        Ok(SplitReadTE::MS(alignment)) => {
+            println!("  MS read: m = {}, s = {}", alignment.m, alignment.s);
+            println!(
+                "  last matching nucleotide (get_last_m): {} (must equal the transposon length to be selected)",
+                alignment.get_last_m()
+            );
+        }
+        Err(e) => println!("  not a split read: {}", e),
+    }
+
+    // phase 4: the selected read was aligned to the genome
+    // (H is parsed as if it were S, and a full match means a reference insertion)
+    println!(
+        "\nAs a genome alignment (phase 4, {} strand, TE alignment at the {} of the transposon with old m = {}, old s = {}):",
+        if new_plus { "+/+" } else { "+/-" },
+        if is_start { "start" } else { "end" },
+        old_m,
+        old_s
+    );
+    match SplitReadGenome::parse(cigar.to_owned(), old_m, old_s, is_start, new_plus, pos, 0) {
+        Ok(SplitReadGenome::SM(alignment)) => {
+            println!(
+                "  SM read (non-reference, downstream of the insertion): s = {}, m = {}",
+                alignment.s, alignment.m
+            );
+            println!(
+                "  boundary nucleotide (get_first_m): {}",
+                alignment.get_first_m()
+            );
+        }
+        Ok(SplitReadGenome::MS(alignment)) => {
+            println!(
+                "  MS read (non-reference, upstream of the insertion): m = {}, s = {}",
+                alignment.m, alignment.s
+            );
+            println!(
+                "  boundary nucleotide (get_last_m): {}",
+                alignment.get_last_m()
+            );
+        }
+        Ok(SplitReadGenome::M(alignment)) => {
+            println!("  M read (reference insertion)");
+            println!(
+                "  boundary nucleotide (get_boundary_old_m): {}",
+                alignment.get_boundary_old_m()
+            );
+        }
+        Err(e) => println!("  not usable: {}", e),
+    }
+}
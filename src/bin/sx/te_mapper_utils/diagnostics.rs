@@ -0,0 +1,310 @@
+use serde::Serialize;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::sync::Mutex;
+
+use crate::errors::{Result, StanexError};
+
+// the stages of the pipeline that can skip data
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    // phase 2: the reads aligned to the transposons
+    TeAlignment,
+    // phase 4: the split-reads aligned to the genome
+    GenomeAlignment,
+    // phase 4: the grouping of the split-reads into insertions
+    InsertionCalling,
+}
+
+impl Stage {
+    pub fn description(self) -> &'static str {
+        match self {
+            Stage::TeAlignment => "TE alignments",
+            Stage::GenomeAlignment => "genome alignments",
+            Stage::InsertionCalling => "split-reads",
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+struct Tallies {
+    // the number of skipped records of every stage and reason
+    skipped: BTreeMap<Stage, BTreeMap<&'static str, u64>>,
+    // the contigs of the genome alignments that are not in the chromosome list
+    unknown_contigs: BTreeSet<String>,
+    // the number of records of every stage that were passed on to the next one (see Diagnostics::funnel)
+    kept: BTreeMap<Stage, u64>,
+}
+
+// a thread-safe tally of the records that are skipped throughout the pipeline,
+// so that they can be summarized once at the end instead of being silent or printed one by one
+// in strict mode (see --strict), the errors that point to a misconfigured run stop it instead
+#[derive(Default)]
+pub struct Diagnostics {
+    tallies: Mutex<Tallies>,
+    strict: bool,
+}
+
+// the reason that a record was skipped because of an error
+fn skip_reason(error: &StanexError) -> &'static str {
+    match error {
+        StanexError::MalformedCigar { .. } => "malformed CIGAR string",
+        StanexError::UnknownTransposon(_) => "unknown transposon",
+        StanexError::NotAtTransposonEnd { .. } => "not at a transposon end",
+        StanexError::ClipTooShort { .. } => "clip shorter than --min-clip",
+        StanexError::TooManyAmbiguousBases { .. } => "more Ns than --max-read-n",
+        StanexError::UnmappedRead(_) => "unmapped",
+        StanexError::UnplacedRead(_) => "no position",
+        StanexError::NotPrimaryAlignment(_) => "secondary or supplementary",
+        StanexError::NotSpanning(_) => "long read not spanning an insertion",
+        StanexError::InvalidChromosome(_) => "contig not in list",
+        StanexError::MissingField(_) | StanexError::InvalidValue { .. } => "malformed line",
+        _ => "other error",
+    }
+}
+
+// the errors that stop a run in strict mode: a contig that isn't in the chromosome list,
+// a transposon that isn't in the TE alignment's header, or a line that can't be parsed
+// (unmapped reads and reads that aren't split-reads are expected, so they are always skipped)
+fn is_fatal(error: &StanexError) -> bool {
+    matches!(
+        error,
+        StanexError::InvalidChromosome(_)
+            | StanexError::UnknownTransposon(_)
+            | StanexError::MissingField(_)
+            | StanexError::InvalidValue { .. }
+    )
+}
+
+// e.g. 12034 -> "12,034"
+fn with_thousands_separators(count: u64) -> String {
+    let digits = count.to_string();
+    let mut result = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index) % 3 == 0 {
+            result.push(',');
+        }
+        result.push(digit);
+    }
+    return result;
+}
+
+impl Diagnostics {
+    pub fn new(strict: bool) -> Diagnostics {
+        Diagnostics {
+            tallies: Mutex::new(Tallies::default()),
+            strict,
+        }
+    }
+
+    pub fn record(&self, stage: Stage, reason: &'static str) {
+        self.record_many(stage, reason, 1);
+    }
+
+    pub fn record_many(&self, stage: Stage, reason: &'static str, count: u64) {
+        if count == 0 {
+            return;
+        }
+        let mut tallies = self.tallies.lock().unwrap();
+        *tallies
+            .skipped
+            .entry(stage)
+            .or_default()
+            .entry(reason)
+            .or_insert(0) += count;
+    }
+
+    pub fn record_kept(&self, stage: Stage, count: u64) {
+        let mut tallies = self.tallies.lock().unwrap();
+        *tallies.kept.entry(stage).or_insert(0) += count;
+    }
+
+    // in strict mode, the run stops at the first fatal error (see is_fatal)
+    pub fn record_error(&self, stage: Stage, error: &StanexError) {
+        if self.strict && is_fatal(error) {
+            eprintln!(
+                "Stopping because of --strict: {}: {}",
+                stage.description(),
+                error
+            );
+            std::process::exit(1);
+        }
+        let mut tallies = self.tallies.lock().unwrap();
+        if let StanexError::InvalidChromosome(contig) = error {
+            if !tallies.unknown_contigs.contains(contig) {
+                tallies.unknown_contigs.insert(contig.clone());
+            }
+        }
+        *tallies
+            .skipped
+            .entry(stage)
+            .or_default()
+            .entry(skip_reason(error))
+            .or_insert(0) += 1;
+    }
+
+    // e.g. "genome alignments: 12,034 skipped: unmapped; 88 skipped: malformed line; 3 contigs not in list"
+    // (one line per stage, None if nothing was skipped)
+    pub fn summary(&self) -> Option<String> {
+        let tallies = self.tallies.lock().unwrap();
+        if tallies.skipped.is_empty() {
+            return None;
+        }
+        let mut lines: Vec<String> = Vec::new();
+        for (stage, reasons) in &tallies.skipped {
+            let mut parts: Vec<String> = reasons
+                .iter()
+                .map(|(reason, count)| {
+                    format!("{} skipped: {}", with_thousands_separators(*count), reason)
+                })
+                .collect();
+            if *stage == Stage::GenomeAlignment && !tallies.unknown_contigs.is_empty() {
+                parts.push(format!(
+                    "{} contigs not in list",
+                    with_thousands_separators(tallies.unknown_contigs.len() as u64)
+                ));
+            }
+            lines.push(format!("{}: {}", stage.description(), parts.join("; ")));
+        }
+        return Some(lines.join("\n"));
+    }
+
+    // the number of skipped records of every stage and reason, e.g. ("genome alignments: unmapped", 12034)
+    // (for the JSON log format, see --log-format)
+    pub fn skipped_counts(&self) -> Vec<(String, u64)> {
+        let tallies = self.tallies.lock().unwrap();
+        let mut counts: Vec<(String, u64)> = Vec::new();
+        for (stage, reasons) in &tallies.skipped {
+            for (reason, count) in reasons {
+                counts.push((format!("{}: {}", stage.description(), reason), *count));
+            }
+        }
+        return counts;
+    }
+
+    // the number of kept and skipped records of every stage that was run, in the order of the pipeline,
+    // e.g. "TE alignments: 1,204 kept, 12,035 skipped", to see where the reads of a run were lost
+    pub fn funnel(&self) -> Vec<(Stage, u64, u64)> {
+        let tallies = self.tallies.lock().unwrap();
+        let stages: BTreeSet<Stage> = tallies
+            .kept
+            .keys()
+            .chain(tallies.skipped.keys())
+            .copied()
+            .collect();
+        return stages
+            .into_iter()
+            .map(|stage| {
+                let kept = tallies.kept.get(&stage).copied().unwrap_or(0);
+                let skipped = tallies
+                    .skipped
+                    .get(&stage)
+                    .map_or(0, |reasons| reasons.values().sum());
+                (stage, kept, skipped)
+            })
+            .collect();
+    }
+
+    pub fn funnel_summary(&self) -> String {
+        let lines: Vec<String> = self
+            .funnel()
+            .into_iter()
+            .map(|(stage, kept, skipped)| {
+                format!(
+                    "{}: {} kept, {} skipped",
+                    stage.description(),
+                    with_thousands_separators(kept),
+                    with_thousands_separators(skipped)
+                )
+            })
+            .collect();
+        return lines.join("\n");
+    }
+
+    pub fn write_json(&self, json_name: &str) -> Result<()> {
+        let tallies = self.tallies.lock().unwrap();
+        serde_json::to_writer_pretty(File::create(json_name)?, &*tallies)
+            .map_err(|e| StanexError::IoError(e.into()))?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_fatal, with_thousands_separators, Diagnostics, Stage};
+
+    use crate::errors::StanexError;
+
+    #[test]
+    fn test_summary() {
+        let diagnostics = Diagnostics::default();
+        assert_eq!(diagnostics.summary(), None);
+
+        diagnostics.record_many(Stage::TeAlignment, "unmapped", 12034);
+        diagnostics.record_error(
+            Stage::TeAlignment,
+            &StanexError::MissingField("CIGAR".to_owned()),
+        );
+        for contig in &["chrUn", "chrM", "chrUn"] {
+            diagnostics.record_error(
+                Stage::GenomeAlignment,
+                &StanexError::InvalidChromosome(contig.to_string()),
+            );
+        }
+        diagnostics.record(Stage::InsertionCalling, "past the chromosome end");
+        assert_eq!(
+            diagnostics.summary().unwrap(),
+            "TE alignments: 1 skipped: malformed line; 12,034 skipped: unmapped\n\
+             genome alignments: 3 skipped: contig not in list; 2 contigs not in list\n\
+             split-reads: 1 skipped: past the chromosome end"
+        );
+    }
+
+    #[test]
+    fn test_funnel() {
+        let diagnostics = Diagnostics::default();
+        assert_eq!(diagnostics.funnel_summary(), "");
+
+        diagnostics.record_kept(Stage::TeAlignment, 1204);
+        diagnostics.record_many(Stage::TeAlignment, "unmapped", 12034);
+        diagnostics.record(Stage::TeAlignment, "transposon filtered out");
+        diagnostics.record_kept(Stage::GenomeAlignment, 0);
+        diagnostics.record_many(Stage::GenomeAlignment, "unmapped", 1204);
+        assert_eq!(
+            diagnostics.funnel(),
+            vec![
+                (Stage::TeAlignment, 1204, 12035),
+                (Stage::GenomeAlignment, 0, 1204)
+            ]
+        );
+        assert_eq!(
+            diagnostics.funnel_summary(),
+            "TE alignments: 1,204 kept, 12,035 skipped\n\
+             genome alignments: 0 kept, 1,204 skipped"
+        );
+    }
+
+    #[test]
+    fn test_is_fatal() {
+        assert!(is_fatal(&StanexError::InvalidChromosome(
+            "chr2L".to_owned()
+        )));
+        assert!(is_fatal(&StanexError::UnknownTransposon("roo".to_owned())));
+        assert!(is_fatal(&StanexError::MissingField("CIGAR".to_owned())));
+        assert!(!is_fatal(&StanexError::UnmappedRead("a1".to_owned())));
+        assert!(!is_fatal(&StanexError::MalformedCigar {
+            cigar: "150M".to_owned(),
+            expected: "SM or MS",
+        }));
+    }
+
+    #[test]
+    fn test_with_thousands_separators() {
+        assert_eq!(with_thousands_separators(0), "0");
+        assert_eq!(with_thousands_separators(999), "999");
+        assert_eq!(with_thousands_separators(1000), "1,000");
+        assert_eq!(with_thousands_separators(1234567), "1,234,567");
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::errors::{Result, StanexError};
+
+// collapses the sub-families of a redundant transposon library into canonical families (see --family-map)
+// when a library has many near-identical sub-families, the split-reads of an insertion are spread
+// across them and none of them has enough support, so the selected reads are renamed after the family
+// in phase 2 and the insertions are called (and their support pooled) at the family level
+// transposons that aren't in the map keep their own names, and an empty map keeps every name
+// names are matched in full: the "#class/family" suffix of a name isn't split off, so only the map collapses names
+#[derive(Default)]
+pub struct FamilyMap {
+    families: HashMap<String, String>,
+}
+
+impl FamilyMap {
+    // a 2-column (transposon, family) tab-separated file (lines starting with "#" are skipped)
+    pub fn read(family_map_file: &str) -> Result<FamilyMap> {
+        return FamilyMap::parse(BufReader::new(File::open(family_map_file)?));
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<FamilyMap> {
+        let mut families: HashMap<String, String> = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 2 || fields[0].is_empty() || fields[1].is_empty() {
+                return Err(StanexError::InvalidValue {
+                    field: "--family-map".to_owned(),
+                    value: line.clone(),
+                });
+            }
+            families.insert(fields[0].to_owned(), fields[1].to_owned());
+        }
+        return Ok(FamilyMap { families });
+    }
+
+    // the name that the insertions of a transposon are called under
+    pub fn family<'a>(&'a self, te_name: &'a str) -> &'a str {
+        match self.families.get(te_name) {
+            Some(family) => family,
+            None => te_name,
+        }
+    }
+
+    // the lengths of the families, from the lengths of the transposons of the library
+    // the sub-families of a family can differ in length (e.g. a full-length element and its truncated copies),
+    // but the window of reference lengths needs a single one: a family is as long as its longest transposon,
+    // so that a full-length copy of it is still within the window (which it might not be with the mean length),
+    // while the shorter sub-families are still within the lower end of the window
+    // a family only has a length if one of its transposons is in the library
+    pub fn family_lengths(
+        &self,
+        transposon_lengths: &HashMap<String, u64>,
+    ) -> HashMap<String, u64> {
+        let mut family_lengths: HashMap<String, u64> = HashMap::new();
+        for (te_name, &length) in transposon_lengths {
+            let family_length = family_lengths
+                .entry(self.family(te_name).to_owned())
+                .or_insert(0);
+            *family_length = std::cmp::max(*family_length, length);
+        }
+        return family_lengths;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FamilyMap;
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_family_map() {
+        let family_map = FamilyMap::parse(Cursor::new(
+            "# sub-family\tfamily\nL1HS\tL1\nL1PA2\tL1\n\nAluYa5\tAlu\n",
+        ))
+        .unwrap();
+        assert_eq!(family_map.family("L1PA2"), "L1");
+        assert_eq!(family_map.family("SVA_A"), "SVA_A");
+
+        let transposon_lengths: HashMap<String, u64> = vec![
+            ("L1HS".to_owned(), 6064),
+            ("L1PA2".to_owned(), 6155),
+            ("AluYa5".to_owned(), 312),
+            ("SVA_A".to_owned(), 1387),
+        ]
+        .into_iter()
+        .collect();
+        let family_lengths = family_map.family_lengths(&transposon_lengths);
+        assert_eq!(family_lengths.len(), 3);
+        assert_eq!(family_lengths["L1"], 6155);
+        assert_eq!(family_lengths["SVA_A"], 1387);
+        assert_eq!(
+            FamilyMap::default().family_lengths(&transposon_lengths),
+            transposon_lengths
+        );
+
+        assert!(FamilyMap::parse(Cursor::new("L1HS\n")).is_err());
+        assert!(FamilyMap::parse(Cursor::new("L1HS\tL1\textra\n")).is_err());
+    }
+}
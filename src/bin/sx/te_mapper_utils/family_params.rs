@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use super::te_name::TeName;
+use crate::errors::{Result, StanexError};
+
+// the TSD and length windows of some transposon families (see --family-params), in place of the global ones
+// different families have characteristic TSD lengths (e.g. 8 bp for the P-element and hobo),
+// so a single window is either too strict for some of them or too loose for the others
+// a family is looked up by the name that its insertions are called under, and then by that name without
+// its "#class/family" suffix; the families that aren't in the file (and the windows left as ".") keep the global ones
+#[derive(Default)]
+pub struct FamilyParams {
+    params: HashMap<String, FamilyWindows>,
+}
+
+struct FamilyWindows {
+    min_tsd_length: Option<u64>,
+    max_tsd_length: Option<u64>,
+    // the fractions of the length of the transposon (see --reference-length-window)
+    min_te_length: Option<f64>,
+    max_te_length: Option<f64>,
+}
+
+impl FamilyParams {
+    // a 5-column (family, min TSD, max TSD, min length ratio, max length ratio) tab-separated file
+    // (lines starting with "#" are skipped)
+    pub fn read(family_params_file: &str) -> Result<FamilyParams> {
+        return FamilyParams::parse(BufReader::new(File::open(family_params_file)?));
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<FamilyParams> {
+        let mut params: HashMap<String, FamilyWindows> = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || StanexError::InvalidValue {
+                field: "--family-params".to_owned(),
+                value: line.clone(),
+            };
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 5 || fields[0].is_empty() {
+                return Err(invalid());
+            }
+            let windows = FamilyWindows {
+                min_tsd_length: parse_optional(fields[1]).map_err(|_| invalid())?,
+                max_tsd_length: parse_optional(fields[2]).map_err(|_| invalid())?,
+                min_te_length: parse_optional(fields[3]).map_err(|_| invalid())?,
+                max_te_length: parse_optional(fields[4]).map_err(|_| invalid())?,
+            };
+            let tsd_reversed = matches!(
+                (windows.min_tsd_length, windows.max_tsd_length),
+                (Some(min), Some(max)) if min > max
+            );
+            let te_length_invalid = [windows.min_te_length, windows.max_te_length]
+                .iter()
+                .flatten()
+                .any(|ratio| !ratio.is_finite() || *ratio < 0.0)
+                || matches!(
+                    (windows.min_te_length, windows.max_te_length),
+                    (Some(min), Some(max)) if min > max
+                );
+            if tsd_reversed || te_length_invalid || params.contains_key(fields[0]) {
+                return Err(invalid());
+            }
+            params.insert(fields[0].to_owned(), windows);
+        }
+        return Ok(FamilyParams { params });
+    }
+
+    fn windows(&self, te_name: &str) -> Option<&FamilyWindows> {
+        return self
+            .params
+            .get(te_name)
+            .or_else(|| self.params.get(TeName::parse(te_name).element));
+    }
+
+    // the shortest and longest TSD of a non-reference insertion of a transposon
+    pub fn tsd_window(
+        &self,
+        te_name: &str,
+        min_tsd_length: u64,
+        max_tsd_length: u64,
+    ) -> (u64, u64) {
+        match self.windows(te_name) {
+            Some(windows) => (
+                windows.min_tsd_length.unwrap_or(min_tsd_length),
+                windows.max_tsd_length.unwrap_or(max_tsd_length),
+            ),
+            None => (min_tsd_length, max_tsd_length),
+        }
+    }
+
+    // the shortest and longest reference insertion of a transposon, as fractions of its length
+    pub fn te_length_window(
+        &self,
+        te_name: &str,
+        min_te_length: f64,
+        max_te_length: f64,
+    ) -> (f64, f64) {
+        match self.windows(te_name) {
+            Some(windows) => (
+                windows.min_te_length.unwrap_or(min_te_length),
+                windows.max_te_length.unwrap_or(max_te_length),
+            ),
+            None => (min_te_length, max_te_length),
+        }
+    }
+}
+
+// a window of the file ("." or an empty field keeps the global window)
+fn parse_optional<T: std::str::FromStr>(field: &str) -> std::result::Result<Option<T>, T::Err> {
+    if field.is_empty() || field == "." {
+        return Ok(None);
+    }
+    return field.parse().map(Some);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FamilyParams;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_family_params() {
+        let family_params = FamilyParams::parse(Cursor::new(
+            "# family\tmin TSD\tmax TSD\tmin length\tmax length\n\
+             P-element\t7\t9\t.\t.\n\
+             roo\t4\t6\t0.5\t1.2\n",
+        ))
+        .unwrap();
+        assert_eq!(family_params.tsd_window("P-element", 0, 20), (7, 9));
+        assert_eq!(family_params.tsd_window("roo#LTR/Bel-Pao", 0, 20), (4, 6));
+        assert_eq!(family_params.tsd_window("jockey", 0, 20), (0, 20));
+        assert_eq!(
+            family_params.te_length_window("P-element", 0.1, 1.5),
+            (0.1, 1.5)
+        );
+        assert_eq!(family_params.te_length_window("roo", 0.1, 1.5), (0.5, 1.2));
+
+        // a reversed window, a missing column and a repeated family
+        assert!(FamilyParams::parse(Cursor::new("roo\t9\t7\t.\t.\n")).is_err());
+        assert!(FamilyParams::parse(Cursor::new("roo\t4\t6\t.\n")).is_err());
+        assert!(FamilyParams::parse(Cursor::new("roo\t4\t6\t.\t.\nroo\t1\t2\t.\t.\n")).is_err());
+    }
+}
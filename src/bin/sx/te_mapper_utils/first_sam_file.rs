@@ -1,24 +1,14 @@
-use anyhow::Result;
 use lazy_static::lazy_static;
 
 use std::collections::HashMap;
-use std::fs::File;
 use std::io::BufRead;
-use std::io::BufReader;
 
 use super::te_alignment::TeAlignment;
+use crate::errors::{Result, StanexError};
 use crate::tabular::Metadata;
+use crate::utils;
 
 lazy_static! {
-    static ref FIRST_SAM_FILE_TE_METADATA: Metadata = {
-        let mut headings = HashMap::new();
-        headings.insert(2, "TE_NAME".to_string());
-        headings.insert(3, "TE_LEN".to_string());
-        Metadata {
-            delimiter: "\t".to_string(),
-            headings: headings,
-        }
-    };
     static ref FIRST_SAM_FILE_ALIGNMENT_METADATA: Metadata = {
         let mut headings = HashMap::new();
         headings.insert(1, "QNAME".to_string());
@@ -27,6 +17,7 @@ lazy_static! {
         headings.insert(4, "POS".to_string());
         headings.insert(6, "CIGAR".to_string());
         headings.insert(10, "SEQ".to_string());
+        headings.insert(11, "QUAL".to_string());
         Metadata {
             delimiter: "\t".to_string(),
             headings: headings,
@@ -34,69 +25,341 @@ lazy_static! {
     };
 }
 
-fn read_te_into_map(te_str: String, transposon_lengths: &mut HashMap<String, u64>) {
-    let te_data = FIRST_SAM_FILE_TE_METADATA.read(te_str);
-    transposon_lengths.insert(
-        te_data.get("TE_NAME").unwrap()[3..].to_string(),
-        te_data.get("TE_LEN").unwrap()[3..].parse().unwrap(),
-    );
+// the record type of a SAM header line, from its first field (e.g. "@SQ")
+// record types that aren't in the SAM spec (lowercase, or other 2-letter codes) are Other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderRecordType {
+    Hd,
+    Sq,
+    Rg,
+    Pg,
+    Co,
+    Other,
 }
 
-pub fn read_all_tes_into_map(reader: &mut BufReader<File>) -> HashMap<String, u64> {
-    // reads all TE's into a map and positions the buffered reader on the first line that is an alignment
-    // read the file line by line
-    // get rid of comments (comments in the SAM file start with "@SQ")
-    // and ignore the last comment line (starts with "@PG")
-    let mut transposon_lengths: HashMap<String, u64> = HashMap::new();
-
-    let mut te_aligned_read;
-
-    loop {
-        te_aligned_read = String::new();
-        reader.read_line(&mut te_aligned_read).unwrap();
-        // get rid of trailing newline
-        te_aligned_read = te_aligned_read[..te_aligned_read.len() - 1].to_string();
-        if te_aligned_read.chars().nth(1).unwrap() == 'P' {
-            break;
-        } else {
-            read_te_into_map(te_aligned_read, &mut transposon_lengths);
+// None if the line isn't a header line ("@" and a 2-letter record type, e.g. a FASTQ read name)
+pub fn header_record_type(line: &str) -> Option<HeaderRecordType> {
+    let record_type = line
+        .trim_end_matches(&['\n', '\r'][..])
+        .split('\t')
+        .next()?
+        .strip_prefix('@')?;
+    if record_type.len() != 2 || !record_type.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    return Some(match record_type {
+        "HD" => HeaderRecordType::Hd,
+        "SQ" => HeaderRecordType::Sq,
+        "RG" => HeaderRecordType::Rg,
+        "PG" => HeaderRecordType::Pg,
+        "CO" => HeaderRecordType::Co,
+        _ => HeaderRecordType::Other,
+    });
+}
+
+// the name ("SN:") and length ("LN:") of an "@SQ" line, in any order among its other tags
+// (e.g. "AS:", "M5:" or "UR:"), or None if either is missing
+pub fn parse_sq_line(sq_line: &str) -> Option<(String, u64)> {
+    let fields = sq_line.trim_end_matches(&['\n', '\r'][..]).split('\t');
+    let mut name = None;
+    let mut length = None;
+    for field in fields.skip(1) {
+        if let Some(sn) = field.strip_prefix("SN:") {
+            name = Some(sn.to_owned());
+        } else if let Some(ln) = field.strip_prefix("LN:") {
+            length = Some(ln.parse().ok()?);
+        }
+    }
+    return Some((name?, length?));
+}
+
+// whether the next line of a buffered reader is a header line (starts with "@"),
+// without consuming it (false at the end of the file)
+pub fn at_header_line<R: BufRead>(reader: &mut R) -> Result<bool> {
+    let buffer = reader.fill_buf()?;
+    return Ok(buffer.first() == Some(&b'@'));
+}
+
+// read the name and length of every reference sequence ("@SQ" line) in a SAM header, in order,
+// and position the buffered reader on the first line that is an alignment
+// other header lines (e.g. "@HD", "@RG", "@PG" and "@CO") are ignored, and an empty file has no sequences
+pub fn read_sq_header<R: BufRead>(reader: &mut R) -> Result<Vec<(String, u64)>> {
+    let mut sequences: Vec<(String, u64)> = Vec::new();
+
+    while at_header_line(reader)? {
+        let header_line = match utils::read_line_trimmed(reader)? {
+            Some(header_line) => header_line,
+            None => break,
+        };
+        // only "@SQ" lines have a name and length ("@RG" lines also have an "SN"-like structure)
+        if header_record_type(&header_line) == Some(HeaderRecordType::Sq) {
+            match parse_sq_line(&header_line) {
+                Some(sequence) => sequences.push(sequence),
+                None => {
+                    return Err(StanexError::InvalidSamHeader(
+                        "has an @SQ line without a name or length",
+                    ))
+                }
+            }
         }
     }
 
-    return transposon_lengths;
+    return Ok(sequences);
+}
+
+// check that a TE alignment file given with --te-aligned looks like the output of phase 1
+// (a SAM header with the transposons as "@SQ" lines and a "@PG" line)
+// before the rest of the pipeline is run on it
+pub fn validate_te_aligned<R: BufRead>(reader: &mut R) -> Result<()> {
+    let mut num_sequences = 0;
+    let mut has_pg = false;
+    // the header ends at the first line that isn't a header record
+    // (the "@PG" line isn't always last, e.g. "@CO" lines can follow it)
+    while let Some(header_line) = utils::read_line_trimmed(reader)? {
+        match header_record_type(&header_line) {
+            Some(HeaderRecordType::Pg) => has_pg = true,
+            Some(HeaderRecordType::Sq) => {
+                if parse_sq_line(&header_line).is_none() {
+                    return Err(StanexError::InvalidSamHeader(
+                        "has an @SQ line without a name or length",
+                    ));
+                }
+                num_sequences += 1;
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    if !has_pg {
+        return Err(StanexError::InvalidSamHeader("has no @PG line"));
+    }
+    if num_sequences == 0 {
+        return Err(StanexError::InvalidSamHeader(
+            "has no @SQ lines (the transposons)",
+        ));
+    }
+    return Ok(());
+}
+
+pub fn read_all_tes_into_map<R: BufRead>(reader: &mut R) -> Result<HashMap<String, u64>> {
+    // reads all TE's into a map and positions the buffered reader on the first line that is an alignment
+    // (the transposons are the reference sequences of the TE alignment)
+    return Ok(read_sq_header(reader)?.into_iter().collect());
+}
+
+// the metadata for the alignments, with the columns moved according to the SAM layout
+// (an empty layout is the standard SAM column order)
+pub fn alignment_metadata(sam_layout: &HashMap<String, usize>) -> Metadata {
+    FIRST_SAM_FILE_ALIGNMENT_METADATA.with_positions(sam_layout)
+}
+
+// the most common read length (the length of SEQ) of the first max_records alignments
+// (the longer length if there is a tie), or None if none of them keep their sequence
+pub fn modal_read_length<R: BufRead>(
+    reader: &mut R,
+    alignment_metadata: &Metadata,
+    max_records: usize,
+) -> Option<u64> {
+    let mut counts: HashMap<u64, u64> = HashMap::new();
+    for line in reader.lines().take(max_records) {
+        let line = line.expect("Something went wrong - unable to read file");
+        let seq = match alignment_metadata
+            .try_read(&line)
+            .and_then(|data| data.get("SEQ"))
+        {
+            Ok(seq) => seq,
+            Err(_) => continue,
+        };
+        if seq != "*" {
+            *counts.entry(seq.len() as u64).or_insert(0) += 1;
+        }
+    }
+    return counts
+        .into_iter()
+        .max_by_key(|(length, count)| (*count, *length))
+        .map(|(length, _)| length);
 }
 
 pub fn read_te_alignment(
     alignment_str: String,
+    alignment_metadata: &Metadata,
     transposon_lengths: &HashMap<String, u64>,
+    min_clip: u64,
+    max_read_n: Option<u64>,
 ) -> Result<TeAlignment> {
-    let alignment_data = FIRST_SAM_FILE_ALIGNMENT_METADATA.read(alignment_str);
-    return TeAlignment::create(alignment_data, transposon_lengths);
+    let alignment_data = alignment_metadata.try_read(&alignment_str)?;
+    return TeAlignment::create(alignment_data, transposon_lengths, min_clip, max_read_n);
+}
+
+// read the TE alignment of a long read (see TeAlignment::create_long_read)
+pub fn read_long_te_alignment(
+    alignment_str: String,
+    alignment_metadata: &Metadata,
+    transposon_lengths: &HashMap<String, u64>,
+    min_clip: u64,
+    max_read_n: Option<u64>,
+) -> Result<TeAlignment> {
+    let alignment_data = alignment_metadata.try_read(&alignment_str)?;
+    return TeAlignment::create_long_read(alignment_data, transposon_lengths, min_clip, max_read_n);
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{read_all_tes_into_map, read_te_alignment};
+    use super::{
+        header_record_type, modal_read_length, read_all_tes_into_map, read_sq_header,
+        read_te_alignment, validate_te_aligned, HeaderRecordType,
+        FIRST_SAM_FILE_ALIGNMENT_METADATA,
+    };
 
     use std::collections::HashMap;
-    use std::fs::File;
-    use std::io::BufReader;
+    use std::io::{BufRead, Cursor};
+
+    use super::super::te_filter::TeFilter;
+    use crate::errors::StanexError;
+
+    // the header of a TE alignment file with the transposons used below
+    const TE_ALIGNED_HEADER: &str = "@SQ\tSN:roo#LTR/Bel-Pao\tLN:9092\n\
+                                     @SQ\tSN:blood#LTR/Gypsy\tLN:7410\n\
+                                     @PG\tID:bwa\tPN:bwa\n";
+
+    #[test]
+    fn test_read_all_tes_into_map() {
+        let mut te_aligned_reader = Cursor::new(TE_ALIGNED_HEADER);
+        let transposon_lengths = read_all_tes_into_map(&mut te_aligned_reader).unwrap();
+        assert_eq!(transposon_lengths.len(), 2);
+        assert_eq!(transposon_lengths.get("roo#LTR/Bel-Pao"), Some(&9092));
+        assert_eq!(transposon_lengths.get("blood#LTR/Gypsy"), Some(&7410));
+    }
+
+    #[test]
+    fn test_read_sq_header() {
+        let mut sam_reader = Cursor::new(
+            "@HD\tVN:1.5\tSO:unsorted\n\
+             @SQ\tSN:X\tLN:23542271\n\
+             @SQ\tSN:2L\tLN:23513712\n\
+             @PG\tID:bwa\tPN:bwa\n\
+             read_1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t~~~~\n",
+        );
+        assert_eq!(
+            read_sq_header(&mut sam_reader).unwrap(),
+            vec![("X".to_owned(), 23542271), ("2L".to_owned(), 23513712)]
+        );
+        // the reader is left on the first alignment
+        let mut next_line = String::new();
+        sam_reader.read_line(&mut next_line).unwrap();
+        assert!(next_line.starts_with("read_1"));
+
+        // phase 1 found no TE alignments: an empty file, or a header without alignments
+        assert!(read_all_tes_into_map(&mut Cursor::new(""))
+            .unwrap()
+            .is_empty());
+        let mut header_only = Cursor::new(TE_ALIGNED_HEADER);
+        assert_eq!(read_all_tes_into_map(&mut header_only).unwrap().len(), 2);
+        assert_eq!(header_only.position(), TE_ALIGNED_HEADER.len() as u64);
+        // an @SQ line without a length is an error instead of a panic
+        assert!(matches!(
+            read_sq_header(&mut Cursor::new("@SQ\tSN:X\n@PG\tID:bwa\n")),
+            Err(StanexError::InvalidSamHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_full_header() {
+        // a header with every standard record type, where the "@RG" line has "SM" and "LB" tags
+        // and the "@SQ" tags aren't in the usual order
+        let header = "@HD\tVN:1.6\tSO:unsorted\n\
+                      @SQ\tSN:roo#LTR/Bel-Pao\tLN:9092\n\
+                      @SQ\tLN:7410\tM5:0123456789abcdef\tSN:blood#LTR/Gypsy\n\
+                      @RG\tID:sample_1\tSM:sample_1\tLB:lib_1\tPL:ILLUMINA\n\
+                      @PG\tID:bwa\tPN:bwa\tCL:bwa mem tes.fa reads.fq\n\
+                      @CO\tthe transposons of the library\n\
+                      read_1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t~~~~\n";
+        assert!(validate_te_aligned(&mut Cursor::new(header)).is_ok());
+        let mut te_aligned_reader = Cursor::new(header);
+        let transposon_lengths = read_all_tes_into_map(&mut te_aligned_reader).unwrap();
+        assert_eq!(transposon_lengths.len(), 2);
+        assert_eq!(transposon_lengths.get("roo#LTR/Bel-Pao"), Some(&9092));
+        assert_eq!(transposon_lengths.get("blood#LTR/Gypsy"), Some(&7410));
+        let mut next_line = String::new();
+        te_aligned_reader.read_line(&mut next_line).unwrap();
+        assert!(next_line.starts_with("read_1"));
+
+        assert_eq!(
+            header_record_type("@RG\tID:1\n"),
+            Some(HeaderRecordType::Rg)
+        );
+        assert_eq!(header_record_type("@CO"), Some(HeaderRecordType::Co));
+        assert_eq!(header_record_type("@SQX\tSN:roo"), None);
+        assert_eq!(header_record_type("@read_1"), None);
+        assert_eq!(header_record_type("read_1\t4"), None);
+    }
+
+    #[test]
+    fn test_modal_read_length() {
+        let alignments = "r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGT\t*\n\
+                          r2\t4\t*\t0\t0\t*\t*\t0\t0\tACGTAC\t*\n\
+                          r3\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGT\t*\n\
+                          r4\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n\
+                          truncated\n";
+        let read = |max_records: usize| {
+            modal_read_length(
+                &mut Cursor::new(alignments),
+                &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+                max_records,
+            )
+        };
+        assert_eq!(read(10), Some(8));
+        // a tie between 8 and 6
+        assert_eq!(read(2), Some(8));
+        assert_eq!(read(0), None);
+        assert_eq!(
+            modal_read_length(
+                &mut Cursor::new("r4\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n"),
+                &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+                10
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate_te_aligned() {
+        assert!(validate_te_aligned(&mut Cursor::new(TE_ALIGNED_HEADER)).is_ok());
+        // a FASTQ file instead of a SAM file
+        assert!(matches!(
+            validate_te_aligned(&mut Cursor::new("@read_1\nACGT\n+\n~~~~\n")),
+            Err(StanexError::InvalidSamHeader(_))
+        ));
+        // a SAM file without the transposons in its header
+        assert!(matches!(
+            validate_te_aligned(&mut Cursor::new("@HD\tVN:1.5\n@PG\tID:bwa\tPN:bwa\n")),
+            Err(StanexError::InvalidSamHeader(_))
+        ));
+        assert!(matches!(
+            validate_te_aligned(&mut Cursor::new("@SQ\tSN:roo\n@PG\tID:bwa\tPN:bwa\n")),
+            Err(StanexError::InvalidSamHeader(_))
+        ));
+    }
 
     #[test]
-    #[ignore]
     fn test_cigar_string_parsing() {
         // test the creation of, m_size, s_size, is_sm, and is_start
 
         // read in the transposons
-        let mut te_aligned_reader =
-            BufReader::with_capacity(65_536, File::open("test/te_aligned.sam").unwrap());
+        let mut te_aligned_reader = Cursor::new(TE_ALIGNED_HEADER);
         let transposon_lengths: HashMap<String, u64> =
-            read_all_tes_into_map(&mut te_aligned_reader);
+            read_all_tes_into_map(&mut te_aligned_reader).unwrap();
 
         // use actual inputs from the file
         // 54S34M62S is invalid
         let input0 = "I_MADE_UP_THIS_READ	0	roo#LTR/Bel-Pao	1	60	54S34M62S	*	0	0	CCTGGCTTGGGGCGGCCGCGGGTTCGTGGCGTCGGCGCTATTTGTTCCTTGGCAGTCGGCTCTTCCTATCATTGTGAAGCAAAATTCATATGGCATTGTCTCCTAAAACTTTTCTATAGTGCCGTATTTCTATGGCGCCCACTGTGAAGN	--F-7-F7----A--F7---------7--77----J7<---77--7---A--7-7-----<A7--7F<7FAAJA7---F-<-F7<<-<----<<--<---<--F7-F-F-<JFJAF7<JFJJAJFJFFJAJJJJJJJJJJJJFJJF<AA#	NM:i:0	MD:Z:34	AS:i:34	XS:i:0";
-        let mut te_alignment = read_te_alignment(input0.to_string(), &transposon_lengths);
+        let mut te_alignment = read_te_alignment(
+            input0.to_string(),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        );
         assert!(te_alignment.is_err());
 
         // case 1: +/+ match at start
@@ -106,7 +369,13 @@ mod tests {
         //              <--------
         // expected: m_size = 31, s_size = 119, is_sm = true, is_start = true
         let input1 = "2L_Read_976816	0	roo#LTR/Bel-Pao	1	0	119S31M	*	0	0	ACATATGATATAAATAGCATTAAATGTTGAGTATAACGTGTCAAAGAATCCTTGGGATGAATAATAACGGAGGAAGCTGTAAATATAACCAGATTAGAAACCTATTCCTATAAACTCTCTGTTCACACATGAACACGAATATATTTAAAG	~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~	NM:i:0	MD:Z:31	AS:i:31	XS:i:31	XA:Z:roo#LTR/Bel-Pao,+8665,119S31M,0;";
-        te_alignment = read_te_alignment(input1.to_string(), &transposon_lengths);
+        te_alignment = read_te_alignment(
+            input1.to_string(),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        );
         assert!(te_alignment.is_ok());
         if let Ok(res) = te_alignment {
             assert_eq!(res.qname, "2L_Read_976816");
@@ -124,7 +393,13 @@ mod tests {
         //              <--------
         // expected: m_size = 144, s_size = 6, is_sm = false, is_start = false
         let input2 = "2L_Read_977219	0	roo#LTR/Bel-Pao	8949	0	144M6S	*	0	0	GGACTATTTACGTAGGCCTCTGCGTAGGCCATTTACTTTAAGATGCGATTCTCATGTCACCTATTTAAACCGAAGATATTTCCAAATAAAACCAGTTTCTTACAAAAACTCAACGAGTAAAGTCTTCTTATTTGGGATTTTACATTTGGT	~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~	NM:i:2	MD:Z:91T36C15	AS:i:134	XS:i:133	XA:Z:roo#LTR/Bel-Pao,+285,95M1D55M,3;";
-        te_alignment = read_te_alignment(input2.to_string(), &transposon_lengths);
+        te_alignment = read_te_alignment(
+            input2.to_string(),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        );
         assert!(te_alignment.is_ok());
         if let Ok(res) = te_alignment {
             assert_eq!(res.qname, "2L_Read_977219");
@@ -142,7 +417,13 @@ mod tests {
         // match ... <----
         // expected: m_size = 141, s_size = 9, is_sm = true, is_start = true
         let input3 = "2L_Read_355243	16	blood#LTR/Gypsy	1	0	9S141M	*	0	0	GTGGCGAATTGTAGTATGTGCATATATCGAGGGTATACTGTACCTATAAGTACACAGCAACACTTAGTTGCATTGCATAAATAAATGTCTCAAGTGAGCGTGATATAAGATCACCCATTTATGCTTTAAGCTAAGTCAGCATCCCCACGC	~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~	NM:i:1	MD:Z:26C114	AS:i:136	XS:i:136	XA:Z:blood#LTR/Gypsy,-7012,9S141M,1;";
-        te_alignment = read_te_alignment(input3.to_string(), &transposon_lengths);
+        te_alignment = read_te_alignment(
+            input3.to_string(),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        );
         assert!(te_alignment.is_ok());
         if let Ok(res) = te_alignment {
             assert_eq!(res.qname, "2L_Read_355243");
@@ -160,7 +441,13 @@ mod tests {
         //          match ... <----
         // expected: m_size = 31, s_size = 119, is_sm = false, is_start = false
         let input4 = "2L_Read_347822	16	blood#LTR/Gypsy	7380	0	31M119S	*	0	0	CTCAATTGGTGGCATATATTGGTTTATTACAGAATATCGAATCACTGATTCGGGATGTGAGAGTCACAATTTATTCCGCGATATCAGTTAAAAAAAATCTTCAAGACTTAAGATTTGACCGACAAAGAACATTTCTACGTGTTGGCCAAG	~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~	NM:i:0	MD:Z:31	AS:i:31	XS:i:31	XA:Z:blood#LTR/Gypsy,-368,31M119S,0;";
-        te_alignment = read_te_alignment(input4.to_string(), &transposon_lengths);
+        te_alignment = read_te_alignment(
+            input4.to_string(),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        );
         assert!(te_alignment.is_ok());
         if let Ok(res) = te_alignment {
             assert_eq!(res.qname, "2L_Read_347822");
@@ -171,4 +458,156 @@ mod tests {
             assert!(!res.is_start);
         }
     }
+
+    #[test]
+    fn test_selected_read_records() {
+        let mut te_aligned_reader = Cursor::new(TE_ALIGNED_HEADER);
+        let transposon_lengths = read_all_tes_into_map(&mut te_aligned_reader).unwrap();
+        let read = |qual: &str| {
+            read_te_alignment(
+                format!(
+                    "r1\t0\troo#LTR/Bel-Pao\t9089\t60\t4M2S\t*\t0\t0\tACGTAC\t{}",
+                    qual
+                ),
+                &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+                &transposon_lengths,
+                0,
+                None,
+            )
+            .unwrap()
+        };
+        let alignment = read("II#I5F");
+        assert_eq!(
+            alignment.to_string(),
+            ">r1|roo#LTR/Bel-Pao|4|2|MS|end\nACGTAC"
+        );
+        assert_eq!(
+            alignment.to_fastq(),
+            "@r1|roo#LTR/Bel-Pao|4|2|MS|end\nACGTAC\n+\nII#I5F"
+        );
+        // an alignment without base qualities
+        assert!(read("*").to_fastq().ends_with("\n+\n~~~~~~"));
+    }
+
+    #[test]
+    fn test_min_clip() {
+        let mut te_aligned_reader = Cursor::new(TE_ALIGNED_HEADER);
+        let transposon_lengths = read_all_tes_into_map(&mut te_aligned_reader).unwrap();
+        let read_with_cigar = |cigar: &str| {
+            format!(
+                "2L_Read_1\t0\troo#LTR/Bel-Pao\t1\t60\t{}\t*\t0\t0\tACGT\t~~~~",
+                cigar
+            )
+        };
+        // a 2 nucleotide clip is too short
+        let te_alignment = read_te_alignment(
+            read_with_cigar("2S148M"),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            5,
+            None,
+        );
+        match te_alignment {
+            Err(StanexError::ClipTooShort { cigar, min_clip }) => {
+                assert_eq!(cigar, "2S148M");
+                assert_eq!(min_clip, 5);
+            }
+            other => panic!("expected a clip that is too short, got {:?}", other),
+        }
+        // without a minimum clip, the same read is accepted
+        assert!(read_te_alignment(
+            read_with_cigar("2S148M"),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        )
+        .is_ok());
+        // a 10 nucleotide clip is long enough
+        let te_alignment = read_te_alignment(
+            read_with_cigar("10S140M"),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            5,
+            None,
+        )
+        .unwrap();
+        assert_eq!(te_alignment.s_size, 10);
+        assert_eq!(te_alignment.m_size, 140);
+    }
+
+    #[test]
+    fn test_max_read_n() {
+        let mut te_aligned_reader = Cursor::new(TE_ALIGNED_HEADER);
+        let transposon_lengths = read_all_tes_into_map(&mut te_aligned_reader).unwrap();
+        // a read with 4 Ns in its clip
+        let read = "2L_Read_1\t0\troo#LTR/Bel-Pao\t1\t60\t10S140M\t*\t0\t0\tACNNGTNNAC\t~~~~~~~~~~";
+        let read_with_max = |max_read_n: Option<u64>| {
+            read_te_alignment(
+                read.to_string(),
+                &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+                &transposon_lengths,
+                0,
+                max_read_n,
+            )
+        };
+        match read_with_max(Some(2)) {
+            Err(StanexError::TooManyAmbiguousBases { count, .. }) => assert_eq!(count, 4),
+            other => panic!("expected too many ambiguous bases, got {:?}", other),
+        }
+        assert!(read_with_max(Some(4)).is_ok());
+        assert!(read_with_max(None).is_ok());
+    }
+
+    #[test]
+    fn test_placeholder_fields() {
+        let mut te_aligned_reader = Cursor::new(TE_ALIGNED_HEADER);
+        let transposon_lengths = read_all_tes_into_map(&mut te_aligned_reader).unwrap();
+        // a mapped flag with "*" in RNAME (and a position of 0) or in CIGAR
+        for input in &[
+            "2L_Read_1\t0\t*\t0\t0\t*\t*\t0\t0\tACGT\t~~~~",
+            "2L_Read_1\t0\troo#LTR/Bel-Pao\t1\t0\t*\t*\t0\t0\tACGT\t~~~~",
+            "2L_Read_1\t0\t*\t1\t0\t10S140M\t*\t0\t0\tACGT\t~~~~",
+        ] {
+            match read_te_alignment(
+                input.to_string(),
+                &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+                &transposon_lengths,
+                0,
+                None,
+            ) {
+                Err(StanexError::UnplacedRead(qname)) => assert_eq!(qname, "2L_Read_1"),
+                other => panic!("expected an unplaced read, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_te_filter() {
+        let mut te_aligned_reader = Cursor::new(TE_ALIGNED_HEADER);
+        let mut transposon_lengths = read_all_tes_into_map(&mut te_aligned_reader).unwrap();
+        TeFilter::new(&["Bel-Pao".to_owned()], &[]).filter_transposons(&mut transposon_lengths);
+
+        // only the reads that align to the included family are kept
+        let included = "2L_Read_1\t0\troo#LTR/Bel-Pao\t1\t0\t10S140M\t*\t0\t0\tACGT\t~~~~";
+        let excluded = "2L_Read_2\t0\tblood#LTR/Gypsy\t1\t0\t10S140M\t*\t0\t0\tACGT\t~~~~";
+        assert!(read_te_alignment(
+            included.to_string(),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        )
+        .is_ok());
+        match read_te_alignment(
+            excluded.to_string(),
+            &FIRST_SAM_FILE_ALIGNMENT_METADATA,
+            &transposon_lengths,
+            0,
+            None,
+        ) {
+            Err(StanexError::UnknownTransposon(te_name)) => assert_eq!(te_name, "blood#LTR/Gypsy"),
+            other => panic!("expected an unknown transposon, got {:?}", other),
+        }
+    }
 }
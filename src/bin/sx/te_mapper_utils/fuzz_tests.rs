@@ -0,0 +1,112 @@
+// property-based tests that feed random-but-plausible CIGAR strings and SAM lines to the parsers
+// (messy real-world SAM files must be skipped with an error, never crash the TE mapper)
+
+use proptest::prelude::*;
+
+use std::collections::HashMap;
+
+use super::first_sam_file::{alignment_metadata, read_te_alignment};
+use super::genome_alignment::SplitReadGenome;
+use super::second_sam_file::{genome_alignment_metadata, read_genome_alignment};
+use super::te_alignment::SplitReadTE;
+
+// CIGAR strings with 1 or 2 operations (like the ones the TE mapper looks for),
+// longer and stranger ones, and lengths that don't fit in a u64
+fn cigar() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[0-9]{1,3}[SMH][0-9]{1,3}[SMH]",
+        "[0-9]{1,25}[SMH]([0-9]{1,25}[SMH])?",
+        "([0-9]{0,4}[MIDNSHP=X])*",
+        "\\*|[0-9A-Z]{0,8}",
+    ]
+}
+
+// the read names of the genome alignments encode the TE alignment
+// (read name|TE name|old M|old S|SM or MS|start or end)
+fn genome_qname() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "read[0-9]{1,3}\\|roo\\|[0-9]{1,4}\\|[0-9]{1,4}\\|(SM\\|start|MS\\|end)",
+        "read[0-9]{1,3}(\\|[a-zA-Z0-9*-]{0,25}){0,6}",
+    ]
+}
+
+// a SAM line with plausible (or missing, or nonsensical) fields
+fn sam_line(qname: impl Strategy<Value = String>) -> impl Strategy<Value = String> {
+    (
+        qname,
+        prop_oneof!["0|4|16|2048", "[0-9]{1,7}", "-?[0-9a-z]{0,3}"],
+        prop_oneof!["roo|2L|3R|\\*", "[a-zA-Z0-9]{0,4}"],
+        prop_oneof!["[0-9]{1,5}", "[0-9]{15,25}", "-?[0-9a-z]{0,3}"],
+        cigar(),
+        "[ACGTN]{0,20}",
+        0..12_usize,
+    )
+        .prop_map(|(qname, flag, rname, pos, cigar, seq, num_columns)| {
+            let columns = vec![
+                qname,
+                flag,
+                rname,
+                pos,
+                "60".to_owned(),
+                cigar,
+                "*".to_owned(),
+                "0".to_owned(),
+                "0".to_owned(),
+                seq,
+                "*".to_owned(),
+            ];
+            columns[..num_columns.min(columns.len())].join("\t")
+        })
+}
+
+proptest! {
+    #[test]
+    fn split_read_te_parse_never_panics(cigar in cigar(), pos in any::<u64>()) {
+        if SplitReadTE::parse(cigar.clone(), pos).is_ok() {
+            // only SM and MS alignments are split-reads
+            prop_assert_eq!(cigar.matches(|c: char| c.is_ascii_alphabetic()).count(), 2);
+        }
+    }
+
+    #[test]
+    fn split_read_genome_parse_never_panics(
+        cigar in cigar(),
+        old_m in any::<u64>(),
+        old_s in any::<u64>(),
+        is_start in any::<bool>(),
+        new_plus in any::<bool>(),
+        pos in any::<u64>(),
+        min_clip in 0..200_u64,
+    ) {
+        let _ = SplitReadGenome::parse(cigar, old_m, old_s, is_start, new_plus, pos, min_clip);
+    }
+
+    #[test]
+    fn te_alignment_create_never_panics(line in sam_line("read[0-9]{1,3}"), min_clip in 0..50_u64) {
+        let transposon_lengths: HashMap<String, u64> =
+            vec![("roo".to_owned(), 100)].into_iter().collect();
+        let _ = read_te_alignment(
+            line,
+            &alignment_metadata(&HashMap::new()),
+            &transposon_lengths,
+            min_clip,
+            None,
+        );
+    }
+
+    #[test]
+    fn genome_alignment_create_never_panics(line in sam_line(genome_qname()), min_clip in 0..50_u64) {
+        let chroms = vec!["2L".to_owned(), "3R".to_owned()];
+        if let Ok((chrom, alignment)) = read_genome_alignment(
+            line,
+            &genome_alignment_metadata(&HashMap::new()),
+            &chroms,
+            &HashMap::new(),
+            min_clip,
+        ) {
+            prop_assert!(chroms.contains(&chrom));
+            // the boundary is computed for every alignment that is kept
+            let _ = alignment.get_boundary_nt();
+        }
+    }
+}
@@ -1,20 +1,25 @@
-use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
 use std::cmp::{Ordering, Reverse};
 use std::collections::{BinaryHeap, HashMap};
 
+use super::diagnostics::{Diagnostics, Stage};
+use super::family_params::FamilyParams;
+use super::long_read::{self, ReadSegment};
 use super::output_data_types::{NonRefTE, Orientation, RefTE};
+use super::split_read::{MSAlignment, SMAlignment};
+use crate::errors::{Result, StanexError};
 use crate::tabular::Data;
 
 // module with some helper structs and functions to represent split reads
 mod split_read_genome {
-    use anyhow::{bail, Result};
+    use serde::{Deserialize, Serialize};
 
     use super::super::split_read::{MAlignment, MSAlignment, SMAlignment};
+    use crate::errors::{Result, StanexError};
     use crate::regexes;
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub enum SplitReadGenome {
         SM(SMAlignment),
         MS(MSAlignment),
@@ -30,6 +35,15 @@ mod split_read_genome {
                 SplitReadGenome::M(m_alignment) => m_alignment.new_pos,
             }
         }
+        // the number of nucleotides that match the genome past the breakpoint (see --min-genome-overlap):
+        // the M of a split-read, or the part of a full match that was clipped off by the TE alignment
+        pub fn genome_anchor(&self) -> u64 {
+            match self {
+                SplitReadGenome::SM(sm_alignment) => sm_alignment.m,
+                SplitReadGenome::MS(ms_alignment) => ms_alignment.m,
+                SplitReadGenome::M(m_alignment) => m_alignment.old_s,
+            }
+        }
         pub fn parse(
             cigar: String,
             old_m: u64,
@@ -37,22 +51,40 @@ mod split_read_genome {
             is_start: bool,
             new_plus: bool,
             pos: u64,
+            min_clip: u64,
+        ) -> Result<SplitReadGenome> {
+            let split_read =
+                SplitReadGenome::parse_cigar(cigar.clone(), old_m, old_s, is_start, new_plus, pos)?;
+            // the clip (S or H) must be at least min_clip nucleotides long
+            // (full matches are reference insertions, so they don't have a clip on the genome side)
+            let clip = match &split_read {
+                SplitReadGenome::SM(alignment) => Some(alignment.s),
+                SplitReadGenome::MS(alignment) => Some(alignment.s),
+                SplitReadGenome::M(_) => None,
+            };
+            match clip {
+                Some(clip) if clip < min_clip => Err(StanexError::ClipTooShort { cigar, min_clip }),
+                _ => Ok(split_read),
+            }
+        }
+        fn parse_cigar(
+            cigar: String,
+            old_m: u64,
+            old_s: u64,
+            is_start: bool,
+            new_plus: bool,
+            pos: u64,
         ) -> Result<SplitReadGenome> {
-            if regexes::HM_REGEX.is_match(&cigar[..]) {
-                let h: u64 = regexes::get_capture(regexes::HM_REGEX.captures(&cigar[..]), 1);
-                let m: u64 = regexes::get_capture(regexes::HM_REGEX.captures(&cigar[..]), 2);
+            if let Some((h, m)) = regexes::get_cigar_lengths(&regexes::HM_REGEX, &cigar[..]) {
                 Ok(SplitReadGenome::SM(SMAlignment { s: h, m, pos }))
-            } else if regexes::MH_REGEX.is_match(&cigar[..]) {
-                let m: u64 = regexes::get_capture(regexes::MH_REGEX.captures(&cigar[..]), 1);
-                let h: u64 = regexes::get_capture(regexes::MH_REGEX.captures(&cigar[..]), 2);
+            } else if let Some((m, h)) = regexes::get_cigar_lengths(&regexes::MH_REGEX, &cigar[..])
+            {
                 Ok(SplitReadGenome::MS(MSAlignment { m, s: h, pos }))
-            } else if regexes::SM_REGEX.is_match(&cigar[..]) {
-                let s: u64 = regexes::get_capture(regexes::SM_REGEX.captures(&cigar[..]), 1);
-                let m: u64 = regexes::get_capture(regexes::SM_REGEX.captures(&cigar[..]), 2);
+            } else if let Some((s, m)) = regexes::get_cigar_lengths(&regexes::SM_REGEX, &cigar[..])
+            {
                 Ok(SplitReadGenome::SM(SMAlignment { s, m, pos }))
-            } else if regexes::MS_REGEX.is_match(&cigar[..]) {
-                let m: u64 = regexes::get_capture(regexes::MS_REGEX.captures(&cigar[..]), 1);
-                let s: u64 = regexes::get_capture(regexes::MS_REGEX.captures(&cigar[..]), 2);
+            } else if let Some((m, s)) = regexes::get_cigar_lengths(&regexes::MS_REGEX, &cigar[..])
+            {
                 Ok(SplitReadGenome::MS(MSAlignment { m, s, pos }))
             } else if regexes::M_REGEX.is_match(&cigar[..]) {
                 Ok(SplitReadGenome::M(MAlignment {
@@ -63,7 +95,10 @@ mod split_read_genome {
                     new_pos: pos,
                 }))
             } else {
-                bail!("CIGAR string is not HM, MH, SM, MS, or M");
+                Err(StanexError::MalformedCigar {
+                    cigar,
+                    expected: "HM, MH, SM, MS, or M",
+                })
             }
         }
     }
@@ -76,14 +111,32 @@ pub use split_read_genome::SplitReadGenome;
 // that is closest to the 5' side of the reference genome)
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SplitReadRanges {
+    // the QNAME of the genome alignment (see --emit-support-reads)
+    pub read_name: String,
     pub te_range: (u64, u64),
     pub genome_range: (u64, u64),
+    // the position of the read's boundary nucleotide in the genome (see GenomeAlignment::get_boundary_nt),
+    // which can differ from the insertion's (see --emit-boundary-positions)
+    #[serde(default)]
+    pub boundary: u64,
+}
+
+impl SplitReadRanges {
+    // the name of the read that the split-read was selected from, without the TE alignment info
+    // of select_reads (both mates of a read pair have the same name, so it names the fragment)
+    pub fn fragment_name(&self) -> &str {
+        self.read_name.rsplitn(6, '|').last().unwrap()
+    }
 }
 
 // store all relevant info from a genome alignment
 // (including the previous info from the TE alignment)
-#[derive(Debug)]
+// (old_s and chrom are only read by the new grouping algorithm)
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GenomeAlignment {
+    // the QNAME of the genome alignment (the read name and the TE alignment info, see select_reads)
+    pub read_name: String,
     pub te_name: String,
     pub old_m: u64,
     pub old_s: u64,
@@ -96,13 +149,11 @@ pub struct GenomeAlignment {
 
 impl GenomeAlignment {
     // is the alignment mapped? The 3rd least-significant bit of the SAM flag must equal 0 (1 means unmapped)
-    fn is_mapped(flag: String) -> bool {
-        let sam_flag: u16 = flag.parse().unwrap();
+    fn is_mapped(sam_flag: u16) -> bool {
         (sam_flag & 4) == 0
     }
     // is the alignment +/+? The 5th least-significant bit of the SAM flag
-    fn is_plus(flag: String) -> bool {
-        let sam_flag: u16 = flag.parse().unwrap();
+    fn is_plus(sam_flag: u16) -> bool {
         (sam_flag & 16) == 0
     }
     // does the alignment occur on a chromosome that we care about?
@@ -111,63 +162,62 @@ impl GenomeAlignment {
     }
 
     // create a TE alignment from a string (skip if it doesn't meet criteria)
-    // we have 3 criteria:
+    // we have 4 criteria:
     // 1. SAM flag does not "&" with 4 (4 means unmapped)
-    // 2. The chromosome is an actual chromosome (like 2L and 2R)
-    // 3. read is a split-read from the genome side (we already know it is from the transposon side)
+    // 2. RNAME and CIGAR are not "*" (placeholder records have no position)
+    // 3. The chromosome is an actual chromosome (like 2L and 2R)
+    // 4. read is a split-read from the genome side (we already know it is from the transposon side)
     pub fn create(
         genome_alignment_data: Data,
         te_alignment_data: Data,
         chroms: &Vec<String>,
+        min_clip: u64,
     ) -> Result<(String, GenomeAlignment)> {
-        if !GenomeAlignment::is_mapped(genome_alignment_data.get("FLAG")?) {
-            bail!("unmapped read");
+        let flag: u16 = genome_alignment_data.get_parsed("FLAG")?;
+        if !GenomeAlignment::is_mapped(flag) {
+            return Err(StanexError::UnmappedRead(
+                genome_alignment_data.get("QNAME")?,
+            ));
+        }
+        if genome_alignment_data.get("RNAME")? == "*" || genome_alignment_data.get("CIGAR")? == "*"
+        {
+            return Err(StanexError::UnplacedRead(
+                genome_alignment_data.get("QNAME")?,
+            ));
         }
 
         let te_name = te_alignment_data.get("TE_NAME")?;
-        let old_m: u64 = te_alignment_data.get("OLD_M")?.parse()?;
-        let old_s: u64 = te_alignment_data.get("OLD_S")?.parse()?;
+        let old_m: u64 = te_alignment_data.get_parsed("OLD_M")?;
+        let old_s: u64 = te_alignment_data.get_parsed("OLD_S")?;
         let is_sm_te = te_alignment_data.get("OLD_SM")? == "SM";
         let is_start = te_alignment_data.get("START_OF_TE")? == "start";
 
+        // the read names are written by select_reads, so an SM alignment is always at the start
+        // of the transposon (anything else comes from a foreign or corrupted genome alignment)
         if is_sm_te != is_start {
-            let sm_str = {
-                if is_sm_te {
-                    "SM"
-                } else {
-                    "MS"
-                }
-            };
-            let start_str = {
-                if is_start {
-                    "start"
-                } else {
-                    "end"
-                }
-            };
-            panic!(
-                "TE mapper error: TE alignment was {} and aligned to the {} of the transposon",
-                sm_str, start_str
-            );
+            return Err(StanexError::InvalidValue {
+                field: "QNAME".to_owned(),
+                value: genome_alignment_data.get("QNAME")?,
+            });
         }
 
-        let flag = genome_alignment_data.get("FLAG")?;
         let chrom = genome_alignment_data.get("RNAME")?;
-        let pos: u64 = genome_alignment_data.get("POS")?.parse()?;
+        let pos: u64 = genome_alignment_data.get_parsed("POS")?;
         let cigar_str = genome_alignment_data.get("CIGAR")?;
 
         if !GenomeAlignment::validate_chrom(&chrom, chroms) {
-            bail!("invalid chromosome");
+            return Err(StanexError::InvalidChromosome(chrom));
         }
 
         let new_plus = GenomeAlignment::is_plus(flag);
 
         let split_read_genome =
-            SplitReadGenome::parse(cigar_str, old_m, old_s, is_start, new_plus, pos)?;
+            SplitReadGenome::parse(cigar_str, old_m, old_s, is_start, new_plus, pos, min_clip)?;
 
         Ok((
             chrom.clone(),
             GenomeAlignment {
+                read_name: genome_alignment_data.get("QNAME")?,
                 te_name,
                 old_m,
                 old_s,
@@ -180,6 +230,110 @@ impl GenomeAlignment {
         ))
     }
 
+    // create the genome alignments of a long read that spans insertions (see long_read):
+    // an MS alignment for the upstream flank and an SM alignment for the downstream flank of each insertion
+    // (as if they were the split-reads at the 2 ends of the insertion, so that they are grouped the same way)
+    // the supplementary alignments come from the SA tag of alignment_str
+    pub fn create_long_read(
+        genome_alignment_data: Data,
+        te_alignment_data: Data,
+        alignment_str: &str,
+        chroms: &Vec<String>,
+        contig_renames: &HashMap<String, String>,
+    ) -> Result<Vec<(String, GenomeAlignment)>> {
+        let read_name = genome_alignment_data.get("QNAME")?;
+        let flag: u16 = genome_alignment_data.get_parsed("FLAG")?;
+        if !GenomeAlignment::is_mapped(flag) {
+            return Err(StanexError::UnmappedRead(read_name));
+        }
+        // every alignment of the read is in the SA tag of the primary alignment
+        if flag & 0x900 != 0 {
+            return Err(StanexError::NotPrimaryAlignment(read_name));
+        }
+        if genome_alignment_data.get("RNAME")? == "*" || genome_alignment_data.get("CIGAR")? == "*"
+        {
+            return Err(StanexError::UnplacedRead(read_name));
+        }
+        let te_name = te_alignment_data.get("TE_NAME")?;
+        let old_m: u64 = te_alignment_data.get_parsed("OLD_M")?;
+        let old_s: u64 = te_alignment_data.get_parsed("OLD_S")?;
+
+        let mut segments = vec![ReadSegment::new(
+            genome_alignment_data.get("RNAME")?,
+            GenomeAlignment::is_plus(flag),
+            genome_alignment_data.get_parsed("POS")?,
+            &genome_alignment_data.get("CIGAR")?,
+        )?];
+        if let Some(sa_tag) = long_read::sa_tag(alignment_str) {
+            segments.extend(long_read::parse_sa_tag(sa_tag)?);
+        }
+        // the chromosome of the primary alignment is already renamed
+        for segment in &mut segments[1..] {
+            if let Some(new_name) = contig_renames.get(&segment.chrom) {
+                segment.chrom = new_name.clone();
+            }
+        }
+
+        let insertions = long_read::spanned_insertions(segments, old_m);
+        if insertions.is_empty() {
+            return Err(StanexError::NotSpanning(read_name));
+        }
+        let mut alignments: Vec<(String, GenomeAlignment)> = Vec::new();
+        let mut unknown_chrom = None;
+        for (upstream, downstream) in insertions {
+            if !GenomeAlignment::validate_chrom(&upstream.chrom, chroms) {
+                unknown_chrom = Some(upstream.chrom);
+                continue;
+            }
+            // the transposon is the part of the read between the flanks
+            let gap = if upstream.plus {
+                downstream.query_start - upstream.query_end
+            } else {
+                upstream.query_start - downstream.query_end
+            };
+            // the same orientation rules as for the split-reads (see get_non_ref_tes):
+            // a +/+ insertion has an SM TE alignment upstream and an MS TE alignment downstream
+            let alignment = |is_sm_te: bool, split_read_genome: SplitReadGenome| GenomeAlignment {
+                read_name: read_name.clone(),
+                te_name: te_name.clone(),
+                old_m,
+                old_s,
+                is_sm_te,
+                is_start: is_sm_te,
+                new_plus: upstream.plus,
+                chrom: upstream.chrom.clone(),
+                split_read_genome,
+            };
+            alignments.push((
+                upstream.chrom.clone(),
+                alignment(
+                    upstream.plus,
+                    SplitReadGenome::MS(MSAlignment {
+                        m: upstream.ref_length,
+                        s: gap,
+                        pos: upstream.pos,
+                    }),
+                ),
+            ));
+            alignments.push((
+                downstream.chrom.clone(),
+                alignment(
+                    !upstream.plus,
+                    SplitReadGenome::SM(SMAlignment {
+                        s: gap,
+                        m: downstream.ref_length,
+                        pos: downstream.pos,
+                    }),
+                ),
+            ));
+        }
+        // every insertion is on a contig that isn't in the list
+        if let (true, Some(chrom)) = (alignments.is_empty(), unknown_chrom) {
+            return Err(StanexError::InvalidChromosome(chrom));
+        }
+        return Ok(alignments);
+    }
+
     // get the position of the boundary nucleotide in the genome
     // right next to the transposon (if it's a non-reference insertion)
     // or the first nucleotide in the transposon itself (if it's a reference insertion)
@@ -198,28 +352,45 @@ impl GenomeAlignment {
 
     // return the TE range and the genome range for the read
     pub fn get_ranges(&self) -> SplitReadRanges {
-        match &self.split_read_genome {
-            SplitReadGenome::MS(alignment) => SplitReadRanges {
-                genome_range: (1, alignment.m),
-                te_range: (alignment.m + 1, alignment.m + alignment.s),
-            },
-            SplitReadGenome::SM(alignment) => SplitReadRanges {
-                te_range: (1, alignment.s),
-                genome_range: (alignment.s + 1, alignment.m + alignment.s),
-            },
+        let (te_range, genome_range) = match &self.split_read_genome {
+            SplitReadGenome::MS(alignment) => (
+                (alignment.m + 1, alignment.m + alignment.s),
+                (1, alignment.m),
+            ),
+            SplitReadGenome::SM(alignment) => (
+                (1, alignment.s),
+                (alignment.s + 1, alignment.m + alignment.s),
+            ),
             SplitReadGenome::M(alignment) => {
                 if !(alignment.new_plus ^ alignment.is_start) {
-                    SplitReadRanges {
-                        genome_range: (1, alignment.old_s),
-                        te_range: (alignment.old_s + 1, alignment.old_m + alignment.old_s),
-                    }
+                    (
+                        (alignment.old_s + 1, alignment.old_m + alignment.old_s),
+                        (1, alignment.old_s),
+                    )
                 } else {
-                    SplitReadRanges {
-                        te_range: (1, alignment.old_m),
-                        genome_range: (alignment.old_m + 1, alignment.old_m + alignment.old_s),
-                    }
+                    (
+                        (1, alignment.old_m),
+                        (alignment.old_m + 1, alignment.old_m + alignment.old_s),
+                    )
                 }
             }
+        };
+        SplitReadRanges {
+            read_name: self.read_name.clone(),
+            te_range,
+            genome_range,
+            boundary: self.get_boundary_nt(),
+        }
+    }
+
+    // is the boundary nucleotide actually on the chromosome?
+    // an MS read whose match runs off the end of the reference (or an M read whose TE
+    // alignment does) gives a boundary past the last nucleotide of the chromosome
+    // (if the chromosome length is unknown, the boundary is not checked)
+    fn boundary_within_chrom(&self, chrom_length: Option<&u64>) -> bool {
+        match chrom_length {
+            Some(chrom_length) => self.get_boundary_nt() <= *chrom_length,
+            None => true,
         }
     }
 
@@ -235,6 +406,31 @@ impl GenomeAlignment {
     }
 }
 
+// the diagnostic reason of the split-reads that match too little of the genome past the breakpoint
+const GENOME_ANCHOR_TOO_SHORT: &str = "genome anchor shorter than --min-genome-overlap";
+
+// the diagnostic reason of the insertions whose ends are in the wrong order for their class
+const REVERSED_POSITIONS: &str = "insertion with reversed ends";
+
+// check the order of the ends of an insertion before it is kept (see NonRefTE and RefTE)
+// reversed ends can only come from a bug in the grouping of the split-reads, so the insertion
+// is dropped and its split-reads are tallied instead of writing reversed coordinates
+// (or stopping a whole run over one insertion)
+fn positions_ordered_or_flagged(
+    ordered: bool,
+    num_reads: usize,
+    diagnostics: &Diagnostics,
+) -> bool {
+    if !ordered {
+        diagnostics.record_many(
+            Stage::InsertionCalling,
+            REVERSED_POSITIONS,
+            num_reads as u64,
+        );
+    }
+    return ordered;
+}
+
 // order the genome alignments first by transposon name, then by position
 // for optimal grouping in a binary heap
 impl PartialEq for GenomeAlignment {
@@ -273,7 +469,9 @@ impl GenomeAlignment {
     // outer dimension: which TE is it?
     // middle dimension: which position is it? (note: all insertions in the heap are in the same chromosome)
     // inner dimension: all of the reads at that specific TE insertion
-    fn make_3d_vector(heap: &mut BinaryHeap<GenomeAlignment>) -> Vec<Vec<Vec<GenomeAlignment>>> {
+    pub fn make_3d_vector(
+        heap: &mut BinaryHeap<GenomeAlignment>,
+    ) -> Vec<Vec<Vec<GenomeAlignment>>> {
         let mut result: Vec<Vec<Vec<GenomeAlignment>>> = Vec::new();
         let mut cur_transposon_name = "".to_string();
         let mut cur_pos: u64 = std::u64::MAX;
@@ -311,21 +509,44 @@ impl GenomeAlignment {
     // get the set of non-ref TE's from a binary heap of genome alignments
     // the heap will be consumed in this function
     // this function should be run once per chromosome
+    #[allow(clippy::too_many_arguments)]
     pub fn get_non_ref_tes(
         alignments: &mut BinaryHeap<GenomeAlignment>,
         min_tsd_length: u64,
         max_tsd_length: u64,
+        family_params: &FamilyParams,
         chrom_name: &String,
+        chrom_lengths: &HashMap<String, u64>,
+        report_one_sided: bool,
+        min_genome_overlap: u64,
+        diagnostics: &Diagnostics,
     ) -> Vec<NonRefTE> {
+        let chrom_length = chrom_lengths.get(chrom_name);
         let alignment_vector = GenomeAlignment::make_3d_vector(alignments);
         let mut tes: Vec<NonRefTE> = Vec::new();
         // each TE will have a few split-reads downstream of it,
         // and then after that will be the upstream reads
         // this is counterintuitive but due to the TSD
         for same_transposon_name in alignment_vector {
+            // the TSD window of the family, if it has its own (see --family-params)
+            let (min_tsd_length, max_tsd_length) = family_params.tsd_window(
+                &same_transposon_name[0][0].te_name,
+                min_tsd_length,
+                max_tsd_length,
+            );
             for same_position in same_transposon_name {
                 for alignment in same_position {
                     let position = alignment.get_boundary_nt();
+                    // reads with a boundary past the end of the chromosome are skipped
+                    // instead of reporting an out-of-range coordinate
+                    if !alignment.boundary_within_chrom(chrom_length) {
+                        diagnostics.record(Stage::InsertionCalling, "past the chromosome end");
+                        continue;
+                    }
+                    if alignment.split_read_genome.genome_anchor() < min_genome_overlap {
+                        diagnostics.record(Stage::InsertionCalling, GENOME_ANCHOR_TOO_SHORT);
+                        continue;
+                    }
                     // upstream of the transposon, MS read
                     if alignment.upstream() {
                         let orientation = if alignment.is_sm_te {
@@ -336,13 +557,14 @@ impl GenomeAlignment {
                         match tes.last_mut() {
                             // no TE's in the vector yet
                             None => tes.push(NonRefTE {
-                                name: alignment.te_name.clone(),
-                                chrom: chrom_name.clone(),
-                                upstream_pos: alignment.get_boundary_nt(),
-                                downstream_pos: std::u64::MAX / 2,
-                                orientation,
                                 upstream_reads: vec![alignment.get_ranges()],
-                                downstream_reads: Vec::new(),
+                                ..NonRefTE::new(
+                                    alignment.te_name.clone(),
+                                    chrom_name.clone(),
+                                    alignment.get_boundary_nt(),
+                                    std::u64::MAX / 2,
+                                    orientation,
+                                )
                             }),
                             // if there are TE's in the vector, match against the previous ones
                             Some(insertion) => {
@@ -361,26 +583,28 @@ impl GenomeAlignment {
                                     // we are in a new insertion
                                     else {
                                         tes.push(NonRefTE {
-                                            name: alignment.te_name.clone(),
-                                            chrom: chrom_name.clone(),
-                                            upstream_pos: position,
-                                            downstream_pos: std::u64::MAX / 2,
-                                            orientation,
                                             upstream_reads: vec![alignment.get_ranges()],
-                                            downstream_reads: Vec::new(),
+                                            ..NonRefTE::new(
+                                                alignment.te_name.clone(),
+                                                chrom_name.clone(),
+                                                position,
+                                                std::u64::MAX / 2,
+                                                orientation,
+                                            )
                                         });
                                     }
                                 }
                                 // we are in a new insertion
                                 else {
                                     tes.push(NonRefTE {
-                                        name: alignment.te_name.clone(),
-                                        chrom: chrom_name.clone(),
-                                        upstream_pos: position,
-                                        downstream_pos: std::u64::MAX / 2,
-                                        orientation,
                                         upstream_reads: vec![alignment.get_ranges()],
-                                        downstream_reads: Vec::new(),
+                                        ..NonRefTE::new(
+                                            alignment.te_name.clone(),
+                                            chrom_name.clone(),
+                                            position,
+                                            std::u64::MAX / 2,
+                                            orientation,
+                                        )
                                     });
                                 }
                             }
@@ -396,13 +620,14 @@ impl GenomeAlignment {
                         match tes.last_mut() {
                             // no TE's in the vector yet
                             None => tes.push(NonRefTE {
-                                name: alignment.te_name.clone(),
-                                chrom: chrom_name.clone(),
-                                upstream_pos: std::u64::MAX / 2,
-                                downstream_pos: alignment.get_boundary_nt(),
-                                orientation,
-                                upstream_reads: Vec::new(),
                                 downstream_reads: vec![alignment.get_ranges()],
+                                ..NonRefTE::new(
+                                    alignment.te_name.clone(),
+                                    chrom_name.clone(),
+                                    std::u64::MAX / 2,
+                                    alignment.get_boundary_nt(),
+                                    orientation,
+                                )
                             }),
                             // if there are TE's in the vector, match against the previous ones
                             Some(insertion) => {
@@ -415,26 +640,28 @@ impl GenomeAlignment {
                                     // we are in a new insertion
                                     else {
                                         tes.push(NonRefTE {
-                                            name: alignment.te_name.clone(),
-                                            chrom: chrom_name.clone(),
-                                            upstream_pos: std::u64::MAX / 2,
-                                            downstream_pos: position,
-                                            orientation,
-                                            upstream_reads: Vec::new(),
                                             downstream_reads: vec![alignment.get_ranges()],
+                                            ..NonRefTE::new(
+                                                alignment.te_name.clone(),
+                                                chrom_name.clone(),
+                                                std::u64::MAX / 2,
+                                                position,
+                                                orientation,
+                                            )
                                         });
                                     }
                                 }
                                 // we are in a new insertion
                                 else {
                                     tes.push(NonRefTE {
-                                        name: alignment.te_name.clone(),
-                                        chrom: chrom_name.clone(),
-                                        upstream_pos: std::u64::MAX / 2,
-                                        downstream_pos: position,
-                                        orientation,
-                                        upstream_reads: Vec::new(),
                                         downstream_reads: vec![alignment.get_ranges()],
+                                        ..NonRefTE::new(
+                                            alignment.te_name.clone(),
+                                            chrom_name.clone(),
+                                            std::u64::MAX / 2,
+                                            position,
+                                            orientation,
+                                        )
                                     });
                                 }
                             }
@@ -444,20 +671,29 @@ impl GenomeAlignment {
             }
         }
         // if the insertion does not have reads on both ends, discard it
+        // (unless one-sided insertions are reported, in which case the missing end is set to the other end)
+        // and discard it if its ends are reversed
         // (can't use iterators because of borrowing)
         let mut filtered_tes: Vec<NonRefTE> = Vec::new();
-        for insertion in tes {
-            if insertion.upstream_reads.len() > 0 && insertion.downstream_reads.len() > 0 {
+        for mut insertion in tes {
+            if insertion.upstream_reads.len() == 0 || insertion.downstream_reads.len() == 0 {
+                if !report_one_sided {
+                    continue;
+                }
+                if insertion.upstream_reads.len() == 0 {
+                    insertion.upstream_pos = insertion.downstream_pos;
+                } else {
+                    insertion.downstream_pos = insertion.upstream_pos;
+                }
+            }
+            let num_reads = insertion.upstream_reads.len() + insertion.downstream_reads.len();
+            if positions_ordered_or_flagged(insertion.positions_ordered(), num_reads, diagnostics) {
                 filtered_tes.push(insertion);
             }
         }
         // finally, sort by location instead of TE name
-        filtered_tes.sort_by(|first, second| {
-            first
-                .upstream_pos
-                .partial_cmp(&second.upstream_pos)
-                .unwrap()
-        });
+        // (with a full tie-break key, so that insertions at the same location always come out in the same order)
+        filtered_tes.sort_by(|first, second| first.sort_key().cmp(&second.sort_key()));
         return filtered_tes;
     }
 
@@ -465,24 +701,57 @@ impl GenomeAlignment {
     // the heap will be consumed in this function
     // this function should be run once per chromosome
     // this function allows for insertions and deletions within the reference transposons
+    #[allow(clippy::too_many_arguments)]
     pub fn get_ref_tes(
         alignments: &mut BinaryHeap<GenomeAlignment>,
         min_te_length: f64,
         max_te_length: f64,
+        family_params: &FamilyParams,
         all_te_lengths: &HashMap<String, u64>,
         chrom_name: &String,
+        chrom_lengths: &HashMap<String, u64>,
+        report_one_sided: bool,
+        min_genome_overlap: u64,
+        diagnostics: &Diagnostics,
     ) -> Vec<RefTE> {
+        let chrom_length = chrom_lengths.get(chrom_name);
         let alignment_vector = GenomeAlignment::make_3d_vector(alignments);
         let mut tes: Vec<RefTE> = Vec::new();
         // each TE will have a few split-reads upstream of it,
         // and then after that will be the downstream reads
         for same_transposon_name in alignment_vector {
-            let cur_te_length = *all_te_lengths
-                .get(&same_transposon_name[0][0].te_name)
-                .unwrap() as f64;
+            // a transposon (or a family of --family-map) without a length has no window of reference lengths,
+            // so its split-reads are skipped instead of calling insertions of any length
+            let cur_te_length = match all_te_lengths.get(&same_transposon_name[0][0].te_name) {
+                Some(&te_length) if te_length > 0 => te_length as f64,
+                _ => {
+                    let skipped: usize = same_transposon_name.iter().map(Vec::len).sum();
+                    diagnostics.record_many(
+                        Stage::InsertionCalling,
+                        "transposon without a length",
+                        skipped as u64,
+                    );
+                    continue;
+                }
+            };
+            let (min_te_length, max_te_length) = family_params.te_length_window(
+                &same_transposon_name[0][0].te_name,
+                min_te_length,
+                max_te_length,
+            );
             for same_position in same_transposon_name {
                 for alignment in same_position {
                     let position = alignment.get_boundary_nt();
+                    // reads with a boundary past the end of the chromosome are skipped
+                    // instead of reporting an out-of-range coordinate
+                    if !alignment.boundary_within_chrom(chrom_length) {
+                        diagnostics.record(Stage::InsertionCalling, "past the chromosome end");
+                        continue;
+                    }
+                    if alignment.split_read_genome.genome_anchor() < min_genome_overlap {
+                        diagnostics.record(Stage::InsertionCalling, GENOME_ANCHOR_TOO_SHORT);
+                        continue;
+                    }
                     let orientation = if alignment.new_plus {
                         Orientation::PlusPlus
                     } else {
@@ -493,13 +762,14 @@ impl GenomeAlignment {
                         match tes.last_mut() {
                             // no TE's in the vector yet
                             None => tes.push(RefTE {
-                                name: alignment.te_name.clone(),
-                                chrom: chrom_name.clone(),
-                                upstream_pos: alignment.get_boundary_nt(),
-                                downstream_pos: std::u64::MAX / 2,
-                                orientation,
                                 upstream_reads: vec![alignment.get_ranges()],
-                                downstream_reads: Vec::new(),
+                                ..RefTE::new(
+                                    alignment.te_name.clone(),
+                                    chrom_name.clone(),
+                                    alignment.get_boundary_nt(),
+                                    std::u64::MAX / 2,
+                                    orientation,
+                                )
                             }),
                             // if there are TE's in the vector, match against the previous ones
                             Some(insertion) => {
@@ -512,26 +782,28 @@ impl GenomeAlignment {
                                     // we are in a new insertion
                                     else {
                                         tes.push(RefTE {
-                                            name: alignment.te_name.clone(),
-                                            chrom: chrom_name.clone(),
-                                            upstream_pos: position,
-                                            downstream_pos: std::u64::MAX / 2,
-                                            orientation,
                                             upstream_reads: vec![alignment.get_ranges()],
-                                            downstream_reads: Vec::new(),
+                                            ..RefTE::new(
+                                                alignment.te_name.clone(),
+                                                chrom_name.clone(),
+                                                position,
+                                                std::u64::MAX / 2,
+                                                orientation,
+                                            )
                                         });
                                     }
                                 }
                                 // we are in a new insertion
                                 else {
                                     tes.push(RefTE {
-                                        name: alignment.te_name.clone(),
-                                        chrom: chrom_name.clone(),
-                                        upstream_pos: position,
-                                        downstream_pos: std::u64::MAX / 2,
-                                        orientation,
                                         upstream_reads: vec![alignment.get_ranges()],
-                                        downstream_reads: Vec::new(),
+                                        ..RefTE::new(
+                                            alignment.te_name.clone(),
+                                            chrom_name.clone(),
+                                            position,
+                                            std::u64::MAX / 2,
+                                            orientation,
+                                        )
                                     });
                                 }
                             }
@@ -542,13 +814,14 @@ impl GenomeAlignment {
                         match tes.last_mut() {
                             // no TE's in the vector yet
                             None => tes.push(RefTE {
-                                name: alignment.te_name.clone(),
-                                chrom: chrom_name.clone(),
-                                upstream_pos: std::u64::MAX / 2,
-                                downstream_pos: alignment.get_boundary_nt(),
-                                orientation,
-                                upstream_reads: Vec::new(),
                                 downstream_reads: vec![alignment.get_ranges()],
+                                ..RefTE::new(
+                                    alignment.te_name.clone(),
+                                    chrom_name.clone(),
+                                    std::u64::MAX / 2,
+                                    alignment.get_boundary_nt(),
+                                    orientation,
+                                )
                             }),
                             // if there are TE's in the vector, match against the previous ones
                             Some(insertion) => {
@@ -571,26 +844,28 @@ impl GenomeAlignment {
                                     // we are in a new insertion
                                     else {
                                         tes.push(RefTE {
-                                            name: alignment.te_name.clone(),
-                                            chrom: chrom_name.clone(),
-                                            upstream_pos: std::u64::MAX / 2,
-                                            downstream_pos: position,
-                                            orientation,
-                                            upstream_reads: Vec::new(),
                                             downstream_reads: vec![alignment.get_ranges()],
+                                            ..RefTE::new(
+                                                alignment.te_name.clone(),
+                                                chrom_name.clone(),
+                                                std::u64::MAX / 2,
+                                                position,
+                                                orientation,
+                                            )
                                         });
                                     }
                                 }
                                 // we are in a new insertion
                                 else {
                                     tes.push(RefTE {
-                                        name: alignment.te_name.clone(),
-                                        chrom: chrom_name.clone(),
-                                        upstream_pos: std::u64::MAX / 2,
-                                        downstream_pos: position,
-                                        orientation,
-                                        upstream_reads: Vec::new(),
                                         downstream_reads: vec![alignment.get_ranges()],
+                                        ..RefTE::new(
+                                            alignment.te_name.clone(),
+                                            chrom_name.clone(),
+                                            std::u64::MAX / 2,
+                                            position,
+                                            orientation,
+                                        )
                                     });
                                 }
                             }
@@ -600,20 +875,516 @@ impl GenomeAlignment {
             }
         }
         // if the insertion does not have reads on both ends, discard it
+        // (unless one-sided insertions are reported, in which case the missing end is set to the other end)
+        // and discard it if its ends are reversed
         // (can't use iterators because of borrowing)
         let mut filtered_tes: Vec<RefTE> = Vec::new();
-        for insertion in tes {
-            if insertion.upstream_reads.len() > 0 && insertion.downstream_reads.len() > 0 {
+        for mut insertion in tes {
+            if insertion.upstream_reads.len() == 0 || insertion.downstream_reads.len() == 0 {
+                if !report_one_sided {
+                    continue;
+                }
+                if insertion.upstream_reads.len() == 0 {
+                    insertion.upstream_pos = insertion.downstream_pos;
+                } else {
+                    insertion.downstream_pos = insertion.upstream_pos;
+                }
+            }
+            let num_reads = insertion.upstream_reads.len() + insertion.downstream_reads.len();
+            if positions_ordered_or_flagged(insertion.positions_ordered(), num_reads, diagnostics) {
                 filtered_tes.push(insertion);
             }
         }
         // finally, sort by location instead of TE name
-        filtered_tes.sort_by(|first, second| {
-            first
-                .upstream_pos
-                .partial_cmp(&second.upstream_pos)
-                .unwrap()
-        });
+        // (with a full tie-break key, so that insertions at the same location always come out in the same order)
+        filtered_tes.sort_by(|first, second| first.sort_key().cmp(&second.sort_key()));
         return filtered_tes;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::family_map::FamilyMap;
+    use super::super::split_read::{MAlignment, MSAlignment, SMAlignment};
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn make_genome_alignment(
+        is_sm_te: bool,
+        new_plus: bool,
+        split_read_genome: SplitReadGenome,
+    ) -> GenomeAlignment {
+        GenomeAlignment {
+            read_name: "a1|roo|50|100|SM|start".to_owned(),
+            te_name: "roo".to_owned(),
+            old_m: 0,
+            old_s: 0,
+            is_sm_te,
+            is_start: is_sm_te,
+            new_plus,
+            chrom: "2L".to_owned(),
+            split_read_genome,
+        }
+    }
+
+    // a non-reference insertion (TSD from 995 to the last M of the MS read)
+    fn make_non_ref_heap(ms_pos: u64) -> BinaryHeap<GenomeAlignment> {
+        let mut heap = BinaryHeap::new();
+        heap.push(make_genome_alignment(
+            false,
+            true,
+            SplitReadGenome::SM(SMAlignment {
+                s: 10,
+                m: 10,
+                pos: 995,
+            }),
+        ));
+        heap.push(make_genome_alignment(
+            true,
+            true,
+            SplitReadGenome::MS(MSAlignment {
+                m: 10,
+                s: 10,
+                pos: ms_pos,
+            }),
+        ));
+        return heap;
+    }
+
+    #[test]
+    fn test_malformed_cigar() {
+        match SplitReadGenome::parse("10M5I5M".to_owned(), 0, 0, true, true, 100, 0) {
+            Err(StanexError::MalformedCigar { cigar, .. }) => assert_eq!(cigar, "10M5I5M"),
+            other => panic!("expected a malformed CIGAR error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_min_clip() {
+        // soft-clips and hard-clips are both checked
+        for cigar in &["2S148M", "148M2S", "2H148M", "148M2H"] {
+            match SplitReadGenome::parse(cigar.to_string(), 50, 100, true, true, 100, 5) {
+                Err(StanexError::ClipTooShort { min_clip, .. }) => assert_eq!(min_clip, 5),
+                other => panic!("expected a clip that is too short, got {:?}", other),
+            }
+        }
+        for cigar in &["10S140M", "140M10S", "10H140M", "140M10H"] {
+            assert!(SplitReadGenome::parse(cigar.to_string(), 50, 100, true, true, 100, 5).is_ok());
+        }
+        // full matches (reference insertions) are not clipped on the genome side
+        assert!(SplitReadGenome::parse("150M".to_owned(), 50, 100, true, true, 100, 5).is_ok());
+    }
+
+    #[test]
+    fn test_ms_boundary_within_chrom() {
+        let chrom_lengths: HashMap<String, u64> =
+            vec![("2L".to_owned(), 1000)].into_iter().collect();
+        // the last M is 999, which is on the chromosome
+        let tes = GenomeAlignment::get_non_ref_tes(
+            &mut make_non_ref_heap(990),
+            0,
+            100,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &chrom_lengths,
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 1);
+        assert_eq!(tes[0].downstream_pos, 995);
+        assert_eq!(tes[0].upstream_pos, 999);
+        // the last M is exactly the last nucleotide of the chromosome
+        let tes = GenomeAlignment::get_non_ref_tes(
+            &mut make_non_ref_heap(991),
+            0,
+            100,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &chrom_lengths,
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 1);
+        assert_eq!(tes[0].upstream_pos, 1000);
+    }
+
+    #[test]
+    fn test_ms_boundary_past_chrom_end() {
+        let chrom_lengths: HashMap<String, u64> =
+            vec![("2L".to_owned(), 1000)].into_iter().collect();
+        // the last M would be 1004, past the end of the chromosome,
+        // so the MS read is skipped and the insertion is left without upstream reads
+        let tes = GenomeAlignment::get_non_ref_tes(
+            &mut make_non_ref_heap(995),
+            0,
+            100,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &chrom_lengths,
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 0);
+        // without a known chromosome length, the read is not checked
+        let tes = GenomeAlignment::get_non_ref_tes(
+            &mut make_non_ref_heap(995),
+            0,
+            100,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &HashMap::new(),
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 1);
+        assert_eq!(tes[0].upstream_pos, 1004);
+    }
+
+    #[test]
+    fn test_family_tsd_windows() {
+        let chrom_lengths: HashMap<String, u64> =
+            vec![("2L".to_owned(), 10000)].into_iter().collect();
+        // a roo insertion with a 4 bp TSD and a P-element insertion with an 8 bp TSD
+        let make_heap = || {
+            let mut heap = make_non_ref_heap(990);
+            for mut alignment in make_non_ref_heap(995 + 8 - 9) {
+                alignment.te_name = "P-element".to_owned();
+                alignment.split_read_genome = match alignment.split_read_genome {
+                    SplitReadGenome::SM(sm) => SplitReadGenome::SM(SMAlignment {
+                        pos: sm.pos + 4000,
+                        ..sm
+                    }),
+                    SplitReadGenome::MS(ms) => SplitReadGenome::MS(MSAlignment {
+                        pos: ms.pos + 4000,
+                        ..ms
+                    }),
+                    other => other,
+                };
+                heap.push(alignment);
+            }
+            heap
+        };
+        let call = |family_params: &FamilyParams| {
+            GenomeAlignment::get_non_ref_tes(
+                &mut make_heap(),
+                0,
+                100,
+                family_params,
+                &"2L".to_owned(),
+                &chrom_lengths,
+                false,
+                0,
+                &Diagnostics::default(),
+            )
+            .iter()
+            .map(|te| (te.name.clone(), te.upstream_pos - te.downstream_pos))
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            call(&FamilyParams::default()),
+            vec![("roo".to_owned(), 4), ("P-element".to_owned(), 8)]
+        );
+        // the roo TSD is too long for its window, and the P-element one is within its own
+        let family_params = FamilyParams::parse(std::io::Cursor::new(
+            "roo\t0\t2\t.\t.\nP-element\t7\t9\t.\t.\n",
+        ))
+        .unwrap();
+        assert_eq!(call(&family_params), vec![("P-element".to_owned(), 8)]);
+        // the P-element TSD is too short for its window
+        let family_params =
+            FamilyParams::parse(std::io::Cursor::new("P-element\t10\t12\t.\t.\n")).unwrap();
+        assert_eq!(call(&family_params), vec![("roo".to_owned(), 4)]);
+    }
+
+    #[test]
+    fn test_report_one_sided() {
+        let chrom_lengths: HashMap<String, u64> =
+            vec![("2L".to_owned(), 1000)].into_iter().collect();
+        // the MS read is past the end of the chromosome, which leaves only the downstream read
+        let tes = GenomeAlignment::get_non_ref_tes(
+            &mut make_non_ref_heap(995),
+            0,
+            100,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &chrom_lengths,
+            true,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 1);
+        assert_eq!(tes[0].upstream_reads.len(), 0);
+        assert_eq!(tes[0].downstream_reads.len(), 1);
+        // the missing end is set to the end that has reads
+        assert_eq!(tes[0].downstream_pos, 995);
+        assert_eq!(tes[0].upstream_pos, 995);
+    }
+
+    #[test]
+    fn test_deterministic_order() {
+        // 2 insertions of different transposons (in different orientations) at the same location
+        let mut heap = make_non_ref_heap(990);
+        for mut alignment in make_non_ref_heap(990).into_vec() {
+            alignment.te_name = "blood".to_owned();
+            alignment.is_sm_te = !alignment.is_sm_te;
+            alignment.is_start = alignment.is_sm_te;
+            heap.push(alignment);
+        }
+        let tes = GenomeAlignment::get_non_ref_tes(
+            &mut heap,
+            0,
+            100,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &HashMap::new(),
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        let names: Vec<&str> = tes.iter().map(|insertion| &insertion.name[..]).collect();
+        assert_eq!(names, vec!["blood", "roo"]);
+        assert_eq!(tes[0].sort_key().0, tes[1].sort_key().0);
+        assert_eq!(tes[0].orientation, Orientation::PlusMinus);
+    }
+
+    #[test]
+    fn test_m_boundary_past_chrom_end() {
+        let chrom_lengths: HashMap<String, u64> =
+            vec![("2L".to_owned(), 1000)].into_iter().collect();
+        let te_lengths: HashMap<String, u64> = vec![("roo".to_owned(), 100)].into_iter().collect();
+        let make_ref_heap = |downstream_pos: u64| {
+            let mut heap = BinaryHeap::new();
+            // upstream end: start of the TE, +/+ (boundary = 850 + 50)
+            heap.push(make_genome_alignment(
+                true,
+                true,
+                SplitReadGenome::M(MAlignment {
+                    old_s: 50,
+                    old_m: 100,
+                    is_start: true,
+                    new_plus: true,
+                    new_pos: 850,
+                }),
+            ));
+            // downstream end: end of the TE, +/+ (boundary = downstream_pos + 20 - 1)
+            heap.push(make_genome_alignment(
+                false,
+                true,
+                SplitReadGenome::M(MAlignment {
+                    old_s: 130,
+                    old_m: 20,
+                    is_start: false,
+                    new_plus: true,
+                    new_pos: downstream_pos,
+                }),
+            ));
+            heap
+        };
+        let tes = GenomeAlignment::get_ref_tes(
+            &mut make_ref_heap(970),
+            0.1,
+            1.5,
+            &FamilyParams::default(),
+            &te_lengths,
+            &"2L".to_owned(),
+            &chrom_lengths,
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 1);
+        assert_eq!(tes[0].upstream_pos, 900);
+        assert_eq!(tes[0].downstream_pos, 989);
+        // the boundary would be 1009
+        let tes = GenomeAlignment::get_ref_tes(
+            &mut make_ref_heap(990),
+            0.1,
+            1.5,
+            &FamilyParams::default(),
+            &te_lengths,
+            &"2L".to_owned(),
+            &chrom_lengths,
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 0);
+    }
+
+    #[test]
+    fn test_family_length() {
+        // a family of a short and a long sub-family
+        let family_map =
+            FamilyMap::parse(Cursor::new("roo_fragment\troo\nroo_full\troo\n")).unwrap();
+        let transposon_lengths: HashMap<String, u64> = vec![
+            ("roo_fragment".to_owned(), 10),
+            ("roo_full".to_owned(), 100),
+        ]
+        .into_iter()
+        .collect();
+        let te_lengths = family_map.family_lengths(&transposon_lengths);
+        let make_ref_heap = || {
+            let mut heap = BinaryHeap::new();
+            // a 90 bp reference insertion (900 - 989), see test_m_boundary_past_chrom_end
+            heap.push(make_genome_alignment(
+                true,
+                true,
+                SplitReadGenome::M(MAlignment {
+                    old_s: 50,
+                    old_m: 100,
+                    is_start: true,
+                    new_plus: true,
+                    new_pos: 850,
+                }),
+            ));
+            heap.push(make_genome_alignment(
+                false,
+                true,
+                SplitReadGenome::M(MAlignment {
+                    old_s: 130,
+                    old_m: 20,
+                    is_start: false,
+                    new_plus: true,
+                    new_pos: 970,
+                }),
+            ));
+            heap
+        };
+        let get_ref_tes = |te_lengths: &HashMap<String, u64>| {
+            GenomeAlignment::get_ref_tes(
+                &mut make_ref_heap(),
+                0.1,
+                1.5,
+                &FamilyParams::default(),
+                te_lengths,
+                &"2L".to_owned(),
+                &HashMap::new(),
+                false,
+                0,
+                &Diagnostics::default(),
+            )
+        };
+        // a full-length copy of the long sub-family is within the window of the family (the longest sub-family),
+        // but not within the window of the mean length (55 bp)
+        assert_eq!(te_lengths["roo"], 100);
+        assert_eq!(get_ref_tes(&te_lengths).len(), 1);
+        let mean_length: HashMap<String, u64> = vec![("roo".to_owned(), 55)].into_iter().collect();
+        assert_eq!(get_ref_tes(&mean_length).len(), 0);
+        // a family without a length has no reference insertions
+        assert_eq!(get_ref_tes(&transposon_lengths).len(), 0);
+        let empty_family: HashMap<String, u64> = vec![("roo".to_owned(), 0)].into_iter().collect();
+        assert_eq!(get_ref_tes(&empty_family).len(), 0);
+    }
+
+    #[test]
+    fn test_min_genome_overlap() {
+        // a 5M anchor downstream (145S5M) and a 30M anchor upstream (30M120S) of the same insertion
+        let make_heap = || {
+            let mut heap = BinaryHeap::new();
+            heap.push(make_genome_alignment(
+                false,
+                true,
+                SplitReadGenome::SM(SMAlignment {
+                    s: 145,
+                    m: 5,
+                    pos: 995,
+                }),
+            ));
+            heap.push(make_genome_alignment(
+                true,
+                true,
+                SplitReadGenome::MS(MSAlignment {
+                    m: 30,
+                    s: 120,
+                    pos: 970,
+                }),
+            ));
+            heap
+        };
+        let call = |min_genome_overlap: u64, report_one_sided: bool, diagnostics: &Diagnostics| {
+            GenomeAlignment::get_non_ref_tes(
+                &mut make_heap(),
+                0,
+                100,
+                &FamilyParams::default(),
+                &"2L".to_owned(),
+                &HashMap::new(),
+                report_one_sided,
+                min_genome_overlap,
+                diagnostics,
+            )
+        };
+        let tes = call(0, false, &Diagnostics::default());
+        assert_eq!(tes.len(), 1);
+        assert_eq!(tes[0].downstream_reads.len(), 1);
+        // the 5M anchor is rejected at 20, which leaves the insertion without downstream reads
+        let diagnostics = Diagnostics::default();
+        assert_eq!(call(20, false, &diagnostics).len(), 0);
+        assert_eq!(
+            diagnostics.summary().unwrap(),
+            "split-reads: 1 skipped: genome anchor shorter than --min-genome-overlap"
+        );
+        let tes = call(20, true, &Diagnostics::default());
+        assert_eq!(tes.len(), 1);
+        assert_eq!(tes[0].upstream_reads.len(), 1);
+        assert_eq!(tes[0].downstream_reads.len(), 0);
+        // the 30M anchor is rejected too at 31
+        assert_eq!(call(31, true, &Diagnostics::default()).len(), 0);
+    }
+
+    #[test]
+    fn test_reversed_positions_flagged() {
+        let non_ref = |upstream_pos: u64, downstream_pos: u64| {
+            NonRefTE::new(
+                "roo".to_owned(),
+                "2L".to_owned(),
+                upstream_pos,
+                downstream_pos,
+                Orientation::PlusPlus,
+            )
+        };
+        let reference = |upstream_pos: u64, downstream_pos: u64| {
+            RefTE::new(
+                "roo".to_owned(),
+                "2L".to_owned(),
+                upstream_pos,
+                downstream_pos,
+                Orientation::PlusPlus,
+            )
+        };
+        // the TSD puts the upstream end of a non-reference insertion after its downstream end,
+        // and the upstream end of a reference insertion before its downstream end
+        assert!(non_ref(1005, 1000).positions_ordered());
+        assert!(non_ref(1000, 1000).positions_ordered());
+        assert!(!non_ref(1000, 1005).positions_ordered());
+        assert!(reference(1000, 5000).positions_ordered());
+        assert!(!reference(5000, 1000).positions_ordered());
+
+        let diagnostics = Diagnostics::default();
+        assert!(positions_ordered_or_flagged(
+            non_ref(1005, 1000).positions_ordered(),
+            3,
+            &diagnostics
+        ));
+        assert_eq!(diagnostics.summary(), None);
+        assert!(!positions_ordered_or_flagged(
+            non_ref(1000, 1005).positions_ordered(),
+            3,
+            &diagnostics
+        ));
+        assert!(!positions_ordered_or_flagged(
+            reference(5000, 1000).positions_ordered(),
+            2,
+            &diagnostics
+        ));
+        assert_eq!(
+            diagnostics.summary().unwrap(),
+            "split-reads: 5 skipped: insertion with reversed ends"
+        );
+    }
+}
@@ -0,0 +1,318 @@
+// the genome-first order of phase 1 (see --genome-first): the reads are aligned to the genome,
+// and only the soft-clipped parts of the reads that are clipped on one side are aligned to the transposons
+// the TE alignment of each clip is then turned back into the TE alignment of its whole read, as if
+// the read had been aligned to the transposons (the split-read parsing in reverse), so that phases 2-4 are unchanged
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use super::indexed_fasta::reverse_complement;
+use crate::errors::{Result, StanexError};
+use crate::regexes;
+use crate::utils;
+use crate::utils::{log_error, progress, Reads, IO_BUFFER_CAPACITY};
+
+// a read whose primary genome alignment is soft-clipped on one side (SM or MS)
+#[derive(Debug, PartialEq)]
+pub struct ClippedRead {
+    pub qname: String,
+    pub seq: String,  // the sequence of the read, on the plus strand of the genome
+    pub qual: String, // the base qualities of the read ("*" if the aligner didn't keep them)
+    pub clip_length: usize,
+    pub clip_on_left: bool, // is it an SM alignment (true) or an MS alignment (false)?
+}
+
+impl ClippedRead {
+    // the clipped read of a genome alignment, if it is a mapped primary alignment with a clip of at least min_clip
+    // (the clip is the part of a split-read that would be in the transposon)
+    pub fn parse(alignment: &str, min_clip: u64) -> Option<ClippedRead> {
+        let fields: Vec<&str> = alignment.split('\t').collect();
+        if fields.len() < 11 {
+            return None;
+        }
+        let flag: u16 = fields[1].parse().ok()?;
+        if flag & 4 != 0 || flag & 0x900 != 0 {
+            return None;
+        }
+        let (clip_length, clip_on_left) =
+            if let Some((s, _)) = regexes::get_cigar_lengths(&regexes::SM_REGEX, fields[5]) {
+                (s, true)
+            } else if let Some((_, s)) = regexes::get_cigar_lengths(&regexes::MS_REGEX, fields[5]) {
+                (s, false)
+            } else {
+                return None;
+            };
+        if clip_length < min_clip.max(1) || clip_length as usize >= fields[9].len() {
+            return None;
+        }
+        return Some(ClippedRead {
+            qname: fields[0].to_owned(),
+            seq: fields[9].to_owned(),
+            qual: fields[10].to_owned(),
+            clip_length: clip_length as usize,
+            clip_on_left,
+        });
+    }
+
+    // the clipped part of the read, on the plus strand of the genome
+    pub fn clip(&self) -> &str {
+        if self.clip_on_left {
+            &self.seq[..self.clip_length]
+        } else {
+            &self.seq[self.seq.len() - self.clip_length..]
+        }
+    }
+
+    // the TE alignment of the whole read (a SAM record without the final newline) from the TE alignment
+    // of its clip, or None if the clip isn't a perfect match from end to end
+    // the read is reverse-complemented if its clip aligns to the minus strand of the transposon,
+    // and the part of the read in the genome becomes the soft-clip
+    fn te_alignment(&self, clip_fields: &[&str]) -> Option<String> {
+        let clip_flag: u16 = clip_fields[1].parse().ok()?;
+        let cigar = clip_fields[5];
+        if !regexes::M_REGEX.is_match(cigar)
+            || cigar.trim_end_matches('M').parse::<usize>().ok()? != self.clip_length
+        {
+            return None;
+        }
+        let reverse = clip_flag & 16 != 0;
+        let (seq, qual) = if reverse {
+            let qual = if self.qual == "*" {
+                self.qual.clone()
+            } else {
+                self.qual.chars().rev().collect()
+            };
+            (reverse_complement(&self.seq), qual)
+        } else {
+            (self.seq.clone(), self.qual.clone())
+        };
+        let genome_length = self.seq.len() - self.clip_length;
+        // the clip comes first in the read, as it aligns to the transposon
+        let cigar = if self.clip_on_left != reverse {
+            format!("{}M{}S", self.clip_length, genome_length)
+        } else {
+            format!("{}S{}M", genome_length, self.clip_length)
+        };
+        return Some(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t{}\t{}",
+            self.qname,
+            clip_flag & 16,
+            clip_fields[2],
+            clip_fields[3],
+            clip_fields[4],
+            cigar,
+            seq,
+            qual
+        ));
+    }
+}
+
+// write the clips of the genome alignments as a FASTA file
+// (each clip is named after the index of its read in the returned vector)
+pub fn extract_clips<R: BufRead, W: Write>(
+    genome_aligned: &mut R,
+    clips: &mut W,
+    min_clip: u64,
+) -> Result<Vec<ClippedRead>> {
+    let mut clipped_reads: Vec<ClippedRead> = Vec::new();
+    while let Some(line) = utils::read_line_trimmed(genome_aligned)? {
+        if line.starts_with('@') {
+            continue;
+        }
+        if let Some(clipped_read) = ClippedRead::parse(&line, min_clip) {
+            writeln!(clips, ">{}\n{}", clipped_reads.len(), clipped_read.clip())?;
+            clipped_reads.push(clipped_read);
+        }
+    }
+    return Ok(clipped_reads);
+}
+
+// write the TE alignment of the reads from the alignment of their clips
+// the header (with the transposons as "@SQ" lines) is kept, and the clips that are unmapped,
+// not primary or not perfect matches are dropped
+// returns the number of alignments that are written
+pub fn write_te_alignment<R: BufRead, W: Write>(
+    clips_aligned: &mut R,
+    clipped_reads: &[ClippedRead],
+    te_aligned: &mut W,
+) -> Result<u64> {
+    let mut written = 0;
+    while let Some(line) = utils::read_line_trimmed(clips_aligned)? {
+        if line.starts_with('@') {
+            writeln!(te_aligned, "{}", line)?;
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 11 {
+            return Err(StanexError::MissingField(line));
+        }
+        let clipped_read = fields[0]
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| clipped_reads.get(index))
+            .ok_or_else(|| StanexError::InvalidValue {
+                field: "QNAME".to_owned(),
+                value: fields[0].to_owned(),
+            })?;
+        let flag: u16 = fields[1].parse().map_err(|_| StanexError::InvalidValue {
+            field: "FLAG".to_owned(),
+            value: fields[1].to_owned(),
+        })?;
+        if flag & 4 != 0 || flag & 0x900 != 0 {
+            continue;
+        }
+        if let Some(alignment) = clipped_read.te_alignment(&fields) {
+            writeln!(te_aligned, "{}", alignment)?;
+            written += 1;
+        }
+    }
+    return Ok(written);
+}
+
+// phase 1 in the genome-first order: genome_first.sam and genome_clips.fasta are written to result_dir
+// and the TE alignment of the clips to te_clips.sam, before the TE alignment of their reads is written to te_aligned_name
+#[allow(clippy::too_many_arguments)]
+pub fn align_genome_first(
+    ref_name: &str,
+    reads: &Reads,
+    transposons_name: &str,
+    te_aligned_name: &str,
+    result_dir: &str,
+    min_clip: u64,
+    bwa_threads: u16,
+    bwa_options: &[String],
+) {
+    let genome_first_name = format!("{}/genome_first.sam", result_dir);
+    let clips_name = format!("{}/genome_clips.fasta", result_dir);
+    let te_clips_name = format!("{}/te_clips.sam", result_dir);
+    utils::bwa_mem_align(
+        ref_name,
+        reads,
+        utils::BwaOutput::File(&genome_first_name[..]),
+        bwa_threads,
+        bwa_options,
+    );
+
+    let clipped_reads = File::open(&genome_first_name)
+        .and_then(|genome_first| File::create(&clips_name).map(|clips| (genome_first, clips)))
+        .map_err(StanexError::from)
+        .and_then(|(genome_first, clips)| {
+            let mut clips = BufWriter::with_capacity(IO_BUFFER_CAPACITY, clips);
+            let clipped_reads = extract_clips(
+                &mut BufReader::with_capacity(IO_BUFFER_CAPACITY, genome_first),
+                &mut clips,
+                min_clip,
+            )?;
+            clips.flush()?;
+            Ok(clipped_reads)
+        });
+    let clipped_reads = match clipped_reads {
+        Ok(clipped_reads) => clipped_reads,
+        Err(e) => {
+            log_error!(
+                "Unable to extract the clips of {}: {}",
+                genome_first_name,
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    progress!(
+        "Soft-clipped reads of the genome alignment: {}",
+        clipped_reads.len()
+    );
+
+    utils::bwa_mem_align(
+        transposons_name,
+        &Reads::SingleEnd(clips_name),
+        utils::BwaOutput::File(&te_clips_name[..]),
+        bwa_threads,
+        bwa_options,
+    );
+
+    let written = File::open(&te_clips_name)
+        .and_then(|te_clips| File::create(te_aligned_name).map(|te_aligned| (te_clips, te_aligned)))
+        .map_err(StanexError::from)
+        .and_then(|(te_clips, te_aligned)| {
+            let mut te_aligned = BufWriter::with_capacity(IO_BUFFER_CAPACITY, te_aligned);
+            let written = write_te_alignment(
+                &mut BufReader::with_capacity(IO_BUFFER_CAPACITY, te_clips),
+                &clipped_reads,
+                &mut te_aligned,
+            )?;
+            te_aligned.flush()?;
+            Ok(written)
+        });
+    match written {
+        Ok(written) => progress!("Clips that align to the end of a transposon: {}", written),
+        Err(e) => {
+            log_error!(
+                "Unable to write the TE alignment {}: {}",
+                te_aligned_name,
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_clips, write_te_alignment, ClippedRead};
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_genome_first() {
+        let genome_aligned = "@SQ\tSN:2L\tLN:10000\n\
+                              r1\t0\t2L\t991\t60\t10M6S\t*\t0\t0\tAAAAAAAAAACCGGTT\tIIIIIIIIIIABCDEF\n\
+                              r2\t16\t2L\t1001\t60\t6S10M\t*\t0\t0\tCCGGTTAAAAAAAAAA\t*\n\
+                              r3\t0\t2L\t500\t60\t16M\t*\t0\t0\tAAAAAAAAAAAAAAAA\t*\n\
+                              r4\t2048\t2L\t500\t60\t10M6S\t*\t0\t0\tAAAAAAAAAACCGGTT\t*\n\
+                              r5\t0\t2L\t500\t60\t13M3S\t*\t0\t0\tAAAAAAAAAAAAACCG\t*\n";
+        let mut clips: Vec<u8> = Vec::new();
+        let clipped_reads = extract_clips(&mut Cursor::new(genome_aligned), &mut clips, 5).unwrap();
+        // neither the full match, the supplementary alignment nor the short clip is kept
+        assert_eq!(clipped_reads.len(), 2);
+        assert_eq!(
+            String::from_utf8(clips).unwrap(),
+            ">0\nCCGGTT\n>1\nCCGGTT\n"
+        );
+        assert_eq!(
+            ClippedRead::parse("r6\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*", 0),
+            None
+        );
+
+        // the clips of r1 (on the right, on the plus strand) and r2 (on the left, on the minus strand)
+        // both align to the start of the transposon, and a partial match is dropped
+        // (whether a split-read is at the right end of the transposon is checked in phase 2)
+        let clips_aligned = "@SQ\tSN:roo\tLN:100\n\
+                             0\t0\troo\t1\t60\t6M\t*\t0\t0\tCCGGTT\t*\n\
+                             1\t16\troo\t1\t60\t6M\t*\t0\t0\tAACCGG\t*\n\
+                             1\t256\troo\t40\t0\t6M\t*\t0\t0\tAACCGG\t*\n\
+                             0\t0\troo\t1\t60\t3S3M\t*\t0\t0\tCCGGTT\t*\n";
+        let mut te_aligned: Vec<u8> = Vec::new();
+        let written = write_te_alignment(
+            &mut Cursor::new(clips_aligned),
+            &clipped_reads,
+            &mut te_aligned,
+        )
+        .unwrap();
+        assert_eq!(written, 2);
+        assert_eq!(
+            String::from_utf8(te_aligned).unwrap(),
+            "@SQ\tSN:roo\tLN:100\n\
+             r1\t0\troo\t1\t60\t10S6M\t*\t0\t0\tAAAAAAAAAACCGGTT\tIIIIIIIIIIABCDEF\n\
+             r2\t16\troo\t1\t60\t10S6M\t*\t0\t0\tTTTTTTTTTTAACCGG\t*\n"
+        );
+
+        // a clip named after a read that isn't in the genome alignment
+        let clips_aligned = "7\t0\troo\t1\t60\t6M\t*\t0\t0\tCCGGTT\t*\n";
+        assert!(write_te_alignment(
+            &mut Cursor::new(clips_aligned),
+            &clipped_reads,
+            &mut Vec::new()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+
+use crate::errors::{Result, StanexError};
+
+// where a sequence is in the FASTA file (the columns of its line in the ".fai" index)
+struct FaiRecord {
+    length: u64,
+    offset: u64,
+    line_bases: u64,
+    line_width: u64,
+}
+
+// random access to the sequences of a FASTA file through its ".fai" index
+// (only the requested bytes are read, so this works on whole genomes)
+pub struct IndexedFasta {
+    reader: BufReader<File>,
+    records: HashMap<String, FaiRecord>,
+}
+
+impl IndexedFasta {
+    // contig_renames are applied to the names in the index (see --rename-contigs),
+    // so that the sequences can be fetched with the chromosome names of the insertions
+    pub fn open(
+        fasta_name: &str,
+        fai_name: &str,
+        contig_renames: &HashMap<String, String>,
+    ) -> Result<IndexedFasta> {
+        let mut records: HashMap<String, FaiRecord> = HashMap::new();
+        for line in BufReader::new(File::open(fai_name)?).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 5 {
+                continue;
+            }
+            let parse_field = |index: usize| -> Result<u64> {
+                fields[index]
+                    .parse()
+                    .map_err(|_| StanexError::InvalidValue {
+                        field: format!("{} column {}", fai_name, index + 1),
+                        value: fields[index].to_owned(),
+                    })
+            };
+            let name = contig_renames
+                .get(fields[0])
+                .cloned()
+                .unwrap_or_else(|| fields[0].to_owned());
+            records.insert(
+                name,
+                FaiRecord {
+                    length: parse_field(1)?,
+                    offset: parse_field(2)?,
+                    line_bases: parse_field(3)?,
+                    line_width: parse_field(4)?,
+                },
+            );
+        }
+        Ok(IndexedFasta {
+            reader: BufReader::new(File::open(fasta_name)?),
+            records: records,
+        })
+    }
+
+    // the sequence of a chromosome between start and end (one-based fully closed), in upper case
+    pub fn fetch(&mut self, chrom: &str, start: u64, end: u64) -> Result<String> {
+        let record = match self.records.get(chrom) {
+            Some(record) => record,
+            None => return Err(StanexError::InvalidChromosome(chrom.to_owned())),
+        };
+        if start == 0 || end > record.length {
+            return Err(StanexError::InvalidValue {
+                field: format!("{} coordinates", chrom),
+                value: format!("{}-{}", start, end),
+            });
+        }
+        if start > end {
+            return Ok(String::new());
+        }
+        // the byte offset of a (zero-based) position, skipping the line ends
+        let byte_offset = |position: u64| {
+            record.offset
+                + (position / record.line_bases) * record.line_width
+                + position % record.line_bases
+        };
+        let first_byte = byte_offset(start - 1);
+        let last_byte = byte_offset(end - 1);
+        let mut bytes = vec![0; (last_byte - first_byte + 1) as usize];
+        self.reader.seek(SeekFrom::Start(first_byte))?;
+        self.reader.read_exact(&mut bytes)?;
+        return Ok(bytes
+            .into_iter()
+            .filter(|byte| *byte != b'\n' && *byte != b'\r')
+            .map(|byte| byte.to_ascii_uppercase() as char)
+            .collect());
+    }
+}
+
+// the reverse complement of a DNA sequence (other characters, e.g. "N", are kept as they are)
+pub fn reverse_complement(sequence: &str) -> String {
+    sequence
+        .chars()
+        .rev()
+        .map(|base| match base {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            'a' => 't',
+            'c' => 'g',
+            'g' => 'c',
+            't' => 'a',
+            other => other,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reverse_complement, IndexedFasta};
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_fetch() {
+        // 2 contigs with lines of 10 bases
+        let fasta = ">chr2L\nACGTACGTAA\nccggttaaCC\nGG\n>chrX\nTTTTTGGGGG\n";
+        let fai = "chr2L\t22\t7\t10\t11\nchrX\t10\t38\t10\t11\n";
+        let test_dir = std::env::temp_dir();
+        let fasta_path = test_dir.join(format!("sx_test_fetch_{}.fa", std::process::id()));
+        let fai_path = test_dir.join(format!("sx_test_fetch_{}.fa.fai", std::process::id()));
+        std::fs::write(&fasta_path, fasta).unwrap();
+        std::fs::write(&fai_path, fai).unwrap();
+
+        let contig_renames: HashMap<String, String> = vec![("chrX".to_owned(), "X".to_owned())]
+            .into_iter()
+            .collect();
+        let mut indexed_fasta = IndexedFasta::open(
+            fasta_path.to_str().unwrap(),
+            fai_path.to_str().unwrap(),
+            &contig_renames,
+        )
+        .unwrap();
+        assert_eq!(indexed_fasta.fetch("chr2L", 1, 4).unwrap(), "ACGT");
+        // across a line end, in upper case
+        assert_eq!(indexed_fasta.fetch("chr2L", 8, 13).unwrap(), "TAACCG");
+        assert_eq!(indexed_fasta.fetch("chr2L", 21, 22).unwrap(), "GG");
+        assert_eq!(indexed_fasta.fetch("X", 5, 6).unwrap(), "TG");
+        assert!(indexed_fasta.fetch("chr2L", 20, 23).is_err());
+        assert!(indexed_fasta.fetch("chrX", 1, 1).is_err());
+
+        std::fs::remove_file(&fasta_path).unwrap();
+        std::fs::remove_file(&fai_path).unwrap();
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement("AACGTN"), "NACGTT");
+        assert_eq!(reverse_complement("ttag"), "ctaa");
+    }
+}
@@ -0,0 +1,245 @@
+// long reads (e.g. Nanopore or PacBio, see --long-reads) can span a whole non-reference insertion,
+// so instead of 2 split-reads on either side of the insertion, a single read has a genome alignment
+// for each flank: the primary alignment and a supplementary alignment (in the SA tag)
+// the transposon is the part of the read between them, which doesn't align to the genome
+
+// the flanks of an insertion are consecutive segments of the read (in the order of the read)
+// on the same chromosome and strand, and they overlap by the TSD in the genome:
+//
+// read:   [  upstream flank  ][ transposon ][ downstream flank ]
+// genome: ...upstream flank TSD]
+//                           [TSD downstream flank...
+//
+// (on the reverse strand the downstream flank comes first in the read)
+
+// the selected reads are written in the orientation of the transposon (see select_reads),
+// so the strand of the flanks is the orientation of the insertion
+
+use crate::errors::{Result, StanexError};
+use crate::regexes;
+
+// the marker of a long read in the selected read names (in place of SM or MS, see TeAlignment)
+pub const LONG_READ_MARKER: &str = "LR";
+
+// the longest TSD that the flanks can overlap by (the maximum TSD length of phase 4)
+const MAX_FLANK_OVERLAP: u64 = 100;
+
+// the part of the read between the flanks must be at least this fraction of the transposon alignment
+// (shorter gaps are more likely to be small insertions or alignment noise)
+const MIN_GAP_FRACTION: f64 = 0.5;
+
+// the lengths of a CIGAR string with any operations (long reads have indels everywhere)
+#[derive(Debug, PartialEq)]
+pub struct CigarSpans {
+    // the soft or hard clips at the start and end of the CIGAR string
+    pub leading_clip: u64,
+    pub trailing_clip: u64,
+    // the number of read nucleotides in the alignment (M, I, = and X)
+    pub query_length: u64,
+    // the number of reference nucleotides in the alignment (M, D, N, = and X)
+    pub ref_length: u64,
+}
+
+impl CigarSpans {
+    pub fn parse(cigar: &str) -> Result<CigarSpans> {
+        let malformed = || StanexError::MalformedCigar {
+            cigar: cigar.to_owned(),
+            expected: "a CIGAR string",
+        };
+        if !regexes::CIGAR_REGEX.is_match(cigar) {
+            return Err(malformed());
+        }
+        let mut spans = CigarSpans {
+            leading_clip: 0,
+            trailing_clip: 0,
+            query_length: 0,
+            ref_length: 0,
+        };
+        let mut seen_match = false;
+        for captures in regexes::CIGAR_OP_REGEX.captures_iter(cigar) {
+            let length: u64 = captures[1].parse().map_err(|_| malformed())?;
+            match &captures[2] {
+                "S" | "H" if seen_match => spans.trailing_clip += length,
+                "S" | "H" => spans.leading_clip += length,
+                "M" | "=" | "X" => {
+                    seen_match = true;
+                    spans.query_length += length;
+                    spans.ref_length += length;
+                }
+                "I" => spans.query_length += length,
+                "D" | "N" => spans.ref_length += length,
+                // padding
+                _ => (),
+            }
+        }
+        if !seen_match {
+            return Err(malformed());
+        }
+        return Ok(spans);
+    }
+}
+
+// a part of a read that aligns to the genome
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReadSegment {
+    pub chrom: String,
+    pub plus: bool,
+    // the first nucleotide of the alignment (one-based, like POS)
+    pub pos: u64,
+    pub ref_length: u64,
+    // the read nucleotides of the alignment (zero-based, end exclusive),
+    // in the orientation of the selected read rather than of the alignment
+    pub query_start: u64,
+    pub query_end: u64,
+}
+
+impl ReadSegment {
+    pub fn new(chrom: String, plus: bool, pos: u64, cigar: &str) -> Result<ReadSegment> {
+        let spans = CigarSpans::parse(cigar)?;
+        // a reverse-strand CIGAR string describes the reverse complement of the read
+        let query_start = if plus {
+            spans.leading_clip
+        } else {
+            spans.trailing_clip
+        };
+        return Ok(ReadSegment {
+            chrom,
+            plus,
+            pos,
+            ref_length: spans.ref_length,
+            query_start,
+            query_end: query_start + spans.query_length,
+        });
+    }
+
+    // the last nucleotide of the alignment
+    pub fn last_nt(&self) -> u64 {
+        self.pos.saturating_add(self.ref_length).saturating_sub(1)
+    }
+}
+
+// the value of the SA tag of a SAM record (without "SA:Z:"), if it has one
+pub fn sa_tag(alignment_str: &str) -> Option<&str> {
+    alignment_str
+        .split('\t')
+        .find(|field| field.starts_with("SA:Z:"))
+        .map(|field| &field["SA:Z:".len()..])
+}
+
+// the supplementary alignments of an SA tag ("rname,pos,strand,CIGAR,mapQ,NM;" for each alignment)
+pub fn parse_sa_tag(sa_tag: &str) -> Result<Vec<ReadSegment>> {
+    let mut segments: Vec<ReadSegment> = Vec::new();
+    for alignment in sa_tag.split(';').filter(|alignment| !alignment.is_empty()) {
+        let invalid = || StanexError::InvalidValue {
+            field: "SA".to_owned(),
+            value: alignment.to_owned(),
+        };
+        let fields: Vec<&str> = alignment.split(',').collect();
+        if fields.len() != 6 {
+            return Err(invalid());
+        }
+        let plus = match fields[2] {
+            "+" => true,
+            "-" => false,
+            _ => return Err(invalid()),
+        };
+        let pos: u64 = fields[1].parse().map_err(|_| invalid())?;
+        segments.push(ReadSegment::new(
+            fields[0].to_owned(),
+            plus,
+            pos,
+            fields[3],
+        )?);
+    }
+    return Ok(segments);
+}
+
+// the upstream and downstream flanks of every insertion that a read spans
+// te_length is the number of read nucleotides that aligned to the transposon in phase 1
+pub fn spanned_insertions(
+    mut segments: Vec<ReadSegment>,
+    te_length: u64,
+) -> Vec<(ReadSegment, ReadSegment)> {
+    segments.sort_by_key(|segment| (segment.query_start, segment.query_end));
+    let min_gap = ((te_length as f64 * MIN_GAP_FRACTION) as u64).max(1);
+    let mut insertions: Vec<(ReadSegment, ReadSegment)> = Vec::new();
+    for pair in segments.windows(2) {
+        let (first, second) = (&pair[0], &pair[1]);
+        if first.chrom != second.chrom || first.plus != second.plus {
+            continue;
+        }
+        if second.query_start < first.query_end + min_gap {
+            continue;
+        }
+        let (upstream, downstream) = if first.plus {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        // the downstream flank must start inside the TSD at the end of the upstream flank
+        let upstream_end = upstream.last_nt();
+        if downstream.pos > upstream_end || upstream_end - downstream.pos >= MAX_FLANK_OVERLAP {
+            continue;
+        }
+        insertions.push((upstream.clone(), downstream.clone()));
+    }
+    return insertions;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sa_tag, sa_tag, spanned_insertions, CigarSpans, ReadSegment};
+
+    #[test]
+    fn test_cigar_spans() {
+        assert_eq!(
+            CigarSpans::parse("500S300M2I100M5D95M1200S").unwrap(),
+            CigarSpans {
+                leading_clip: 500,
+                trailing_clip: 1200,
+                query_length: 497,
+                ref_length: 500,
+            }
+        );
+        assert_eq!(CigarSpans::parse("10H90M").unwrap().leading_clip, 10);
+        assert!(CigarSpans::parse("500S").is_err());
+        assert!(CigarSpans::parse("10M5Q").is_err());
+        assert!(CigarSpans::parse("*").is_err());
+    }
+
+    #[test]
+    fn test_spanned_insertions() {
+        // a +/+ insertion: 500 nt of 2L up to 1000, 1000 nt of transposon,
+        // then 505 nt of 2L from 996 (a 5-nt TSD)
+        let primary = ReadSegment::new("2L".to_owned(), true, 501, "500M1505S").unwrap();
+        let line = "r1|roo|1000|1005|LR|span\t0\t2L\t501\t60\t500M1505S\t*\t0\t0\t*\t*\t\
+                    NM:i:0\tSA:Z:2L,996,+,1500S505M,60,0;";
+        let supplementary = parse_sa_tag(sa_tag(line).unwrap()).unwrap();
+        assert_eq!(supplementary[0].query_start, 1500);
+        let insertions = spanned_insertions(vec![supplementary[0].clone(), primary.clone()], 1000);
+        assert_eq!(insertions.len(), 1);
+        assert_eq!(insertions[0].0.last_nt(), 1000);
+        assert_eq!(insertions[0].1.pos, 996);
+
+        // the same insertion in the other orientation: the selected read is the reverse complement,
+        // so the downstream flank comes first and both flanks are on the reverse strand
+        let downstream = ReadSegment::new("2L".to_owned(), false, 996, "1500S505M").unwrap();
+        let upstream = ReadSegment::new("2L".to_owned(), false, 501, "500M1505S").unwrap();
+        assert_eq!(downstream.query_start, 0);
+        assert_eq!(upstream.query_start, 1505);
+        let insertions = spanned_insertions(vec![upstream, downstream], 1000);
+        assert_eq!(insertions.len(), 1);
+        assert_eq!(insertions[0].0.last_nt(), 1000);
+        assert_eq!(insertions[0].1.pos, 996);
+
+        // flanks without the transposon between them, and flanks that don't meet in the genome
+        assert!(
+            spanned_insertions(vec![primary.clone(), supplementary[0].clone()], 5000).is_empty()
+        );
+        let far = ReadSegment::new("2L".to_owned(), true, 5000, "1500S505M").unwrap();
+        assert!(spanned_insertions(vec![primary, far], 1000).is_empty());
+
+        assert!(parse_sa_tag("2L,996,*,1500S505M,60,0;").is_err());
+        assert_eq!(sa_tag("r1\t0\t2L\t501\t60\t500M\t*\t0\t0\t*\t*"), None);
+    }
+}
@@ -1,9 +1,28 @@
-mod first_sam_file;
-mod genome_alignment;
+pub mod alignment_cache;
+pub mod annotation;
+pub mod bgzf;
+pub mod debug_cigar;
+pub mod diagnostics;
+pub mod family_map;
+pub mod family_params;
+pub mod first_sam_file;
+#[cfg(test)]
+mod fuzz_tests;
+pub mod genome_alignment;
+pub mod genome_first;
+pub mod indexed_fasta;
+mod long_read;
+// the new grouping algorithm is not wired into the pipeline yet
+#[allow(dead_code)]
 mod new_algo;
-mod output_data_types;
-mod second_sam_file;
+pub mod output_data_types;
+pub mod second_sam_file;
 pub mod select_alignments;
 pub mod select_reads;
 mod split_read;
+pub mod support_reads;
 mod te_alignment;
+pub mod te_filter;
+pub mod te_lengths;
+pub mod te_name;
+pub mod zygosity;
@@ -12,6 +12,7 @@
 
 use super::genome_alignment::GenomeAlignment;
 use super::output_data_types::Orientation;
+use crate::utils;
 
 use std::collections::HashMap;
 use std::rc::Rc;
@@ -55,6 +56,33 @@ struct Group {
     mode: u64,
 }
 
+// every consensus location of a group (step 5), so that the consensus methods can be compared
+// (step 6 pairs the groups by their median)
+#[derive(Debug, PartialEq)]
+struct ConsensusStats {
+    // the lowest and highest boundary locations of the reads
+    min: u64,
+    max: u64,
+    // the average boundary location (not rounded, so it can fall between nucleotides)
+    mean: f64,
+    // the middle boundary location, or the average of the 2 middle locations rounded up
+    median: u64,
+    // the most common boundary location (the lowest one if there is a tie)
+    mode: u64,
+}
+
+impl Group {
+    fn consensus_stats(&self) -> ConsensusStats {
+        ConsensusStats {
+            min: self.min,
+            max: self.max,
+            mean: self.mean,
+            median: self.median,
+            mode: self.mode,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct NewNonRefTE {
     pub upstream_group: Group,
@@ -67,7 +95,29 @@ struct NewRefTE {
     pub downstream_group: Group,
 }
 
+impl NewNonRefTE {
+    // the consensus statistics of the upstream and downstream ends
+    fn consensus_stats(&self) -> (ConsensusStats, ConsensusStats) {
+        (
+            self.upstream_group.consensus_stats(),
+            self.downstream_group.consensus_stats(),
+        )
+    }
+}
+
+impl NewRefTE {
+    // the consensus statistics of the upstream and downstream ends
+    fn consensus_stats(&self) -> (ConsensusStats, ConsensusStats) {
+        (
+            self.upstream_group.consensus_stats(),
+            self.downstream_group.consensus_stats(),
+        )
+    }
+}
+
 struct NewAlgoTEResults {
+    // the number of groups of the TE's reads (step 4), before they are capped (see GroupLimit)
+    num_groups: usize,
     plus_plus_nonref: Vec<NewNonRefTE>,
     plus_plus_ref: Vec<NewRefTE>,
     plus_minus_nonref: Vec<NewNonRefTE>,
@@ -76,6 +126,26 @@ struct NewAlgoTEResults {
 
 type NewAlgoResults = HashMap<String, NewAlgoTEResults>;
 
+// a guard against a group_blur that is too tight for noisy, high-coverage data: step 4 then makes
+// an enormous number of groups (most of them a single read), which blows up the memory and step 6
+#[derive(Clone, Copy, Debug)]
+struct GroupLimit {
+    // warn when a TE has more groups than this on a chromosome
+    warn_at: usize,
+    // if set, keep only this many groups of each sub-list (the ones with the most reads)
+    cap: Option<usize>,
+}
+
+impl GroupLimit {
+    const DEFAULT: GroupLimit = GroupLimit {
+        warn_at: 10_000,
+        cap: None,
+    };
+}
+
+// the number of TEs listed in the group count warning
+const MAX_WARNED_TES: usize = 5;
+
 fn step1(chrom_list: &mut ChromList) -> Vec<TEList> {
     chrom_list.reads.sort_by(|a, b| a.te_name.cmp(&b.te_name));
     let mut te_lists: Vec<TEList> = Vec::new();
@@ -159,6 +229,10 @@ fn step3(sub_list: &mut SubList, chrom_list: &ChromList) {
 }
 
 fn step4(sub_list: SubList, group_blur: u64, chrom_list: &ChromList) -> Vec<Group> {
+    // a TE usually has no reads for some of its ends and orientations
+    if sub_list.reads.is_empty() {
+        return Vec::new();
+    }
     let mut res = vec![Group {
         te_name: sub_list.te_name.clone(),
         orientation: sub_list.orientation.clone(),
@@ -203,7 +277,7 @@ fn step5(group: &mut Group, chrom_list: &ChromList) {
     let max = chrom_list.reads[group.reads[len - 1]].get_boundary_nt();
     let median = if len % 2 == 0 {
         let left_side = chrom_list.reads[group.reads[len / 2 - 1]].get_boundary_nt();
-        let right_side = chrom_list.reads[group.reads[len / 2 - 1]].get_boundary_nt();
+        let right_side = chrom_list.reads[group.reads[len / 2]].get_boundary_nt();
         (left_side + right_side + 1) / 2 // note: this rounds up the .5 if necessary
     } else {
         chrom_list.reads[group.reads[len / 2]].get_boundary_nt()
@@ -226,7 +300,7 @@ fn step5(group: &mut Group, chrom_list: &ChromList) {
     let mut max_key = 0;
     let mut max_value = 0;
     for (key, value) in &freq {
-        if value > &max_value {
+        if value > &max_value || (value == &max_value && key < &max_key) {
             max_key = *key;
             max_value = *value;
         }
@@ -238,7 +312,75 @@ fn step5(group: &mut Group, chrom_list: &ChromList) {
     group.mode = max_key;
 }
 
+// keep the cap groups with the most reads (the earlier ones on a tie), in their order on the chromosome
+fn cap_groups(groups: Vec<Group>, cap: usize) -> Vec<Group> {
+    if groups.len() <= cap {
+        return groups;
+    }
+    let mut by_size: Vec<usize> = (0..groups.len()).collect();
+    by_size.sort_by(|&a, &b| {
+        groups[b]
+            .reads
+            .len()
+            .cmp(&groups[a].reads.len())
+            .then(a.cmp(&b))
+    });
+    let mut kept = vec![false; groups.len()];
+    for &index in by_size.iter().take(cap) {
+        kept[index] = true;
+    }
+    return groups
+        .into_iter()
+        .zip(kept)
+        .filter(|(_, kept)| *kept)
+        .map(|(group, _)| group)
+        .collect();
+}
+
+// the TEs with the most groups, the largest first (the ones to look at when tuning group_blur)
+fn largest_group_counts(results: &NewAlgoResults, max_tes: usize) -> Vec<(&str, usize)> {
+    let mut counts: Vec<(&str, usize)> = results
+        .iter()
+        .map(|(te_name, te_results)| (&te_name[..], te_results.num_groups))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    counts.truncate(max_tes);
+    return counts;
+}
+
+// the warning about the TEs of a chromosome with more groups than group_limit.warn_at (None if there are none)
+fn group_count_warning(
+    results: &NewAlgoResults,
+    chrom_name: &str,
+    group_limit: &GroupLimit,
+) -> Option<String> {
+    let num_over = results
+        .values()
+        .filter(|te_results| te_results.num_groups > group_limit.warn_at)
+        .count();
+    if num_over == 0 {
+        return None;
+    }
+    let largest: Vec<String> = largest_group_counts(results, MAX_WARNED_TES.min(num_over))
+        .iter()
+        .map(|(te_name, num_groups)| format!("{} ({} groups)", te_name, num_groups))
+        .collect();
+    let capped = match group_limit.cap {
+        Some(cap) => format!(", capped at {} groups per end and orientation", cap),
+        None => String::new(),
+    };
+    return Some(format!(
+        "{} TEs have more than {} groups on {}{} (group_blur may be too small for this data): {}",
+        num_over,
+        group_limit.warn_at,
+        chrom_name,
+        capped,
+        largest.join(", ")
+    ));
+}
+
 /// max_inverted_repeat should be something small but not negligible, like 20 or 30
+/// (the TSD and inverted repeat sizes depend on the type of element)
 fn step6_plus_plus_nonref(
     start_side: &Vec<Group>,
     end_side: &Vec<Group>,
@@ -253,7 +395,7 @@ fn step6_plus_plus_nonref(
                 break;
             }
             let end_pos = end_side[end_group_idx].median;
-            if end_pos > start_pos - max_inverted_repeat {
+            if end_pos > start_pos.saturating_sub(max_inverted_repeat) {
                 if end_pos < start_pos {
                     tes.push(NewNonRefTE {
                         upstream_group: start_group.clone(),
@@ -378,10 +520,15 @@ fn step6_plus_minus_ref(
 }
 
 macro_rules! steps345 {
-    ( $chrom_list: ident; $($sub_list: ident, $sub_list_groups: ident;)+ ) => {
+    ( $chrom_list: ident, $group_blur: ident, $group_limit: ident, $num_groups: ident;
+      $($sub_list: ident, $sub_list_groups: ident;)+ ) => {
         $(
             step3(&mut $sub_list, $chrom_list);
-            let mut $sub_list_groups = step4($sub_list, 10, $chrom_list);
+            let mut $sub_list_groups = step4($sub_list, $group_blur, $chrom_list);
+            $num_groups += $sub_list_groups.len();
+            if let Some(cap) = $group_limit.cap {
+                $sub_list_groups = cap_groups($sub_list_groups, cap);
+            }
             for mut group in &mut $sub_list_groups {
                 step5(&mut group, $chrom_list);
             }
@@ -389,22 +536,36 @@ macro_rules! steps345 {
     }
 }
 
-fn new_algo(chrom_list: &mut ChromList, te_lengths: &HashMap<String, u64>) -> NewAlgoResults {
+/// max_inverted_repeat is the largest distance between the 2 ends of a non-reference TE
+/// (see step6_plus_plus_nonref)
+/// group_blur is the largest distance between the reads of a group (see step4), and group_limit
+/// warns about (and optionally caps) the TEs that it splits into too many groups
+fn new_algo(
+    chrom_list: &mut ChromList,
+    te_lengths: &HashMap<String, u64>,
+    max_inverted_repeat: u64,
+    group_blur: u64,
+    group_limit: &GroupLimit,
+) -> NewAlgoResults {
     let mut res = NewAlgoResults::new();
     for te_list in step1(chrom_list) {
         let te_name = (*te_list.te_name).clone();
         let sub_lists = step2(te_list, chrom_list);
         let (mut plus_plus_start, mut plus_plus_end, mut plus_minus_start, mut plus_minus_end) =
             sub_lists;
+        let mut num_groups = 0;
         steps345!(
-            chrom_list;
+            chrom_list, group_blur, group_limit, num_groups;
             plus_plus_start, plus_plus_start_groups;
             plus_plus_end, plus_plus_end_groups;
             plus_minus_start, plus_minus_start_groups;
             plus_minus_end, plus_minus_end_groups;
         );
-        let plus_plus_nonref =
-            step6_plus_plus_nonref(&plus_plus_start_groups, &plus_plus_end_groups, 30);
+        let plus_plus_nonref = step6_plus_plus_nonref(
+            &plus_plus_start_groups,
+            &plus_plus_end_groups,
+            max_inverted_repeat,
+        );
         let plus_plus_ref = step6_plus_plus_ref(
             &plus_plus_start_groups,
             &plus_plus_end_groups,
@@ -412,8 +573,11 @@ fn new_algo(chrom_list: &mut ChromList, te_lengths: &HashMap<String, u64>) -> Ne
             1.5,
             te_lengths,
         );
-        let plus_minus_nonref =
-            step6_plus_minus_nonref(&plus_minus_start_groups, &plus_minus_end_groups, 30);
+        let plus_minus_nonref = step6_plus_minus_nonref(
+            &plus_minus_start_groups,
+            &plus_minus_end_groups,
+            max_inverted_repeat,
+        );
         let plus_minus_ref = step6_plus_minus_ref(
             &plus_minus_start_groups,
             &plus_minus_end_groups,
@@ -424,6 +588,7 @@ fn new_algo(chrom_list: &mut ChromList, te_lengths: &HashMap<String, u64>) -> Ne
         res.insert(
             te_name,
             NewAlgoTEResults {
+                num_groups: num_groups,
                 plus_plus_nonref: plus_plus_nonref,
                 plus_plus_ref: plus_plus_ref,
                 plus_minus_nonref: plus_minus_nonref,
@@ -431,6 +596,9 @@ fn new_algo(chrom_list: &mut ChromList, te_lengths: &HashMap<String, u64>) -> Ne
             },
         );
     }
+    if let Some(warning) = group_count_warning(&res, &chrom_list.chrom_name, group_limit) {
+        utils::print_warning(format_args!("{}", warning));
+    }
     return res;
 }
 
@@ -445,6 +613,7 @@ mod tests {
         fn make_genome_alignment(idx: usize) -> GenomeAlignment {
             let te_name = (('a' as u8 as usize + idx % 7) as u8 as char).to_string();
             return GenomeAlignment {
+                read_name: format!("r{}", idx),
                 te_name: te_name,
                 old_m: 0,
                 old_s: 0,
@@ -554,4 +723,207 @@ mod tests {
             assert_eq!(te_lists[i].reads, reads);
         }
     }
+
+    #[test]
+    fn test_step6_max_inverted_repeat() {
+        fn make_group(orientation: Orientation, end: TEEnd, median: u64) -> Group {
+            return Group {
+                te_name: Rc::new("roo".to_string()),
+                orientation: orientation,
+                end: end,
+                reads: vec![0],
+                min: median,
+                max: median,
+                mean: median as f64,
+                median: median,
+                mode: median,
+            };
+        }
+
+        // +/+: the end of the TE is 25 nt before its start
+        let start_side = vec![make_group(Orientation::PlusPlus, TEEnd::Start, 1025)];
+        let end_side = vec![make_group(Orientation::PlusPlus, TEEnd::End, 1000)];
+        assert_eq!(step6_plus_plus_nonref(&start_side, &end_side, 30).len(), 1);
+        assert_eq!(step6_plus_plus_nonref(&start_side, &end_side, 20).len(), 0);
+
+        // +/-: the end of the TE is 25 nt after its start
+        let start_side = vec![make_group(Orientation::PlusMinus, TEEnd::Start, 1000)];
+        let end_side = vec![make_group(Orientation::PlusMinus, TEEnd::End, 1025)];
+        assert_eq!(step6_plus_minus_nonref(&start_side, &end_side, 30).len(), 1);
+        assert_eq!(step6_plus_minus_nonref(&start_side, &end_side, 20).len(), 0);
+
+        // a TE start closer to the start of the chromosome than max_inverted_repeat
+        let start_side = vec![make_group(Orientation::PlusPlus, TEEnd::Start, 10)];
+        let end_side = vec![make_group(Orientation::PlusPlus, TEEnd::End, 5)];
+        assert_eq!(step6_plus_plus_nonref(&start_side, &end_side, 30).len(), 1);
+    }
+
+    #[test]
+    fn test_end_consensus_stats() {
+        fn make_group(end: TEEnd, min: u64, max: u64) -> Group {
+            return Group {
+                te_name: Rc::new("roo".to_string()),
+                orientation: Orientation::PlusPlus,
+                end: end,
+                reads: vec![0, 1],
+                min: min,
+                max: max,
+                mean: (min + max) as f64 / 2.0,
+                median: max,
+                mode: max,
+            };
+        }
+
+        let te = NewNonRefTE {
+            upstream_group: make_group(TEEnd::Start, 1023, 1025),
+            downstream_group: make_group(TEEnd::End, 1000, 1000),
+        };
+        let (upstream_stats, downstream_stats) = te.consensus_stats();
+        assert_eq!(upstream_stats.mean, 1024.0);
+        assert_eq!((upstream_stats.min, upstream_stats.max), (1023, 1025));
+        assert_eq!((downstream_stats.min, downstream_stats.max), (1000, 1000));
+    }
+
+    #[test]
+    fn test_step5() {
+        // a group of reads with their boundaries at 1000, 1000, 1002 and 1003
+        let chrom_list = ChromList {
+            chrom_name: "2L".to_string(),
+            reads: [1000, 1000, 1002, 1003]
+                .iter()
+                .map(|new_pos| GenomeAlignment {
+                    read_name: format!("r{}", new_pos),
+                    te_name: "roo".to_string(),
+                    old_m: 50,
+                    old_s: 0,
+                    is_sm_te: true,
+                    is_start: true,
+                    new_plus: true,
+                    chrom: "2L".to_string(),
+                    split_read_genome: SplitReadGenome::M(MAlignment {
+                        is_start: true,
+                        new_plus: true,
+                        old_m: 50,
+                        old_s: 0,
+                        new_pos: *new_pos,
+                    }),
+                })
+                .collect(),
+        };
+        let mut group = Group {
+            te_name: Rc::new("roo".to_string()),
+            orientation: Orientation::PlusPlus,
+            end: TEEnd::Start,
+            reads: vec![0, 1, 2, 3],
+            min: 0,
+            max: 0,
+            mean: 0.0,
+            median: 0,
+            mode: 0,
+        };
+        step5(&mut group, &chrom_list);
+        assert_eq!(
+            group.consensus_stats(),
+            ConsensusStats {
+                min: 1000,
+                max: 1003,
+                mean: 1001.25,
+                median: 1001,
+                mode: 1000,
+            }
+        );
+
+        // a tie between the most common locations
+        group.reads = vec![1, 2];
+        step5(&mut group, &chrom_list);
+        assert_eq!((group.median, group.mode), (1001, 1000));
+    }
+
+    #[test]
+    fn test_group_limit() {
+        // 40 reads at the start of roo, 20 nt apart, and 2 reads of jockey
+        let make_alignment = |te_name: &str, new_pos: u64| GenomeAlignment {
+            read_name: format!("r{}", new_pos),
+            te_name: te_name.to_string(),
+            old_m: 50,
+            old_s: 0,
+            is_sm_te: true,
+            is_start: true,
+            new_plus: true,
+            chrom: "2L".to_string(),
+            split_read_genome: SplitReadGenome::M(MAlignment {
+                is_start: true,
+                new_plus: true,
+                old_m: 50,
+                old_s: 0,
+                new_pos: new_pos,
+            }),
+        };
+        let make_chrom_list = || {
+            let mut reads: Vec<GenomeAlignment> = (0..40)
+                .map(|read_num| make_alignment("roo", 1000 + 20 * read_num))
+                .collect();
+            reads.push(make_alignment("jockey", 5000));
+            reads.push(make_alignment("jockey", 5001));
+            ChromList {
+                chrom_name: "2L".to_string(),
+                reads: reads,
+            }
+        };
+        let te_lengths: HashMap<String, u64> =
+            vec![("roo".to_owned(), 9092), ("jockey".to_owned(), 5000)]
+                .into_iter()
+                .collect();
+        let group_limit = GroupLimit {
+            warn_at: 10,
+            cap: None,
+        };
+
+        // a group_blur that is too tight makes a group for every read of roo
+        let results = new_algo(&mut make_chrom_list(), &te_lengths, 30, 5, &group_limit);
+        assert_eq!(results["roo"].num_groups, 40);
+        assert_eq!(results["jockey"].num_groups, 1);
+        assert_eq!(
+            largest_group_counts(&results, 5),
+            vec![("roo", 40), ("jockey", 1)]
+        );
+        assert_eq!(
+            group_count_warning(&results, "2L", &group_limit).unwrap(),
+            "1 TEs have more than 10 groups on 2L (group_blur may be too small for this data): roo (40 groups)"
+        );
+        assert!(group_count_warning(&results, "2L", &GroupLimit::DEFAULT).is_none());
+
+        // the groups with the most reads are kept, in their order on the chromosome
+        let groups: Vec<Group> = [3, 1, 2, 2]
+            .iter()
+            .enumerate()
+            .map(|(index, num_reads)| Group {
+                te_name: Rc::new("roo".to_string()),
+                orientation: Orientation::PlusPlus,
+                end: TEEnd::Start,
+                reads: vec![index; *num_reads],
+                min: 0,
+                max: 0,
+                mean: 0.0,
+                median: 0,
+                mode: 0,
+            })
+            .collect();
+        let kept: Vec<usize> = cap_groups(groups, 2)
+            .iter()
+            .map(|group| group.reads[0])
+            .collect();
+        assert_eq!(kept, vec![0, 2]);
+
+        // the warning still reports the groups before they are capped
+        let group_limit = GroupLimit {
+            warn_at: 10,
+            cap: Some(10),
+        };
+        let results = new_algo(&mut make_chrom_list(), &te_lengths, 30, 5, &group_limit);
+        assert_eq!(results["roo"].num_groups, 40);
+        assert!(group_count_warning(&results, "2L", &group_limit)
+            .unwrap()
+            .contains("capped at 10 groups per end and orientation"));
+    }
 }
@@ -2,11 +2,15 @@ use serde::{Deserialize, Serialize};
 
 use std::fmt::{Display, Formatter, Result};
 
+use super::annotation::AnnotationOverlap;
 use super::genome_alignment::SplitReadRanges;
+use super::te_name::TeName;
+use super::zygosity::ZygosityHint;
+use crate::tabular::Data;
 
 // I could store orientation in a bool
 // but this is more readable
-#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Serialize, Deserialize, Clone)]
 pub enum Orientation {
     PlusPlus,
     PlusMinus,
@@ -28,6 +32,380 @@ enum TSDCoords {
     ZeroBasedHalfOpen { start_pos: u64, end_pos: u64 },
 }
 
+// the fields of an insertion in the TSV output
+// (the column names and order are set by select_alignments::HeaderStyle)
+pub const TSV_FIELDS: [&str; 23] = [
+    "CHROM",
+    "START",
+    "END",
+    "ORIENTATION",
+    "NAME",
+    "UPSTREAM_READS",
+    "DOWNSTREAM_READS",
+    "UPSTREAM_FRAGMENTS",
+    "DOWNSTREAM_FRAGMENTS",
+    "UPSTREAM_BREAKPOINT_RANGE",
+    "UPSTREAM_BREAKPOINT_SPREAD",
+    "DOWNSTREAM_BREAKPOINT_RANGE",
+    "DOWNSTREAM_BREAKPOINT_SPREAD",
+    "INSERTION_TYPE",
+    "ONE_SIDED",
+    "TSD_SEQUENCE",
+    "ZYGOSITY",
+    "FEATURE",
+    "GENE_ID",
+    "OVERLAPPING_CALLS",
+    "NEIGHBOR_DISTANCE",
+    "NEIGHBOR",
+    "REFERENCE_SOURCE",
+];
+
+// build the TSV fields for an insertion (in the same coordinate system as the default display)
+fn tsv_data(
+    chrom: &str,
+    coords: TSDCoords,
+    orientation: &Orientation,
+    name: &str,
+    num_upstream_reads: usize,
+    num_downstream_reads: usize,
+    insertion_type: &str,
+) -> Data {
+    let one_sided = num_upstream_reads == 0 || num_downstream_reads == 0;
+    let (start_pos, end_pos) = match coords {
+        TSDCoords::OneBasedFullyClosed { start_pos, end_pos } => (start_pos, end_pos),
+        TSDCoords::ZeroBasedHalfOpen { start_pos, end_pos } => (start_pos, end_pos),
+    };
+    let orientation_string = match orientation {
+        Orientation::PlusPlus => "+/+",
+        Orientation::PlusMinus => "+/-",
+    };
+    let mut data = Data::new();
+    data.insert("CHROM", chrom.to_owned());
+    data.insert("START", start_pos.to_string());
+    data.insert("END", end_pos.to_string());
+    data.insert("ORIENTATION", orientation_string.to_owned());
+    data.insert("NAME", name.to_owned());
+    data.insert("UPSTREAM_READS", num_upstream_reads.to_string());
+    data.insert("DOWNSTREAM_READS", num_downstream_reads.to_string());
+    data.insert("INSERTION_TYPE", insertion_type.to_owned());
+    data.insert("ONE_SIDED", if one_sided { "yes" } else { "no" }.to_owned());
+    return data;
+}
+
+// the fragment counts of an insertion in the TSV output (see --count-fragments)
+fn insert_fragments(data: &mut Data, insertion: &Insertion) {
+    let (upstream_fragments, downstream_fragments) = insertion.fragments();
+    data.insert("UPSTREAM_FRAGMENTS", upstream_fragments.to_string());
+    data.insert("DOWNSTREAM_FRAGMENTS", downstream_fragments.to_string());
+}
+
+// how precisely the split-reads of one end of an insertion agree on its breakpoint
+// (see select_alignments::add_breakpoint_ranges)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BreakpointRange {
+    // the first and last boundaries of the split-reads (the UBR and DBR of --vcf-read-evidence)
+    pub min: u64,
+    pub max: u64,
+    // the number of nucleotides between them (0 if all the split-reads agree)
+    pub spread: u64,
+}
+
+impl BreakpointRange {
+    // the range of the boundaries of the split-reads of an end (None for an end without split-reads)
+    pub fn of_reads(reads: &[SplitReadRanges]) -> Option<BreakpointRange> {
+        let min = reads.iter().map(|read| read.boundary).min()?;
+        let max = reads.iter().map(|read| read.boundary).max()?;
+        return Some(BreakpointRange {
+            min,
+            max,
+            spread: max - min,
+        });
+    }
+}
+
+// the breakpoint ranges of an insertion in the TSV output (see --breakpoint-ranges),
+// as "min-max" and the spread, or "." for an end without split-reads
+fn insert_breakpoint_ranges(
+    data: &mut Data,
+    upstream_range: &Option<BreakpointRange>,
+    downstream_range: &Option<BreakpointRange>,
+) {
+    let columns = |range: &Option<BreakpointRange>| match range {
+        Some(range) => (
+            format!("{}-{}", range.min, range.max),
+            range.spread.to_string(),
+        ),
+        None => (".".to_owned(), ".".to_owned()),
+    };
+    let (range, spread) = columns(upstream_range);
+    data.insert("UPSTREAM_BREAKPOINT_RANGE", range);
+    data.insert("UPSTREAM_BREAKPOINT_SPREAD", spread);
+    let (range, spread) = columns(downstream_range);
+    data.insert("DOWNSTREAM_BREAKPOINT_RANGE", range);
+    data.insert("DOWNSTREAM_BREAKPOINT_SPREAD", spread);
+}
+
+// the annotation of an insertion in the TSV output (see --annotation)
+// an insertion that overlaps several genes lists all of them, and an intergenic one has "."
+fn insert_annotation(data: &mut Data, annotation: &Option<AnnotationOverlap>) {
+    if let Some(annotation) = annotation {
+        data.insert("FEATURE", annotation.feature.clone());
+        data.insert(
+            "GENE_ID",
+            if annotation.gene_ids.is_empty() {
+                ".".to_owned()
+            } else {
+                annotation.gene_ids.join(",")
+            },
+        );
+    }
+}
+
+// the annotation of an insertion as GFF3 attributes (multiple gene IDs are separated by commas)
+fn annotation_gff3_attributes(annotation: &Option<AnnotationOverlap>) -> String {
+    match annotation {
+        Some(annotation) if annotation.gene_ids.is_empty() => {
+            format!(";feature={}", annotation.feature)
+        }
+        Some(annotation) => format!(
+            ";feature={};gene_id={}",
+            annotation.feature,
+            annotation
+                .gene_ids
+                .iter()
+                .map(|gene_id| escape_gff3_value(gene_id))
+                .collect::<Vec<String>>()
+                .join(",")
+        ),
+        None => String::new(),
+    }
+}
+
+// the annotation of an insertion as VCF INFO fields
+fn annotation_vcf_info(annotation: &Option<AnnotationOverlap>) -> String {
+    match annotation {
+        Some(annotation) if annotation.gene_ids.is_empty() => {
+            format!(";FEATURE={}", annotation.feature)
+        }
+        Some(annotation) => format!(
+            ";FEATURE={};GENE_ID={}",
+            annotation.feature,
+            annotation.gene_ids.join(",")
+        ),
+        None => String::new(),
+    }
+}
+
+// the calls of the other insertion type that overlap an insertion in the TSV output
+// (see select_alignments::reconcile_overlapping_calls), or "." if there are none
+fn insert_overlapping_calls(data: &mut Data, overlapping_calls: &[String]) {
+    data.insert(
+        "OVERLAPPING_CALLS",
+        if overlapping_calls.is_empty() {
+            ".".to_owned()
+        } else {
+            overlapping_calls.join(",")
+        },
+    );
+}
+
+fn overlapping_calls_gff3_attribute(overlapping_calls: &[String]) -> String {
+    if overlapping_calls.is_empty() {
+        return String::new();
+    }
+    let values: Vec<String> = overlapping_calls
+        .iter()
+        .map(|call| escape_gff3_value(call))
+        .collect();
+    return format!(";overlapping_calls={}", values.join(","));
+}
+
+fn overlapping_calls_vcf_info(overlapping_calls: &[String]) -> String {
+    if overlapping_calls.is_empty() {
+        return String::new();
+    }
+    let values: Vec<String> = overlapping_calls
+        .iter()
+        .map(|call| escape_vcf_value(call))
+        .collect();
+    return format!(";OVERLAPPING_CALLS={}", values.join(","));
+}
+
+// the nearest other insertion on the same chromosome (see select_alignments::annotate_neighbors)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Neighbor {
+    // the number of nucleotides between the 2 insertions (0 if they overlap, e.g. nested elements)
+    pub distance: u64,
+    // the neighbor, as "name@start-end"
+    pub call: String,
+}
+
+// the nearest neighbor of an insertion in the TSV output, or "." if it is alone on its chromosome
+fn insert_neighbor(data: &mut Data, nearest_neighbor: &Option<Neighbor>) {
+    let (distance, call) = match nearest_neighbor {
+        Some(neighbor) => (neighbor.distance.to_string(), neighbor.call.clone()),
+        None => (".".to_owned(), ".".to_owned()),
+    };
+    data.insert("NEIGHBOR_DISTANCE", distance);
+    data.insert("NEIGHBOR", call);
+}
+
+fn neighbor_gff3_attributes(nearest_neighbor: &Option<Neighbor>) -> String {
+    match nearest_neighbor {
+        Some(neighbor) => format!(
+            ";neighbor_distance={};neighbor={}",
+            neighbor.distance,
+            escape_gff3_value(&neighbor.call)
+        ),
+        None => String::new(),
+    }
+}
+
+fn neighbor_vcf_info(nearest_neighbor: &Option<Neighbor>) -> String {
+    match nearest_neighbor {
+        Some(neighbor) => format!(
+            ";NEIGHBOR_DISTANCE={};NEIGHBOR={}",
+            neighbor.distance,
+            escape_vcf_value(&neighbor.call)
+        ),
+        None => String::new(),
+    }
+}
+
+// escape the characters that have a special meaning in GFF3 attribute values
+fn escape_gff3_value(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace(';', "%3B")
+        .replace('=', "%3D")
+        .replace('&', "%26")
+        .replace(',', "%2C")
+}
+
+// build a GFF3 line for an insertion
+// GFF3 is one-based fully closed, so zero-based half-open coordinates are shifted by 1
+#[allow(clippy::too_many_arguments)]
+fn gff3_line(
+    id: &str,
+    chrom: &str,
+    coords: TSDCoords,
+    orientation: &Orientation,
+    name: &str,
+    num_upstream_reads: usize,
+    num_downstream_reads: usize,
+    insertion_type: &str,
+) -> String {
+    let (start_pos, end_pos) = match coords {
+        TSDCoords::OneBasedFullyClosed { start_pos, end_pos } => (start_pos, end_pos),
+        TSDCoords::ZeroBasedHalfOpen { start_pos, end_pos } => (start_pos + 1, end_pos),
+    };
+    let strand = match orientation {
+        Orientation::PlusPlus => "+",
+        Orientation::PlusMinus => "-",
+    };
+    // insertions with split-reads on only one end (see --report-one-sided) are marked as such
+    let one_sided_attribute = if num_upstream_reads == 0 || num_downstream_reads == 0 {
+        ";one_sided=true"
+    } else {
+        ""
+    };
+    format!(
+        "{}\tStanX_tools\tmobile_genetic_element\t{}\t{}\t.\t{}\t.\tID={};Name={};insertion_type={};upstream_reads={};downstream_reads={}{}",
+        chrom,
+        start_pos,
+        end_pos,
+        strand,
+        escape_gff3_value(id),
+        escape_gff3_value(name),
+        insertion_type,
+        num_upstream_reads,
+        num_downstream_reads,
+        one_sided_attribute,
+    )
+}
+
+// escape the characters that have a special meaning in VCF INFO and sample values
+fn escape_vcf_value(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace(':', "%3A")
+        .replace(';', "%3B")
+        .replace('=', "%3D")
+        .replace(',', "%2C")
+        .replace(' ', "%20")
+}
+
+// build a VCF record for an insertion (without the sample column)
+// the insertion is a symbolic <INS:ME> allele at the first position of the TSD,
+// and the reference base is "N" because the reference sequence is not read in phase 4
+#[allow(clippy::too_many_arguments)]
+fn vcf_line(
+    id: &str,
+    chrom: &str,
+    coords: TSDCoords,
+    orientation: &Orientation,
+    name: &str,
+    num_upstream_reads: usize,
+    num_downstream_reads: usize,
+    insertion_type: &str,
+) -> String {
+    let (start_pos, end_pos) = match coords {
+        TSDCoords::OneBasedFullyClosed { start_pos, end_pos } => (start_pos, end_pos),
+        TSDCoords::ZeroBasedHalfOpen { start_pos, end_pos } => (start_pos + 1, end_pos),
+    };
+    let polarity = match orientation {
+        Orientation::PlusPlus => "+",
+        Orientation::PlusMinus => "-",
+    };
+    // insertions with split-reads on only one end (see --report-one-sided) fail the OneSided filter
+    let filter = if num_upstream_reads == 0 || num_downstream_reads == 0 {
+        "OneSided"
+    } else {
+        "PASS"
+    };
+    format!(
+        "{}\t{}\t{}\tN\t<INS:ME>\t.\t{}\tSVTYPE=INS;MEINFO={},.,.,{};TSD_END={};INSERTION_TYPE={};UPSTREAM_READS={};DOWNSTREAM_READS={}",
+        chrom,
+        start_pos,
+        id,
+        filter,
+        escape_vcf_value(name),
+        polarity,
+        end_pos,
+        insertion_type,
+        num_upstream_reads,
+        num_downstream_reads,
+    )
+}
+
+// the distinct boundaries of the split-reads of an end, in order
+fn read_boundaries(reads: &[SplitReadRanges]) -> Vec<u64> {
+    let mut boundaries: Vec<u64> = reads.iter().map(|read| read.boundary).collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    return boundaries;
+}
+
+// the first and last boundaries as a VCF value ("." for an end without split-reads)
+fn vcf_boundary_range(boundaries: &[u64]) -> String {
+    match (boundaries.first(), boundaries.last()) {
+        (Some(first), Some(last)) => format!("{},{}", first, last),
+        _ => ".".to_owned(),
+    }
+}
+
+// a VCF list of values ("." if there are none)
+fn vcf_list(values: &[u64]) -> String {
+    if values.is_empty() {
+        return ".".to_owned();
+    }
+    return values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+}
+
 // struct NonRefTE keeps the TE insertion info relevant to the final TSV file
 // that is not already within the genome_aligned file
 // the TE is NOT found in the reference
@@ -46,9 +424,85 @@ pub struct NonRefTE {
     pub orientation: Orientation,
     pub upstream_reads: Vec<SplitReadRanges>,
     pub downstream_reads: Vec<SplitReadRanges>,
+    // the sequence of the TSD in the reference genome, on the strand of the transposon
+    // (only filled in with --tsd-sequence)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tsd_sequence: Option<String>,
+    // a heuristic zygosity from the split-reads and the read depth at the TSD
+    // (only filled in with --coverage, see zygosity::ZygosityThresholds::classify)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub zygosity: Option<ZygosityHint>,
+    // where the insertion is relative to the genes (only filled in with --annotation)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub annotation: Option<AnnotationOverlap>,
+    // the calls of the other insertion type and the same family that overlap this one, as "name@start-end"
+    // (only filled in with --reconcile-overlaps, see select_alignments::reconcile_overlapping_calls)
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub overlapping_calls: Vec<String>,
+    // the number of distinct fragments (reads or read pairs) among the upstream and downstream split-reads
+    // (only filled in with --count-fragments, see select_alignments::count_fragments)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub upstream_fragments: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub downstream_fragments: Option<usize>,
+    // the range of the boundaries of the upstream and downstream split-reads
+    // (only filled in with --breakpoint-ranges, see select_alignments::add_breakpoint_ranges)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub upstream_breakpoint_range: Option<BreakpointRange>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub downstream_breakpoint_range: Option<BreakpointRange>,
+    // the nearest other insertion on the same chromosome (None if there is none)
+    // (only filled in with --annotate-neighbors, see select_alignments::annotate_neighbors)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nearest_neighbor: Option<Neighbor>,
 }
 
 impl NonRefTE {
+    // an insertion without split-reads, with none of the optional annotations filled in
+    pub fn new(
+        name: String,
+        chrom: String,
+        upstream_pos: u64,
+        downstream_pos: u64,
+        orientation: Orientation,
+    ) -> NonRefTE {
+        return NonRefTE {
+            name,
+            chrom,
+            upstream_pos,
+            downstream_pos,
+            orientation,
+            upstream_reads: Vec::new(),
+            downstream_reads: Vec::new(),
+            tsd_sequence: None,
+            zygosity: None,
+            annotation: None,
+            overlapping_calls: Vec::new(),
+            upstream_fragments: None,
+            downstream_fragments: None,
+            upstream_breakpoint_range: None,
+            downstream_breakpoint_range: None,
+            nearest_neighbor: None,
+        };
+    }
+    // the order of the insertions within a chromosome
+    pub fn sort_key(&self) -> (u64, u64, &str, &Orientation) {
+        (
+            self.upstream_pos,
+            self.downstream_pos,
+            &self.name,
+            &self.orientation,
+        )
+    }
+    // are the ends in the order of a non-reference insertion? (the TSD makes upstream_pos the greater one,
+    // and a one-sided insertion has both ends at the same position)
+    pub fn positions_ordered(&self) -> bool {
+        self.upstream_pos >= self.downstream_pos
+    }
+    // the first and last nucleotides of the TSD (one-based fully closed, whatever the output coordinates)
+    pub fn tsd_range(&self) -> (u64, u64) {
+        (self.downstream_pos, self.upstream_pos)
+    }
     // get which nucleotides are in the tsd from a NonRefTE struct
     fn get_coords(&self) -> TSDCoords {
         // one-based fully-closed
@@ -64,6 +518,102 @@ impl NonRefTE {
         };
         */
     }
+    // get the GFF3 feature for the insertion (the feature spans the TSD)
+    pub fn to_gff3(&self, id: &str) -> String {
+        let line = gff3_line(
+            id,
+            &self.chrom,
+            self.get_coords(),
+            &self.orientation,
+            &self.name,
+            self.upstream_reads.len(),
+            self.downstream_reads.len(),
+            "non-reference",
+        );
+        let line = match &self.tsd_sequence {
+            Some(tsd_sequence) => format!("{};tsd_sequence={}", line, tsd_sequence),
+            None => line,
+        };
+        let line = match &self.zygosity {
+            Some(zygosity) => format!(
+                "{};zygosity_hint={};support_fraction={:.2}",
+                line,
+                zygosity.zygosity.name(),
+                zygosity.support_fraction
+            ),
+            None => line,
+        };
+        format!(
+            "{}{}{}{}",
+            line,
+            annotation_gff3_attributes(&self.annotation),
+            overlapping_calls_gff3_attribute(&self.overlapping_calls),
+            neighbor_gff3_attributes(&self.nearest_neighbor)
+        )
+    }
+    // get the TSV fields for the insertion
+    pub fn to_data(&self) -> Data {
+        let mut data = tsv_data(
+            &self.chrom,
+            self.get_coords(),
+            &self.orientation,
+            &self.name,
+            self.upstream_reads.len(),
+            self.downstream_reads.len(),
+            "non-reference",
+        );
+        insert_fragments(&mut data, &Insertion::NonReference(self));
+        insert_breakpoint_ranges(
+            &mut data,
+            &self.upstream_breakpoint_range,
+            &self.downstream_breakpoint_range,
+        );
+        if let Some(tsd_sequence) = &self.tsd_sequence {
+            data.insert("TSD_SEQUENCE", tsd_sequence.clone());
+        }
+        if let Some(zygosity) = &self.zygosity {
+            data.insert("ZYGOSITY", zygosity.zygosity.name().to_owned());
+        }
+        insert_annotation(&mut data, &self.annotation);
+        insert_overlapping_calls(&mut data, &self.overlapping_calls);
+        insert_neighbor(&mut data, &self.nearest_neighbor);
+        return data;
+    }
+    pub fn to_vcf(&self, id: &str) -> String {
+        let line = vcf_line(
+            id,
+            &self.chrom,
+            self.get_coords(),
+            &self.orientation,
+            &self.name,
+            self.upstream_reads.len(),
+            self.downstream_reads.len(),
+            "non-reference",
+        );
+        // a TSD can be empty (when the 2 ends of the insertion are next to each other)
+        let line = match &self.tsd_sequence {
+            Some(tsd_sequence) if !tsd_sequence.is_empty() => {
+                format!("{};TSD_SEQ={}", line, tsd_sequence)
+            }
+            _ => line,
+        };
+        let line = match &self.zygosity {
+            Some(zygosity) => format!(
+                "{};ZYGOSITY_HINT={};SUPPORT_FRACTION={:.2}",
+                line,
+                zygosity.zygosity.name(),
+                zygosity.support_fraction
+            ),
+            None => line,
+        };
+        format!(
+            "{}{}{}{}",
+            line,
+            annotation_vcf_info(&self.annotation),
+            overlapping_calls_vcf_info(&self.overlapping_calls),
+            neighbor_vcf_info(&self.nearest_neighbor)
+        )
+    }
 }
 
 // how to display a non-reference TE by default
@@ -118,9 +668,71 @@ pub struct RefTE {
     pub orientation: Orientation,
     pub upstream_reads: Vec<SplitReadRanges>,
     pub downstream_reads: Vec<SplitReadRanges>,
+    // where the insertion is relative to the genes (only filled in with --annotation)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub annotation: Option<AnnotationOverlap>,
+    // the calls of the other insertion type and the same family that overlap this one, as "name@start-end"
+    // (only filled in with --reconcile-overlaps, see select_alignments::reconcile_overlapping_calls)
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub overlapping_calls: Vec<String>,
+    // the number of distinct fragments (reads or read pairs) among the upstream and downstream split-reads
+    // (only filled in with --count-fragments, see select_alignments::count_fragments)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub upstream_fragments: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub downstream_fragments: Option<usize>,
+    // the range of the boundaries of the upstream and downstream split-reads
+    // (only filled in with --breakpoint-ranges, see select_alignments::add_breakpoint_ranges)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub upstream_breakpoint_range: Option<BreakpointRange>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub downstream_breakpoint_range: Option<BreakpointRange>,
+    // the nearest other insertion on the same chromosome (None if there is none)
+    // (only filled in with --annotate-neighbors, see select_alignments::annotate_neighbors)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nearest_neighbor: Option<Neighbor>,
 }
 
 impl RefTE {
+    // an insertion without split-reads, with none of the optional annotations filled in
+    pub fn new(
+        name: String,
+        chrom: String,
+        upstream_pos: u64,
+        downstream_pos: u64,
+        orientation: Orientation,
+    ) -> RefTE {
+        return RefTE {
+            name,
+            chrom,
+            upstream_pos,
+            downstream_pos,
+            orientation,
+            upstream_reads: Vec::new(),
+            downstream_reads: Vec::new(),
+            annotation: None,
+            overlapping_calls: Vec::new(),
+            upstream_fragments: None,
+            downstream_fragments: None,
+            upstream_breakpoint_range: None,
+            downstream_breakpoint_range: None,
+            nearest_neighbor: None,
+        };
+    }
+    // the order of the insertions within a chromosome
+    pub fn sort_key(&self) -> (u64, u64, &str, &Orientation) {
+        (
+            self.upstream_pos,
+            self.downstream_pos,
+            &self.name,
+            &self.orientation,
+        )
+    }
+    // are the ends in the order of a reference insertion? (upstream_pos is the lesser one,
+    // and a one-sided insertion has both ends at the same position)
+    pub fn positions_ordered(&self) -> bool {
+        self.upstream_pos <= self.downstream_pos
+    }
     // get which nucleotides are in the tsd from a RefTE struct
     fn get_coords(&self) -> TSDCoords {
         // one-based fully-closed
@@ -136,6 +748,67 @@ impl RefTE {
         };
         */
     }
+    // get the GFF3 feature for the insertion (the feature spans the transposon in the reference)
+    pub fn to_gff3(&self, id: &str) -> String {
+        let line = gff3_line(
+            id,
+            &self.chrom,
+            self.get_coords(),
+            &self.orientation,
+            &self.name,
+            self.upstream_reads.len(),
+            self.downstream_reads.len(),
+            "reference",
+        );
+        format!(
+            "{}{}{}{}",
+            line,
+            annotation_gff3_attributes(&self.annotation),
+            overlapping_calls_gff3_attribute(&self.overlapping_calls),
+            neighbor_gff3_attributes(&self.nearest_neighbor)
+        )
+    }
+    // get the TSV fields for the insertion
+    pub fn to_data(&self) -> Data {
+        let mut data = tsv_data(
+            &self.chrom,
+            self.get_coords(),
+            &self.orientation,
+            &self.name,
+            self.upstream_reads.len(),
+            self.downstream_reads.len(),
+            "reference",
+        );
+        insert_fragments(&mut data, &Insertion::Reference(self));
+        insert_breakpoint_ranges(
+            &mut data,
+            &self.upstream_breakpoint_range,
+            &self.downstream_breakpoint_range,
+        );
+        insert_annotation(&mut data, &self.annotation);
+        insert_overlapping_calls(&mut data, &self.overlapping_calls);
+        insert_neighbor(&mut data, &self.nearest_neighbor);
+        return data;
+    }
+    pub fn to_vcf(&self, id: &str) -> String {
+        let line = vcf_line(
+            id,
+            &self.chrom,
+            self.get_coords(),
+            &self.orientation,
+            &self.name,
+            self.upstream_reads.len(),
+            self.downstream_reads.len(),
+            "reference",
+        );
+        format!(
+            "{}{}{}{}",
+            line,
+            annotation_vcf_info(&self.annotation),
+            overlapping_calls_vcf_info(&self.overlapping_calls),
+            neighbor_vcf_info(&self.nearest_neighbor)
+        )
+    }
 }
 
 // how to display a non-reference TE by default
@@ -179,4 +852,168 @@ impl Display for RefTE {
 pub struct OutputInsertions {
     pub non_reference: Vec<NonRefTE>,
     pub reference: Vec<RefTE>,
+    // which reference genome the insertions were found in
+    // (only set when mapping against more than 1 reference genome)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reference_source: Option<String>,
+}
+
+impl OutputInsertions {
+    // all of the insertions (the non-reference insertions first)
+    pub fn insertions(&self) -> Vec<Insertion<'_>> {
+        self.non_reference
+            .iter()
+            .map(Insertion::NonReference)
+            .chain(self.reference.iter().map(Insertion::Reference))
+            .collect()
+    }
+    // the chromosome of the insertions (None if there are none)
+    pub fn chrom(&self) -> Option<&str> {
+        match self.non_reference.first() {
+            Some(insertion) => Some(&insertion.chrom),
+            None => self.reference.first().map(|insertion| &insertion.chrom[..]),
+        }
+    }
+    // drop the split-reads of the insertions once they are written (see OutputStream),
+    // since they take most of the memory, and only the insertions themselves are needed
+    // for the counts at the end of the run
+    pub fn drop_reads(&mut self) {
+        for insertion in &mut self.non_reference {
+            insertion.upstream_reads = Vec::new();
+            insertion.downstream_reads = Vec::new();
+        }
+        for insertion in &mut self.reference {
+            insertion.upstream_reads = Vec::new();
+            insertion.downstream_reads = Vec::new();
+        }
+    }
+}
+
+// a non-reference or reference insertion, so that both kinds can be written in a single order
+pub enum Insertion<'a> {
+    NonReference(&'a NonRefTE),
+    Reference(&'a RefTE),
+}
+
+impl<'a> Insertion<'a> {
+    pub fn chrom(&self) -> &'a str {
+        match self {
+            Insertion::NonReference(insertion) => &insertion.chrom,
+            Insertion::Reference(insertion) => &insertion.chrom,
+        }
+    }
+    pub fn name(&self) -> &'a str {
+        match self {
+            Insertion::NonReference(insertion) => &insertion.name,
+            Insertion::Reference(insertion) => &insertion.name,
+        }
+    }
+    // the start and end of the insertion (in the coordinate system of the output)
+    pub fn coords(&self) -> (u64, u64) {
+        let coords = match self {
+            Insertion::NonReference(insertion) => insertion.get_coords(),
+            Insertion::Reference(insertion) => insertion.get_coords(),
+        };
+        match coords {
+            TSDCoords::OneBasedFullyClosed { start_pos, end_pos } => (start_pos, end_pos),
+            TSDCoords::ZeroBasedHalfOpen { start_pos, end_pos } => (start_pos, end_pos),
+        }
+    }
+    // the upstream and downstream split-reads
+    pub fn reads(&self) -> (&'a [SplitReadRanges], &'a [SplitReadRanges]) {
+        match self {
+            Insertion::NonReference(insertion) => {
+                (&insertion.upstream_reads, &insertion.downstream_reads)
+            }
+            Insertion::Reference(insertion) => {
+                (&insertion.upstream_reads, &insertion.downstream_reads)
+            }
+        }
+    }
+    // the values of the read evidence FORMAT fields of the VCF output (see --vcf-read-evidence):
+    // the number of split-reads of each end, the first and last of their boundaries,
+    // and every distinct boundary (the candidate positions of the end)
+    pub fn vcf_read_evidence(&self) -> String {
+        let (upstream_reads, downstream_reads) = self.reads();
+        let upstream_boundaries = read_boundaries(upstream_reads);
+        let downstream_boundaries = read_boundaries(downstream_reads);
+        format!(
+            "{}:{}:{}:{}:{}:{}",
+            upstream_reads.len(),
+            downstream_reads.len(),
+            vcf_boundary_range(&upstream_boundaries),
+            vcf_boundary_range(&downstream_boundaries),
+            vcf_list(&upstream_boundaries),
+            vcf_list(&downstream_boundaries),
+        )
+    }
+    // the number of distinct fragments that support the upstream and downstream ends
+    // (the number of split-reads unless the fragments were counted, see --count-fragments)
+    pub fn fragments(&self) -> (usize, usize) {
+        let (upstream_fragments, downstream_fragments) = match self {
+            Insertion::NonReference(insertion) => {
+                (insertion.upstream_fragments, insertion.downstream_fragments)
+            }
+            Insertion::Reference(insertion) => {
+                (insertion.upstream_fragments, insertion.downstream_fragments)
+            }
+        };
+        let (upstream_reads, downstream_reads) = self.reads();
+        (
+            upstream_fragments.unwrap_or(upstream_reads.len()),
+            downstream_fragments.unwrap_or(downstream_reads.len()),
+        )
+    }
+    pub fn to_data(&self) -> Data {
+        match self {
+            Insertion::NonReference(insertion) => insertion.to_data(),
+            Insertion::Reference(insertion) => insertion.to_data(),
+        }
+    }
+    pub fn to_gff3(&self, id: &str) -> String {
+        match self {
+            Insertion::NonReference(insertion) => insertion.to_gff3(id),
+            Insertion::Reference(insertion) => insertion.to_gff3(id),
+        }
+    }
+    pub fn to_vcf(&self, id: &str) -> String {
+        match self {
+            Insertion::NonReference(insertion) => insertion.to_vcf(id),
+            Insertion::Reference(insertion) => insertion.to_vcf(id),
+        }
+    }
+    // a line of the McClintock BED output (see select_alignments::OutputFormat::McClintock):
+    // chromosome, 0-based start, end (the TSD of a non-reference insertion, the element of a
+    // reference insertion), "<family>_<non-reference|reference>_<insertion_num>", score 0 and strand
+    // (the family is the consensus the reads aligned to, e.g. "roo" for "roo#LTR/Bel-Pao", as in McClintock)
+    pub fn to_mcclintock_bed(&self, insertion_num: usize) -> String {
+        let (coords, orientation, insertion_type) = match self {
+            Insertion::NonReference(insertion) => (
+                insertion.get_coords(),
+                &insertion.orientation,
+                "non-reference",
+            ),
+            Insertion::Reference(insertion) => {
+                (insertion.get_coords(), &insertion.orientation, "reference")
+            }
+        };
+        let (start_pos, end_pos) = match coords {
+            TSDCoords::OneBasedFullyClosed { start_pos, end_pos } => (start_pos - 1, end_pos),
+            TSDCoords::ZeroBasedHalfOpen { start_pos, end_pos } => (start_pos, end_pos),
+        };
+        let strand = match orientation {
+            Orientation::PlusPlus => "+",
+            Orientation::PlusMinus => "-",
+        };
+        format!(
+            "{}\t{}\t{}\t{}_{}_{}\t0\t{}",
+            self.chrom(),
+            start_pos,
+            end_pos,
+            TeName::parse(self.name()).element,
+            insertion_type,
+            insertion_num,
+            strand
+        )
+    }
 }
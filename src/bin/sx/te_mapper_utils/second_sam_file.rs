@@ -1,13 +1,24 @@
-use anyhow::Result;
 use lazy_static::lazy_static;
 
+use rayon::prelude::*;
+
 use std::collections::{BinaryHeap, HashMap};
 use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
 
+use super::diagnostics::{Diagnostics, Stage};
+use super::first_sam_file;
 use super::genome_alignment::{GenomeAlignment, SplitReadGenome};
+use super::long_read;
+use super::select_alignments::{InsertionTypes, Region};
+use crate::errors::Result;
 use crate::tabular::Metadata;
+use crate::utils;
+use crate::utils::IO_BUFFER_CAPACITY;
+
+// the smallest byte range that is parsed on its own thread
+const MIN_CHUNK_SIZE: u64 = 1 << 22;
 
 lazy_static! {
     static ref SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA: Metadata = {
@@ -36,98 +47,798 @@ lazy_static! {
     };
 }
 
-pub fn skip_all_comments(reader: &mut BufReader<File>) {
+// the metadata for the genome alignments, with the columns moved according to the SAM layout
+// (the TE alignment info encoded in the QNAME is not affected)
+pub fn genome_alignment_metadata(sam_layout: &HashMap<String, usize>) -> Metadata {
+    SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA.with_positions(sam_layout)
+}
+
+pub fn skip_all_comments<R: BufRead>(reader: &mut R) -> Result<()> {
     // skips all comments and positions the buffered reader on the first line that is an alignment
+    // (or at the end of a file without alignments)
     // read the file line by line
-    // get rid of comments (comments in the SAM file start with "@SQ")
-    // and ignore the last comment line (starts with "@PG")
-    let mut read_line;
-
-    loop {
-        read_line = String::new();
-        reader.read_line(&mut read_line).unwrap();
-        if read_line.chars().nth(1).unwrap() == 'P' {
-            break;
-        }
+    // get rid of comments (header lines in the SAM file start with "@")
+    while first_sam_file::at_header_line(reader)? {
+        utils::read_line_trimmed(reader)?;
     }
+    return Ok(());
 }
 
+// contig_renames maps the contig names of the alignment file to the names in chroms (e.g. "chr2L" to "2L"),
+// and is applied before the chromosome is validated
 pub fn read_genome_alignment(
     alignment_str: String,
+    genome_alignment_metadata: &Metadata,
     chroms: &Vec<String>,
+    contig_renames: &HashMap<String, String>,
+    min_clip: u64,
 ) -> Result<(String, GenomeAlignment)> {
-    let genome_alignment_data = SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA.read(alignment_str);
+    let mut genome_alignment_data = genome_alignment_metadata.try_read(&alignment_str)?;
+    if let Some(new_name) = contig_renames.get(&genome_alignment_data.get("RNAME")?) {
+        genome_alignment_data.insert("RNAME", new_name.clone());
+    }
     let te_alignment_data =
-        SECOND_SAM_FILE_TE_ALIGNMENT_METADATA.read(genome_alignment_data.get("QNAME")?);
-    return GenomeAlignment::create(genome_alignment_data, te_alignment_data, chroms);
+        SECOND_SAM_FILE_TE_ALIGNMENT_METADATA.try_read(&genome_alignment_data.get("QNAME")?)?;
+    return GenomeAlignment::create(genome_alignment_data, te_alignment_data, chroms, min_clip);
 }
 
-pub fn read_all_alignments_into_bin_heaps(
-    reader: &mut BufReader<File>,
+// the genome alignments of a record: a single split-read, or the flanks of every insertion
+// that a long read spans (the selected reads of --long-reads are marked in their names, see long_read)
+pub fn read_genome_alignments(
+    alignment_str: String,
+    genome_alignment_metadata: &Metadata,
     chroms: &Vec<String>,
-) -> HashMap<String, (BinaryHeap<GenomeAlignment>, BinaryHeap<GenomeAlignment>)> {
-    // return a map between chromosomes and their non-ref alignments and ref alignments
-    let mut sorted_result: HashMap<
-        String,
-        (BinaryHeap<GenomeAlignment>, BinaryHeap<GenomeAlignment>),
-    > = HashMap::new();
-    // read into a vector first and then convert to a binary heap
-    // for O(n) performance compared to O(n log n) for inserting elements 1 by 1
-    let mut unsorted_result: HashMap<String, (Vec<GenomeAlignment>, Vec<GenomeAlignment>)> =
-        HashMap::new();
+    contig_renames: &HashMap<String, String>,
+    min_clip: u64,
+) -> Result<Vec<(String, GenomeAlignment)>> {
+    let mut genome_alignment_data = genome_alignment_metadata.try_read(&alignment_str)?;
+    let te_alignment_data =
+        SECOND_SAM_FILE_TE_ALIGNMENT_METADATA.try_read(&genome_alignment_data.get("QNAME")?)?;
+    if te_alignment_data.get("OLD_SM")? != long_read::LONG_READ_MARKER {
+        return Ok(vec![read_genome_alignment(
+            alignment_str,
+            genome_alignment_metadata,
+            chroms,
+            contig_renames,
+            min_clip,
+        )?]);
+    }
+    if let Some(new_name) = contig_renames.get(&genome_alignment_data.get("RNAME")?) {
+        genome_alignment_data.insert("RNAME", new_name.clone());
+    }
+    return GenomeAlignment::create_long_read(
+        genome_alignment_data,
+        te_alignment_data,
+        &alignment_str,
+        chroms,
+        contig_renames,
+    );
+}
+
+// a map between chromosomes and their non-ref alignments and ref alignments
+pub type BinHeaps = HashMap<String, (BinaryHeap<GenomeAlignment>, BinaryHeap<GenomeAlignment>)>;
 
+// the alignments of each chromosome before they are put into binary heaps
+type UnsortedAlignments = HashMap<String, (Vec<GenomeAlignment>, Vec<GenomeAlignment>)>;
+
+// read the alignments that start before byte "end" (relative to the reader's current position)
+// into per-chromosome vectors (the non-ref alignments and ref alignments are kept separate)
+#[allow(clippy::too_many_arguments)]
+fn read_alignments_into_vectors<R: BufRead>(
+    reader: &mut R,
+    end: u64,
+    genome_alignment_metadata: &Metadata,
+    chroms: &Vec<String>,
+    contig_renames: &HashMap<String, String>,
+    min_clip: u64,
+    insertion_types: InsertionTypes,
+    region: Option<&Region>,
+    diagnostics: &Diagnostics,
+) -> Result<UnsortedAlignments> {
+    let mut unsorted_result: UnsortedAlignments = HashMap::new();
     for chrom in chroms {
         unsorted_result.insert(chrom.clone(), (Vec::new(), Vec::new()));
     }
 
-    // read in all alignments into unsorted result
-    let mut genome_aligned_read;
+    let mut position: u64 = 0;
 
-    loop {
-        genome_aligned_read = String::new();
-        match reader.read_line(&mut genome_aligned_read) {
-            Err(_) => panic!("Something went wrong - unable to read file"),
-            Ok(0) => break,
-            Ok(_) => (),
-        }
-        if let Ok((chrom, alignment)) = read_genome_alignment(genome_aligned_read, chroms) {
+    while position < end {
+        let genome_aligned_read = match utils::read_line_with_length(reader)? {
+            Some((genome_aligned_read, num_bytes)) => {
+                position += num_bytes as u64;
+                genome_aligned_read
+            }
+            None => break,
+        };
+        let alignments = match read_genome_alignments(
+            genome_aligned_read,
+            genome_alignment_metadata,
+            chroms,
+            contig_renames,
+            min_clip,
+        ) {
+            Ok(alignments) => alignments,
+            Err(e) => {
+                diagnostics.record_error(Stage::GenomeAlignment, &e);
+                continue;
+            }
+        };
+        for (chrom, alignment) in alignments {
+            if let Some(region) = region {
+                if !region.contains(&chrom, alignment.get_boundary_nt()) {
+                    continue;
+                }
+            }
+            // the alignments of a type of insertion that isn't called are not kept
             match alignment.split_read_genome {
                 // ref
                 SplitReadGenome::M(_) => {
-                    unsorted_result.get_mut(&chrom).unwrap().1.push(alignment);
+                    if insertion_types.reference {
+                        unsorted_result.get_mut(&chrom).unwrap().1.push(alignment);
+                    }
                 }
                 // non-ref
                 _ => {
-                    unsorted_result.get_mut(&chrom).unwrap().0.push(alignment);
+                    if insertion_types.non_reference {
+                        unsorted_result.get_mut(&chrom).unwrap().0.push(alignment);
+                    }
                 }
             }
         }
     }
+    return Ok(unsorted_result);
+}
 
-    // sort the result
+// convert the per-chromosome vectors into binary heaps
+// (this is O(n) compared to O(n log n) for inserting elements 1 by 1)
+fn into_bin_heaps(mut unsorted_result: UnsortedAlignments, chroms: &Vec<String>) -> BinHeaps {
+    let mut sorted_result: BinHeaps = HashMap::new();
     for chrom in chroms {
         let (unsorted_nonref, unsorted_ref) = unsorted_result.remove(chrom).unwrap();
         let sorted_nonref = BinaryHeap::from(unsorted_nonref);
         let sorted_ref = BinaryHeap::from(unsorted_ref);
         sorted_result.insert(chrom.clone(), (sorted_nonref, sorted_ref));
     }
-
     return sorted_result;
 }
 
+// the single-threaded reader for readers that can't seek: the output of bwa mem with --stream
+// (the SAM files use read_all_alignments_into_bin_heaps_parallel) and the in-memory SAM files of the tests
+#[allow(clippy::too_many_arguments)]
+pub fn read_all_alignments_into_bin_heaps<R: BufRead>(
+    reader: &mut R,
+    chroms: &Vec<String>,
+    sam_layout: &HashMap<String, usize>,
+    contig_renames: &HashMap<String, String>,
+    min_clip: u64,
+    insertion_types: InsertionTypes,
+    region: Option<&Region>,
+    diagnostics: &Diagnostics,
+) -> Result<BinHeaps> {
+    // the columns of the genome alignments can be moved by the SAM layout
+    let genome_alignment_metadata = genome_alignment_metadata(sam_layout);
+    skip_all_comments(reader)?;
+    // return a map between chromosomes and their non-ref alignments and ref alignments
+    let unsorted_result = read_alignments_into_vectors(
+        reader,
+        std::u64::MAX,
+        &genome_alignment_metadata,
+        chroms,
+        contig_renames,
+        min_clip,
+        insertion_types,
+        region,
+        diagnostics,
+    )?;
+    return Ok(into_bin_heaps(unsorted_result, chroms));
+}
+
+// parse the alignments between alignments_start and file_size in chunks of chunk_size bytes
+// and merge the per-chromosome vectors of the chunks
+#[allow(clippy::too_many_arguments)]
+fn read_chunks(
+    genome_aligned_path: &Path,
+    alignments_start: u64,
+    file_size: u64,
+    chunk_size: u64,
+    genome_alignment_metadata: &Metadata,
+    chroms: &Vec<String>,
+    contig_renames: &HashMap<String, String>,
+    min_clip: u64,
+    insertion_types: InsertionTypes,
+    region: Option<&Region>,
+    diagnostics: &Diagnostics,
+) -> Result<UnsortedAlignments> {
+    let chunk_starts: Vec<u64> = (alignments_start..file_size)
+        .step_by(chunk_size as usize)
+        .collect();
+    let unsorted_chunks: Vec<UnsortedAlignments> = chunk_starts
+        .par_iter()
+        .map(|&chunk_start| {
+            let chunk_end = std::cmp::min(chunk_start + chunk_size, file_size);
+            let mut reader =
+                BufReader::with_capacity(IO_BUFFER_CAPACITY, File::open(genome_aligned_path)?);
+            // start on the first line that begins at or after chunk_start
+            // (a line that begins before chunk_start belongs to the previous chunk)
+            let mut line_start = chunk_start;
+            if chunk_start > alignments_start {
+                reader.seek(SeekFrom::Start(chunk_start - 1))?;
+                let mut partial_line = Vec::new();
+                let num_bytes = reader.read_until(b'\n', &mut partial_line)?;
+                line_start = chunk_start - 1 + num_bytes as u64;
+            } else {
+                reader.seek(SeekFrom::Start(chunk_start))?;
+            }
+            read_alignments_into_vectors(
+                &mut reader,
+                chunk_end.saturating_sub(line_start),
+                genome_alignment_metadata,
+                chroms,
+                contig_renames,
+                min_clip,
+                insertion_types,
+                region,
+                diagnostics,
+            )
+        })
+        .collect::<Result<_>>()?;
+
+    // merge the chunks
+    let mut unsorted_result: UnsortedAlignments = HashMap::new();
+    for chrom in chroms {
+        unsorted_result.insert(chrom.clone(), (Vec::new(), Vec::new()));
+    }
+    for mut unsorted_chunk in unsorted_chunks {
+        for chrom in chroms {
+            let (chunk_nonref, chunk_ref) = unsorted_chunk.remove(chrom).unwrap();
+            let (nonref, reference) = unsorted_result.get_mut(chrom).unwrap();
+            nonref.extend(chunk_nonref);
+            reference.extend(chunk_ref);
+        }
+    }
+    return Ok(unsorted_result);
+}
+
+// the same as read_all_alignments_into_bin_heaps, but the file is split into byte ranges
+// (aligned to line boundaries) which are parsed on the rayon thread pool
+// this is the slowest part of phase 4 on large SAM files
+#[allow(clippy::too_many_arguments)]
+pub fn read_all_alignments_into_bin_heaps_parallel(
+    genome_aligned_path: &Path,
+    chroms: &Vec<String>,
+    sam_layout: &HashMap<String, usize>,
+    contig_renames: &HashMap<String, String>,
+    min_clip: u64,
+    insertion_types: InsertionTypes,
+    region: Option<&Region>,
+    diagnostics: &Diagnostics,
+) -> Result<BinHeaps> {
+    let genome_alignment_metadata = genome_alignment_metadata(sam_layout);
+
+    // the alignments start right after the header
+    let mut header_reader =
+        BufReader::with_capacity(IO_BUFFER_CAPACITY, File::open(genome_aligned_path)?);
+    skip_all_comments(&mut header_reader)?;
+    let alignments_start = header_reader.stream_position()?;
+    let file_size = std::fs::metadata(genome_aligned_path)?.len();
+
+    // a few chunks per thread so that the threads stay busy even if some chunks parse faster,
+    // but not so many that small files are split into tiny chunks
+    let num_chunks = (rayon::current_num_threads() * 4) as u64;
+    let chunk_size = std::cmp::max(
+        (file_size - alignments_start) / num_chunks + 1,
+        MIN_CHUNK_SIZE,
+    );
+    let unsorted_result = read_chunks(
+        genome_aligned_path,
+        alignments_start,
+        file_size,
+        chunk_size,
+        &genome_alignment_metadata,
+        chroms,
+        contig_renames,
+        min_clip,
+        insertion_types,
+        region,
+        diagnostics,
+    )?;
+
+    return Ok(into_bin_heaps(unsorted_result, chroms));
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
-    use std::io::{BufReader, BufWriter, Write};
+    use std::io::{BufReader, BufWriter, Cursor, Write};
 
+    use super::super::family_params::FamilyParams;
+    use super::super::output_data_types::Orientation;
     use super::super::{first_sam_file, genome_alignment::GenomeAlignment};
     use super::*;
+    use crate::errors::StanexError;
+
+    // a genome alignment file with a non-reference roo insertion on 2L,
+    // a reference jockey insertion on 3R, an unmapped read, 2 placeholder records
+    // with "*" in RNAME or CIGAR, and a read on an unused contig
+    const GENOME_ALIGNED_SAM: &str = "@SQ\tSN:2L\tLN:23000000\n\
+        @SQ\tSN:3R\tLN:32000000\n\
+        @PG\tID:bwa\tPN:bwa\n\
+        a1|roo|50|100|SM|start\t0\t2L\t906\t60\t100M50S\t*\t0\t0\t*\t*\n\
+        a2|roo|50|100|SM|start\t0\t2L\t906\t60\t100M50S\t*\t0\t0\t*\t*\n\
+        a3|roo|50|100|MS|end\t0\t2L\t1000\t60\t50S100M\t*\t0\t0\t*\t*\n\
+        b1|jockey|30|120|SM|start\t0\t3R\t4870\t60\t150M\t*\t0\t0\t*\t*\n\
+        b2|jockey|30|120|MS|end\t0\t3R\t9971\t60\t150M\t*\t0\t0\t*\t*\n\
+        u1|roo|30|120|MS|end\t4\t*\t0\t60\t*\t*\t0\t0\t*\t*\n\
+        p1|roo|30|120|MS|end\t0\t*\t0\t0\t*\t*\t0\t0\t*\t*\n\
+        p2|roo|30|120|MS|end\t0\t2L\t900\t0\t*\t*\t0\t0\t*\t*\n\
+        c1|roo|50|100|SM|start\t0\tchrUn\t906\t60\t100M50S\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_insertion_creation_from_memory() {
+        let mut transposons_map = HashMap::new();
+        transposons_map.insert("roo".to_owned(), 9092);
+        transposons_map.insert("jockey".to_owned(), 5000);
+        let chroms = vec!["2L".to_owned(), "3R".to_owned()];
+
+        let mut second_sam_file_reader = Cursor::new(GENOME_ALIGNED_SAM);
+        skip_all_comments(&mut second_sam_file_reader).unwrap();
+        let diagnostics = Diagnostics::default();
+        let mut bin_heaps = read_all_alignments_into_bin_heaps(
+            &mut second_sam_file_reader,
+            &chroms,
+            &HashMap::new(),
+            &HashMap::new(),
+            0,
+            InsertionTypes::ALL,
+            None,
+            &diagnostics,
+        )
+        .unwrap();
+        // the unmapped read, the placeholder records and the read on chrUn are skipped
+        assert_eq!(bin_heaps["2L"].0.len(), 3);
+        assert_eq!(bin_heaps["2L"].1.len(), 0);
+        assert_eq!(bin_heaps["3R"].0.len(), 0);
+        assert_eq!(bin_heaps["3R"].1.len(), 2);
+        assert_eq!(
+            diagnostics.summary().unwrap(),
+            "genome alignments: 1 skipped: contig not in list; 2 skipped: no position; \
+             1 skipped: unmapped; 1 contigs not in list"
+        );
+
+        let non_ref_insertions = GenomeAlignment::get_non_ref_tes(
+            &mut bin_heaps.get_mut("2L").unwrap().0,
+            0,
+            100,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &HashMap::new(),
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(non_ref_insertions.len(), 1);
+        assert_eq!(non_ref_insertions[0].name, "roo");
+        assert_eq!(non_ref_insertions[0].downstream_pos, 1000);
+        assert_eq!(non_ref_insertions[0].upstream_pos, 1005);
+        assert_eq!(non_ref_insertions[0].upstream_reads.len(), 2);
+        assert_eq!(non_ref_insertions[0].downstream_reads.len(), 1);
+
+        let ref_insertions = GenomeAlignment::get_ref_tes(
+            &mut bin_heaps.get_mut("3R").unwrap().1,
+            0.1,
+            1.5,
+            &FamilyParams::default(),
+            &transposons_map,
+            &"3R".to_owned(),
+            &HashMap::new(),
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(ref_insertions.len(), 1);
+        assert_eq!(ref_insertions[0].name, "jockey");
+        assert_eq!(ref_insertions[0].upstream_pos, 4990);
+        assert_eq!(ref_insertions[0].downstream_pos, 10000);
+    }
+
+    #[test]
+    fn test_insertion_types() {
+        let chroms = vec!["2L".to_owned(), "3R".to_owned()];
+        let read_bin_heaps = |insertion_types: InsertionTypes| {
+            let mut second_sam_file_reader = Cursor::new(GENOME_ALIGNED_SAM);
+            skip_all_comments(&mut second_sam_file_reader).unwrap();
+            read_all_alignments_into_bin_heaps(
+                &mut second_sam_file_reader,
+                &chroms,
+                &HashMap::new(),
+                &HashMap::new(),
+                0,
+                insertion_types,
+                None,
+                &Diagnostics::default(),
+            )
+            .unwrap()
+        };
+        // the alignments of the type of insertion that isn't called are not kept
+        let non_ref_only = read_bin_heaps(InsertionTypes {
+            non_reference: true,
+            reference: false,
+        });
+        assert_eq!(non_ref_only["2L"].0.len(), 3);
+        assert_eq!(non_ref_only["3R"].1.len(), 0);
+        let ref_only = read_bin_heaps(InsertionTypes {
+            non_reference: false,
+            reference: true,
+        });
+        assert_eq!(ref_only["2L"].0.len(), 0);
+        assert_eq!(ref_only["3R"].1.len(), 2);
+    }
+
+    #[test]
+    fn test_skip_all_comments() {
+        // every header line is skipped, including a second "@PG" line (e.g. from samtools)
+        let mut reader =
+            Cursor::new("@SQ\tSN:2L\tLN:23000000\n@PG\tID:bwa\n@PG\tID:samtools\nPa1\t0\t2L\n");
+        skip_all_comments(&mut reader).unwrap();
+        let mut next_line = String::new();
+        reader.read_line(&mut next_line).unwrap();
+        assert!(next_line.starts_with("Pa1"));
+        // a file without alignments, or an empty file
+        for input in &["@SQ\tSN:2L\tLN:23000000\n@PG\tID:bwa\n", ""] {
+            let mut reader = Cursor::new(*input);
+            skip_all_comments(&mut reader).unwrap();
+            assert_eq!(reader.position(), input.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_region() {
+        let chroms = vec!["2L".to_owned(), "3R".to_owned()];
+        let read_bin_heaps = |region: &str| {
+            let mut second_sam_file_reader = Cursor::new(GENOME_ALIGNED_SAM);
+            skip_all_comments(&mut second_sam_file_reader).unwrap();
+            read_all_alignments_into_bin_heaps(
+                &mut second_sam_file_reader,
+                &chroms,
+                &HashMap::new(),
+                &HashMap::new(),
+                0,
+                InsertionTypes::ALL,
+                Some(&Region::parse(region).unwrap()),
+                &Diagnostics::default(),
+            )
+            .unwrap()
+        };
+        let roo_only = read_bin_heaps("2L:1-2000");
+        assert_eq!(roo_only["2L"].0.len(), 3);
+        assert_eq!(roo_only["3R"].1.len(), 0);
+        // only the upstream breakpoint of the reference jockey insertion is in the region
+        let jockey_start = read_bin_heaps("3R:4000-5000");
+        assert_eq!(jockey_start["2L"].0.len(), 0);
+        assert_eq!(jockey_start["3R"].1.len(), 1);
+    }
+
+    #[test]
+    fn test_placeholder_fields() {
+        let chroms = vec!["2L".to_owned()];
+        for input in &[
+            "p1|roo|30|120|MS|end\t0\t*\t0\t0\t*\t*\t0\t0\t*\t*",
+            "p2|roo|30|120|MS|end\t0\t2L\t900\t0\t*\t*\t0\t0\t*\t*",
+            "p3|roo|30|120|MS|end\t0\t*\t900\t0\t100M50S\t*\t0\t0\t*\t*",
+        ] {
+            match read_genome_alignment(
+                input.to_string(),
+                &SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA,
+                &chroms,
+                &HashMap::new(),
+                0,
+            ) {
+                Err(StanexError::UnplacedRead(qname)) => assert!(qname.starts_with('p')),
+                other => panic!("expected an unplaced read, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_contig_renames() {
+        // a reference with UCSC-style contig names, mapped to the names in the chromosome list
+        let chroms = vec!["2L".to_owned(), "X".to_owned()];
+        let contig_renames: HashMap<String, String> = vec![("chrX".to_owned(), "X".to_owned())]
+            .into_iter()
+            .collect();
+        let (chrom, alignment) = read_genome_alignment(
+            "a1|roo|50|100|SM|start\t0\tchrX\t906\t60\t100M50S\t*\t0\t0\t*\t*".to_owned(),
+            &SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA,
+            &chroms,
+            &contig_renames,
+            0,
+        )
+        .unwrap();
+        assert_eq!(chrom, "X");
+        assert_eq!(alignment.chrom, "X");
+        // contigs without a new name are validated as they are
+        assert!(matches!(
+            read_genome_alignment(
+                "a1|roo|50|100|SM|start\t0\tchr2L\t906\t60\t100M50S\t*\t0\t0\t*\t*".to_owned(),
+                &SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA,
+                &chroms,
+                &contig_renames,
+                0,
+            ),
+            Err(StanexError::InvalidChromosome(_))
+        ));
+    }
+
+    #[test]
+    fn test_long_reads() {
+        let chroms = vec!["2L".to_owned()];
+        let read = |line: &str| {
+            read_genome_alignments(
+                line.to_owned(),
+                &SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA,
+                &chroms,
+                &HashMap::new(),
+                0,
+            )
+        };
+        // a read with 500 nt of 2L up to 1000, 1000 nt of roo, then 505 nt of 2L from 996
+        let alignments = read(
+            "l1|roo|1000|1005|LR|span\t0\t2L\t501\t60\t500M1505S\t*\t0\t0\t*\t*\t\
+             SA:Z:2L,996,+,1500S505M,60,0;",
+        )
+        .unwrap();
+        assert_eq!(alignments.len(), 2);
+        let mut heap: BinaryHeap<GenomeAlignment> = alignments
+            .into_iter()
+            .map(|(_, alignment)| alignment)
+            .collect();
+        let tes = GenomeAlignment::get_non_ref_tes(
+            &mut heap,
+            0,
+            20,
+            &FamilyParams::default(),
+            &"2L".to_owned(),
+            &HashMap::new(),
+            false,
+            0,
+            &Diagnostics::default(),
+        );
+        assert_eq!(tes.len(), 1);
+        assert_eq!((tes[0].downstream_pos, tes[0].upstream_pos), (996, 1000));
+        assert_eq!(tes[0].orientation, Orientation::PlusPlus);
+
+        // a read without a supplementary alignment on the other side of the transposon
+        assert!(matches!(
+            read("l2|roo|1000|1005|LR|span\t0\t2L\t501\t60\t500M1505S\t*\t0\t0\t*\t*"),
+            Err(StanexError::NotSpanning(_))
+        ));
+        // the supplementary alignments are read from the primary alignment's SA tag
+        assert!(matches!(
+            read("l1|roo|1000|1005|LR|span\t2048\t2L\t996\t60\t1500H505M\t*\t0\t0\t*\t*"),
+            Err(StanexError::NotPrimaryAlignment(_))
+        ));
+    }
+
+    // the same insertions on a 2100 bp chromosome and on its reverse complement, where nucleotide p
+    // becomes nucleotide 2101 - p (so the reads of the mirrored insertions are reverse-strand reads)
+    // BWA reports the SEQ and CIGAR of a reverse-strand read on the forward strand,
+    // so the boundaries of the mirrored insertion must be the mirrored boundaries
+    #[test]
+    fn test_reverse_strand_reads() {
+        let chroms = vec!["2L".to_owned()];
+        let mirror = |pos: u64| 2101 - pos;
+        let read_heaps = |alignments: &[(&str, u16, u64, &str)]| {
+            let mut heaps = (BinaryHeap::new(), BinaryHeap::new());
+            for (qname, flag, pos, cigar) in alignments {
+                let (_, alignment) = read_genome_alignment(
+                    format!(
+                        "{}\t{}\t2L\t{}\t60\t{}\t*\t0\t0\t*\t*",
+                        qname, flag, pos, cigar
+                    ),
+                    &SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA,
+                    &chroms,
+                    &HashMap::new(),
+                    0,
+                )
+                .unwrap();
+                match alignment.split_read_genome {
+                    SplitReadGenome::M(_) => heaps.1.push(alignment),
+                    _ => heaps.0.push(alignment),
+                }
+            }
+            heaps
+        };
+
+        // a non-reference roo insertion with the TSD 1001-1005:
+        // 906-1005 followed by the start of roo, and the end of roo followed by 1001-1100
+        let forward = read_heaps(&[
+            ("n1|roo|50|100|SM|start", 0, 906, "100M50S"),
+            ("n2|roo|50|100|MS|end", 0, 1001, "50S100M"),
+        ]);
+        // mirrored: the end of roo followed by 1001-1100 (reverse complemented), and
+        // the start of roo followed by 1096-1195 (reverse complemented)
+        let reverse = read_heaps(&[
+            ("n1|roo|50|100|SM|start", 16, mirror(1005), "50S100M"),
+            ("n2|roo|50|100|MS|end", 16, mirror(1100), "100M50S"),
+        ]);
+        let call_non_ref = |mut heaps: (BinaryHeap<GenomeAlignment>, _)| {
+            GenomeAlignment::get_non_ref_tes(
+                &mut heaps.0,
+                0,
+                20,
+                &FamilyParams::default(),
+                &"2L".to_owned(),
+                &HashMap::new(),
+                false,
+                0,
+                &Diagnostics::default(),
+            )
+        };
+        let forward_tes = call_non_ref(forward);
+        let reverse_tes = call_non_ref(reverse);
+        assert_eq!(forward_tes.len(), 1);
+        assert_eq!(reverse_tes.len(), 1);
+        assert_eq!(
+            (forward_tes[0].downstream_pos, forward_tes[0].upstream_pos),
+            (1001, 1005)
+        );
+        // the mirrored TSD is 1096-1100
+        assert_eq!(reverse_tes[0].downstream_pos, mirror(1005));
+        assert_eq!(reverse_tes[0].upstream_pos, mirror(1001));
+        assert_eq!(forward_tes[0].orientation, Orientation::PlusPlus);
+        assert_eq!(reverse_tes[0].orientation, Orientation::PlusMinus);
+
+        // a 100 bp reference roo insertion at 1001-1100:
+        // 951-1000 followed by the start of roo, and the end of roo followed by 1101-1150
+        let forward = read_heaps(&[
+            ("r1|roo|100|50|SM|start", 0, 951, "150M"),
+            ("r2|roo|100|50|MS|end", 0, 1001, "150M"),
+        ]);
+        // mirrored: the end of roo is at 1001 and the start of roo is at 1100
+        let reverse = read_heaps(&[
+            ("r1|roo|100|50|SM|start", 16, mirror(1100), "150M"),
+            ("r2|roo|100|50|MS|end", 16, mirror(1150), "150M"),
+        ]);
+        let te_lengths: HashMap<String, u64> = vec![("roo".to_owned(), 100)].into_iter().collect();
+        let call_ref = |mut heaps: (_, BinaryHeap<GenomeAlignment>)| {
+            GenomeAlignment::get_ref_tes(
+                &mut heaps.1,
+                0.1,
+                1.5,
+                &FamilyParams::default(),
+                &te_lengths,
+                &"2L".to_owned(),
+                &HashMap::new(),
+                false,
+                0,
+                &Diagnostics::default(),
+            )
+        };
+        let forward_tes = call_ref(forward);
+        let reverse_tes = call_ref(reverse);
+        assert_eq!(forward_tes.len(), 1);
+        assert_eq!(reverse_tes.len(), 1);
+        // the insertion covers the same nucleotides on both strands (its ends are swapped)
+        assert_eq!(
+            (forward_tes[0].upstream_pos, forward_tes[0].downstream_pos),
+            (1001, 1100)
+        );
+        assert_eq!(
+            (reverse_tes[0].upstream_pos, reverse_tes[0].downstream_pos),
+            (mirror(1100), mirror(1001))
+        );
+        assert_eq!(forward_tes[0].orientation, Orientation::PlusPlus);
+        assert_eq!(reverse_tes[0].orientation, Orientation::PlusMinus);
+        // the ranges are in the order of the genome, so the TE follows the flank upstream
+        // and precedes it downstream on both strands
+        for tes in &[forward_tes, reverse_tes] {
+            assert_eq!(tes[0].upstream_reads[0].te_range, (51, 150));
+            assert_eq!(tes[0].downstream_reads[0].te_range, (1, 100));
+        }
+    }
+
+    #[test]
+    fn test_parallel_reading_matches_serial_reading() {
+        let chroms = vec!["2L".to_owned(), "3R".to_owned()];
+        let genome_aligned_path = std::env::temp_dir().join(format!(
+            "sx_test_parallel_reading_{}.sam",
+            std::process::id()
+        ));
+        std::fs::write(&genome_aligned_path, GENOME_ALIGNED_SAM).unwrap();
+
+        // describe the heaps by the (TE name, boundary nucleotide, position) of each alignment
+        let describe = |mut bin_heaps: BinHeaps| {
+            let describe_heap = |heap: BinaryHeap<GenomeAlignment>| {
+                heap.into_sorted_vec()
+                    .iter()
+                    .map(|alignment| {
+                        (
+                            alignment.te_name.clone(),
+                            alignment.get_boundary_nt(),
+                            alignment.split_read_genome.pos(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let mut description = Vec::new();
+            for chrom in &chroms {
+                let (nonref, reference) = bin_heaps.remove(chrom).unwrap();
+                description.push((
+                    chrom.clone(),
+                    describe_heap(nonref),
+                    describe_heap(reference),
+                ));
+            }
+            description
+        };
+
+        let mut second_sam_file_reader = Cursor::new(GENOME_ALIGNED_SAM);
+        skip_all_comments(&mut second_sam_file_reader).unwrap();
+        let alignments_start = second_sam_file_reader.position();
+        let serial = describe(
+            read_all_alignments_into_bin_heaps(
+                &mut second_sam_file_reader,
+                &chroms,
+                &HashMap::new(),
+                &HashMap::new(),
+                0,
+                InsertionTypes::ALL,
+                None,
+                &Diagnostics::default(),
+            )
+            .unwrap(),
+        );
+
+        // split the file into chunks of every size (including chunks that end mid-line)
+        let file_size = GENOME_ALIGNED_SAM.len() as u64;
+        for chunk_size in 1..=(file_size - alignments_start) {
+            let unsorted_result = read_chunks(
+                &genome_aligned_path,
+                alignments_start,
+                file_size,
+                chunk_size,
+                &SECOND_SAM_FILE_GENOME_ALIGNMENT_METADATA,
+                &chroms,
+                &HashMap::new(),
+                0,
+                InsertionTypes::ALL,
+                None,
+                &Diagnostics::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                describe(into_bin_heaps(unsorted_result, &chroms)),
+                serial,
+                "chunk size {}",
+                chunk_size
+            );
+        }
+        // the public function skips the header by itself
+        assert_eq!(
+            describe(
+                read_all_alignments_into_bin_heaps_parallel(
+                    &genome_aligned_path,
+                    &chroms,
+                    &HashMap::new(),
+                    &HashMap::new(),
+                    0,
+                    InsertionTypes::ALL,
+                    None,
+                    &Diagnostics::default(),
+                )
+                .unwrap()
+            ),
+            serial
+        );
+        std::fs::remove_file(&genome_aligned_path).unwrap();
+    }
 
     #[test]
     #[ignore]
     fn test_insertion_creation() {
         let mut first_sam_file_reader = BufReader::new(File::open("test/te_aligned.sam").unwrap());
-        let transposons_map = first_sam_file::read_all_tes_into_map(&mut first_sam_file_reader);
+        let transposons_map =
+            first_sam_file::read_all_tes_into_map(&mut first_sam_file_reader).unwrap();
         let chroms = vec![
             "2L".to_owned(),
             "2R".to_owned(),
@@ -142,22 +853,41 @@ mod tests {
         let mut output_writer =
             BufWriter::new(File::create("test/TEST_SECOND_SAM_te_mapper_output.tsv").unwrap());
         output_writer.write("Chromosome\tTSD Upstream\tTSD Downstream\tOrientation\tName\t# Upstream Reads\t# Downstream Reads\tFound in Reference?\n".as_bytes()).unwrap();
-        skip_all_comments(&mut second_sam_file_reader);
-        let mut bin_heaps =
-            read_all_alignments_into_bin_heaps(&mut second_sam_file_reader, &chroms);
+        skip_all_comments(&mut second_sam_file_reader).unwrap();
+        let mut bin_heaps = read_all_alignments_into_bin_heaps(
+            &mut second_sam_file_reader,
+            &chroms,
+            &HashMap::new(),
+            &HashMap::new(),
+            0,
+            InsertionTypes::ALL,
+            None,
+            &Diagnostics::default(),
+        )
+        .unwrap();
         for chrom in chroms {
             let non_ref_insertions = GenomeAlignment::get_non_ref_tes(
                 &mut bin_heaps.get_mut(&chrom).unwrap().0,
                 0,
                 100,
+                &FamilyParams::default(),
                 &chrom,
+                &HashMap::new(),
+                false,
+                0,
+                &Diagnostics::default(),
             );
             let ref_insertions = GenomeAlignment::get_ref_tes(
                 &mut bin_heaps.get_mut(&chrom).unwrap().1,
                 0.1,
                 1.5,
+                &FamilyParams::default(),
                 &transposons_map,
                 &chrom,
+                &HashMap::new(),
+                false,
+                0,
+                &Diagnostics::default(),
             );
 
             for insertion in non_ref_insertions {
@@ -1,68 +1,2458 @@
-use path_abs::PathFile;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
 use serde_json;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ChildStdout;
 
-use super::genome_alignment::GenomeAlignment;
-use super::output_data_types::OutputInsertions;
+use super::alignment_cache::{self, AlignmentSource, CacheSettings};
+use super::annotation::Annotation;
+use super::bgzf::{self, TabixColumns};
+use super::diagnostics::{Diagnostics, Stage};
+use super::family_params::FamilyParams;
+use super::genome_alignment::{GenomeAlignment, SplitReadRanges};
+use super::indexed_fasta::{self, IndexedFasta};
+use super::output_data_types::{
+    BreakpointRange, Insertion, Neighbor, NonRefTE, Orientation, OutputInsertions, RefTE,
+    TSV_FIELDS,
+};
 use super::second_sam_file;
+use super::te_name::TeName;
+use super::zygosity::{Coverage, ZygosityThresholds};
+use crate::errors::{Result, StanexError};
+use crate::tabular::{Data, Metadata};
+use crate::utils::{self, log_error, progress, BwaOutput, CountingReader, IO_BUFFER_CAPACITY};
 
-pub fn select_alignments(
+// the supported formats for the final output file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputFormat {
+    Tsv,
+    Json,
+    Gff3,
+    Vcf,
+    Ndjson,
+    McClintock,
+}
+
+impl OutputFormat {
+    pub fn parse(format: &str) -> Option<OutputFormat> {
+        match format {
+            "tsv" => Some(OutputFormat::Tsv),
+            "json" => Some(OutputFormat::Json),
+            "gff3" => Some(OutputFormat::Gff3),
+            "vcf" => Some(OutputFormat::Vcf),
+            "ndjson" => Some(OutputFormat::Ndjson),
+            "mcclintock" => Some(OutputFormat::McClintock),
+            _ => None,
+        }
+    }
+    // the file extension of the output file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Json => "json",
+            OutputFormat::Gff3 => "gff3",
+            OutputFormat::Vcf => "vcf",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::McClintock => "bed",
+        }
+    }
+    // the columns tabix indexes (only the formats that are sorted by coordinate can be indexed)
+    pub fn tabix_columns(&self) -> Option<&'static TabixColumns> {
+        match self {
+            OutputFormat::Tsv => Some(&bgzf::TSV_COLUMNS),
+            OutputFormat::Json => None,
+            OutputFormat::Gff3 => Some(&bgzf::GFF3_COLUMNS),
+            OutputFormat::Vcf => Some(&bgzf::VCF_COLUMNS),
+            OutputFormat::Ndjson => None,
+            OutputFormat::McClintock => Some(&bgzf::BED_COLUMNS),
+        }
+    }
+    // can the output be written one chromosome at a time? (see OutputStream)
+    // the JSON output is a single array, the GFF3 and VCF outputs need every insertion
+    // for their headers, and the names of the McClintock output are numbered through the whole file,
+    // so they are written once all the insertions are called
+    pub fn can_stream(&self) -> bool {
+        match self {
+            OutputFormat::Tsv | OutputFormat::Ndjson => true,
+            OutputFormat::Json
+            | OutputFormat::Gff3
+            | OutputFormat::Vcf
+            | OutputFormat::McClintock => false,
+        }
+    }
+}
+
+// the column names of the TSV output
+// Bergman: the original descriptive header (e.g. "TSD Upstream")
+// Generic: short lowercase names for pipelines (e.g. "chrom", "start", "end")
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HeaderStyle {
+    Bergman,
+    Generic,
+}
+
+impl HeaderStyle {
+    pub fn parse(style: &str) -> Option<HeaderStyle> {
+        match style {
+            "bergman" => Some(HeaderStyle::Bergman),
+            "generic" => Some(HeaderStyle::Generic),
+            _ => None,
+        }
+    }
+    // the name of the column for a TSV field
+    fn column_name(&self, field: &str) -> &'static str {
+        match (self, field) {
+            (HeaderStyle::Bergman, "CHROM") => "Chromosome",
+            (HeaderStyle::Bergman, "START") => "TSD Upstream",
+            (HeaderStyle::Bergman, "END") => "TSD Downstream",
+            (HeaderStyle::Bergman, "ORIENTATION") => "Orientation",
+            (HeaderStyle::Bergman, "NAME") => "Name",
+            (HeaderStyle::Bergman, "UPSTREAM_READS") => "# Upstream Reads",
+            (HeaderStyle::Bergman, "DOWNSTREAM_READS") => "# Downstream Reads",
+            (HeaderStyle::Bergman, "UPSTREAM_FRAGMENTS") => "# Upstream Fragments",
+            (HeaderStyle::Bergman, "DOWNSTREAM_FRAGMENTS") => "# Downstream Fragments",
+            (HeaderStyle::Bergman, "UPSTREAM_BREAKPOINT_RANGE") => "Upstream Breakpoint Range",
+            (HeaderStyle::Bergman, "UPSTREAM_BREAKPOINT_SPREAD") => "Upstream Breakpoint Spread",
+            (HeaderStyle::Bergman, "DOWNSTREAM_BREAKPOINT_RANGE") => "Downstream Breakpoint Range",
+            (HeaderStyle::Bergman, "DOWNSTREAM_BREAKPOINT_SPREAD") => {
+                "Downstream Breakpoint Spread"
+            }
+            (HeaderStyle::Bergman, "INSERTION_TYPE") => "Found in Reference?",
+            (HeaderStyle::Bergman, "ONE_SIDED") => "One-Sided?",
+            (HeaderStyle::Bergman, "TSD_SEQUENCE") => "TSD Sequence",
+            (HeaderStyle::Bergman, "ZYGOSITY") => "Zygosity (Heuristic)",
+            (HeaderStyle::Bergman, "FEATURE") => "Feature",
+            (HeaderStyle::Bergman, "GENE_ID") => "Gene ID",
+            (HeaderStyle::Bergman, "OVERLAPPING_CALLS") => "Overlapping Calls",
+            (HeaderStyle::Bergman, "NEIGHBOR_DISTANCE") => "Nearest Neighbor Distance",
+            (HeaderStyle::Bergman, "NEIGHBOR") => "Nearest Neighbor",
+            (HeaderStyle::Bergman, "REFERENCE_SOURCE") => "Reference Source",
+            (HeaderStyle::Generic, "CHROM") => "chrom",
+            (HeaderStyle::Generic, "START") => "start",
+            (HeaderStyle::Generic, "END") => "end",
+            (HeaderStyle::Generic, "ORIENTATION") => "orientation",
+            (HeaderStyle::Generic, "NAME") => "name",
+            (HeaderStyle::Generic, "UPSTREAM_READS") => "upstream_reads",
+            (HeaderStyle::Generic, "DOWNSTREAM_READS") => "downstream_reads",
+            (HeaderStyle::Generic, "UPSTREAM_FRAGMENTS") => "upstream_fragments",
+            (HeaderStyle::Generic, "DOWNSTREAM_FRAGMENTS") => "downstream_fragments",
+            (HeaderStyle::Generic, "UPSTREAM_BREAKPOINT_RANGE") => "upstream_breakpoint_range",
+            (HeaderStyle::Generic, "UPSTREAM_BREAKPOINT_SPREAD") => "upstream_breakpoint_spread",
+            (HeaderStyle::Generic, "DOWNSTREAM_BREAKPOINT_RANGE") => "downstream_breakpoint_range",
+            (HeaderStyle::Generic, "DOWNSTREAM_BREAKPOINT_SPREAD") => {
+                "downstream_breakpoint_spread"
+            }
+            (HeaderStyle::Generic, "INSERTION_TYPE") => "insertion_type",
+            (HeaderStyle::Generic, "ONE_SIDED") => "one_sided",
+            (HeaderStyle::Generic, "TSD_SEQUENCE") => "tsd_sequence",
+            (HeaderStyle::Generic, "ZYGOSITY") => "zygosity_hint",
+            (HeaderStyle::Generic, "FEATURE") => "feature",
+            (HeaderStyle::Generic, "GENE_ID") => "gene_id",
+            (HeaderStyle::Generic, "OVERLAPPING_CALLS") => "overlapping_calls",
+            (HeaderStyle::Generic, "NEIGHBOR_DISTANCE") => "neighbor_distance",
+            (HeaderStyle::Generic, "NEIGHBOR") => "neighbor",
+            (HeaderStyle::Generic, "REFERENCE_SOURCE") => "reference_source",
+            _ => panic!("unknown TSV field: {}", field),
+        }
+    }
+    // the header row, written through the same metadata as the insertions
+    // so that the header and the rows always have the same columns
+    fn header_data(&self) -> Data {
+        let mut data = Data::new();
+        for field in TSV_FIELDS.iter() {
+            data.insert(field, self.column_name(field).to_owned());
+        }
+        return data;
+    }
+}
+
+// the optional columns of the TSV output (and fields of the VCF output) of a run
+// they are chosen from the options of the run rather than from the insertions, so that the header is the same
+// whether the output is streamed or buffered, and even if no insertion fills a column
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputColumns {
+    // there are multiple reference genomes
+    pub reference_source: bool,
+    // --tsd-sequence
+    pub tsd_sequence: bool,
+    // --coverage
+    pub zygosity: bool,
+    // --annotation
+    pub annotation: bool,
+    // --reconcile-overlaps annotate
+    pub overlapping_calls: bool,
+    // --report-one-sided
+    pub one_sided: bool,
+    // --count-fragments
+    pub fragments: bool,
+    // --breakpoint-ranges
+    pub breakpoint_ranges: bool,
+    // --annotate-neighbors
+    pub neighbors: bool,
+}
+
+// the columns of the TSV output
+// (the reference source column is only there if there are multiple reference genomes,
+// the one-sided column is only there if one-sided insertions are reported,
+// the fragment columns are only there if the fragments are counted,
+// the breakpoint range columns are only there if the breakpoint ranges were reported,
+// the TSD sequence column is only there if the TSD sequences were extracted,
+// the zygosity column is only there if the zygosity was estimated from the coverage,
+// the feature and gene ID columns are only there if the insertions were annotated,
+// the overlapping calls column is only there if the overlapping calls were annotated,
+// and the neighbor columns are only there if the nearest neighbors were annotated)
+fn tsv_metadata(columns: &OutputColumns) -> Metadata {
+    Metadata {
+        delimiter: "\t".to_string(),
+        headings: TSV_FIELDS
+            .iter()
+            .filter(|field| match **field {
+                "REFERENCE_SOURCE" => columns.reference_source,
+                "TSD_SEQUENCE" => columns.tsd_sequence,
+                "ZYGOSITY" => columns.zygosity,
+                "FEATURE" | "GENE_ID" => columns.annotation,
+                "OVERLAPPING_CALLS" => columns.overlapping_calls,
+                "ONE_SIDED" => columns.one_sided,
+                "UPSTREAM_FRAGMENTS" | "DOWNSTREAM_FRAGMENTS" => columns.fragments,
+                "UPSTREAM_BREAKPOINT_RANGE"
+                | "UPSTREAM_BREAKPOINT_SPREAD"
+                | "DOWNSTREAM_BREAKPOINT_RANGE"
+                | "DOWNSTREAM_BREAKPOINT_SPREAD" => columns.breakpoint_ranges,
+                "NEIGHBOR_DISTANCE" | "NEIGHBOR" => columns.neighbors,
+                _ => true,
+            })
+            .enumerate()
+            .map(|(index, field)| (index + 1, field.to_string()))
+            .collect(),
+    }
+}
+
+// the types of insertions that are called (--no-reference-tes and --no-nonreference-tes turn them off)
+#[derive(Clone, Copy, Debug)]
+pub struct InsertionTypes {
+    pub non_reference: bool,
+    pub reference: bool,
+}
+
+impl InsertionTypes {
+    pub const ALL: InsertionTypes = InsertionTypes {
+        non_reference: true,
+        reference: true,
+    };
+
+    // the single type of insertion that --recall calls again ("reference" or "nonreference")
+    pub fn parse_recall(recall: &str) -> Option<InsertionTypes> {
+        match recall {
+            "reference" => Some(InsertionTypes {
+                non_reference: false,
+                reference: true,
+            }),
+            "nonreference" => Some(InsertionTypes {
+                non_reference: true,
+                reference: false,
+            }),
+            _ => None,
+        }
+    }
+
+    // the name of the types in the file names of --recall
+    pub fn name(&self) -> &'static str {
+        match (self.non_reference, self.reference) {
+            (true, true) => "all",
+            (true, false) => "nonreference",
+            (false, true) => "reference",
+            (false, false) => "none",
+        }
+    }
+}
+
+// the single locus of --region (one-based fully closed)
+// only the split-reads with a breakpoint inside it are used, so reference insertions
+// that extend past the region are only reported with --report-one-sided
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub chrom: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Region {
+    // e.g. "2L:1000-2000" or "2L:1,000-2,000"
+    // (the coordinates are split at the last ":", so contig names can contain ":")
+    pub fn parse(region: &str) -> Result<Region> {
+        let invalid_region = || StanexError::InvalidValue {
+            field: "region".to_owned(),
+            value: region.to_owned(),
+        };
+        let (chrom, range) = region.rsplit_once(':').ok_or_else(invalid_region)?;
+        let (start, end) = range.split_once('-').ok_or_else(invalid_region)?;
+        let parse_coord = |coord: &str| -> Result<u64> {
+            coord
+                .replace(',', "")
+                .parse::<u64>()
+                .map_err(|_| invalid_region())
+        };
+        let (start, end) = (parse_coord(start)?, parse_coord(end)?);
+        if chrom.is_empty() || start == 0 || start > end {
+            return Err(invalid_region());
+        }
+        return Ok(Region {
+            chrom: chrom.to_owned(),
+            start,
+            end,
+        });
+    }
+
+    pub fn contains(&self, chrom: &str, position: u64) -> bool {
+        chrom == self.chrom && position >= self.start && position <= self.end
+    }
+}
+
+fn exit_unreadable_alignments(genome_aligned_path: &Path, e: StanexError) -> ! {
+    log_error!(
+        "Unable to read the genome alignments {}: {}",
+        genome_aligned_path.display(),
+        e
+    );
+    std::process::exit(1);
+}
+
+// the insertions of each chromosome are passed to handle_chrom as soon as they are called
+// (and the alignments of the chromosome are dropped), so that the output can be written
+// without keeping every insertion of the genome in memory
+#[allow(clippy::too_many_arguments)]
+pub fn select_alignments<F: FnMut(OutputInsertions)>(
     chroms: Vec<String>,
     min_tsd_length: u64,
     max_tsd_length: u64,
     min_te_length: f64,
     max_te_length: f64,
-    genome_aligned_path: &PathFile,
-    output_path: &PathFile,
+    family_params: &FamilyParams,
+    alignment_source: AlignmentSource,
     transposons_map: &HashMap<String, u64>,
-    output_should_be_json: bool,
+    reference_te_lengths: &HashMap<String, u64>,
+    chrom_lengths: &HashMap<String, u64>,
+    sam_layout: &HashMap<String, usize>,
+    contig_renames: &HashMap<String, String>,
+    min_clip: u64,
+    report_one_sided: bool,
+    min_genome_overlap: u64,
+    insertion_types: InsertionTypes,
+    reference_source: Option<String>,
+    region: Option<&Region>,
+    diagnostics: &Diagnostics,
+    mut handle_chrom: F,
 ) {
-    let mut second_sam_file_reader = BufReader::new(File::open(genome_aligned_path).unwrap());
-    let mut output_writer = BufWriter::new(File::create(output_path).unwrap());
-    second_sam_file::skip_all_comments(&mut second_sam_file_reader);
-    let mut bin_heaps =
-        second_sam_file::read_all_alignments_into_bin_heaps(&mut second_sam_file_reader, &chroms);
-    let mut output: Vec<OutputInsertions> = Vec::new();
+    let cache_settings = CacheSettings::new(&chroms, min_clip, contig_renames, sam_layout);
+    let mut bin_heaps = match alignment_source {
+        AlignmentSource::Cache(cache_path) => {
+            alignment_cache::read_cache(cache_path, &cache_settings)
+        }
+        AlignmentSource::Sam(genome_aligned_path) => {
+            second_sam_file::read_all_alignments_into_bin_heaps_parallel(
+                genome_aligned_path.as_ref(),
+                &chroms,
+                sam_layout,
+                contig_renames,
+                min_clip,
+                insertion_types,
+                region,
+                diagnostics,
+            )
+            .unwrap_or_else(|e| exit_unreadable_alignments(genome_aligned_path.as_ref(), e))
+        }
+        AlignmentSource::Bwa(ref bwa_stream) => {
+            let mut bin_heaps = None;
+            let mut read_output = |bwa_stdout: &mut BufReader<ChildStdout>| {
+                let mut reader = CountingReader::new(bwa_stdout);
+                let read = second_sam_file::read_all_alignments_into_bin_heaps(
+                    &mut reader,
+                    &chroms,
+                    sam_layout,
+                    contig_renames,
+                    min_clip,
+                    insertion_types,
+                    region,
+                    diagnostics,
+                );
+                match read {
+                    Ok(read) => bin_heaps = Some(read),
+                    Err(e) => {
+                        log_error!("Unable to read the genome alignments from bwa mem: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                // the I/O of a genome-aligned SAM file that was saved (written once and read once)
+                progress!(
+                    "{:.1} MB of genome alignments streamed from bwa mem (no genome-aligned SAM file written)",
+                    reader.bytes_read() as f64 / 1e6
+                );
+            };
+            utils::bwa_mem_align(
+                bwa_stream.ref_name,
+                bwa_stream.reads,
+                BwaOutput::Stream(&mut read_output),
+                bwa_stream.bwa_threads,
+                bwa_stream.bwa_options,
+            );
+            bin_heaps.unwrap()
+        }
+        // the cache keeps both types of alignments, so that it can be re-used with any insertion types
+        AlignmentSource::SamWithCache(genome_aligned_path, _) => {
+            second_sam_file::read_all_alignments_into_bin_heaps_parallel(
+                genome_aligned_path.as_ref(),
+                &chroms,
+                sam_layout,
+                contig_renames,
+                min_clip,
+                InsertionTypes::ALL,
+                None,
+                diagnostics,
+            )
+            .unwrap_or_else(|e| exit_unreadable_alignments(genome_aligned_path.as_ref(), e))
+        }
+    };
+    if let AlignmentSource::SamWithCache(_, cache_path) = alignment_source {
+        alignment_cache::write_cache(cache_path, &bin_heaps, &cache_settings);
+    }
+    // the cache covers every position, so the alignments of a cache are only restricted to the region here
+    if let Some(region) = region {
+        for (chrom, (non_ref_heap, ref_heap)) in bin_heaps.iter_mut() {
+            non_ref_heap.retain(|alignment| region.contains(chrom, alignment.get_boundary_nt()));
+            ref_heap.retain(|alignment| region.contains(chrom, alignment.get_boundary_nt()));
+        }
+    }
+    // drop the alignments to the transposons that were filtered out in phase 2
+    // (the selected reads can be older than the current --te-include/--te-exclude)
+    for (non_ref_heap, ref_heap) in bin_heaps.values_mut() {
+        let num_alignments = non_ref_heap.len() + ref_heap.len();
+        non_ref_heap.retain(|alignment| transposons_map.contains_key(&alignment.te_name));
+        ref_heap.retain(|alignment| transposons_map.contains_key(&alignment.te_name));
+        diagnostics.record_many(
+            Stage::GenomeAlignment,
+            "transposon filtered out",
+            (num_alignments - non_ref_heap.len() - ref_heap.len()) as u64,
+        );
+        // the reference transposons can also be missing elements that are too short (see --min-element-length)
+        let num_ref_alignments = ref_heap.len();
+        ref_heap.retain(|alignment| reference_te_lengths.contains_key(&alignment.te_name));
+        diagnostics.record_many(
+            Stage::GenomeAlignment,
+            "transposon shorter than --min-element-length",
+            (num_ref_alignments - ref_heap.len()) as u64,
+        );
+        diagnostics.record_kept(
+            Stage::GenomeAlignment,
+            (non_ref_heap.len() + ref_heap.len()) as u64,
+        );
+    }
     for chrom in chroms {
-        let non_reference = GenomeAlignment::get_non_ref_tes(
-            &mut bin_heaps.get_mut(&chrom).unwrap().0,
-            min_tsd_length,
-            max_tsd_length,
-            &chrom,
-        );
-        let reference = GenomeAlignment::get_ref_tes(
-            &mut bin_heaps.get_mut(&chrom).unwrap().1,
-            min_te_length,
-            max_te_length,
-            &transposons_map,
-            &chrom,
-        );
-        output.push(OutputInsertions {
+        // a chromosome that isn't in the alignments (e.g. one of --chromosomes or --region that
+        // wasn't in the run of a cache) has no alignments
+        let (mut non_ref_heap, mut ref_heap) = bin_heaps.remove(&chrom).unwrap_or_default();
+        let non_reference = if insertion_types.non_reference {
+            GenomeAlignment::get_non_ref_tes(
+                &mut non_ref_heap,
+                min_tsd_length,
+                max_tsd_length,
+                family_params,
+                &chrom,
+                chrom_lengths,
+                report_one_sided,
+                min_genome_overlap,
+                diagnostics,
+            )
+        } else {
+            Vec::new()
+        };
+        let reference = if insertion_types.reference {
+            GenomeAlignment::get_ref_tes(
+                &mut ref_heap,
+                min_te_length,
+                max_te_length,
+                family_params,
+                reference_te_lengths,
+                &chrom,
+                chrom_lengths,
+                report_one_sided,
+                min_genome_overlap,
+                diagnostics,
+            )
+        } else {
+            Vec::new()
+        };
+        let chrom_output = OutputInsertions {
             non_reference,
             reference,
+            reference_source: reference_source.clone(),
+        };
+        // the split-reads that support a called insertion
+        let num_reads: usize = chrom_output
+            .insertions()
+            .iter()
+            .map(|insertion| {
+                let (upstream_reads, downstream_reads) = insertion.reads();
+                upstream_reads.len() + downstream_reads.len()
+            })
+            .sum();
+        diagnostics.record_kept(Stage::InsertionCalling, num_reads as u64);
+        handle_chrom(chrom_output);
+    }
+}
+
+// the length window of the reference insertions (see --reference-length-window), e.g. "0.1,1.5":
+// the shortest and longest insertion as fractions of the transposon's length
+pub fn parse_length_window(window: &str) -> Option<(f64, f64)> {
+    let mut bounds = window.split(',').map(|bound| bound.trim().parse::<f64>());
+    match (bounds.next(), bounds.next(), bounds.next()) {
+        (Some(Ok(min)), Some(Ok(max)), None) if min >= 0.0 && min <= max => Some((min, max)),
+        _ => None,
+    }
+}
+
+// the longest TSD of a non-reference insertion when the read length is unknown
+pub const DEFAULT_MAX_TSD_LENGTH: u64 = 100;
+
+// the longest TSD that can be called with reads of read_length: a split-read has to reach
+// at least min_clip nucleotides (and at least 1) into the transposon, so a longer bound only
+// pairs up unrelated split-reads
+pub fn max_tsd_length(read_length: Option<u64>, min_clip: u64) -> u64 {
+    match read_length {
+        Some(read_length) => std::cmp::min(
+            DEFAULT_MAX_TSD_LENGTH,
+            read_length.saturating_sub(std::cmp::max(min_clip, 1)),
+        ),
+        None => DEFAULT_MAX_TSD_LENGTH,
+    }
+}
+
+// the lengths of the transposons that can be called as reference insertions, and the names
+// of the ones that are shorter than min_element_length (sorted)
+// short library entries (e.g. fragments or solo LTRs) make the 0.1x - 1.5x length window
+// of a reference insertion so narrow that it is mostly noise, so they are only used for
+// non-reference insertions
+pub fn reference_te_lengths(
+    transposons_map: &HashMap<String, u64>,
+    min_element_length: u64,
+) -> (HashMap<String, u64>, Vec<String>) {
+    let mut excluded: Vec<String> = Vec::new();
+    let mut reference_te_lengths: HashMap<String, u64> = HashMap::new();
+    for (te_name, &length) in transposons_map {
+        if length < min_element_length {
+            excluded.push(te_name.clone());
+        } else {
+            reference_te_lengths.insert(te_name.clone(), length);
+        }
+    }
+    excluded.sort();
+    return (reference_te_lengths, excluded);
+}
+
+// count the distinct fragments among the split-reads of each end of every insertion of a chromosome
+// (see --count-fragments): the 2 mates of a read pair can both be split at the same end,
+// so the number of split-reads can overstate the independent evidence for an insertion
+pub fn count_fragments(chrom: &mut OutputInsertions) {
+    let distinct_fragments = |reads: &[SplitReadRanges]| -> Option<usize> {
+        Some(
+            reads
+                .iter()
+                .map(|read| read.fragment_name())
+                .collect::<HashSet<&str>>()
+                .len(),
+        )
+    };
+    for insertion in &mut chrom.non_reference {
+        insertion.upstream_fragments = distinct_fragments(&insertion.upstream_reads);
+        insertion.downstream_fragments = distinct_fragments(&insertion.downstream_reads);
+    }
+    for insertion in &mut chrom.reference {
+        insertion.upstream_fragments = distinct_fragments(&insertion.upstream_reads);
+        insertion.downstream_fragments = distinct_fragments(&insertion.downstream_reads);
+    }
+}
+
+// report how precisely the split-reads of each end of every insertion of a chromosome agree on its breakpoint
+// (see --breakpoint-ranges): the consensus position hides how far apart the boundaries of the split-reads are
+pub fn add_breakpoint_ranges(chrom: &mut OutputInsertions) {
+    for insertion in &mut chrom.non_reference {
+        insertion.upstream_breakpoint_range = BreakpointRange::of_reads(&insertion.upstream_reads);
+        insertion.downstream_breakpoint_range =
+            BreakpointRange::of_reads(&insertion.downstream_reads);
+    }
+    for insertion in &mut chrom.reference {
+        insertion.upstream_breakpoint_range = BreakpointRange::of_reads(&insertion.upstream_reads);
+        insertion.downstream_breakpoint_range =
+            BreakpointRange::of_reads(&insertion.downstream_reads);
+    }
+}
+
+// annotate every insertion of a chromosome with the nearest other insertion of either type (see --annotate-neighbors),
+// e.g. to find the hotspots of insertions and the nested elements
+// in a single pass over the insertions sorted by position: the nearest one before an insertion is the one that ends
+// last among those that start before it (a long reference element can cover several of them),
+// and the nearest one after it is the next one
+pub fn annotate_neighbors(chrom: &mut OutputInsertions) {
+    // the start and end of every insertion, with its type and index
+    let mut positions: Vec<(u64, u64, bool, usize)> = chrom
+        .non_reference
+        .iter()
+        .enumerate()
+        .map(|(index, insertion)| {
+            let (start_pos, end_pos) = insertion.tsd_range();
+            (start_pos, end_pos, false, index)
+        })
+        .chain(
+            chrom
+                .reference
+                .iter()
+                .enumerate()
+                .map(|(index, insertion)| {
+                    (
+                        insertion.upstream_pos,
+                        insertion.downstream_pos,
+                        true,
+                        index,
+                    )
+                }),
+        )
+        .collect();
+    positions.sort();
+    let describe =
+        |chrom: &OutputInsertions,
+         (start_pos, end_pos, is_reference, index): (u64, u64, bool, usize)| {
+            let name = if is_reference {
+                &chrom.reference[index].name
+            } else {
+                &chrom.non_reference[index].name
+            };
+            format!("{}@{}-{}", name, start_pos, end_pos)
+        };
+    let mut neighbors: Vec<Option<Neighbor>> = Vec::with_capacity(positions.len());
+    // the insertion that ends last so far
+    let mut last_ending: Option<(u64, u64, bool, usize)> = None;
+    for (sorted_index, &position) in positions.iter().enumerate() {
+        let (start_pos, end_pos, _, _) = position;
+        // the distance is the number of nucleotides between the 2 insertions
+        let before = last_ending.map(|before| (start_pos.saturating_sub(before.1 + 1), before));
+        let after = positions
+            .get(sorted_index + 1)
+            .map(|&after| (after.0.saturating_sub(end_pos + 1), after));
+        // the one before wins a tie, so that the neighbors of 2 insertions are each other
+        let nearest = match (before, after) {
+            (Some(before), Some(after)) if after.0 < before.0 => Some(after),
+            (Some(before), _) => Some(before),
+            (None, after) => after,
+        };
+        neighbors.push(nearest.map(|(distance, neighbor)| Neighbor {
+            distance,
+            call: describe(chrom, neighbor),
+        }));
+        if last_ending.is_none_or(|last_ending| end_pos > last_ending.1) {
+            last_ending = Some(position);
+        }
+    }
+    for (position, neighbor) in positions.into_iter().zip(neighbors) {
+        let (_, _, is_reference, index) = position;
+        if is_reference {
+            chrom.reference[index].nearest_neighbor = neighbor;
+        } else {
+            chrom.non_reference[index].nearest_neighbor = neighbor;
+        }
+    }
+}
+
+// fill in the TSD sequence of every non-reference insertion of a chromosome from the reference genome
+// the sequence is on the strand of the transposon, so it is reverse-complemented for +/- insertions
+pub fn add_tsd_sequences(chrom: &mut OutputInsertions, reference: &mut IndexedFasta) -> Result<()> {
+    for insertion in &mut chrom.non_reference {
+        let (start_pos, end_pos) = insertion.tsd_range();
+        let tsd_sequence = reference.fetch(&insertion.chrom, start_pos, end_pos)?;
+        insertion.tsd_sequence = Some(match insertion.orientation {
+            Orientation::PlusPlus => tsd_sequence,
+            Orientation::PlusMinus => indexed_fasta::reverse_complement(&tsd_sequence),
         });
     }
+    return Ok(());
+}
+
+// annotate every insertion of a chromosome with the genes it overlaps (see --annotation)
+// the non-reference insertions are annotated by their TSD, the reference ones by the whole transposon
+pub fn add_annotations(chrom: &mut OutputInsertions, annotation: &Annotation) {
+    for insertion in &mut chrom.non_reference {
+        let (start_pos, end_pos) = insertion.tsd_range();
+        insertion.annotation = Some(annotation.annotate(&insertion.chrom, start_pos, end_pos));
+    }
+    for insertion in &mut chrom.reference {
+        insertion.annotation = Some(annotation.annotate(
+            &insertion.chrom,
+            insertion.upstream_pos,
+            insertion.downstream_pos,
+        ));
+    }
+}
+
+// fill in the heuristic zygosity of every non-reference insertion of a chromosome (see zygosity)
+// the support is the mean number of split-reads of the ends that have any
+// (so that a one-sided insertion isn't halved), and insertions without coverage get no hint
+pub fn add_zygosity_hints(
+    chrom: &mut OutputInsertions,
+    coverage: &Coverage,
+    thresholds: &ZygosityThresholds,
+) {
+    for insertion in &mut chrom.non_reference {
+        let (start_pos, end_pos) = insertion.tsd_range();
+        let read_counts: Vec<usize> = vec![
+            insertion.upstream_reads.len(),
+            insertion.downstream_reads.len(),
+        ]
+        .into_iter()
+        .filter(|&num_reads| num_reads > 0)
+        .collect();
+        if read_counts.is_empty() {
+            continue;
+        }
+        let reads_per_end = read_counts.iter().sum::<usize>() as f64 / read_counts.len() as f64;
+        insertion.zygosity = coverage
+            .tsd_depth(&insertion.chrom, start_pos, end_pos)
+            .and_then(|depth| thresholds.classify(reads_per_end, depth));
+    }
+}
+
+// the insertions in the order they are written, along with their reference source
+// without a chromosome order, they are grouped by chromosome (in the order of the chroms vector)
+// with a chromosome order, they are sorted by (chromosome, start, end) across all chromosomes
+// so that the output can be indexed with tabix (chromosomes that are not in the order go last)
+pub fn ordered_insertions<'a>(
+    output: &'a Vec<OutputInsertions>,
+    chrom_order: Option<&Vec<String>>,
+) -> Vec<(Insertion<'a>, &'a Option<String>)> {
+    let mut insertions: Vec<(Insertion, &Option<String>)> = Vec::new();
+    for chrom in output {
+        for insertion in chrom.insertions() {
+            insertions.push((insertion, &chrom.reference_source));
+        }
+    }
+    if let Some(chrom_order) = chrom_order {
+        let chrom_indices: HashMap<&String, usize> = chrom_order
+            .iter()
+            .enumerate()
+            .map(|(index, chrom)| (chrom, index))
+            .collect();
+        insertions.sort_by_key(|(insertion, _)| {
+            let chrom = insertion.chrom();
+            let chrom_index = chrom_indices
+                .get(&chrom.to_owned())
+                .copied()
+                .unwrap_or(chrom_order.len());
+            (chrom_index, chrom, insertion.coords())
+        });
+    }
+    return insertions;
+}
+
+// the class of the transposons whose reference calls are reconciled by reconcile_ltr_calls
+const LTR_CLASS: &str = "LTR";
+
+// a full LTR retrotransposon and the solo LTR of a related family share their LTR sequence,
+// so the same reference locus can be called twice under different names (see --reconcile-ltr)
+// of 2 overlapping reference calls of LTR transposons (e.g. "roo#LTR/Bel-Pao" and "roo_LTR#LTR/Bel-Pao"),
+// only the longer one (the full element) is kept
+// returns a description of every call that was merged into another one
+pub fn reconcile_ltr_calls(chrom: &mut OutputInsertions) -> Vec<String> {
+    let is_ltr = |insertion: &RefTE| TeName::parse(&insertion.name).class() == Some(LTR_CLASS);
+    let describe = |insertion: &RefTE| {
+        format!(
+            "{}:{}-{} {}",
+            insertion.chrom, insertion.upstream_pos, insertion.downstream_pos, insertion.name
+        )
+    };
+    let mut calls = std::mem::take(&mut chrom.reference);
+    calls.sort_by(|te1, te2| te1.sort_key().cmp(&te2.sort_key()));
+    let mut merges: Vec<String> = Vec::new();
+    for call in calls {
+        let overlapping = chrom.reference.iter().rposition(|kept| {
+            is_ltr(kept)
+                && is_ltr(&call)
+                && kept.upstream_pos <= call.downstream_pos
+                && call.upstream_pos <= kept.downstream_pos
+        });
+        let kept_index = match overlapping {
+            Some(kept_index) => kept_index,
+            None => {
+                chrom.reference.push(call);
+                continue;
+            }
+        };
+        let kept = &chrom.reference[kept_index];
+        let call_is_longer =
+            call.downstream_pos - call.upstream_pos > kept.downstream_pos - kept.upstream_pos;
+        let (merged, full) = if call_is_longer {
+            (kept, &call)
+        } else {
+            (&call, kept)
+        };
+        merges.push(format!(
+            "{} merged into {} (overlapping LTR calls: the shorter one is likely a solo LTR)",
+            describe(merged),
+            describe(full)
+        ));
+        if call_is_longer {
+            chrom.reference[kept_index] = call;
+        }
+    }
+    return merges;
+}
+
+// what to do with a non-reference and a reference call of the same family that overlap
+// (see --reconcile-overlaps): a new insertion next to a copy of its family in the reference
+// can be called both ways at the same locus
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverlapResolution {
+    // keep both calls, and name each one in the overlapping calls of the other
+    Annotate,
+    // drop the non-reference call
+    PreferReference,
+    // drop the reference call
+    PreferNonReference,
+}
+
+impl OverlapResolution {
+    pub fn parse(resolution: &str) -> Option<OverlapResolution> {
+        match resolution {
+            "annotate" => Some(OverlapResolution::Annotate),
+            "reference" => Some(OverlapResolution::PreferReference),
+            "non-reference" => Some(OverlapResolution::PreferNonReference),
+            _ => None,
+        }
+    }
+}
+
+// find the non-reference and reference calls of a chromosome that are of the same family (see TeName::family)
+// and overlap, or are at most window bp apart (the TSD of a non-reference call against the whole transposon
+// of a reference call), and resolve them (see OverlapResolution)
+// returns a description of every call that was dropped
+pub fn reconcile_overlapping_calls(
+    chrom: &mut OutputInsertions,
+    window: u64,
+    resolution: OverlapResolution,
+) -> Vec<String> {
+    // the reference calls are sorted by their upstream position (see genome_alignment::get_ref_tes)
+    let mut overlaps: Vec<(usize, usize)> = Vec::new();
+    for (non_ref_index, non_ref) in chrom.non_reference.iter().enumerate() {
+        let (start_pos, end_pos) = non_ref.tsd_range();
+        let family = TeName::parse(&non_ref.name).family();
+        for (ref_index, reference) in chrom.reference.iter().enumerate() {
+            if reference.upstream_pos > end_pos + window {
+                break;
+            }
+            if reference.downstream_pos + window >= start_pos
+                && TeName::parse(&reference.name).family() == family
+            {
+                overlaps.push((non_ref_index, ref_index));
+            }
+        }
+    }
+    let describe_non_ref = |insertion: &NonRefTE| {
+        let (start_pos, end_pos) = insertion.tsd_range();
+        format!("{}@{}-{}", insertion.name, start_pos, end_pos)
+    };
+    let describe_ref = |insertion: &RefTE| {
+        format!(
+            "{}@{}-{}",
+            insertion.name, insertion.upstream_pos, insertion.downstream_pos
+        )
+    };
+    let mut drops: Vec<String> = Vec::new();
+    match resolution {
+        OverlapResolution::Annotate => {
+            for &(non_ref_index, ref_index) in &overlaps {
+                let non_ref_call = describe_non_ref(&chrom.non_reference[non_ref_index]);
+                let ref_call = describe_ref(&chrom.reference[ref_index]);
+                chrom.non_reference[non_ref_index]
+                    .overlapping_calls
+                    .push(ref_call);
+                chrom.reference[ref_index]
+                    .overlapping_calls
+                    .push(non_ref_call);
+            }
+        }
+        OverlapResolution::PreferReference => {
+            let mut dropped: Vec<usize> = Vec::new();
+            for &(non_ref_index, ref_index) in &overlaps {
+                if !dropped.contains(&non_ref_index) {
+                    dropped.push(non_ref_index);
+                    drops.push(format!(
+                        "{}:{} (non-reference) dropped for the overlapping reference call {}",
+                        chrom.non_reference[non_ref_index].chrom,
+                        describe_non_ref(&chrom.non_reference[non_ref_index]),
+                        describe_ref(&chrom.reference[ref_index])
+                    ));
+                }
+            }
+            let mut index = 0;
+            chrom.non_reference.retain(|_| {
+                index += 1;
+                !dropped.contains(&(index - 1))
+            });
+        }
+        OverlapResolution::PreferNonReference => {
+            let mut dropped: Vec<usize> = Vec::new();
+            for &(non_ref_index, ref_index) in &overlaps {
+                if !dropped.contains(&ref_index) {
+                    dropped.push(ref_index);
+                    drops.push(format!(
+                        "{}:{} (reference) dropped for the overlapping non-reference call {}",
+                        chrom.reference[ref_index].chrom,
+                        describe_ref(&chrom.reference[ref_index]),
+                        describe_non_ref(&chrom.non_reference[non_ref_index])
+                    ));
+                }
+            }
+            let mut index = 0;
+            chrom.reference.retain(|_| {
+                index += 1;
+                !dropped.contains(&(index - 1))
+            });
+        }
+    }
+    return drops;
+}
+
+// the insertions of each chromosome, for the per-chromosome outputs (see --split-by-chromosome)
+// the chromosomes are in the chromosome order if there is one (and the others go last),
+// or else in the order they were called; chromosomes without insertions are left out
+pub fn group_by_chromosome(
+    output: Vec<OutputInsertions>,
+    chrom_order: Option<&Vec<String>>,
+) -> Vec<(String, Vec<OutputInsertions>)> {
+    let mut groups: Vec<(String, Vec<OutputInsertions>)> = Vec::new();
+    for chrom_output in output {
+        let chrom = match chrom_output.chrom() {
+            Some(chrom) => chrom.to_owned(),
+            None => continue,
+        };
+        match groups
+            .iter_mut()
+            .find(|(group_chrom, _)| *group_chrom == chrom)
+        {
+            Some((_, group)) => group.push(chrom_output),
+            None => groups.push((chrom, vec![chrom_output])),
+        }
+    }
+    if let Some(chrom_order) = chrom_order {
+        groups.sort_by_key(|(chrom, _)| {
+            chrom_order
+                .iter()
+                .position(|order_chrom| order_chrom == chrom)
+                .unwrap_or(chrom_order.len())
+        });
+    }
+    return groups;
+}
+
+// the number of insertions of each transposon family (see TeName::family),
+// from the most to the least common (and by name for equal counts)
+pub fn family_counts(output: &Vec<OutputInsertions>) -> Vec<(&str, usize)> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for chrom in output {
+        for insertion in chrom.insertions() {
+            *counts
+                .entry(TeName::parse(insertion.name()).family())
+                .or_insert(0) += 1;
+        }
+    }
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_by(|(family_a, count_a), (family_b, count_b)| {
+        count_b.cmp(count_a).then(family_a.cmp(family_b))
+    });
+    return counts;
+}
+
+// the number of distinct transposon families or elements (see TeName) with at least 1 insertion,
+// in total and for the non-reference and reference insertions separately
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DistinctCounts {
+    pub total: usize,
+    pub non_reference: usize,
+    pub reference: usize,
+}
+
+// the distinct families and the distinct elements with at least 1 insertion
+pub fn distinct_te_counts(output: &Vec<OutputInsertions>) -> (DistinctCounts, DistinctCounts) {
+    let mut non_ref_names: HashSet<&str> = HashSet::new();
+    let mut ref_names: HashSet<&str> = HashSet::new();
+    for chrom in output {
+        non_ref_names.extend(
+            chrom
+                .non_reference
+                .iter()
+                .map(|insertion| &insertion.name[..]),
+        );
+        ref_names.extend(chrom.reference.iter().map(|insertion| &insertion.name[..]));
+    }
+    // count the families or the elements of the transposon names
+    fn part(te_name: &str, families: bool) -> &str {
+        let te_name = TeName::parse(te_name);
+        if families {
+            te_name.family()
+        } else {
+            te_name.element
+        }
+    }
+    let count = |families: bool| {
+        let non_ref: HashSet<&str> = non_ref_names
+            .iter()
+            .map(|te_name| part(te_name, families))
+            .collect();
+        let reference: HashSet<&str> = ref_names
+            .iter()
+            .map(|te_name| part(te_name, families))
+            .collect();
+        DistinctCounts {
+            total: non_ref.union(&reference).count(),
+            non_reference: non_ref.len(),
+            reference: reference.len(),
+        }
+    };
+    return (count(true), count(false));
+}
+
+// a line of the NDJSON output: a self-contained insertion object
+// (the fields of the insertion, plus its kind and reference source)
+#[derive(Serialize)]
+struct NdjsonRecord<'a, T: Serialize> {
+    kind: &'static str,
+    #[serde(flatten)]
+    insertion: &'a T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference_source: Option<&'a String>,
+}
+
+// write an insertion as a line of the NDJSON output
+fn write_ndjson_record<W: Write>(
+    writer: &mut W,
+    insertion: &Insertion,
+    reference_source: &Option<String>,
+) -> serde_json::Result<()> {
+    let reference_source = reference_source.as_ref();
+    match insertion {
+        Insertion::NonReference(insertion) => serde_json::to_writer(
+            &mut *writer,
+            &NdjsonRecord {
+                kind: "non-reference",
+                insertion: *insertion,
+                reference_source,
+            },
+        )?,
+        Insertion::Reference(insertion) => serde_json::to_writer(
+            &mut *writer,
+            &NdjsonRecord {
+                kind: "reference",
+                insertion: *insertion,
+                reference_source,
+            },
+        )?,
+    }
+    writer.write_all(b"\n").map_err(serde_json::Error::io)
+}
+
+// the output path that writes the final output to stdout (see --output)
+pub const STDOUT_PATH: &str = "-";
+
+// the writer of the final output file, which compresses the output with gzip
+// if the file name ends in ".gz" (all the output formats are written through it)
+// the path "-" is stdout
+// a file is written under a temporary ".partial" name and only renamed when it's finished,
+// so that an output that failed midway (e.g. on a full disk) isn't mistaken for a complete one
+struct OutputWriter {
+    sink: OutputSink,
+    // the temporary and final paths of the output file (None for stdout)
+    paths: Option<(PathBuf, PathBuf)>,
+}
+
+enum OutputSink {
+    Plain(BufWriter<File>),
+    Gzip(BufWriter<GzEncoder<File>>),
+    Stdout(BufWriter<io::Stdout>),
+}
+
+// the temporary name of an output file while it's written
+fn partial_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".partial");
+    return path.with_file_name(file_name);
+}
+
+impl OutputWriter {
+    fn create(path: &Path) -> io::Result<OutputWriter> {
+        if path == Path::new(STDOUT_PATH) {
+            return Ok(OutputWriter {
+                sink: OutputSink::Stdout(BufWriter::with_capacity(
+                    IO_BUFFER_CAPACITY,
+                    io::stdout(),
+                )),
+                paths: None,
+            });
+        }
+        let temporary_path = partial_path(path);
+        let file = File::create(&temporary_path)?;
+        let sink = if path.extension().is_some_and(|extension| extension == "gz") {
+            OutputSink::Gzip(BufWriter::with_capacity(
+                IO_BUFFER_CAPACITY,
+                GzEncoder::new(file, Compression::default()),
+            ))
+        } else {
+            OutputSink::Plain(BufWriter::with_capacity(IO_BUFFER_CAPACITY, file))
+        };
+        return Ok(OutputWriter {
+            sink,
+            paths: Some((temporary_path, path.to_path_buf())),
+        });
+    }
+
+    // flush the output, write the gzip footer and give the file its final name
+    // (errors would be lost if this were left to drop)
+    // if any of it fails, the temporary file is removed, so the output is either complete or absent
+    fn finish(self) -> io::Result<()> {
+        let paths = self.paths.clone();
+        let finished = self.finish_sink().and_then(|_| match &paths {
+            Some((temporary_path, path)) => std::fs::rename(temporary_path, path),
+            None => Ok(()),
+        });
+        if finished.is_err() {
+            if let Some((temporary_path, _)) = &paths {
+                let _ = std::fs::remove_file(temporary_path);
+            }
+        }
+        return finished;
+    }
+
+    // remove the temporary file of an output that failed midway
+    fn discard(self) {
+        if let Some((temporary_path, _)) = &self.paths {
+            // it's closed before it's removed
+            let temporary_path = temporary_path.clone();
+            drop(self);
+            let _ = std::fs::remove_file(temporary_path);
+        }
+    }
+
+    fn finish_sink(self) -> io::Result<()> {
+        match self.sink {
+            OutputSink::Plain(writer) => {
+                writer
+                    .into_inner()
+                    .map_err(|e| e.into_error())?
+                    .sync_all()?;
+            }
+            OutputSink::Stdout(mut writer) => writer.flush()?,
+            OutputSink::Gzip(writer) => {
+                writer
+                    .into_inner()
+                    .map_err(|e| e.into_error())?
+                    .finish()?
+                    .sync_all()?;
+            }
+        }
+        return Ok(());
+    }
+}
 
-    if output_should_be_json {
-        output_writer
-            .write_all(serde_json::to_string_pretty(&output).unwrap().as_bytes())
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.sink {
+            OutputSink::Plain(writer) => writer.write(buf),
+            OutputSink::Gzip(writer) => writer.write(buf),
+            OutputSink::Stdout(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            OutputSink::Plain(writer) => writer.flush(),
+            OutputSink::Gzip(writer) => writer.flush(),
+            OutputSink::Stdout(writer) => writer.flush(),
+        }
+    }
+}
+
+// write an insertion as a line of the TSV output
+fn write_tsv_line<W: Write>(
+    writer: &mut W,
+    metadata: &Metadata,
+    insertion: &Insertion,
+    reference_source: &Option<String>,
+) -> io::Result<()> {
+    let mut data = insertion.to_data();
+    if let Some(reference_source) = reference_source {
+        data.insert("REFERENCE_SOURCE", reference_source.clone());
+    }
+    writer.write_all(format!("{}\n", metadata.write(&data)).as_bytes())
+}
+
+// an output file that is written one chromosome at a time, as soon as its insertions are called
+// (only for the formats that can be streamed, see OutputFormat::can_stream)
+// (the header is written before any insertion is called)
+pub struct OutputStream {
+    output_writer: OutputWriter,
+    output_format: OutputFormat,
+    metadata: Metadata,
+}
+
+impl OutputStream {
+    pub fn create(
+        output_path: &Path,
+        output_format: OutputFormat,
+        header_style: HeaderStyle,
+        columns: &OutputColumns,
+    ) -> io::Result<OutputStream> {
+        let mut output_writer = OutputWriter::create(output_path)?;
+        let metadata = tsv_metadata(columns);
+        if output_format == OutputFormat::Tsv {
+            output_writer.write_all(
+                format!("{}\n", metadata.write(&header_style.header_data())).as_bytes(),
+            )?;
+        }
+        return Ok(OutputStream {
+            output_writer,
+            output_format,
+            metadata,
+        });
+    }
+
+    // write the insertions of a chromosome (in the same order as write_output without a chromosome order)
+    pub fn write_chrom(&mut self, chrom: &OutputInsertions) -> io::Result<()> {
+        for insertion in chrom.insertions() {
+            match self.output_format {
+                OutputFormat::Tsv => write_tsv_line(
+                    &mut self.output_writer,
+                    &self.metadata,
+                    &insertion,
+                    &chrom.reference_source,
+                )?,
+                _ => write_ndjson_record(
+                    &mut self.output_writer,
+                    &insertion,
+                    &chrom.reference_source,
+                )?,
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.output_writer.finish()
+    }
+
+    // remove the output after a write error (see OutputWriter::discard)
+    pub fn discard(self) {
+        self.output_writer.discard()
+    }
+}
+
+// write the selected insertions to the output file (as TSV, JSON, GFF3, NDJSON or VCF)
+// header_style sets the column names of the TSV
+// columns sets the optional columns of the TSV and fields of the VCF (the GFF3 gets a reference source
+// attribute if the insertions come from more than 1 reference genome)
+// chrom_lengths is only used for the GFF3 "##sequence-region" lines
+// if chrom_order is given, the TSV and GFF3 lines are sorted by genomic coordinate
+#[allow(clippy::too_many_arguments)]
+pub fn write_output(
+    output: &Vec<OutputInsertions>,
+    output_path: &Path,
+    output_format: OutputFormat,
+    header_style: HeaderStyle,
+    columns: &OutputColumns,
+    chrom_lengths: &HashMap<String, u64>,
+    chrom_order: Option<&Vec<String>>,
+    sample: &str,
+    vcf_read_evidence: bool,
+    compact_json: bool,
+) -> io::Result<()> {
+    let mut output_writer = OutputWriter::create(output_path)?;
+    let written = write_insertions(
+        &mut output_writer,
+        output,
+        output_format,
+        header_style,
+        columns,
+        chrom_lengths,
+        chrom_order,
+        sample,
+        vcf_read_evidence,
+        compact_json,
+    );
+    if let Err(e) = written {
+        output_writer.discard();
+        return Err(e);
+    }
+    return output_writer.finish();
+}
+
+// write the selected insertions to a writer (see write_output)
+#[allow(clippy::too_many_arguments)]
+fn write_insertions<W: Write>(
+    output_writer: &mut W,
+    output: &Vec<OutputInsertions>,
+    output_format: OutputFormat,
+    header_style: HeaderStyle,
+    columns: &OutputColumns,
+    chrom_lengths: &HashMap<String, u64>,
+    chrom_order: Option<&Vec<String>>,
+    sample: &str,
+    vcf_read_evidence: bool,
+    compact_json: bool,
+) -> io::Result<()> {
+    match output_format {
+        // the whitespace of the pretty-printed JSON adds up with the split-reads of a genome-wide run
+        OutputFormat::Json if compact_json => {
+            serde_json::to_writer(&mut *output_writer, &output)?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *output_writer, &output)?;
+        }
+        OutputFormat::Tsv => {
+            let metadata = tsv_metadata(columns);
+            output_writer.write_all(
+                format!("{}\n", metadata.write(&header_style.header_data())).as_bytes(),
+            )?;
+            for (insertion, reference_source) in ordered_insertions(output, chrom_order) {
+                write_tsv_line(output_writer, &metadata, &insertion, reference_source)?;
+            }
+        }
+        OutputFormat::Gff3 => {
+            let insertions = ordered_insertions(output, chrom_order);
+            output_writer.write_all(b"##gff-version 3\n")?;
+            // one "##sequence-region" line per chromosome that has insertions
+            let mut seen_chroms: Vec<&str> = Vec::new();
+            for (insertion, _) in &insertions {
+                let chrom_name = insertion.chrom();
+                if seen_chroms.contains(&chrom_name) {
+                    continue;
+                }
+                seen_chroms.push(chrom_name);
+                if let Some(length) = chrom_lengths.get(chrom_name) {
+                    output_writer.write_all(
+                        format!("##sequence-region {} 1 {}\n", chrom_name, length).as_bytes(),
+                    )?;
+                }
+            }
+            for (insertion_num, (insertion, reference_source)) in insertions.iter().enumerate() {
+                let id = format!("insertion_{}", insertion_num + 1);
+                let reference_source_attribute = match reference_source {
+                    Some(reference_source) => format!(";reference_source={}", reference_source),
+                    None => String::new(),
+                };
+                let fragments_attribute = if columns.fragments {
+                    let (upstream_fragments, downstream_fragments) = insertion.fragments();
+                    format!(
+                        ";upstream_fragments={};downstream_fragments={}",
+                        upstream_fragments, downstream_fragments
+                    )
+                } else {
+                    String::new()
+                };
+                output_writer.write_all(
+                    format!(
+                        "{}{}{}\n",
+                        insertion.to_gff3(&id),
+                        fragments_attribute,
+                        reference_source_attribute
+                    )
+                    .as_bytes(),
+                )?;
+            }
+        }
+        // one insertion per line, serialized straight into the writer
+        // (unlike the JSON output, which is serialized as a whole)
+        OutputFormat::Ndjson => {
+            for (insertion, reference_source) in ordered_insertions(output, chrom_order) {
+                write_ndjson_record(output_writer, &insertion, reference_source)?;
+            }
+        }
+        // BED6 without a header, named as in the McClintock benchmarks of TE callers
+        // (the reads, fragments and other columns have no place in it)
+        OutputFormat::McClintock => {
+            for (insertion_num, (insertion, _)) in
+                ordered_insertions(output, chrom_order).iter().enumerate()
+            {
+                output_writer.write_all(
+                    format!("{}\n", insertion.to_mcclintock_bed(insertion_num + 1)).as_bytes(),
+                )?;
+            }
+        }
+        OutputFormat::Vcf => {
+            output_writer.write_all(
+                vcf_header(
+                    columns,
+                    vcf_read_evidence,
+                    chrom_lengths,
+                    chrom_order,
+                    sample,
+                )
+                .as_bytes(),
+            )?;
+            for (insertion_num, (insertion, reference_source)) in
+                ordered_insertions(output, chrom_order).iter().enumerate()
+            {
+                let id = format!("insertion_{}", insertion_num + 1);
+                let reference_source_info = match reference_source {
+                    Some(reference_source) => format!(";REFERENCE_SOURCE={}", reference_source),
+                    None => String::new(),
+                };
+                let fragments_info = if columns.fragments {
+                    let (upstream_fragments, downstream_fragments) = insertion.fragments();
+                    format!(
+                        ";UPSTREAM_FRAGMENTS={};DOWNSTREAM_FRAGMENTS={}",
+                        upstream_fragments, downstream_fragments
+                    )
+                } else {
+                    String::new()
+                };
+                // the insertions are not genotyped, so the sample only records that it was called there
+                // (and the split-reads of its ends with vcf_read_evidence)
+                let sample_columns = if vcf_read_evidence {
+                    format!(
+                        "{}\t.:{}",
+                        VCF_READ_EVIDENCE_FORMAT,
+                        insertion.vcf_read_evidence()
+                    )
+                } else {
+                    "GT\t.".to_owned()
+                };
+                output_writer.write_all(
+                    format!(
+                        "{}{}{}\t{}\n",
+                        insertion.to_vcf(&id),
+                        fragments_info,
+                        reference_source_info,
+                        sample_columns
+                    )
+                    .as_bytes(),
+                )?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+// the FORMAT of the sample column of the VCF output with the read evidence of the insertions
+// (see Insertion::vcf_read_evidence)
+const VCF_READ_EVIDENCE_FORMAT: &str = "GT:UR:DR:UBR:DBR:UBP:DBP";
+
+// the meta-information and header lines of the VCF output
+// one "##contig" line per chromosome (in the sorting order if there is one, so that bcftools accepts the file)
+fn vcf_header(
+    columns: &OutputColumns,
+    vcf_read_evidence: bool,
+    chrom_lengths: &HashMap<String, u64>,
+    chrom_order: Option<&Vec<String>>,
+    sample: &str,
+) -> String {
+    let mut header = String::from("##fileformat=VCFv4.2\n##source=StanX_tools\n");
+    let mut chroms: Vec<&String> = chrom_lengths.keys().collect();
+    chroms.sort();
+    if let Some(chrom_order) = chrom_order {
+        chroms.sort_by_key(|chrom| {
+            chrom_order
+                .iter()
+                .position(|ordered_chrom| ordered_chrom == *chrom)
+                .unwrap_or(chrom_order.len())
+        });
+    }
+    for chrom in chroms {
+        header.push_str(&format!(
+            "##contig=<ID={},length={}>\n",
+            chrom, chrom_lengths[chrom]
+        ));
+    }
+    header.push_str(
+        "##ALT=<ID=INS:ME,Description=\"Insertion of a mobile element\">\n\
+         ##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">\n\
+         ##INFO=<ID=MEINFO,Number=4,Type=String,Description=\"Mobile element info of the form NAME,START,END,POLARITY\">\n\
+         ##INFO=<ID=TSD_END,Number=1,Type=Integer,Description=\"The last position of the target site duplication\">\n\
+         ##INFO=<ID=INSERTION_TYPE,Number=1,Type=String,Description=\"Whether the insertion is in the reference genome (reference or non-reference)\">\n\
+         ##INFO=<ID=UPSTREAM_READS,Number=1,Type=Integer,Description=\"The number of split-reads supporting the upstream end\">\n\
+         ##INFO=<ID=DOWNSTREAM_READS,Number=1,Type=Integer,Description=\"The number of split-reads supporting the downstream end\">\n",
+    );
+    if columns.fragments {
+        header.push_str("##INFO=<ID=UPSTREAM_FRAGMENTS,Number=1,Type=Integer,Description=\"The number of distinct fragments (reads or read pairs) supporting the upstream end\">\n");
+        header.push_str("##INFO=<ID=DOWNSTREAM_FRAGMENTS,Number=1,Type=Integer,Description=\"The number of distinct fragments (reads or read pairs) supporting the downstream end\">\n");
+    }
+    if columns.one_sided {
+        header.push_str(
+            "##FILTER=<ID=OneSided,Description=\"Split-reads on only one end of the insertion\">\n",
+        );
+    }
+    if columns.tsd_sequence {
+        header.push_str("##INFO=<ID=TSD_SEQ,Number=1,Type=String,Description=\"The sequence of the target site duplication, on the strand of the mobile element\">\n");
+    }
+    if columns.zygosity {
+        header.push_str("##INFO=<ID=ZYGOSITY_HINT,Number=1,Type=String,Description=\"A heuristic zygosity for a diploid individual (heterozygous, homozygous or uncertain), from the split-reads per end over the read depth at the TSD\">\n");
+        header.push_str("##INFO=<ID=SUPPORT_FRACTION,Number=1,Type=Float,Description=\"The split-reads per end over the read depth at the TSD\">\n");
+    }
+    if columns.annotation {
+        header.push_str("##INFO=<ID=FEATURE,Number=1,Type=String,Description=\"Where the insertion is relative to the genes of the annotation (exon, intron, gene or intergenic)\">\n");
+        header.push_str("##INFO=<ID=GENE_ID,Number=.,Type=String,Description=\"The genes that the insertion overlaps\">\n");
+    }
+    if columns.overlapping_calls {
+        header.push_str("##INFO=<ID=OVERLAPPING_CALLS,Number=.,Type=String,Description=\"The calls of the other insertion type (reference or non-reference) and the same family that overlap the insertion, as NAME@START-END\">\n");
+    }
+    if columns.neighbors {
+        header.push_str("##INFO=<ID=NEIGHBOR_DISTANCE,Number=1,Type=Integer,Description=\"The number of nucleotides between the insertion and the nearest other insertion on the chromosome (0 if they overlap)\">\n");
+        header.push_str("##INFO=<ID=NEIGHBOR,Number=1,Type=String,Description=\"The nearest other insertion on the chromosome, as NAME@START-END\">\n");
+    }
+    if columns.reference_source {
+        header.push_str("##INFO=<ID=REFERENCE_SOURCE,Number=1,Type=String,Description=\"The reference genome the insertion was found in\">\n");
+    }
+    header.push_str("##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">\n");
+    if vcf_read_evidence {
+        header.push_str("##FORMAT=<ID=UR,Number=1,Type=Integer,Description=\"The number of split-reads supporting the upstream end\">\n");
+        header.push_str("##FORMAT=<ID=DR,Number=1,Type=Integer,Description=\"The number of split-reads supporting the downstream end\">\n");
+        header.push_str("##FORMAT=<ID=UBR,Number=2,Type=Integer,Description=\"The first and last boundaries of the upstream split-reads\">\n");
+        header.push_str("##FORMAT=<ID=DBR,Number=2,Type=Integer,Description=\"The first and last boundaries of the downstream split-reads\">\n");
+        header.push_str("##FORMAT=<ID=UBP,Number=.,Type=Integer,Description=\"Every distinct boundary of the upstream split-reads (the candidate positions of the upstream end)\">\n");
+        header.push_str("##FORMAT=<ID=DBP,Number=.,Type=Integer,Description=\"Every distinct boundary of the downstream split-reads (the candidate positions of the downstream end)\">\n");
+    }
+    header.push_str(&format!(
+        "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{}\n",
+        sample
+    ));
+    return header;
+}
+
+#[cfg(test)]
+mod tests {
+    use path_abs::PathFile;
+
+    use super::super::genome_alignment::SplitReadRanges;
+    use super::super::output_data_types::{NonRefTE, Orientation, RefTE};
+    use super::*;
+
+    fn non_ref_te(chrom: &str, start: u64) -> NonRefTE {
+        NonRefTE::new(
+            "roo".to_owned(),
+            chrom.to_owned(),
+            start + 5,
+            start,
+            Orientation::PlusPlus,
+        )
+    }
+
+    fn ref_te(chrom: &str, start: u64) -> RefTE {
+        RefTE::new(
+            "jockey".to_owned(),
+            chrom.to_owned(),
+            start,
+            start + 5000,
+            Orientation::PlusPlus,
+        )
+    }
+
+    #[test]
+    fn test_ordered_insertions() {
+        let output = vec![
+            OutputInsertions {
+                non_reference: vec![non_ref_te("2L", 3000)],
+                reference: vec![ref_te("2L", 1000)],
+                reference_source: None,
+            },
+            OutputInsertions {
+                non_reference: vec![non_ref_te("X", 10), non_ref_te("X", 500)],
+                reference: Vec::new(),
+                reference_source: None,
+            },
+            OutputInsertions {
+                non_reference: vec![non_ref_te("chrUn", 1)],
+                reference: vec![ref_te("2R", 20)],
+                reference_source: None,
+            },
+        ];
+        let describe = |chrom_order: Option<&Vec<String>>| {
+            ordered_insertions(&output, chrom_order)
+                .iter()
+                .map(|(insertion, _)| (insertion.chrom().to_owned(), insertion.coords().0))
+                .collect::<Vec<_>>()
+        };
+        let expected_unsorted: Vec<(String, u64)> = vec![
+            ("2L".to_owned(), 3000),
+            ("2L".to_owned(), 1000),
+            ("X".to_owned(), 10),
+            ("X".to_owned(), 500),
+            ("chrUn".to_owned(), 1),
+            ("2R".to_owned(), 20),
+        ];
+        assert_eq!(describe(None), expected_unsorted);
+        // sorted by the chromosome order, then by position (chrUn is not in the order, so it goes last)
+        let chrom_order = vec!["X".to_owned(), "2L".to_owned(), "2R".to_owned()];
+        let expected_sorted: Vec<(String, u64)> = vec![
+            ("X".to_owned(), 10),
+            ("X".to_owned(), 500),
+            ("2L".to_owned(), 1000),
+            ("2L".to_owned(), 3000),
+            ("2R".to_owned(), 20),
+            ("chrUn".to_owned(), 1),
+        ];
+        assert_eq!(describe(Some(&chrom_order)), expected_sorted);
+    }
+
+    #[test]
+    fn test_reconcile_ltr_calls() {
+        let call = |name: &str, start: u64, end: u64| RefTE {
+            name: name.to_owned(),
+            upstream_pos: start,
+            downstream_pos: end,
+            ..ref_te("2L", start)
+        };
+        let mut chrom = OutputInsertions {
+            non_reference: Vec::new(),
+            reference: vec![
+                // a full element and the solo LTR of a related family at its 3' end
+                call("roo#LTR/Bel-Pao", 1000, 10000),
+                call("roo_LTR#LTR/Bel-Pao", 9500, 10000),
+                // a solo LTR that is called before the full element
+                call("blood_LTR#LTR/Gypsy", 20000, 20400),
+                call("blood#LTR/Gypsy", 20000, 27000),
+                // overlapping calls that aren't both LTR transposons are kept
+                call("jockey#LINE/Jockey", 26000, 31000),
+                call("jockey", 30000, 35000),
+            ],
+            reference_source: None,
+        };
+        let merges = reconcile_ltr_calls(&mut chrom);
+        assert_eq!(
+            merges,
+            vec![
+                "2L:9500-10000 roo_LTR#LTR/Bel-Pao merged into 2L:1000-10000 roo#LTR/Bel-Pao \
+                 (overlapping LTR calls: the shorter one is likely a solo LTR)",
+                "2L:20000-20400 blood_LTR#LTR/Gypsy merged into 2L:20000-27000 blood#LTR/Gypsy \
+                 (overlapping LTR calls: the shorter one is likely a solo LTR)",
+            ]
+        );
+        let names: Vec<&str> = chrom.reference.iter().map(|te| &te.name[..]).collect();
+        assert_eq!(
+            names,
+            vec![
+                "roo#LTR/Bel-Pao",
+                "blood#LTR/Gypsy",
+                "jockey#LINE/Jockey",
+                "jockey"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reconcile_overlapping_calls() {
+        let chrom = || OutputInsertions {
+            non_reference: vec![
+                // a new roo insertion inside a reference roo, and one just past the end of a reference jockey
+                NonRefTE {
+                    name: "roo#LTR/Bel-Pao".to_owned(),
+                    ..non_ref_te("2L", 2000)
+                },
+                NonRefTE {
+                    name: "jockey#LINE/Jockey".to_owned(),
+                    ..non_ref_te("2L", 11020)
+                },
+                // a new roo insertion far from any reference call
+                NonRefTE {
+                    name: "roo#LTR/Bel-Pao".to_owned(),
+                    ..non_ref_te("2L", 30000)
+                },
+            ],
+            reference: vec![
+                RefTE {
+                    name: "roo#LTR/Bel-Pao".to_owned(),
+                    ..ref_te("2L", 1000)
+                },
+                RefTE {
+                    name: "jockey#LINE/Jockey".to_owned(),
+                    ..ref_te("2L", 6000)
+                },
+                // a reference call of another family that overlaps the first roo insertion
+                RefTE {
+                    name: "blood#LTR/Gypsy".to_owned(),
+                    ..ref_te("2L", 1500)
+                },
+            ],
+            reference_source: None,
+        };
+
+        // both calls are kept and name each other
+        let mut annotated = chrom();
+        assert!(
+            reconcile_overlapping_calls(&mut annotated, 50, OverlapResolution::Annotate).is_empty()
+        );
+        assert_eq!(
+            annotated.non_reference[0].overlapping_calls,
+            vec!["roo#LTR/Bel-Pao@1000-6000"]
+        );
+        assert_eq!(
+            annotated.reference[0].overlapping_calls,
+            vec!["roo#LTR/Bel-Pao@2000-2005"]
+        );
+        assert_eq!(
+            annotated.non_reference[1].overlapping_calls,
+            vec!["jockey#LINE/Jockey@6000-11000"]
+        );
+        assert!(annotated.non_reference[2].overlapping_calls.is_empty());
+        assert!(annotated.reference[2].overlapping_calls.is_empty());
+        let metadata = tsv_metadata(&OutputColumns {
+            overlapping_calls: true,
+            ..Default::default()
+        });
+        let mut line = Vec::new();
+        write_tsv_line(&mut line, &metadata, &annotated.insertions()[2], &None).unwrap();
+        assert!(String::from_utf8(line).unwrap().ends_with("\t.\n"));
+
+        // a smaller window leaves the jockey insertion alone
+        let mut narrow = chrom();
+        reconcile_overlapping_calls(&mut narrow, 10, OverlapResolution::Annotate);
+        assert!(narrow.non_reference[1].overlapping_calls.is_empty());
+
+        let mut prefer_reference = chrom();
+        let drops = reconcile_overlapping_calls(
+            &mut prefer_reference,
+            50,
+            OverlapResolution::PreferReference,
+        );
+        assert_eq!(
+            drops[0],
+            "2L:roo#LTR/Bel-Pao@2000-2005 (non-reference) dropped for the overlapping reference call roo#LTR/Bel-Pao@1000-6000"
+        );
+        assert_eq!(prefer_reference.non_reference.len(), 1);
+        assert_eq!(prefer_reference.reference.len(), 3);
+
+        let mut prefer_non_reference = chrom();
+        let drops = reconcile_overlapping_calls(
+            &mut prefer_non_reference,
+            50,
+            OverlapResolution::PreferNonReference,
+        );
+        assert_eq!(drops.len(), 2);
+        let names: Vec<&str> = prefer_non_reference
+            .reference
+            .iter()
+            .map(|te| &te.name[..])
+            .collect();
+        assert_eq!(names, vec!["blood#LTR/Gypsy"]);
+        assert!(prefer_non_reference.non_reference[0]
+            .overlapping_calls
+            .is_empty());
+    }
+
+    #[test]
+    fn test_group_by_chromosome() {
+        let chrom_output = |chrom: &str, reference_source: Option<&str>| OutputInsertions {
+            non_reference: vec![non_ref_te(chrom, 1000)],
+            reference: Vec::new(),
+            reference_source: reference_source.map(|source| source.to_owned()),
+        };
+        let output = || {
+            vec![
+                chrom_output("2L", Some("dmel")),
+                chrom_output("X", Some("dmel")),
+                OutputInsertions {
+                    non_reference: Vec::new(),
+                    reference: Vec::new(),
+                    reference_source: Some("dmel".to_owned()),
+                },
+                chrom_output("2L", Some("dsim")),
+            ]
+        };
+        let describe = |groups: Vec<(String, Vec<OutputInsertions>)>| {
+            groups
+                .iter()
+                .map(|(chrom, group)| (chrom.clone(), group.len()))
+                .collect::<Vec<_>>()
+        };
+        // the insertions of 2L from both references are in the same group
+        assert_eq!(
+            describe(group_by_chromosome(output(), None)),
+            vec![("2L".to_owned(), 2), ("X".to_owned(), 1)]
+        );
+        let chrom_order = vec!["X".to_owned(), "2L".to_owned()];
+        assert_eq!(
+            describe(group_by_chromosome(output(), Some(&chrom_order))),
+            vec![("X".to_owned(), 1), ("2L".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_vcf_output() {
+        let chrom_lengths: HashMap<String, u64> =
+            vec![("3R".to_owned(), 30000), ("2L".to_owned(), 20000)]
+                .into_iter()
+                .collect();
+        let chrom_order = vec!["3R".to_owned(), "2L".to_owned()];
+        let header = vcf_header(
+            &OutputColumns::default(),
+            false,
+            &chrom_lengths,
+            Some(&chrom_order),
+            "SRR1234",
+        );
+        let contig_lines: Vec<&str> = header
+            .lines()
+            .filter(|line| line.starts_with("##contig"))
+            .collect();
+        assert_eq!(
+            contig_lines,
+            vec![
+                "##contig=<ID=3R,length=30000>",
+                "##contig=<ID=2L,length=20000>"
+            ]
+        );
+        assert!(header.ends_with("\tFORMAT\tSRR1234\n"));
+        assert!(!header.contains("REFERENCE_SOURCE"));
+
+        let mut insertion = non_ref_te("2L", 1000);
+        let read_ranges = || SplitReadRanges {
+            read_name: "r1".to_owned(),
+            te_range: (1, 50),
+            genome_range: (1000, 1099),
+            boundary: 1000,
+        };
+        insertion.upstream_reads.push(read_ranges());
+        insertion.downstream_reads.push(read_ranges());
+        assert_eq!(
+            Insertion::NonReference(&insertion).to_vcf("insertion_1"),
+            "2L\t1000\tinsertion_1\tN\t<INS:ME>\t.\tPASS\tSVTYPE=INS;MEINFO=roo,.,.,+;TSD_END=1005;INSERTION_TYPE=non-reference;UPSTREAM_READS=1;DOWNSTREAM_READS=1"
+        );
+        // the read evidence of the sample column (see --vcf-read-evidence)
+        insertion.upstream_reads.push(SplitReadRanges {
+            boundary: 1004,
+            ..read_ranges()
+        });
+        insertion.upstream_reads.push(SplitReadRanges {
+            boundary: 1004,
+            ..read_ranges()
+        });
+        assert_eq!(
+            Insertion::NonReference(&insertion).vcf_read_evidence(),
+            "3:1:1000,1004:1000,1000:1000,1004:1000"
+        );
+        // one-sided insertions (see --report-one-sided) fail the OneSided filter
+        insertion.upstream_reads.clear();
+        assert!(Insertion::NonReference(&insertion)
+            .to_vcf("insertion_1")
+            .contains("\tOneSided\t"));
+        assert_eq!(
+            Insertion::NonReference(&insertion).vcf_read_evidence(),
+            "0:1:.:1000,1000:.:1000"
+        );
+        let header = vcf_header(
+            &OutputColumns::default(),
+            true,
+            &chrom_lengths,
+            None,
+            "SRR1234",
+        );
+        for id in VCF_READ_EVIDENCE_FORMAT.split(':') {
+            assert!(header.contains(&format!("##FORMAT=<ID={},", id)));
+        }
+    }
+
+    #[test]
+    fn test_gzip_output() {
+        use flate2::read::MultiGzDecoder;
+        use std::io::Read;
+
+        let output = vec![OutputInsertions {
+            non_reference: vec![non_ref_te("2L", 3000)],
+            reference: vec![ref_te("2L", 1000)],
+            reference_source: None,
+        }];
+        let output_path =
+            PathFile::create(std::env::temp_dir().join("sx_test_gzip_output.tsv.gz")).unwrap();
+        write_output(
+            &output,
+            output_path.as_path(),
+            OutputFormat::Tsv,
+            HeaderStyle::Bergman,
+            &OutputColumns::default(),
+            &HashMap::new(),
+            None,
+            "sample",
+            false,
+            false,
+        )
+        .unwrap();
+        let mut decompressed = String::new();
+        MultiGzDecoder::new(File::open(&output_path).unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+        let lines: Vec<&str> = decompressed.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("2L\t3000\t3005\t+/+\troo\t0\t0\tnon-reference"));
+        assert!(lines[2].starts_with("2L\t1000\t6000\t+/+\tjockey\t0\t0\treference"));
+    }
+
+    // a writer that fails after its first bytes, like a disk that fills up
+    struct FullDisk {
+        written: usize,
+    }
+
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written > 0 {
+                return Err(io::Error::other("No space left on device"));
+            }
+            self.written += buf.len();
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn test_write_errors() {
+        let output = vec![OutputInsertions {
+            non_reference: vec![non_ref_te("2L", 3000)],
+            reference: vec![ref_te("2L", 1000)],
+            reference_source: None,
+        }];
+        for output_format in &[OutputFormat::Tsv, OutputFormat::Json, OutputFormat::Vcf] {
+            let result = write_insertions(
+                &mut FullDisk { written: 0 },
+                &output,
+                *output_format,
+                HeaderStyle::Bergman,
+                &OutputColumns::default(),
+                &HashMap::new(),
+                None,
+                "sample",
+                false,
+                false,
+            );
+            assert!(result.unwrap_err().to_string().contains("No space left"));
+        }
+
+        // the output only gets its name once it's complete
+        let output_path =
+            std::env::temp_dir().join(format!("sx_test_write_errors_{}.tsv", std::process::id()));
+        let output_writer = OutputWriter::create(&output_path).unwrap();
+        assert!(partial_path(&output_path).exists());
+        assert!(!output_path.exists());
+        output_writer.finish().unwrap();
+        assert!(!partial_path(&output_path).exists());
+        assert!(output_path.exists());
+        std::fs::remove_file(&output_path).unwrap();
+
+        // an output that failed midway leaves neither a partial nor a final file
+        let mut output_writer = OutputWriter::create(&output_path).unwrap();
+        output_writer.write_all(b"2L\t1000\t").unwrap();
+        output_writer.discard();
+        assert!(!partial_path(&output_path).exists());
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn test_mcclintock_output() {
+        let mut non_reference = non_ref_te("2L", 3000);
+        non_reference.name = "roo#LTR/Bel-Pao".to_owned();
+        non_reference.orientation = Orientation::PlusMinus;
+        let output = vec![OutputInsertions {
+            non_reference: vec![non_reference],
+            reference: vec![ref_te("2L", 1000)],
+            reference_source: None,
+        }];
+        let output_path = PathFile::create(
+            std::env::temp_dir().join(format!("sx_test_mcclintock_{}.bed", std::process::id())),
+        )
+        .unwrap();
+        let chrom_order = vec!["2L".to_owned()];
+        write_output(
+            &output,
+            output_path.as_path(),
+            OutputFormat::McClintock,
+            HeaderStyle::Bergman,
+            &OutputColumns::default(),
+            &HashMap::new(),
+            Some(&chrom_order),
+            "sample",
+            false,
+            false,
+        )
+        .unwrap();
+        let bed = std::fs::read_to_string(&output_path).unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+        // 0-based starts: the TSD 3000-3005 and the element 1000-6000 (one-based fully closed)
+        assert_eq!(
+            bed,
+            "2L\t999\t6000\tjockey_reference_1\t0\t+\n\
+             2L\t2999\t3005\troo_non-reference_2\t0\t-\n"
+        );
+    }
+
+    #[test]
+    fn test_output_stream() {
+        let output = vec![
+            OutputInsertions {
+                non_reference: vec![non_ref_te("2L", 3000)],
+                reference: vec![ref_te("2L", 1000)],
+                reference_source: Some("dmel".to_owned()),
+            },
+            OutputInsertions {
+                non_reference: vec![non_ref_te("3R", 500)],
+                reference: Vec::new(),
+                reference_source: Some("dsim".to_owned()),
+            },
+        ];
+        // no insertion has a TSD sequence or overlapping calls, but their columns are still written
+        let columns = OutputColumns {
+            reference_source: true,
+            tsd_sequence: true,
+            overlapping_calls: true,
+            fragments: true,
+            ..Default::default()
+        };
+        // the streamed output is the same as the output that is written at the end
+        for output_format in &[OutputFormat::Tsv, OutputFormat::Ndjson] {
+            let path = |name: &str| {
+                PathFile::create(std::env::temp_dir().join(format!(
+                    "sx_test_output_stream_{}_{}.{}",
+                    name,
+                    std::process::id(),
+                    output_format.extension()
+                )))
+                .unwrap()
+            };
+            let streamed_path = path("streamed");
+            let mut output_stream = OutputStream::create(
+                streamed_path.as_path(),
+                *output_format,
+                HeaderStyle::Generic,
+                &columns,
+            )
+            .unwrap();
+            for chrom in &output {
+                output_stream.write_chrom(chrom).unwrap();
+            }
+            output_stream.finish().unwrap();
+            let buffered_path = path("buffered");
+            write_output(
+                &output,
+                buffered_path.as_path(),
+                *output_format,
+                HeaderStyle::Generic,
+                &columns,
+                &HashMap::new(),
+                None,
+                "sample",
+                false,
+                false,
+            )
+            .unwrap();
+            let streamed = std::fs::read_to_string(&streamed_path).unwrap();
+            let buffered = std::fs::read_to_string(&buffered_path).unwrap();
+            std::fs::remove_file(&streamed_path).unwrap();
+            std::fs::remove_file(&buffered_path).unwrap();
+            assert_eq!(
+                streamed.lines().count(),
+                3 + (*output_format == OutputFormat::Tsv) as usize
+            );
+            assert_eq!(streamed, buffered);
+            if *output_format == OutputFormat::Tsv {
+                let lines: Vec<&str> = buffered.lines().collect();
+                assert!(
+                    lines[0].contains("\ttsd_sequence\t")
+                        && lines[0].contains("\toverlapping_calls\t")
+                );
+                assert!(lines
+                    .iter()
+                    .all(|line| line.split('\t').count() == lines[0].split('\t').count()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_fragments() {
+        let read = |read_name: &str| SplitReadRanges {
+            read_name: read_name.to_owned(),
+            te_range: (1, 50),
+            genome_range: (51, 150),
+            boundary: 1000,
+        };
+        let mut insertion = non_ref_te("2L", 1000);
+        // both mates of SRR1.7 are split at the upstream end
+        insertion.upstream_reads = vec![
+            read("SRR1.7|roo|100|50|MS|start"),
+            read("SRR1.7|roo|80|70|MS|start"),
+            read("SRR1.9|roo|120|30|SM|end"),
+        ];
+        insertion.downstream_reads = vec![read("SRR1.12|roo|90|60|SM|start")];
+        let mut chrom = OutputInsertions {
+            non_reference: vec![insertion],
+            reference: vec![ref_te("2L", 5000)],
+            reference_source: None,
+        };
+        // the fragments are the split-reads until they are counted
+        assert_eq!(chrom.insertions()[0].fragments(), (3, 1));
+        count_fragments(&mut chrom);
+        assert_eq!(chrom.insertions()[0].fragments(), (2, 1));
+        assert_eq!(chrom.non_reference[0].upstream_fragments, Some(2));
+        assert_eq!(chrom.reference[0].upstream_fragments, Some(0));
+
+        let metadata = tsv_metadata(&OutputColumns {
+            fragments: true,
+            ..Default::default()
+        });
+        let mut line = Vec::new();
+        write_tsv_line(&mut line, &metadata, &chrom.insertions()[0], &None).unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "2L\t1000\t1005\t+/+\troo\t3\t1\t2\t1\tnon-reference\n"
+        );
+    }
+
+    #[test]
+    fn test_add_breakpoint_ranges() {
+        let read = |boundary: u64| SplitReadRanges {
+            read_name: format!("SRR1.{}|roo|100|50|MS|start", boundary),
+            te_range: (1, 50),
+            genome_range: (51, 150),
+            boundary,
+        };
+        let mut insertion = non_ref_te("2L", 1000);
+        insertion.upstream_reads = vec![read(1005), read(1003), read(1005)];
+        let mut chrom = OutputInsertions {
+            non_reference: vec![insertion],
+            reference: vec![],
+            reference_source: None,
+        };
+        add_breakpoint_ranges(&mut chrom);
+        assert_eq!(
+            chrom.non_reference[0].upstream_breakpoint_range,
+            Some(BreakpointRange {
+                min: 1003,
+                max: 1005,
+                spread: 2
+            })
+        );
+        // an end without split-reads has no range
+        assert_eq!(chrom.non_reference[0].downstream_breakpoint_range, None);
+
+        let metadata = tsv_metadata(&OutputColumns {
+            breakpoint_ranges: true,
+            ..Default::default()
+        });
+        let mut line = Vec::new();
+        write_tsv_line(&mut line, &metadata, &chrom.insertions()[0], &None).unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "2L\t1000\t1005\t+/+\troo\t3\t0\t1003-1005\t2\t.\t.\tnon-reference\n"
+        );
+        let json = serde_json::to_string(&chrom.non_reference[0]).unwrap();
+        assert!(json.contains(r#""upstream_breakpoint_range":{"min":1003,"max":1005,"spread":2}"#));
+        assert!(!json.contains("downstream_breakpoint_range"));
+    }
+
+    #[test]
+    fn test_missing_cached_chromosome() {
+        let cache_path = std::env::temp_dir().join(format!(
+            "sx_test_missing_cached_chromosome_{}.bin",
+            std::process::id()
+        ));
+        let cache_path = PathFile::create(&cache_path).unwrap();
+        let chroms = vec!["2L".to_owned(), "X".to_owned()];
+        // a cache of both chromosomes without the alignments of X
+        // (a chromosome that wasn't in the cached run is rejected, see alignment_cache::CacheSettings)
+        let mut bin_heaps = second_sam_file::BinHeaps::new();
+        bin_heaps.insert("2L".to_owned(), Default::default());
+        let settings = CacheSettings::new(&chroms, 0, &HashMap::new(), &HashMap::new());
+        alignment_cache::write_cache(&cache_path, &bin_heaps, &settings);
+
+        let mut called: Vec<OutputInsertions> = Vec::new();
+        select_alignments(
+            chroms,
+            0,
+            100,
+            0.1,
+            1.5,
+            &FamilyParams::default(),
+            AlignmentSource::Cache(&cache_path),
+            &HashMap::new(),
+            &HashMap::new(),
+            &HashMap::new(),
+            &HashMap::new(),
+            &HashMap::new(),
+            0,
+            false,
+            0,
+            InsertionTypes::ALL,
+            None,
+            None,
+            &Diagnostics::default(),
+            |chrom| called.push(chrom),
+        );
+        std::fs::remove_file(&cache_path).unwrap();
+        assert_eq!(called.len(), 2);
+        assert!(called.iter().all(|chrom| chrom.insertions().is_empty()));
+    }
+
+    #[test]
+    fn test_compact_json() {
+        let output = vec![OutputInsertions {
+            non_reference: vec![non_ref_te("2L", 3000)],
+            reference: vec![ref_te("2L", 1000)],
+            reference_source: None,
+        }];
+        let json = |compact_json: bool| {
+            let mut written: Vec<u8> = Vec::new();
+            write_insertions(
+                &mut written,
+                &output,
+                OutputFormat::Json,
+                HeaderStyle::Bergman,
+                &OutputColumns::default(),
+                &HashMap::new(),
+                None,
+                "sample",
+                false,
+                compact_json,
+            )
             .unwrap();
-    } else {
-        output_writer.write("Chromosome\tTSD Upstream\tTSD Downstream\tOrientation\tName\t# Upstream Reads\t# Downstream Reads\tFound in Reference?\n".as_bytes()).unwrap();
-        for chrom in output {
-            for insertion in chrom.non_reference {
-                output_writer
-                    .write_all(format!("{}\n", insertion).as_bytes())
-                    .unwrap();
+            return String::from_utf8(written).unwrap();
+        };
+        let (pretty, compact) = (json(false), json(true));
+        assert!(pretty.contains('\n'));
+        assert!(!compact.contains('\n') && compact.len() < pretty.len());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&compact).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&pretty).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_annotate_neighbors() {
+        // 2 new insertions nested in a reference jockey, and a new one further away
+        let mut chrom = OutputInsertions {
+            non_reference: vec![
+                non_ref_te("2L", 2000),
+                non_ref_te("2L", 3000),
+                non_ref_te("2L", 6100),
+            ],
+            reference: vec![ref_te("2L", 1000)],
+            reference_source: None,
+        };
+        annotate_neighbors(&mut chrom);
+        let neighbor = |distance: u64, call: &str| {
+            Some(Neighbor {
+                distance,
+                call: call.to_owned(),
+            })
+        };
+        assert_eq!(
+            chrom.reference[0].nearest_neighbor,
+            neighbor(0, "roo@2000-2005")
+        );
+        assert_eq!(
+            chrom.non_reference[0].nearest_neighbor,
+            neighbor(0, "jockey@1000-6000")
+        );
+        // the reference jockey is still the nearest one before the 2nd nested insertion
+        assert_eq!(
+            chrom.non_reference[1].nearest_neighbor,
+            neighbor(0, "jockey@1000-6000")
+        );
+        assert_eq!(
+            chrom.non_reference[2].nearest_neighbor,
+            neighbor(99, "jockey@1000-6000")
+        );
+
+        let metadata = tsv_metadata(&OutputColumns {
+            neighbors: true,
+            ..Default::default()
+        });
+        let mut line = Vec::new();
+        write_tsv_line(&mut line, &metadata, &chrom.insertions()[2], &None).unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "2L\t6100\t6105\t+/+\troo\t0\t0\tnon-reference\t99\tjockey@1000-6000\n"
+        );
+
+        // the only insertion of a chromosome has no neighbor
+        let mut alone = OutputInsertions {
+            non_reference: vec![non_ref_te("4", 500)],
+            reference: Vec::new(),
+            reference_source: None,
+        };
+        annotate_neighbors(&mut alone);
+        assert_eq!(alone.non_reference[0].nearest_neighbor, None);
+        let mut line = Vec::new();
+        write_tsv_line(&mut line, &metadata, &alone.insertions()[0], &None).unwrap();
+        assert!(String::from_utf8(line).unwrap().ends_with("\t.\t.\n"));
+    }
+
+    #[test]
+    fn test_family_counts() {
+        let mut blood = non_ref_te("X", 10);
+        blood.name = "blood#LTR/Gypsy".to_owned();
+        let mut roo = non_ref_te("X", 500);
+        roo.name = "roo#LTR/Bel-Pao".to_owned();
+        let output = vec![
+            OutputInsertions {
+                non_reference: vec![non_ref_te("2L", 3000), non_ref_te("2L", 4000)],
+                reference: vec![ref_te("2L", 1000)],
+                reference_source: None,
+            },
+            OutputInsertions {
+                non_reference: vec![blood, roo],
+                reference: vec![ref_te("X", 1000)],
+                reference_source: None,
+            },
+        ];
+        assert_eq!(
+            family_counts(&output),
+            vec![
+                ("jockey", 2),
+                ("roo", 2),
+                ("LTR/Bel-Pao", 1),
+                ("LTR/Gypsy", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_distinct_te_counts() {
+        let te_named = |te: NonRefTE, name: &str| NonRefTE {
+            name: name.to_owned(),
+            ..te
+        };
+        let output = vec![OutputInsertions {
+            non_reference: vec![
+                te_named(non_ref_te("2L", 10), "roo#LTR/Bel-Pao"),
+                te_named(non_ref_te("2L", 500), "roo#LTR/Bel-Pao"),
+                te_named(non_ref_te("2L", 900), "blood#LTR/Gypsy"),
+                te_named(non_ref_te("2L", 1500), "mdg1#LTR/Gypsy"),
+            ],
+            reference: vec![RefTE {
+                name: "mdg1#LTR/Gypsy".to_owned(),
+                ..ref_te("2L", 3000)
+            }],
+            reference_source: None,
+        }];
+        let (families, elements) = distinct_te_counts(&output);
+        assert_eq!(
+            families,
+            DistinctCounts {
+                total: 2,
+                non_reference: 2,
+                reference: 1
             }
-            for insertion in chrom.reference {
-                output_writer
-                    .write_all(format!("{}\n", insertion).as_bytes())
-                    .unwrap();
+        );
+        assert_eq!(
+            elements,
+            DistinctCounts {
+                total: 3,
+                non_reference: 3,
+                reference: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_ndjson_record() {
+        let non_ref = non_ref_te("2L", 3000);
+        let mut line: Vec<u8> = Vec::new();
+        write_ndjson_record(
+            &mut line,
+            &Insertion::NonReference(&non_ref),
+            &Some("dmel".to_owned()),
+        )
+        .unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert!(line.ends_with("}\n") && line.matches('\n').count() == 1);
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["kind"], "non-reference");
+        assert_eq!(record["name"], "roo");
+        assert_eq!(record["chrom"], "2L");
+        assert_eq!(record["reference_source"], "dmel");
+
+        let reference = ref_te("3R", 1000);
+        let mut line: Vec<u8> = Vec::new();
+        write_ndjson_record(&mut line, &Insertion::Reference(&reference), &None).unwrap();
+        let record: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(record["kind"], "reference");
+        assert!(record.get("reference_source").is_none());
+    }
+
+    #[test]
+    fn test_region_parse() {
+        let region = Region::parse("2L:1,000-2,000").unwrap();
+        assert_eq!(
+            region,
+            Region {
+                chrom: "2L".to_owned(),
+                start: 1000,
+                end: 2000,
             }
+        );
+        assert!(region.contains("2L", 1000) && region.contains("2L", 2000));
+        assert!(!region.contains("2L", 999) && !region.contains("2R", 1500));
+        assert_eq!(Region::parse("HLA:A:5-5").unwrap().chrom, "HLA:A");
+        for invalid in &["2L", "2L:1000", "2L:0-10", "2L:20-10", ":1-10", "2L:a-b"] {
+            assert!(Region::parse(invalid).is_err(), "{}", invalid);
         }
     }
+
+    #[test]
+    fn test_reference_te_lengths() {
+        let transposons_map: HashMap<String, u64> = vec![
+            ("roo".to_owned(), 9092),
+            ("short".to_owned(), 50),
+            ("jockey".to_owned(), 100),
+        ]
+        .into_iter()
+        .collect();
+        let (lengths, excluded) = reference_te_lengths(&transposons_map, 100);
+        assert_eq!(excluded, vec!["short".to_owned()]);
+        assert_eq!(lengths.len(), 2);
+        assert_eq!(lengths.get("jockey"), Some(&100));
+        assert!(!lengths.contains_key("short"));
+
+        let (lengths, excluded) = reference_te_lengths(&transposons_map, 0);
+        assert!(excluded.is_empty());
+        assert_eq!(lengths, transposons_map);
+    }
+
+    #[test]
+    fn test_parse_recall() {
+        let reference = InsertionTypes::parse_recall("reference").unwrap();
+        assert!(reference.reference && !reference.non_reference);
+        assert_eq!(reference.name(), "reference");
+        assert_eq!(
+            InsertionTypes::parse_recall("nonreference").unwrap().name(),
+            "nonreference"
+        );
+        assert!(InsertionTypes::parse_recall("both").is_none());
+    }
+
+    #[test]
+    fn test_parse_length_window() {
+        assert_eq!(parse_length_window("0.1,1.5"), Some((0.1, 1.5)));
+        assert_eq!(parse_length_window("0.5, 1"), Some((0.5, 1.0)));
+        assert_eq!(parse_length_window("1.5,0.1"), None);
+        assert_eq!(parse_length_window("-1,1"), None);
+        assert_eq!(parse_length_window("0.1"), None);
+        assert_eq!(parse_length_window("0.1,1.5,2"), None);
+    }
+
+    #[test]
+    fn test_max_tsd_length() {
+        assert_eq!(max_tsd_length(None, 20), DEFAULT_MAX_TSD_LENGTH);
+        assert_eq!(max_tsd_length(Some(150), 20), DEFAULT_MAX_TSD_LENGTH);
+        assert_eq!(max_tsd_length(Some(50), 10), 40);
+        assert_eq!(max_tsd_length(Some(50), 0), 49);
+        assert_eq!(max_tsd_length(Some(5), 10), 0);
+    }
 }
@@ -1,23 +1,84 @@
-use path_abs::PathFile;
+use path_abs::{PathFile, PathInfo};
 use rayon::prelude::*;
 
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
+use super::diagnostics::{Diagnostics, Stage};
+use super::family_map::FamilyMap;
 use super::first_sam_file;
+use super::te_filter::TeFilter;
+use super::te_lengths;
+use super::te_name;
+use crate::errors::StanexError;
+use crate::utils;
+use crate::utils::{log_error, progress, IO_BUFFER_CAPACITY};
 
+// the format of the selected reads (FASTQ keeps the base qualities for the genome alignment of phase 3)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SelectedReadsFormat {
+    Fasta,
+    Fastq,
+}
+
+impl SelectedReadsFormat {
+    pub fn parse(format: &str) -> Option<SelectedReadsFormat> {
+        match format {
+            "fasta" => Some(SelectedReadsFormat::Fasta),
+            "fastq" => Some(SelectedReadsFormat::Fastq),
+            _ => None,
+        }
+    }
+    // the file extension of the selected reads file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SelectedReadsFormat::Fasta => "fasta",
+            SelectedReadsFormat::Fastq => "fastq",
+        }
+    }
+}
+
+// the number of TE alignments that the read length is taken from (see first_sam_file::modal_read_length)
+// (the first alignments of the file, so that the same length is found whether or not phase 2 is run)
+const READ_LENGTH_SAMPLE_SIZE: usize = 100_000;
+
+// a line of tab-separated fields with the field of a column (one-based) replaced
+fn replace_field(line: &str, column: usize, value: &str) -> String {
+    let fields: Vec<&str> = line
+        .split('\t')
+        .enumerate()
+        .map(|(index, field)| if index + 1 == column { value } else { field })
+        .collect();
+    return fields.join("\t");
+}
+
+// the transposon lengths (of the families, see --family-map), and the most common read length if it is known
+// with case_insensitive_te_names, the alignments to a transposon whose name only differs in case
+// from a name of the header (or --te-lengths) are selected under the header's name
+#[allow(clippy::too_many_arguments)]
 pub fn select_reads(
     te_aligned_path: &PathFile,
     selected_reads_path: &PathFile,
     only_create_transposon_map: bool,
-) -> HashMap<String, u64> {
+    sam_layout: &HashMap<String, usize>,
+    min_clip: u64,
+    max_read_n: Option<u64>,
+    long_reads: bool,
+    te_filter: &TeFilter,
+    family_map: &FamilyMap,
+    te_lengths: Option<&HashMap<String, u64>>,
+    case_insensitive_te_names: bool,
+    selected_reads_format: SelectedReadsFormat,
+    diagnostics: &Diagnostics,
+) -> (HashMap<String, u64>, Option<u64>) {
     // select split-reads from TE alignment
     let mut te_aligned_reader =
-        BufReader::with_capacity(65_536, File::open(&te_aligned_path).unwrap());
+        BufReader::with_capacity(IO_BUFFER_CAPACITY, File::open(&te_aligned_path).unwrap());
     let selected_reads_writer_arc = Arc::new(Mutex::new(BufWriter::with_capacity(
-        65_536,
+        IO_BUFFER_CAPACITY,
         File::create(&selected_reads_path).unwrap(),
     )));
 
@@ -26,40 +87,277 @@ pub fn select_reads(
     // store the line number in a mutex for later use
     let line_num_arc = Arc::new(Mutex::new(0));
 
-    // first, get rid of comments (comments in the SAM file start with "@SQ")
-    // and ignore the last comment line (starts with "@PG")
-    // make a clone because transposons will be put into an Arc and cannot be returned
-    let transposons = first_sam_file::read_all_tes_into_map(&mut te_aligned_reader);
+    // first, get rid of comments (comments in the SAM file start with "@")
+    // (the header is only read once: the read length is sampled from the first alignments,
+    // and then the reader goes back to the first alignment to select the reads)
+    let mut transposons = match first_sam_file::read_all_tes_into_map(&mut te_aligned_reader) {
+        Ok(transposons) => transposons,
+        Err(e) => {
+            log_error!(
+                "Unable to read the header of {}: {}",
+                te_aligned_path.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    // the lengths of --te-lengths take the place of the header's
+    if let Some(te_lengths) = te_lengths {
+        let missing =
+            te_lengths::apply_te_lengths(&mut transposons, te_lengths, case_insensitive_te_names);
+        if !missing.is_empty() {
+            utils::print_warning(format_args!(
+                "{} transposons of the TE alignment are not in --te-lengths (their @SQ lengths are used): {}",
+                missing.len(),
+                missing.join(", ")
+            ));
+        }
+    }
+    // reads that align to the transposons filtered out by --te-include/--te-exclude are dropped here
+    te_filter.filter_transposons(&mut transposons);
 
+    let alignment_metadata = first_sam_file::alignment_metadata(sam_layout);
+    let header_names = if case_insensitive_te_names {
+        let (header_names, ambiguous) = te_name::case_insensitive_names(transposons.keys());
+        if !ambiguous.is_empty() {
+            utils::print_warning(format_args!(
+                "{} transposon names of the TE alignment only differ in case (the first one in sorted order is used): {}",
+                ambiguous.len(),
+                ambiguous.join(", ")
+            ));
+        }
+        Some(header_names)
+    } else {
+        None
+    };
+    let rname_column = alignment_metadata
+        .headings
+        .iter()
+        .find(|(_, heading)| *heading == "RNAME")
+        .map(|(&position, _)| position)
+        .unwrap();
+    let first_alignment = te_aligned_reader.stream_position().unwrap();
+    let modal_read_length = first_sam_file::modal_read_length(
+        &mut te_aligned_reader,
+        &alignment_metadata,
+        READ_LENGTH_SAMPLE_SIZE,
+    );
+    te_aligned_reader
+        .seek(SeekFrom::Start(first_alignment))
+        .unwrap();
+
+    // the insertions are called under the names of the families (see --family-map),
+    // but the alignments are checked against the lengths of their own transposons
+    let family_lengths = family_map.family_lengths(&transposons);
     if only_create_transposon_map {
-        return transposons;
+        return (family_lengths, modal_read_length);
     }
 
-    let transposons_clone = transposons.clone();
-
     // next, process the normal reads
 
     // let transposons be borrowed by other threads
     let transposons_arc = Arc::new(transposons);
 
-    te_aligned_reader.lines().par_bridge().for_each(|line| {
+    let num_selected = AtomicU64::new(0);
+    let lines = std::iter::from_fn(|| utils::read_line_trimmed(&mut te_aligned_reader).transpose());
+    lines.par_bridge().for_each(|line| {
         {
             let mut i = line_num_arc.lock().unwrap();
             *i += 1;
             // print status every 1,000,000 lines
             if *i % 1_000_000 == 0 {
-                println!("processing line: {}", i);
+                progress!("processing line: {}", i);
             }
         }
-        let line = line.expect("Something went wrong - unable to read file");
-        let alignment = first_sam_file::read_te_alignment(line, &transposons_arc);
-        if let Ok(alignment) = alignment {
-            selected_reads_writer_arc
-                .lock()
-                .unwrap()
-                .write(format!("{}\n", alignment).as_bytes())
-                .unwrap();
+        let line = line.unwrap_or_else(|e| {
+            log_error!("Unable to read {}: {}", te_aligned_path.display(), e);
+            std::process::exit(1);
+        });
+        let read_alignment = |line: String| {
+            if long_reads {
+                first_sam_file::read_long_te_alignment(
+                    line,
+                    &alignment_metadata,
+                    &transposons_arc,
+                    min_clip,
+                    max_read_n,
+                )
+            } else {
+                first_sam_file::read_te_alignment(
+                    line,
+                    &alignment_metadata,
+                    &transposons_arc,
+                    min_clip,
+                    max_read_n,
+                )
+            }
+        };
+        // the line is only kept to read it again under the header's name
+        let case_insensitive_line = header_names.as_ref().map(|_| line.clone());
+        let mut alignment = read_alignment(line);
+        if let (Err(StanexError::UnknownTransposon(te_name)), Some(header_names), Some(line)) =
+            (&alignment, &header_names, case_insensitive_line)
+        {
+            if let Some(header_name) = header_names.get(&te_name.to_lowercase()) {
+                alignment = read_alignment(replace_field(&line, rname_column, header_name));
+            }
+        }
+        match alignment {
+            Ok(mut alignment) => {
+                alignment.rname = family_map.family(&alignment.rname).to_owned();
+                let record = match selected_reads_format {
+                    SelectedReadsFormat::Fasta => format!("{}\n", alignment),
+                    SelectedReadsFormat::Fastq => format!("{}\n", alignment.to_fastq()),
+                };
+                if let Err(e) = selected_reads_writer_arc
+                    .lock()
+                    .unwrap()
+                    .write_all(record.as_bytes())
+                {
+                    log_error!(
+                        "Unable to write the selected reads {}: {}",
+                        selected_reads_path.display(),
+                        e
+                    );
+                    std::process::exit(1);
+                }
+                num_selected.fetch_add(1, Ordering::Relaxed);
+            }
+            // the transposons filtered out by --te-include/--te-exclude are not in the map,
+            // but they are not unknown
+            Err(StanexError::UnknownTransposon(te_name)) if !te_filter.is_allowed(&te_name) => {
+                diagnostics.record(Stage::TeAlignment, "transposon filtered out")
+            }
+            Err(e) => diagnostics.record_error(Stage::TeAlignment, &e),
         }
     });
-    return transposons_clone;
+    diagnostics.record_kept(Stage::TeAlignment, num_selected.into_inner());
+    // a write error would be lost if the selected reads were flushed when they are dropped
+    if let Err(e) = selected_reads_writer_arc.lock().unwrap().flush() {
+        log_error!(
+            "Unable to write the selected reads {}: {}",
+            selected_reads_path.display(),
+            e
+        );
+        std::process::exit(1);
+    }
+    // e.g. the transposon library doesn't match the reads at all
+    if *line_num_arc.lock().unwrap() == 0 {
+        eprintln!(
+            "No TE alignments found in {} (no reads aligned to the transposons), so no insertions will be called",
+            te_aligned_path.as_path().display()
+        );
+    }
+    return (family_lengths, modal_read_length);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select_reads, SelectedReadsFormat};
+    use crate::te_mapper_utils::diagnostics::Diagnostics;
+    use crate::te_mapper_utils::family_map::FamilyMap;
+    use crate::te_mapper_utils::first_sam_file;
+    use crate::te_mapper_utils::te_filter::TeFilter;
+
+    use path_abs::PathFile;
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_transposons_read_once() {
+        let header = "@SQ\tSN:roo\tLN:9092\n@SQ\tSN:jockey\tLN:5000\n@PG\tID:bwa\tPN:bwa\n";
+        let seq = "A".repeat(150);
+        let te_aligned = format!(
+            "{}r1\t0\troo\t1\t60\t10S140M\t*\t0\t0\t{}\t*\nr2\t0\troo\t1\t60\t20S130M\t*\t0\t0\t{}\t*\n",
+            header, seq, seq
+        );
+        let test_dir = std::env::temp_dir();
+        let te_aligned_path =
+            test_dir.join(format!("sx_test_select_reads_{}.sam", std::process::id()));
+        let selected_reads_path =
+            test_dir.join(format!("sx_test_select_reads_{}.fasta", std::process::id()));
+        std::fs::write(&te_aligned_path, te_aligned).unwrap();
+        let te_filter = TeFilter::new(&[], &["jockey".to_owned()]);
+        let select = |only_create_transposon_map: bool| {
+            select_reads(
+                &PathFile::new(&te_aligned_path).unwrap(),
+                &PathFile::create(&selected_reads_path).unwrap(),
+                only_create_transposon_map,
+                &HashMap::new(),
+                0,
+                None,
+                false,
+                &te_filter,
+                &FamilyMap::default(),
+                None,
+                false,
+                SelectedReadsFormat::Fasta,
+                &Diagnostics::default(),
+            )
+        };
+
+        // the returned map is the filtered header, whether or not the reads are selected
+        let mut expected = first_sam_file::read_all_tes_into_map(&mut Cursor::new(header)).unwrap();
+        te_filter.filter_transposons(&mut expected);
+        let (transposons, read_length) = select(true);
+        assert_eq!(transposons, expected);
+        assert_eq!(read_length, Some(150));
+        let (transposons, read_length) = select(false);
+        assert_eq!(transposons, expected);
+        assert_eq!(read_length, Some(150));
+        // sampling the read length doesn't skip any alignment
+        let selected_reads = std::fs::read_to_string(&selected_reads_path).unwrap();
+        assert_eq!(selected_reads.matches('>').count(), 2);
+
+        std::fs::remove_file(&te_aligned_path).unwrap();
+        std::fs::remove_file(&selected_reads_path).unwrap();
+    }
+
+    #[test]
+    fn test_case_insensitive_te_names() {
+        // the library (the header) has "roo", but the alignment has "Roo"
+        let te_aligned = format!(
+            "@SQ\tSN:roo\tLN:9092\n@PG\tID:bwa\tPN:bwa\nr1\t0\tRoo\t1\t60\t10S140M\t*\t0\t0\t{}\t*\n",
+            "A".repeat(150)
+        );
+        let test_dir = std::env::temp_dir();
+        let te_aligned_path = test_dir.join(format!(
+            "sx_test_case_insensitive_{}.sam",
+            std::process::id()
+        ));
+        let selected_reads_path = test_dir.join(format!(
+            "sx_test_case_insensitive_{}.fasta",
+            std::process::id()
+        ));
+        std::fs::write(&te_aligned_path, te_aligned).unwrap();
+        let select = |case_insensitive_te_names: bool| {
+            let diagnostics = Diagnostics::default();
+            select_reads(
+                &PathFile::new(&te_aligned_path).unwrap(),
+                &PathFile::create(&selected_reads_path).unwrap(),
+                false,
+                &HashMap::new(),
+                0,
+                None,
+                false,
+                &TeFilter::new(&[], &[]),
+                &FamilyMap::default(),
+                None,
+                case_insensitive_te_names,
+                SelectedReadsFormat::Fasta,
+                &diagnostics,
+            );
+            std::fs::read_to_string(&selected_reads_path).unwrap()
+        };
+
+        assert!(select(false).is_empty());
+        // the read is selected under the name of the header
+        let selected_reads = select(true);
+        assert_eq!(selected_reads.matches('>').count(), 1);
+        assert!(selected_reads.contains("|roo|"));
+
+        std::fs::remove_file(&te_aligned_path).unwrap();
+        std::fs::remove_file(&selected_reads_path).unwrap();
+    }
 }
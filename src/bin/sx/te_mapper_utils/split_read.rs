@@ -45,7 +45,9 @@
 // and which end of the transposon it aligned to (start or end)
 // where "start" is the 5' end and "end" is the 3' end
 
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SMAlignment {
     pub s: u64,
     pub m: u64,
@@ -63,7 +65,7 @@ impl SMAlignment {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MSAlignment {
     pub m: u64,
     pub s: u64,
@@ -71,8 +73,9 @@ pub struct MSAlignment {
 }
 
 impl MSAlignment {
+    // (saturating, so that malformed alignments with huge or zero lengths can't overflow)
     pub fn get_last_m(&self) -> u64 {
-        return self.pos + self.m - 1;
+        return self.pos.saturating_add(self.m).saturating_sub(1);
     }
     /* never used
     pub fn get_first_s(&self) -> u64 {
@@ -81,7 +84,7 @@ impl MSAlignment {
     */
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MAlignment {
     // old_s and old_m are from the TE alignment
     pub old_s: u64,
@@ -96,21 +99,24 @@ pub struct MAlignment {
 
 impl MAlignment {
     // the old M on the boundary is either the first or last nucleotide of the transposon
+    // (saturating, like MSAlignment::get_last_m)
     pub fn get_boundary_old_m(&self) -> u64 {
         if self.new_plus {
             // start => the TE match is SM
             if self.is_start {
-                return self.new_pos + self.old_s;
+                return self.new_pos.saturating_add(self.old_s);
             }
             // end => the TE match is MS
             else {
-                return self.new_pos + self.old_m - 1;
+                return self.new_pos.saturating_add(self.old_m).saturating_sub(1);
             }
         } else {
+            // +/- => BWA reports the reverse complement of the read, so the TE match comes first
+            // if the TE alignment is SM and last if it is MS
             if self.is_start {
-                return self.new_pos + self.old_m - 1;
+                return self.new_pos.saturating_add(self.old_m).saturating_sub(1);
             } else {
-                return self.new_pos + self.old_s;
+                return self.new_pos.saturating_add(self.old_s);
             }
         }
     }
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use super::output_data_types::OutputInsertions;
+use super::second_sam_file;
+use super::select_alignments;
+use crate::errors::Result;
+
+// the IDs of the insertions (as in the GFF3 and VCF outputs, e.g. "insertion_3") that each read supports,
+// keyed by the QNAME of its genome alignment
+// only the insertions of reference_source are included, since each reference has its own genome alignment
+// at most max_reads_per_insertion reads are kept per insertion, alternating between the upstream
+// and downstream reads so that both ends of the insertion are represented
+pub fn support_read_names(
+    output: &Vec<OutputInsertions>,
+    chrom_order: Option<&Vec<String>>,
+    reference_source: &Option<String>,
+    max_reads_per_insertion: usize,
+) -> HashMap<String, Vec<String>> {
+    let mut read_names: HashMap<String, Vec<String>> = HashMap::new();
+    let insertions = select_alignments::ordered_insertions(output, chrom_order);
+    for (insertion_num, (insertion, insertion_source)) in insertions.iter().enumerate() {
+        if *insertion_source != reference_source {
+            continue;
+        }
+        let id = format!("insertion_{}", insertion_num + 1);
+        let (upstream_reads, downstream_reads) = insertion.reads();
+        let mut num_reads = 0;
+        for read_num in 0..std::cmp::max(upstream_reads.len(), downstream_reads.len()) {
+            for reads in &[upstream_reads, downstream_reads] {
+                if num_reads == max_reads_per_insertion {
+                    break;
+                }
+                if let Some(read) = reads.get(read_num) {
+                    read_names
+                        .entry(read.read_name.clone())
+                        .or_default()
+                        .push(id.clone());
+                    num_reads += 1;
+                }
+            }
+        }
+    }
+    return read_names;
+}
+
+// write the boundary position of every split-read of every insertion (see --emit-boundary-positions)
+// as a long-format TSV, with the insertions numbered as in the GFF3 and VCF outputs,
+// to see how far the reads of an insertion are spread around its breakpoints (e.g. to choose a group blur)
+// and return the number of reads that were written
+pub fn write_boundary_positions<W: Write>(
+    writer: &mut W,
+    output: &Vec<OutputInsertions>,
+    chrom_order: Option<&Vec<String>>,
+) -> Result<u64> {
+    writeln!(writer, "insertion_id\tside\tread_boundary")?;
+    let mut num_reads: u64 = 0;
+    let insertions = select_alignments::ordered_insertions(output, chrom_order);
+    for (insertion_num, (insertion, _)) in insertions.iter().enumerate() {
+        let (upstream_reads, downstream_reads) = insertion.reads();
+        for (side, reads) in &[
+            ("upstream", upstream_reads),
+            ("downstream", downstream_reads),
+        ] {
+            for read in reads.iter() {
+                writeln!(
+                    writer,
+                    "insertion_{}\t{}\t{}",
+                    insertion_num + 1,
+                    side,
+                    read.boundary
+                )?;
+                num_reads += 1;
+            }
+        }
+    }
+    return Ok(num_reads);
+}
+
+// copy the header and the records of a genome-aligned SAM file whose QNAME is in read_names,
+// with the IDs of the insertions that they support in an "XI" tag (e.g. "XI:Z:insertion_1,insertion_3")
+// and return the number of records that were copied
+pub fn extract_support_reads<R: BufRead, W: Write>(
+    reader: R,
+    writer: &mut W,
+    read_names: &HashMap<String, Vec<String>>,
+    sam_layout: &HashMap<String, usize>,
+) -> Result<u64> {
+    let genome_alignment_metadata = second_sam_file::genome_alignment_metadata(sam_layout);
+    let mut num_records: u64 = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('@') {
+            writeln!(writer, "{}", line)?;
+            continue;
+        }
+        let qname = match genome_alignment_metadata.try_read(&line) {
+            Ok(data) => data.get("QNAME")?,
+            Err(_) => continue,
+        };
+        if let Some(ids) = read_names.get(&qname) {
+            writeln!(writer, "{}\tXI:Z:{}", line, ids.join(","))?;
+            num_records += 1;
+        }
+    }
+    return Ok(num_records);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_support_reads, support_read_names, write_boundary_positions};
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use super::super::genome_alignment::SplitReadRanges;
+    use super::super::output_data_types::{NonRefTE, Orientation, OutputInsertions};
+
+    fn read(read_name: &str) -> SplitReadRanges {
+        SplitReadRanges {
+            read_name: read_name.to_owned(),
+            te_range: (1, 50),
+            genome_range: (51, 150),
+            boundary: 1000,
+        }
+    }
+
+    #[test]
+    fn test_boundary_positions() {
+        let read_at = |boundary: u64| SplitReadRanges {
+            boundary,
+            ..read("r")
+        };
+        let output = vec![OutputInsertions {
+            non_reference: vec![NonRefTE {
+                upstream_reads: vec![read_at(1005), read_at(1007)],
+                downstream_reads: vec![read_at(1000)],
+                ..NonRefTE::new(
+                    "roo".to_owned(),
+                    "2L".to_owned(),
+                    1005,
+                    1000,
+                    Orientation::PlusPlus,
+                )
+            }],
+            reference: Vec::new(),
+            reference_source: None,
+        }];
+        let mut written: Vec<u8> = Vec::new();
+        assert_eq!(
+            write_boundary_positions(&mut written, &output, None).unwrap(),
+            3
+        );
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "insertion_id\tside\tread_boundary\n\
+             insertion_1\tupstream\t1005\n\
+             insertion_1\tupstream\t1007\n\
+             insertion_1\tdownstream\t1000\n"
+        );
+    }
+
+    #[test]
+    fn test_support_reads() {
+        let insertion = |start: u64, upstream: &[&str], downstream: &[&str]| NonRefTE {
+            upstream_reads: upstream.iter().map(|name| read(name)).collect(),
+            downstream_reads: downstream.iter().map(|name| read(name)).collect(),
+            ..NonRefTE::new(
+                "roo".to_owned(),
+                "2L".to_owned(),
+                start + 5,
+                start,
+                Orientation::PlusPlus,
+            )
+        };
+        let output = vec![OutputInsertions {
+            non_reference: vec![
+                insertion(1000, &["a1", "a2", "a3"], &["b1"]),
+                insertion(5000, &["c1"], &[]),
+            ],
+            reference: Vec::new(),
+            reference_source: None,
+        }];
+
+        // the cap keeps a read from both ends of the first insertion
+        let read_names = support_read_names(&output, None, &None, 2);
+        let mut names: Vec<&String> = read_names.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["a1", "b1", "c1"]);
+        assert_eq!(read_names["c1"], vec!["insertion_2"]);
+        assert!(support_read_names(&output, None, &Some("dmel".to_owned()), 2).is_empty());
+
+        let sam = "@SQ\tSN:2L\tLN:23000000\n\
+                   a1\t0\t2L\t906\t60\t100M50S\t*\t0\t0\t*\t*\n\
+                   a2\t0\t2L\t906\t60\t100M50S\t*\t0\t0\t*\t*\n\
+                   c1\t16\t2L\t4906\t60\t100M50S\t*\t0\t0\t*\t*\n\
+                   truncated\n";
+        let mut extracted: Vec<u8> = Vec::new();
+        let num_records = extract_support_reads(
+            Cursor::new(sam),
+            &mut extracted,
+            &read_names,
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(num_records, 2);
+        assert_eq!(
+            String::from_utf8(extracted).unwrap(),
+            "@SQ\tSN:2L\tLN:23000000\n\
+             a1\t0\t2L\t906\t60\t100M50S\t*\t0\t0\t*\t*\tXI:Z:insertion_1\n\
+             c1\t16\t2L\t4906\t60\t100M50S\t*\t0\t0\t*\t*\tXI:Z:insertion_2\n"
+        );
+    }
+}
@@ -1,16 +1,15 @@
-use anyhow::{bail, Context, Result};
-
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
+use super::long_read::{self, CigarSpans};
+use crate::errors::{Result, StanexError};
 use crate::tabular::Data;
 
 // module with some helper structs and functions to represent split reads
 mod split_read_te {
-    use anyhow::{bail, Result};
-
     use super::super::split_read::{MSAlignment, SMAlignment};
+    use crate::errors::{Result, StanexError};
     use crate::regexes;
 
     #[derive(Debug)]
@@ -33,24 +32,24 @@ mod split_read_te {
             }
         }
         pub fn parse(cigar: String, pos: u64) -> Result<SplitReadTE> {
-            if regexes::SM_REGEX.is_match(&cigar[..]) {
-                let s: u64 = regexes::get_capture(regexes::SM_REGEX.captures(&cigar[..]), 1);
-                let m: u64 = regexes::get_capture(regexes::SM_REGEX.captures(&cigar[..]), 2);
+            if let Some((s, m)) = regexes::get_cigar_lengths(&regexes::SM_REGEX, &cigar[..]) {
                 Ok(SplitReadTE::SM(SMAlignment {
                     s: s,
                     m: m,
                     pos: pos,
                 }))
-            } else if regexes::MS_REGEX.is_match(&cigar[..]) {
-                let m: u64 = regexes::get_capture(regexes::MS_REGEX.captures(&cigar[..]), 1);
-                let s: u64 = regexes::get_capture(regexes::MS_REGEX.captures(&cigar[..]), 2);
+            } else if let Some((m, s)) = regexes::get_cigar_lengths(&regexes::MS_REGEX, &cigar[..])
+            {
                 Ok(SplitReadTE::MS(MSAlignment {
                     m: m,
                     s: s,
                     pos: pos,
                 }))
             } else {
-                bail!("CIGAR string is not SM or MS");
+                Err(StanexError::MalformedCigar {
+                    cigar,
+                    expected: "SM or MS",
+                })
             }
         }
     }
@@ -62,37 +61,48 @@ pub use split_read_te::SplitReadTE;
 // note: is_sm and is_start should be the same in all selected reads
 #[derive(Debug)]
 pub struct TeAlignment {
-    pub qname: String,  // name of the read
-    pub rname: String,  // name of the transposon
-    pub m_size: u64,    // size of the match
-    pub s_size: u64,    // size of the alignment that's outside the transposon
-    pub is_sm: bool,    // is it an SM alignment (true) or an MS alignment (false)?
-    pub is_start: bool, // is it at the start (true) or end (false) of the transposon?
-    pub seq: String,    // the sequence of the read
+    pub qname: String,   // name of the read
+    pub rname: String,   // name of the transposon
+    pub m_size: u64,     // size of the match
+    pub s_size: u64,     // size of the alignment that's outside the transposon
+    pub is_sm: bool,     // is it an SM alignment (true) or an MS alignment (false)?
+    pub is_start: bool,  // is it at the start (true) or end (false) of the transposon?
+    pub seq: String,     // the sequence of the read
+    pub qual: String,    // the base qualities of the read ("*" if the aligner didn't keep them)
+    pub long_read: bool, // is it a long read (see long_read), which can have clips on both sides?
 }
 
 impl TeAlignment {
     // is the alignment mapped? The 3rd least-significant bit of the SAM flag must equal 0 (1 means unmapped)
-    fn is_mapped(flag: String) -> bool {
-        let sam_flag: u16 = flag.parse().unwrap();
+    fn is_mapped(sam_flag: u16) -> bool {
         (sam_flag & 4) == 0
     }
 
     // is the CIGAR string valid? In other words, is it ...S...M or ...M...S?
+    // the soft-clip must also be at least min_clip nucleotides long
+    // (a read like 1S149M carries almost no evidence of a transposon junction)
     fn validate_cigar_string(
         cigar_str: String,
         pos: u64,
         rname: &String,
         transposon_lengths: &HashMap<String, u64>,
+        min_clip: u64,
     ) -> Result<SplitReadTE> {
-        let split_read = SplitReadTE::parse(cigar_str, pos);
+        let split_read = SplitReadTE::parse(cigar_str.clone(), pos);
+        if let Ok(split_read) = &split_read {
+            if split_read.s() < min_clip {
+                return Err(StanexError::ClipTooShort {
+                    cigar: cigar_str,
+                    min_clip,
+                });
+            }
+        }
 
         // if it is a SM read, we need it to match at the start of the transposon
         // if it is a MS read, we need it to match at the end of the transposon
-        let transposon_length = *transposon_lengths.get(rname).context(format!(
-            "unable to find transposon \"{}\" in transposon list",
-            rname
-        ))?;
+        let transposon_length = *transposon_lengths
+            .get(rname)
+            .ok_or_else(|| StanexError::UnknownTransposon(rname.clone()))?;
 
         match split_read {
             // not a split read (matches neither regex)
@@ -101,36 +111,78 @@ impl TeAlignment {
                 if sm_read.get_first_m() == 1 {
                     Ok(SplitReadTE::SM(sm_read))
                 } else {
-                    bail!("SM read does not align to start of transposon");
+                    Err(StanexError::NotAtTransposonEnd {
+                        cigar: cigar_str,
+                        te_name: rname.clone(),
+                    })
                 }
             }
             Ok(SplitReadTE::MS(ms_read)) => {
                 if ms_read.get_last_m() == transposon_length {
                     Ok(SplitReadTE::MS(ms_read))
                 } else {
-                    bail!("MS read does not align to end of transposon");
+                    Err(StanexError::NotAtTransposonEnd {
+                        cigar: cigar_str,
+                        te_name: rname.clone(),
+                    })
                 }
             }
         }
     }
 
+    // reads with more than max_read_n ambiguous bases ("N") are skipped, since an N-rich clip
+    // (e.g. a low-quality read end) can align to the genome anywhere
+    fn check_ambiguous_bases(qname: &str, seq: &str, max_read_n: Option<u64>) -> Result<()> {
+        if let Some(max_read_n) = max_read_n {
+            let count = seq
+                .bytes()
+                .filter(|base| base.eq_ignore_ascii_case(&b'N'))
+                .count() as u64;
+            if count > max_read_n {
+                return Err(StanexError::TooManyAmbiguousBases {
+                    read: qname.to_owned(),
+                    count,
+                    max_read_n,
+                });
+            }
+        }
+        return Ok(());
+    }
+
     // create a TE alignment from a tabular::Data (skip if it doesn't meet criteria)
-    // we have 2 criteria:
+    // we have 4 criteria:
     // 1. SAM flag does not "&" with 4 (4 means unmapped)
-    // 2. read aligns at the start or end of the transposon
-    pub fn create(data: Data, transposon_lengths: &HashMap<String, u64>) -> Result<TeAlignment> {
-        if !TeAlignment::is_mapped(data.get("FLAG")?) {
-            bail!("unmapped read");
+    // 2. RNAME and CIGAR are not "*" (placeholder records have no position)
+    // 3. read aligns at the start or end of the transposon
+    // 4. read has at most max_read_n ambiguous bases (if given, see --max-read-n)
+    pub fn create(
+        data: Data,
+        transposon_lengths: &HashMap<String, u64>,
+        min_clip: u64,
+        max_read_n: Option<u64>,
+    ) -> Result<TeAlignment> {
+        let qname = data.get("QNAME")?;
+        if !TeAlignment::is_mapped(data.get_parsed("FLAG")?) {
+            return Err(StanexError::UnmappedRead(qname));
         }
 
-        let qname = data.get("QNAME")?;
         let rname = data.get("RNAME")?;
-        let pos: u64 = data.get("POS")?.parse()?;
         let cigar_str = data.get("CIGAR")?;
+        if rname == "*" || cigar_str == "*" {
+            return Err(StanexError::UnplacedRead(qname));
+        }
+        let pos: u64 = data.get_parsed("POS")?;
         let seq = data.get("SEQ")?;
+        let qual = data.get("QUAL")?;
 
-        let split_read =
-            TeAlignment::validate_cigar_string(cigar_str, pos, &rname, transposon_lengths)?;
+        let split_read = TeAlignment::validate_cigar_string(
+            cigar_str,
+            pos,
+            &rname,
+            transposon_lengths,
+            min_clip,
+        )?;
+        TeAlignment::check_ambiguous_bases(&qname, &seq, max_read_n)?;
 
         let s_size = split_read.s();
         let m_size = split_read.m();
@@ -156,13 +208,80 @@ impl TeAlignment {
             is_sm: is_sm,
             is_start: is_start,
             seq: seq,
+            qual: qual,
+            long_read: false,
         })
     }
-}
 
-// how to display a TE alignment by default
-impl Display for TeAlignment {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    // create the TE alignment of a long read (see --long-reads)
+    // the alignment can have clips on both sides, and indels anywhere,
+    // since the read can span the whole transposon (the flanks are checked in phase 4)
+    pub fn create_long_read(
+        data: Data,
+        transposon_lengths: &HashMap<String, u64>,
+        min_clip: u64,
+        max_read_n: Option<u64>,
+    ) -> Result<TeAlignment> {
+        let qname = data.get("QNAME")?;
+        let flag: u16 = data.get_parsed("FLAG")?;
+        if !TeAlignment::is_mapped(flag) {
+            return Err(StanexError::UnmappedRead(qname));
+        }
+        // the primary alignment has the whole sequence of the read
+        if flag & 0x900 != 0 {
+            return Err(StanexError::NotPrimaryAlignment(qname));
+        }
+        let rname = data.get("RNAME")?;
+        let cigar_str = data.get("CIGAR")?;
+        if rname == "*" || cigar_str == "*" {
+            return Err(StanexError::UnplacedRead(qname));
+        }
+        let spans = CigarSpans::parse(&cigar_str)?;
+        if !transposon_lengths.contains_key(&rname) {
+            return Err(StanexError::UnknownTransposon(rname));
+        }
+        // a read without a clip is entirely inside the transposon
+        let clip = spans.leading_clip.max(spans.trailing_clip);
+        if clip == 0 {
+            return Err(StanexError::MalformedCigar {
+                cigar: cigar_str,
+                expected: "clipped on at least one side",
+            });
+        }
+        if clip < min_clip {
+            return Err(StanexError::ClipTooShort {
+                cigar: cigar_str,
+                min_clip,
+            });
+        }
+        let seq = data.get("SEQ")?;
+        TeAlignment::check_ambiguous_bases(&qname, &seq, max_read_n)?;
+        Ok(TeAlignment {
+            qname,
+            rname,
+            m_size: spans.query_length,
+            s_size: spans.leading_clip + spans.trailing_clip,
+            is_sm: false,
+            is_start: false,
+            seq,
+            qual: data.get("QUAL")?,
+            long_read: true,
+        })
+    }
+
+    // the name of the selected read: the read name and the TE alignment info
+    // (this is how the info gets through the genome alignment of phase 3, see second_sam_file)
+    fn selected_read_name(&self) -> String {
+        if self.long_read {
+            return format!(
+                "{}|{}|{}|{}|{}|span",
+                self.qname,
+                self.rname,
+                self.m_size,
+                self.s_size,
+                long_read::LONG_READ_MARKER
+            );
+        }
         let start_str = {
             if self.is_start {
                 "start"
@@ -177,10 +296,27 @@ impl Display for TeAlignment {
                 "MS"
             }
         };
-        write!(
-            f,
-            ">{}|{}|{}|{}|{}|{}\n{}",
-            self.qname, self.rname, self.m_size, self.s_size, sm_str, start_str, self.seq
+        format!(
+            "{}|{}|{}|{}|{}|{}",
+            self.qname, self.rname, self.m_size, self.s_size, sm_str, start_str
         )
     }
+
+    // the selected read as a FASTQ record (without the final newline)
+    // reads without base qualities get the highest quality ("~") for every base
+    pub fn to_fastq(&self) -> String {
+        let qual = if self.qual == "*" {
+            "~".repeat(self.seq.len())
+        } else {
+            self.qual.clone()
+        };
+        format!("@{}\n{}\n+\n{}", self.selected_read_name(), self.seq, qual)
+    }
+}
+
+// how to display a TE alignment by default (the selected read as a FASTA record)
+impl Display for TeAlignment {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, ">{}\n{}", self.selected_read_name(), self.seq)
+    }
 }
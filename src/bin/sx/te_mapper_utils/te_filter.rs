@@ -0,0 +1,123 @@
+use regex::Regex;
+
+use std::collections::HashMap;
+
+use super::te_name::TeName;
+
+// restricts the TE mapper to a set of transposon families
+// transposon names look like "roo#LTR/Bel-Pao" (name#class/family), and a pattern
+// (a name or a glob with "*" and "?") matches a transposon if it matches its full name,
+// its name without the suffix, its "class/family" suffix, its class or its family
+// e.g. "LTR" or "LTR/*" match every LTR element, and "roo*" matches roo and its variants
+pub struct TeFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+// turn a glob pattern into a regex that matches the whole string
+fn glob_to_regex(pattern: &str) -> Regex {
+    let mut regex_str = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex_str.push_str(".*"),
+            '?' => regex_str.push('.'),
+            _ => regex_str.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex_str.push('$');
+    Regex::new(&regex_str).unwrap()
+}
+
+// the parts of a transposon name that a pattern is matched against
+fn name_parts(te_name: &str) -> Vec<&str> {
+    let mut parts = vec![te_name];
+    let parsed_name = TeName::parse(te_name);
+    if let Some(class_family) = parsed_name.class_family {
+        parts.push(parsed_name.element);
+        parts.push(class_family);
+        parts.extend(class_family.split('/'));
+    }
+    return parts;
+}
+
+impl TeFilter {
+    // an empty list of included patterns includes every transposon
+    pub fn new(include: &[String], exclude: &[String]) -> TeFilter {
+        TeFilter {
+            include: include
+                .iter()
+                .map(|pattern| glob_to_regex(pattern))
+                .collect(),
+            exclude: exclude
+                .iter()
+                .map(|pattern| glob_to_regex(pattern))
+                .collect(),
+        }
+    }
+
+    fn matches_any(patterns: &[Regex], te_name: &str) -> bool {
+        let parts = name_parts(te_name);
+        patterns
+            .iter()
+            .any(|pattern| parts.iter().any(|part| pattern.is_match(part)))
+    }
+
+    pub fn is_allowed(&self, te_name: &str) -> bool {
+        (self.include.is_empty() || TeFilter::matches_any(&self.include, te_name))
+            && !TeFilter::matches_any(&self.exclude, te_name)
+    }
+
+    // remove the transposons that are filtered out from a map of transposon lengths
+    // (reads that align to a transposon that is not in the map are dropped)
+    pub fn filter_transposons(&self, transposon_lengths: &mut HashMap<String, u64>) {
+        transposon_lengths.retain(|te_name, _| self.is_allowed(te_name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TeFilter;
+
+    use std::collections::HashMap;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let ltr_only = TeFilter::new(&patterns(&["LTR"]), &[]);
+        assert!(ltr_only.is_allowed("roo#LTR/Bel-Pao"));
+        assert!(ltr_only.is_allowed("blood#LTR/Gypsy"));
+        assert!(!ltr_only.is_allowed("pogo#DNA/TcMar-Pogo"));
+        assert!(!ltr_only.is_allowed("roo"));
+
+        let no_gypsy = TeFilter::new(&[], &patterns(&["LTR/Gyp*"]));
+        assert!(no_gypsy.is_allowed("roo#LTR/Bel-Pao"));
+        assert!(!no_gypsy.is_allowed("blood#LTR/Gypsy"));
+        assert!(no_gypsy.is_allowed("pogo#DNA/TcMar-Pogo"));
+
+        let everything = TeFilter::new(&[], &[]);
+        assert!(everything.is_allowed("roo"));
+
+        // the exclusions win over the inclusions, and names can be given directly
+        let ltr_but_roo = TeFilter::new(&patterns(&["LTR"]), &patterns(&["ro?"]));
+        assert!(!ltr_but_roo.is_allowed("roo#LTR/Bel-Pao"));
+        assert!(ltr_but_roo.is_allowed("blood#LTR/Gypsy"));
+    }
+
+    #[test]
+    fn test_filter_transposons() {
+        let mut transposon_lengths: HashMap<String, u64> = vec![
+            ("roo#LTR/Bel-Pao".to_owned(), 9092),
+            ("blood#LTR/Gypsy".to_owned(), 7410),
+            ("pogo#DNA/TcMar-Pogo".to_owned(), 2121),
+        ]
+        .into_iter()
+        .collect();
+        TeFilter::new(&patterns(&["LTR/*"]), &[]).filter_transposons(&mut transposon_lengths);
+        let mut te_names: Vec<&String> = transposon_lengths.keys().collect();
+        te_names.sort();
+        assert_eq!(te_names, vec!["blood#LTR/Gypsy", "roo#LTR/Bel-Pao"]);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use super::te_name;
+use crate::errors::{Result, StanexError};
+
+// the lengths of the transposons from a library TSV (see --te-lengths), for pipelines that already
+// know them, instead of the lengths of the "@SQ" lines of the TE alignment's header
+
+// a 2-column (transposon, length) tab-separated file (lines starting with "#" are skipped)
+pub fn read_te_lengths(te_lengths_file: &str) -> Result<HashMap<String, u64>> {
+    return parse_te_lengths(BufReader::new(File::open(te_lengths_file)?));
+}
+
+pub fn parse_te_lengths<R: BufRead>(reader: R) -> Result<HashMap<String, u64>> {
+    let mut te_lengths: HashMap<String, u64> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let length = match fields.get(1).map(|length| length.trim().parse::<u64>()) {
+            Some(Ok(length)) if fields.len() == 2 && !fields[0].is_empty() && length > 0 => length,
+            _ => {
+                return Err(StanexError::InvalidValue {
+                    field: "--te-lengths".to_owned(),
+                    value: line.clone(),
+                })
+            }
+        };
+        te_lengths.insert(fields[0].to_owned(), length);
+    }
+    return Ok(te_lengths);
+}
+
+// replace the lengths of the header's transposons with the ones of the TSV
+// the transposons of the header that aren't in the TSV keep their header lengths,
+// and are returned (sorted) so that the gaps can be reported
+// if case_insensitive, the names of the TSV are matched to the header's whatever their case
+// (and the header's names are kept)
+pub fn apply_te_lengths(
+    transposons: &mut HashMap<String, u64>,
+    te_lengths: &HashMap<String, u64>,
+    case_insensitive: bool,
+) -> Vec<String> {
+    let te_lengths: HashMap<String, u64> = if case_insensitive {
+        let (header_names, _) = te_name::case_insensitive_names(transposons.keys());
+        te_lengths
+            .iter()
+            .map(|(te_name, &length)| {
+                let header_name = header_names.get(&te_name.to_lowercase());
+                (header_name.unwrap_or(te_name).clone(), length)
+            })
+            .collect()
+    } else {
+        te_lengths.clone()
+    };
+    let mut missing: Vec<String> = transposons
+        .keys()
+        .filter(|te_name| !te_lengths.contains_key(*te_name))
+        .cloned()
+        .collect();
+    missing.sort();
+    for (te_name, length) in te_lengths {
+        transposons.insert(te_name, length);
+    }
+    return missing;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_te_lengths, parse_te_lengths};
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_te_lengths() {
+        let te_lengths =
+            parse_te_lengths(Cursor::new("# name\tlength\nroo\t9092\n\njockey\t5020\n")).unwrap();
+        assert_eq!(te_lengths.len(), 2);
+        assert_eq!(te_lengths["jockey"], 5020);
+        for invalid in &[
+            "roo\n",
+            "roo\tlong\n",
+            "roo\t0\n",
+            "\t9092\n",
+            "roo\t9092\textra\n",
+        ] {
+            assert!(parse_te_lengths(Cursor::new(*invalid)).is_err());
+        }
+
+        // the lengths of the header (e.g. a truncated "@SQ" line) are replaced,
+        // and the transposons that the TSV doesn't have are reported
+        let mut transposons: HashMap<String, u64> = vec![
+            ("roo".to_owned(), 909),
+            ("jockey".to_owned(), 5020),
+            ("blood".to_owned(), 7410),
+        ]
+        .into_iter()
+        .collect();
+        let header = transposons.clone();
+        let missing = apply_te_lengths(&mut transposons, &te_lengths, false);
+        assert_eq!(missing, vec!["blood"]);
+        assert_eq!(transposons["roo"], 9092);
+        assert_eq!(transposons["blood"], 7410);
+
+        // the names of the TSV can differ in case from the header's with --case-insensitive-te-names
+        let te_lengths = parse_te_lengths(Cursor::new("Roo\t9092\nJOCKEY\t5020\n")).unwrap();
+        let mut transposons = header;
+        let missing = apply_te_lengths(&mut transposons, &te_lengths, true);
+        assert_eq!(missing, vec!["blood"]);
+        assert_eq!(transposons.len(), 3);
+        assert_eq!(transposons["roo"], 9092);
+    }
+}
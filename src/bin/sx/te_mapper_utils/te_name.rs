@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+// transposon names look like "roo#LTR/Bel-Pao" (element#class/family, as in the RepeatMasker libraries)
+// or just "roo" (as in the Drosophila transposon library, where every element is its own family)
+pub struct TeName<'a> {
+    pub element: &'a str,
+    // the "class/family" suffix (None if the name doesn't have one)
+    pub class_family: Option<&'a str>,
+}
+
+impl<'a> TeName<'a> {
+    pub fn parse(te_name: &'a str) -> TeName<'a> {
+        match te_name.split_once('#') {
+            Some((element, class_family)) => TeName {
+                element: element,
+                class_family: Some(class_family),
+            },
+            None => TeName {
+                element: te_name,
+                class_family: None,
+            },
+        }
+    }
+
+    // the family that the transposon is counted in (its "class/family", or the element itself)
+    pub fn family(&self) -> &'a str {
+        self.class_family.unwrap_or(self.element)
+    }
+
+    // the class of the transposon (e.g. "LTR" for "roo#LTR/Bel-Pao", None if the name doesn't have one)
+    pub fn class(&self) -> Option<&'a str> {
+        self.class_family
+            .map(|class_family| class_family.split('/').next().unwrap())
+    }
+}
+
+// the names of the transposons keyed by their lowercase names, to match the names that only differ
+// in case (see --case-insensitive-te-names), and the lowercase names that more than 1 transposon has
+// (the first of them in sorted order is kept)
+pub fn case_insensitive_names<'a, I: IntoIterator<Item = &'a String>>(
+    te_names: I,
+) -> (HashMap<String, String>, Vec<String>) {
+    let mut te_names: Vec<&String> = te_names.into_iter().collect();
+    te_names.sort();
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut ambiguous: Vec<String> = Vec::new();
+    for te_name in te_names {
+        let lowercase_name = te_name.to_lowercase();
+        if names.contains_key(&lowercase_name) {
+            if !ambiguous.contains(&lowercase_name) {
+                ambiguous.push(lowercase_name);
+            }
+            continue;
+        }
+        names.insert(lowercase_name, te_name.clone());
+    }
+    return (names, ambiguous);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{case_insensitive_names, TeName};
+
+    #[test]
+    fn test_parse() {
+        let te_name = TeName::parse("roo#LTR/Bel-Pao");
+        assert_eq!(te_name.element, "roo");
+        assert_eq!(te_name.class_family, Some("LTR/Bel-Pao"));
+        assert_eq!(te_name.family(), "LTR/Bel-Pao");
+        assert_eq!(te_name.class(), Some("LTR"));
+        assert_eq!(TeName::parse("Helitron#RC").class(), Some("RC"));
+
+        let te_name = TeName::parse("jockey");
+        assert_eq!(te_name.element, "jockey");
+        assert_eq!(te_name.class_family, None);
+        assert_eq!(te_name.family(), "jockey");
+        assert_eq!(te_name.class(), None);
+    }
+
+    #[test]
+    fn test_case_insensitive_names() {
+        let te_names = vec!["roo".to_owned(), "Jockey".to_owned(), "JOCKEY".to_owned()];
+        let (names, ambiguous) = case_insensitive_names(&te_names);
+        assert_eq!(names["roo"], "roo");
+        assert_eq!(names["jockey"], "JOCKEY");
+        assert_eq!(ambiguous, vec!["jockey"]);
+    }
+}
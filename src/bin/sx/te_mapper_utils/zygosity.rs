@@ -0,0 +1,211 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::errors::{Result, StanexError};
+
+// a heuristic zygosity for the non-reference insertions of a single diploid individual (see --coverage)
+// the split-reads of an insertion come from the reads that cross its junctions, and so does the
+// read depth at the TSD, so the fraction of the depth that supports the insertion is about 0.5
+// on one homolog and about 1 on both
+// this is only a hint: the split-reads of an end are usually fewer than the reads of the allele
+// (reads with a short clip don't align to the transposon), and the depth is noisy at low coverage
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Zygosity {
+    Heterozygous,
+    Homozygous,
+    // less support than a heterozygous insertion (e.g. a somatic insertion or a mapping artifact)
+    Uncertain,
+}
+
+impl Zygosity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Zygosity::Heterozygous => "heterozygous",
+            Zygosity::Homozygous => "homozygous",
+            Zygosity::Uncertain => "uncertain",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ZygosityHint {
+    // the read depth at the TSD
+    pub depth: f64,
+    // the split-reads per end over the depth
+    pub support_fraction: f64,
+    pub zygosity: Zygosity,
+}
+
+// the support fractions where an insertion starts being called heterozygous and homozygous
+// (see --zygosity-thresholds)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZygosityThresholds {
+    pub heterozygous: f64,
+    pub homozygous: f64,
+}
+
+impl ZygosityThresholds {
+    pub const DEFAULT: ZygosityThresholds = ZygosityThresholds {
+        heterozygous: 0.2,
+        homozygous: 0.75,
+    };
+
+    // e.g. "0.2,0.75" (with 0 < HET <= HOM)
+    pub fn parse(thresholds: &str) -> Option<ZygosityThresholds> {
+        let (heterozygous, homozygous) = thresholds.split_once(',')?;
+        let heterozygous: f64 = heterozygous.trim().parse().ok()?;
+        let homozygous: f64 = homozygous.trim().parse().ok()?;
+        if !(heterozygous > 0.0 && heterozygous <= homozygous) {
+            return None;
+        }
+        return Some(ZygosityThresholds {
+            heterozygous,
+            homozygous,
+        });
+    }
+
+    // the hint of an insertion with this many split-reads per end (None without coverage)
+    pub fn classify(&self, reads_per_end: f64, depth: f64) -> Option<ZygosityHint> {
+        if depth <= 0.0 {
+            return None;
+        }
+        let support_fraction = reads_per_end / depth;
+        let zygosity = if support_fraction >= self.homozygous {
+            Zygosity::Homozygous
+        } else if support_fraction >= self.heterozygous {
+            Zygosity::Heterozygous
+        } else {
+            Zygosity::Uncertain
+        };
+        return Some(ZygosityHint {
+            depth,
+            support_fraction,
+            zygosity,
+        });
+    }
+}
+
+// the read depth of a sample, from a bedGraph file (zero-based half-open intervals with a depth,
+// e.g. from "bedtools genomecov -bga" or mosdepth), sorted by start within each chromosome
+pub struct Coverage {
+    chroms: HashMap<String, Vec<(u64, u64, f64)>>,
+}
+
+impl Coverage {
+    pub fn open(bedgraph_name: &str, contig_renames: &HashMap<String, String>) -> Result<Coverage> {
+        Coverage::read(BufReader::new(File::open(bedgraph_name)?), contig_renames)
+    }
+
+    // contig_renames are applied to the chromosome names (see --rename-contigs)
+    // ("track" and "browser" lines and "#" comments are skipped)
+    pub fn read<R: BufRead>(
+        reader: R,
+        contig_renames: &HashMap<String, String>,
+    ) -> Result<Coverage> {
+        let mut chroms: HashMap<String, Vec<(u64, u64, f64)>> = HashMap::new();
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let invalid_line = || StanexError::InvalidValue {
+                field: format!("bedGraph line {}", line_num + 1),
+                value: line.clone(),
+            };
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 4 {
+                return Err(invalid_line());
+            }
+            let start: u64 = fields[1].parse().map_err(|_| invalid_line())?;
+            let end: u64 = fields[2].parse().map_err(|_| invalid_line())?;
+            let depth: f64 = fields[3].parse().map_err(|_| invalid_line())?;
+            let chrom = contig_renames
+                .get(fields[0])
+                .cloned()
+                .unwrap_or_else(|| fields[0].to_owned());
+            chroms.entry(chrom).or_default().push((start, end, depth));
+        }
+        for intervals in chroms.values_mut() {
+            intervals.sort_by_key(|&(start, end, _)| (start, end));
+        }
+        return Ok(Coverage { chroms });
+    }
+
+    // the depth at a one-based position (None if no interval covers it)
+    pub fn depth_at(&self, chrom: &str, pos: u64) -> Option<f64> {
+        let intervals = self.chroms.get(chrom)?;
+        // the last interval that starts before the position (zero-based pos - 1)
+        let index = intervals.partition_point(|&(start, _, _)| start < pos);
+        let &(_, end, depth) = intervals.get(index.checked_sub(1)?)?;
+        if end >= pos {
+            return Some(depth);
+        }
+        return None;
+    }
+
+    // the mean depth at the first and last nucleotides of a TSD (one-based fully closed),
+    // or at the one that is covered
+    pub fn tsd_depth(&self, chrom: &str, start: u64, end: u64) -> Option<f64> {
+        match (self.depth_at(chrom, start), self.depth_at(chrom, end)) {
+            (Some(start_depth), Some(end_depth)) => Some((start_depth + end_depth) / 2.0),
+            (Some(depth), None) | (None, Some(depth)) => Some(depth),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coverage, Zygosity, ZygosityThresholds};
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_coverage() {
+        let renames: HashMap<String, String> = vec![("chr2L".to_owned(), "2L".to_owned())]
+            .into_iter()
+            .collect();
+        let coverage = Coverage::read(
+            Cursor::new("track type=bedGraph\nchr2L\t1000\t2000\t30\nchr2L\t0\t1000\t10\n"),
+            &renames,
+        )
+        .unwrap();
+        assert_eq!(coverage.depth_at("2L", 1), Some(10.0));
+        assert_eq!(coverage.depth_at("2L", 1000), Some(10.0));
+        assert_eq!(coverage.depth_at("2L", 1001), Some(30.0));
+        assert_eq!(coverage.depth_at("2L", 2001), None);
+        assert_eq!(coverage.depth_at("3R", 1), None);
+        assert_eq!(coverage.tsd_depth("2L", 996, 1005), Some(20.0));
+        assert_eq!(coverage.tsd_depth("2L", 1996, 2005), Some(30.0));
+        assert!(Coverage::read(Cursor::new("2L\t0\t1000\n"), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_zygosity_thresholds() {
+        let thresholds = ZygosityThresholds::parse("0.2, 0.75").unwrap();
+        assert_eq!(thresholds, ZygosityThresholds::DEFAULT);
+        assert!(ZygosityThresholds::parse("0.8,0.2").is_none());
+        assert!(ZygosityThresholds::parse("0,0.5").is_none());
+        assert!(ZygosityThresholds::parse("0.5").is_none());
+
+        let zygosity = |reads_per_end: f64, depth: f64| {
+            thresholds
+                .classify(reads_per_end, depth)
+                .map(|hint| hint.zygosity)
+        };
+        assert_eq!(zygosity(14.0, 30.0), Some(Zygosity::Heterozygous));
+        assert_eq!(zygosity(27.0, 30.0), Some(Zygosity::Homozygous));
+        assert_eq!(zygosity(2.0, 30.0), Some(Zygosity::Uncertain));
+        assert_eq!(zygosity(2.0, 0.0), None);
+    }
+}
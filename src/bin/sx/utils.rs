@@ -1,8 +1,141 @@
 // a set of common utilities for all StanEx subcommands
 
-use path_abs::PathFile;
+use flate2::read::MultiGzDecoder;
+use path_abs::{PathDir, PathFile};
+use std::collections::{HashMap, VecDeque};
 use std::ffi::OsStr;
-use std::process::Command;
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::errors::{Result, StanexError};
+use crate::regexes;
+
+// the capacity of the buffered readers and writers used for the large SAM, FASTA and FASTQ files
+// (a large buffer means fewer system calls, which matters for multi-gigabyte files on fast disks)
+pub const IO_BUFFER_CAPACITY: usize = 1 << 20;
+
+// the progress messages of a run go to stdout, unless the output itself is written to stdout
+// (see --output -), in which case they go to stderr so that they don't mix with it
+static PROGRESS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn progress_to_stderr() {
+    PROGRESS_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+// the format of the progress and error messages (see --log-format)
+// Text: plain lines for people; Json: one JSON object per line on stderr for pipeline orchestrators
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+static LOG_JSON: AtomicBool = AtomicBool::new(false);
+// the phase of the TE mapper that the log events belong to (0 before the first phase)
+static LOG_PHASE: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_log_format(format: LogFormat) {
+    LOG_JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn log_json() -> bool {
+    LOG_JSON.load(Ordering::Relaxed)
+}
+
+pub fn set_log_phase(phase: u8) {
+    LOG_PHASE.store(phase, Ordering::Relaxed);
+}
+
+// a log event as a single line of JSON, e.g.
+// {"level":"info","phase":4,"message":"Alignment complete","timestamp":1760000000.123}
+// (the counts are only there if there are any, and the phase only once a phase started)
+fn json_event(level: &str, message: &str, counts: &[(&str, u64)], timestamp: f64) -> String {
+    let mut event = serde_json::Map::new();
+    event.insert("level".to_owned(), level.into());
+    let phase = LOG_PHASE.load(Ordering::Relaxed);
+    if phase > 0 {
+        event.insert("phase".to_owned(), phase.into());
+    }
+    // the blank lines around the phase headers are only there for the text log
+    event.insert("message".to_owned(), message.trim().into());
+    event.insert("timestamp".to_owned(), timestamp.into());
+    if !counts.is_empty() {
+        let counts: serde_json::Map<String, serde_json::Value> = counts
+            .iter()
+            .map(|(name, count)| (name.to_string(), (*count).into()))
+            .collect();
+        event.insert("counts".to_owned(), counts.into());
+    }
+    return serde_json::Value::Object(event).to_string();
+}
+
+// write a log event to stderr in the JSON log format (see json_event)
+// level is "info", "warning" or "error"
+pub fn print_json_event(level: &str, message: &str, counts: &[(&str, u64)]) {
+    // seconds since the Unix epoch, to the millisecond
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| (elapsed.as_millis() as f64) / 1000.0)
+        .unwrap_or(0.0);
+    eprintln!("{}", json_event(level, message, counts, timestamp));
+}
+
+pub fn print_progress(message: std::fmt::Arguments) {
+    if log_json() {
+        print_json_event("info", &message.to_string(), &[]);
+    } else if PROGRESS_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+pub fn print_warning(message: std::fmt::Arguments) {
+    if log_json() {
+        print_json_event("warning", &message.to_string(), &[]);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+pub fn print_error(message: std::fmt::Arguments) {
+    if log_json() {
+        print_json_event("error", &message.to_string(), &[]);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+// println! for progress messages (see print_progress)
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        $crate::utils::print_progress(format_args!($($arg)*))
+    };
+}
+pub(crate) use progress;
+
+// eprintln! for the errors that stop a run (see print_error)
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::utils::print_error(format_args!($($arg)*))
+    };
+}
+pub(crate) use log_error;
 
 // create an absolute file path from a relative file path
 // (file must already exist)
@@ -10,7 +143,7 @@ pub fn absolute_filepath_checked(relative: &str) -> PathFile {
     match PathFile::new(relative) {
         Ok(absolute) => absolute,
         Err(x) => {
-            eprintln!("File does not exist: {}", relative);
+            log_error!("File does not exist: {}", relative);
             panic!("{}", x);
         }
     }
@@ -22,17 +155,141 @@ pub fn absolute_filepath_unchecked(relative: &str) -> PathFile {
     match PathFile::create(relative) {
         Ok(absolute) => absolute,
         Err(x) => {
-            eprintln!("An error occurred creating the file: {}", relative);
+            log_error!("An error occurred creating the file: {}", relative);
             panic!("{}", x);
         }
     }
 }
 
+// create the result directory of a run if it's not already there
+// (a regular file at the same path is reported as such, instead of the OS error of creating a directory over it)
+pub fn create_result_dir(result_dir: &str) -> PathDir {
+    let path = Path::new(result_dir);
+    if path.exists() && !path.is_dir() {
+        log_error!(
+            "The result path {} exists and is not a directory",
+            result_dir
+        );
+        std::process::exit(2);
+    }
+    match PathDir::create(result_dir) {
+        Ok(result_dir_path) => result_dir_path,
+        Err(e) => {
+            log_error!(
+                "Unable to create the result directory {}: {}",
+                result_dir,
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// the files of the result directory that are already there (in the order of file_names)
+fn existing_files<'a>(result_dir: &str, file_names: &'a [String]) -> Vec<&'a String> {
+    file_names
+        .iter()
+        .filter(|file_name| Path::new(result_dir).join(file_name).exists())
+        .collect()
+}
+
+// stop before a run replaces the output files of an earlier run in the same result directory,
+// unless --overwrite is given (the inputs of the later phases are not output files of the run)
+pub fn check_overwrite(result_dir: &str, file_names: &[String], overwrite: bool) {
+    if overwrite {
+        return;
+    }
+    let existing = existing_files(result_dir, file_names);
+    if !existing.is_empty() {
+        let existing: Vec<&str> = existing.iter().map(|file_name| &file_name[..]).collect();
+        log_error!(
+            "{} already in {} (use --overwrite to replace)",
+            existing.join(", "),
+            result_dir
+        );
+        std::process::exit(2);
+    }
+}
+
+// check the exit status of the subprocess that ran a pipeline step
+pub fn check_step_status(step: &str, status: ExitStatus) -> Result<()> {
+    if !status.success() {
+        return Err(StanexError::SubprocessFailed {
+            step: step.to_owned(),
+            status: status,
+        });
+    }
+    return Ok(());
+}
+
+// abort with the exit code of a failed pipeline step
+// (otherwise the next step would go on to parse a truncated or missing output file)
+// a subprocess killed by a signal has no exit code, so exit with 1 for it
+pub fn exit_if_failed(step: &str, status: ExitStatus) {
+    if let Err(e) = check_step_status(step, status) {
+        log_error!("Aborting: {}", e);
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+// start the subprocess of a pipeline step, aborting if it can't be started (e.g. it is not installed)
+pub fn spawn_step(step: &str, command: &mut Command) -> Child {
+    match command.spawn() {
+        Ok(child_proc) => child_proc,
+        Err(e) => {
+            log_error!("Aborting: unable to run {}: {}", step, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// wait for the subprocess of a pipeline step to finish, aborting if it failed
+pub fn wait_for_step(step: &str, child_proc: &mut Child) {
+    let status = child_proc.wait().unwrap();
+    exit_if_failed(step, status);
+}
+
 // enum struct to represent both single-end and paired-ends reads files
+// (interleaved paired-ends reads are stored in a single file, with each read followed by its mate)
 #[derive(Clone)]
 pub enum Reads {
     SingleEnd(String),
     PairedEnds(String, String),
+    Interleaved(String),
+}
+
+impl Reads {
+    pub fn files(&self) -> Vec<&String> {
+        match self {
+            Reads::SingleEnd(filename) | Reads::Interleaved(filename) => vec![filename],
+            Reads::PairedEnds(file1, file2) => vec![file1, file2],
+        }
+    }
+
+    // the default sample name: the reads file name without its extensions
+    // (and without the "_1"/"_R1" mate suffix for paired-end reads), e.g. "SRR1234_1.fastq.gz" -> "SRR1234"
+    pub fn sample_name(&self) -> String {
+        let filename = self.files()[0];
+        let mut sample_name = Path::new(filename)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(filename)
+            .to_owned();
+        for extension in &[".gz", ".bz2", ".fastq", ".fq"] {
+            if sample_name.ends_with(extension) {
+                sample_name.truncate(sample_name.len() - extension.len());
+            }
+        }
+        if let Reads::PairedEnds(_, _) = self {
+            for mate_suffix in &["_R1", "_1"] {
+                if sample_name.ends_with(mate_suffix) {
+                    sample_name.truncate(sample_name.len() - mate_suffix.len());
+                    break;
+                }
+            }
+        }
+        return sample_name;
+    }
 }
 
 // creates a bwa index if one does not already exist
@@ -45,23 +302,444 @@ pub fn bwa_index_if_required(ref_name: &str) {
 
     // now check if the BWA index already exists
     let bwa_index_path_str = format!("{}.{}", ref_path_str, "bwt");
-    if let Ok(_) = PathFile::new(bwa_index_path_str) {
-        println!("BWA index already exists");
+    if PathFile::new(bwa_index_path_str).is_ok() {
+        progress!("BWA index already exists");
         return;
     }
 
     // Now that we know that we have to index:
-    println!("Waiting for bwa index...");
-    let mut child_proc = Command::new("bwa")
-        .args(&["index", ref_path_str])
-        .spawn()
-        .unwrap();
-    let _result = child_proc.wait().unwrap();
-    println!("BWA index complete");
+    progress!("Waiting for bwa index...");
+    let mut child_proc = spawn_step(
+        "bwa index",
+        Command::new("bwa").args(&["index", ref_path_str]),
+    );
+    wait_for_step("bwa index", &mut child_proc);
+    progress!("BWA index complete");
+}
+
+// the files that bwa index creates next to a FASTA file
+const BWA_INDEX_EXTENSIONS: [&str; 5] = ["amb", "ann", "bwt", "pac", "sa"];
+
+// the paths of the BWA index files of a FASTA file that exist
+pub fn bwa_index_files(ref_name: &str) -> Vec<String> {
+    let ref_path: PathFile = absolute_filepath_checked(ref_name);
+    let ref_path_os_str: &OsStr = ref_path.as_ref();
+    let ref_path_str: &str = ref_path_os_str.to_str().unwrap();
+    return BWA_INDEX_EXTENSIONS
+        .iter()
+        .map(|extension| format!("{}.{}", ref_path_str, extension))
+        .filter(|index_path| Path::new(index_path).is_file())
+        .collect();
+}
+
+// creates a samtools FASTA index if one does not already exist
+// by default, samtools faidx will create a new file with name equal to the original file name + ".fai"
+// returns the path to the index
+pub fn samtools_faidx_if_required(ref_name: &str) -> String {
+    let ref_path: PathFile = absolute_filepath_checked(ref_name);
+    let ref_path_os_str: &OsStr = ref_path.as_ref();
+    let ref_path_str: &str = ref_path_os_str.to_str().unwrap();
+
+    let fai_path_str = format!("{}.{}", ref_path_str, "fai");
+    if PathFile::new(&fai_path_str).is_ok() {
+        return fai_path_str;
+    }
+
+    progress!("Waiting for samtools faidx...");
+    let mut child_proc = spawn_step(
+        "samtools faidx",
+        Command::new("samtools").args(&["faidx", ref_path_str]),
+    );
+    wait_for_step("samtools faidx", &mut child_proc);
+    progress!("FASTA index complete");
+    return fai_path_str;
+}
+
+// sort a SAM file into a BAM file and index it (so that the alignments can be loaded into IGV)
+pub fn samtools_sort_and_index(sam_name: &str, bam_name: &str) {
+    let mut child_proc = spawn_step(
+        "samtools sort",
+        Command::new("samtools").args(&["sort", "-O", "bam", sam_name, "-o", bam_name]),
+    );
+    wait_for_step("samtools sort", &mut child_proc);
+    let mut child_proc = spawn_step(
+        "samtools index",
+        Command::new("samtools").args(&["index", bam_name]),
+    );
+    wait_for_step("samtools index", &mut child_proc);
+}
+
+// read the next line without its line ending ("\n" or "\r\n"), with the number of bytes it took up
+// in the file (None at the end of the file)
+pub fn read_line_with_length<R: BufRead>(reader: &mut R) -> Result<Option<(String, usize)>> {
+    let mut line = String::new();
+    let num_bytes = reader.read_line(&mut line)?;
+    if num_bytes == 0 {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    return Ok(Some((line, num_bytes)));
+}
+
+// read the next line without its line ending (None at the end of the file)
+pub fn read_line_trimmed<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    return Ok(read_line_with_length(reader)?.map(|(line, _)| line));
+}
+
+// get the name and length of every chromosome (contig) in a ".fai" index, in order
+// (the first 2 columns of the index are the contig name and its length)
+pub fn read_fai(fai_path_str: &str) -> Vec<(String, u64)> {
+    let mut contigs: Vec<(String, u64)> = Vec::new();
+    let lines = BufReader::new(File::open(fai_path_str).unwrap())
+        .lines()
+        .map(|l| l.unwrap());
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 {
+            continue;
+        }
+        let length: u64 = fields[1]
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid contig length in {}: {}", fai_path_str, line));
+        contigs.push((fields[0].to_owned(), length));
+    }
+    return contigs;
+}
+
+// get the length of every chromosome (contig) in a reference FASTA file from its ".fai" index
+pub fn read_chrom_lengths(ref_name: &str) -> HashMap<String, u64> {
+    let fai_path_str = samtools_faidx_if_required(ref_name);
+    return read_fai(&fai_path_str).into_iter().collect();
+}
+
+// get the order of the chromosomes (contigs) from a ".fai" index or a ".genome" file
+// (the first column of each line; lines starting with "#" are skipped)
+pub fn read_chrom_order(chrom_order_file: &str) -> Vec<String> {
+    let lines = BufReader::new(File::open(chrom_order_file).unwrap_or_else(|e| {
+        panic!(
+            "Unable to open chromosome order file {}: {}",
+            chrom_order_file, e
+        )
+    }))
+    .lines()
+    .map(|l| l.unwrap());
+    let mut chrom_order: Vec<String> = Vec::new();
+    for line in lines {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let chrom = line.split('\t').nth(0).unwrap().to_owned();
+        if !chrom_order.contains(&chrom) {
+            chrom_order.push(chrom);
+        }
+    }
+    return chrom_order;
+}
+
+// parse the contig renames given with --rename-contigs (a map from the contig names of the reference
+// and alignments to the names used by the TE mapper, e.g. "chr2L" to "2L")
+// each value is either "old=new" or the path to a 2-column (old, new) tab-separated file
+pub fn parse_contig_renames(values: &[String]) -> Result<HashMap<String, String>> {
+    let mut contig_renames: HashMap<String, String> = HashMap::new();
+    for value in values {
+        if let Some((old_name, new_name)) = value.split_once('=') {
+            if old_name.is_empty() || new_name.is_empty() {
+                return Err(StanexError::InvalidValue {
+                    field: "--rename-contigs".to_owned(),
+                    value: value.clone(),
+                });
+            }
+            contig_renames.insert(old_name.to_owned(), new_name.to_owned());
+            continue;
+        }
+        // a value that is neither a rename nor a file is reported as it is (e.g. a missing "=new")
+        let renames_file = File::open(value).map_err(|_| StanexError::InvalidValue {
+            field: "--rename-contigs".to_owned(),
+            value: value.clone(),
+        })?;
+        for line in BufReader::new(renames_file).lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 2 || fields[0].is_empty() || fields[1].is_empty() {
+                return Err(StanexError::InvalidValue {
+                    field: format!("--rename-contigs ({})", value),
+                    value: line.clone(),
+                });
+            }
+            contig_renames.insert(fields[0].to_owned(), fields[1].to_owned());
+        }
+    }
+    return Ok(contig_renames);
+}
+
+// the chromosome allow-list of --chromosomes and --chromosomes-file (in order, without duplicates)
+// the file has one contig name per line; blank lines and "#" comments are ignored
+pub fn parse_chromosome_list(
+    chromosomes: &[String],
+    chromosomes_file: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut chromosome_list: Vec<String> = Vec::new();
+    let mut add_chromosome = |chrom: &str| {
+        if !chromosome_list.iter().any(|listed| listed == chrom) {
+            chromosome_list.push(chrom.to_owned());
+        }
+    };
+    for chrom in chromosomes {
+        add_chromosome(chrom.trim());
+    }
+    if let Some(chromosomes_file) = chromosomes_file {
+        for line in BufReader::new(File::open(chromosomes_file)?).lines() {
+            let line = line?;
+            let chrom = match line.find('#') {
+                Some(comment_start) => &line[..comment_start],
+                None => &line[..],
+            }
+            .trim();
+            if !chrom.is_empty() {
+                add_chromosome(chrom);
+            }
+        }
+    }
+    return Ok(chromosome_list);
+}
+
+// the name of a contig after the renames of --rename-contigs
+pub fn rename_contig<'a>(contig: &'a str, contig_renames: &'a HashMap<String, String>) -> &'a str {
+    contig_renames
+        .get(contig)
+        .map(|new_name| &new_name[..])
+        .unwrap_or(contig)
+}
+
+// split the chromosome list into the contigs that are at least min_length long and the ones that are
+// shorter (e.g. the tiny scaffolds of a draft assembly, see --min-contig-length)
+// contigs that aren't in chrom_lengths are kept, since their length is unknown
+pub fn exclude_short_contigs(
+    chroms: Vec<String>,
+    chrom_lengths: &HashMap<String, u64>,
+    min_length: u64,
+) -> (Vec<String>, Vec<String>) {
+    return chroms.into_iter().partition(|chrom| {
+        chrom_lengths
+            .get(chrom)
+            .is_none_or(|&length| length >= min_length)
+    });
+}
+
+// open a (possibly gzipped) FASTQ file
+fn open_fastq(fastq_file: &str) -> Result<Box<dyn BufRead>> {
+    let file = File::open(fastq_file)?;
+    if fastq_file.ends_with(".gz") {
+        Ok(Box::new(BufReader::with_capacity(
+            IO_BUFFER_CAPACITY,
+            MultiGzDecoder::new(file),
+        )))
+    } else {
+        Ok(Box::new(BufReader::with_capacity(IO_BUFFER_CAPACITY, file)))
+    }
+}
+
+// the name of a read from its FASTQ header line, without the "@", the comment,
+// and the "/1" or "/2" mate suffix (so that the 2 reads of a pair have the same name)
+fn read_pair_name(header: &str) -> &str {
+    let name = header
+        .trim_start_matches('@')
+        .split_whitespace()
+        .nth(0)
+        .unwrap_or("");
+    name.strip_suffix("/1")
+        .or_else(|| name.strip_suffix("/2"))
+        .unwrap_or(name)
+}
+
+// the number of records in a FASTQ file and the names of its first and last reads
+#[derive(Debug, PartialEq)]
+struct FastqSummary {
+    num_records: u64,
+    first_name: String,
+    last_name: String,
+}
+
+fn summarize_fastq<R: BufRead>(reader: R, fastq_file: &str) -> Result<FastqSummary> {
+    let mut summary = FastqSummary {
+        num_records: 0,
+        first_name: String::new(),
+        last_name: String::new(),
+    };
+    let mut num_lines: u64 = 0;
+    for line in reader.lines() {
+        let line = line?;
+        // every record is 4 lines long and starts with the header line
+        if num_lines % 4 == 0 {
+            let name = read_pair_name(&line).to_owned();
+            if summary.num_records == 0 {
+                summary.first_name = name.clone();
+            }
+            summary.last_name = name;
+            summary.num_records += 1;
+        }
+        num_lines += 1;
+    }
+    if num_lines % 4 != 0 {
+        return Err(StanexError::TruncatedFastq(fastq_file.to_owned()));
+    }
+    return Ok(summary);
+}
+
+// make sure that 2 paired-end FASTQ files have the same number of reads
+// and that their first and last reads are mates (BWA silently produces garbage alignments otherwise)
+// this reads both files once, so it is only done if the user asks for it
+pub fn validate_read_pairs(reads1: &str, reads2: &str) -> Result<()> {
+    progress!("Validating read pairs...");
+    let summary1 = summarize_fastq(open_fastq(reads1)?, reads1)?;
+    let summary2 = summarize_fastq(open_fastq(reads2)?, reads2)?;
+    if summary1.num_records != summary2.num_records {
+        return Err(StanexError::MismatchedReadPairs(format!(
+            "{} has {} reads but {} has {} reads",
+            reads1, summary1.num_records, reads2, summary2.num_records
+        )));
+    }
+    for (name1, name2) in &[
+        (&summary1.first_name, &summary2.first_name),
+        (&summary1.last_name, &summary2.last_name),
+    ] {
+        if name1 != name2 {
+            return Err(StanexError::MismatchedReadPairs(format!(
+                "read {} in {} is paired with read {} in {}",
+                name1, reads1, name2, reads2
+            )));
+        }
+    }
+    progress!("Read pairs validated ({} pairs)", summary1.num_records);
+    return Ok(());
+}
+
+// the options of "bwa mem" that take a value, and the ones that are flags
+// (-t, -o and -p are left out since bwa_mem_align sets them)
+const BWA_MEM_VALUE_OPTIONS: &str = "kwdrycDWmABOELUxRHKvThI";
+const BWA_MEM_FLAG_OPTIONS: &str = "SP5qaCVYMj";
+
+// check the extra BWA MEM arguments (e.g. from --bwa-k or --bwa-extra) before they are
+// appended to the "bwa mem" arguments: only the options of "bwa mem" are allowed, so that they can't
+// add input files or run another command (a value can be attached to its option, e.g. "-k19")
+pub fn check_bwa_mem_args(args: &[String]) -> Result<()> {
+    let invalid_arg = |arg: &String| StanexError::InvalidValue {
+        field: "BWA MEM argument".to_owned(),
+        value: arg.clone(),
+    };
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        let option = match arg
+            .strip_prefix('-')
+            .and_then(|option| option.chars().nth(0))
+        {
+            Some(option) => option,
+            None => return Err(invalid_arg(arg)),
+        };
+        if BWA_MEM_VALUE_OPTIONS.contains(option) {
+            // the value is either attached or the next argument
+            if arg.len() == 2 && args_iter.next().is_none() {
+                return Err(invalid_arg(arg));
+            }
+        } else if !BWA_MEM_FLAG_OPTIONS.contains(option) || arg.len() != 2 {
+            return Err(invalid_arg(arg));
+        }
+    }
+    return Ok(());
+}
+
+// the number of CPUs that this process can use (1 if it is unknown)
+pub fn available_cpus() -> usize {
+    std::thread::available_parallelism()
+        .map(|cpus| cpus.get())
+        .unwrap_or(1)
+}
+
+// the number of threads to run with: more threads than the available CPUs are capped to them,
+// since they would only slow down the other jobs of a shared node (unless allow_oversubscribe is set)
+pub fn checked_thread_count(
+    requested: usize,
+    available: usize,
+    allow_oversubscribe: bool,
+    threads_name: &str,
+) -> usize {
+    if requested <= available || allow_oversubscribe {
+        return requested;
+    }
+    print_warning(format_args!(
+        "{} {} threads requested, but only {} CPUs are available: running with {} (use --allow-oversubscribe to keep {})",
+        requested, threads_name, available, available, requested
+    ));
+    return available;
+}
+
+// where BWA MEM writes its SAM output
+pub enum BwaOutput<'a> {
+    // a SAM file
+    File(&'a str),
+    // the standard input of the next pipeline step (its name and command, e.g. samtools fixmate),
+    // so that the SAM output is never written to disk
+    Pipe(&'a str, &'a mut Command),
+    // read by this process while BWA writes it (e.g. parsed into the alignments of phase 4, see --stream),
+    // so that the SAM output is never written to disk
+    Stream(&'a mut dyn FnMut(&mut BufReader<ChildStdout>)),
+}
+
+// a reader that counts the bytes that were read through it, e.g. the size of a streamed SAM output
+pub struct CountingReader<R> {
+    reader: R,
+    bytes_read: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(reader: R) -> CountingReader<R> {
+        CountingReader {
+            reader,
+            bytes_read: 0,
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num_bytes = self.reader.read(buf)?;
+        self.bytes_read += num_bytes as u64;
+        return Ok(num_bytes);
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.bytes_read += amt as u64;
+        self.reader.consume(amt);
+    }
 }
 
 // does an alignment using BWA MEM
-pub fn bwa_mem_align(ref_name: &str, reads_names: &Reads, result_file: &str, bwa_threads: u16) {
+// bwa_options are extra "bwa mem" arguments (checked with check_bwa_mem_args)
+pub fn bwa_mem_align(
+    ref_name: &str,
+    reads_names: &Reads,
+    output: BwaOutput,
+    bwa_threads: u16,
+    bwa_options: &[String],
+) {
     // first, create the absolute filepaths from the relative filepaths of the ref and reads (throw an error if they don't exist)
     let ref_path: PathFile = absolute_filepath_checked(ref_name);
     let ref_path_os_str: &OsStr = ref_path.as_ref();
@@ -85,49 +763,403 @@ pub fn bwa_mem_align(ref_name: &str, reads_names: &Reads, result_file: &str, bwa
 
             Reads::PairedEnds(file1_path_str.to_owned(), file2_path_str.to_owned())
         }
+        Reads::Interleaved(filename) => {
+            let file_path: PathFile = absolute_filepath_checked(filename);
+            let file_path_os_str: &OsStr = file_path.as_ref();
+            let file_path_str: &str = file_path_os_str.to_str().unwrap();
+            Reads::Interleaved(file_path_str.to_owned())
+        }
     };
 
-    // now do the alignment and store in the result file
-    println!("Waiting for bwa mem...");
-    match absolute_reads {
-        Reads::SingleEnd(filepath) => {
-            println!(
-                "bwa mem -t {} -o {} {} {}",
-                &bwa_threads.to_string()[..],
-                &result_file[..],
-                ref_path_str,
-                &filepath[..]
+    let bwa_threads_str = bwa_threads.to_string();
+    let mut args: Vec<&str> = vec!["mem"];
+    // "-p" tells BWA that the reads file contains interleaved pairs
+    if let Reads::Interleaved(_) = absolute_reads {
+        args.push("-p");
+    }
+    args.extend(&["-t", &bwa_threads_str[..]]);
+    args.extend(bwa_options.iter().map(|option| &option[..]));
+    // without "-o", BWA writes the SAM output to stdout
+    if let BwaOutput::File(result_file) = &output {
+        args.extend(&["-o", *result_file]);
+    }
+    args.push(ref_path_str);
+    let reads_files = absolute_reads.files();
+    args.extend(reads_files.iter().map(|file| &file[..]));
+
+    // now do the alignment and store in the result file (or pass it on to the next step)
+    match &output {
+        BwaOutput::File(_) => progress!("Waiting for bwa mem..."),
+        BwaOutput::Pipe(step, _) => progress!("Waiting for bwa mem | {}...", step),
+        BwaOutput::Stream(_) => progress!("Waiting for bwa mem (streamed into the next phase)..."),
+    }
+    progress!("bwa {}", args.join(" "));
+    run_bwa_mem_with_progress(
+        &args,
+        estimate_fastq_bases(
+            &reads_files
+                .iter()
+                .map(|file| &file[..])
+                .collect::<Vec<&str>>(),
+        ),
+        output,
+    );
+    progress!("Alignment complete");
+}
+
+// roughly estimate the number of bases in a set of FASTQ files from their size on disk
+// (every record stores its sequence and its quality string, plus 2 short header lines,
+// so a little under half of the bytes in the file are bases)
+// compressed inputs can't be estimated this way, so return None for them
+fn estimate_fastq_bases(fastq_files: &[&str]) -> Option<u64> {
+    let mut total_bytes: u64 = 0;
+    for fastq_file in fastq_files {
+        if fastq_file.ends_with(".gz") {
+            return None;
+        }
+        total_bytes += fs::metadata(fastq_file).ok()?.len();
+    }
+    Some(total_bytes * 9 / 20)
+}
+
+// format a number of seconds as e.g. "1h 02m 03s"
+fn format_duration(seconds: u64) -> String {
+    format!(
+        "{}h {:02}m {:02}s",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+// runs BWA MEM with the given arguments, passing BWA's own log lines through to stderr
+// BWA prints a line for every batch of reads it loads ("[M::process] read N sequences (M bp)")
+// and for every batch it finishes aligning ("[M::mem_process_seqs] Processed N reads"),
+// so we parse those lines to print a progress line (and an ETA if we know roughly how many bases there are)
+// the SAM output itself is written by BWA to the file given with "-o" and is unaffected,
+// or piped straight into the next step, which runs at the same time as BWA
+fn run_bwa_mem_with_progress(args: &[&str], estimated_total_bases: Option<u64>, output: BwaOutput) {
+    let mut bwa_command = Command::new("bwa");
+    bwa_command.args(args).stderr(Stdio::piped());
+    if let BwaOutput::Pipe(..) | BwaOutput::Stream(_) = output {
+        bwa_command.stdout(Stdio::piped());
+    }
+    let mut child_proc = spawn_step("bwa mem", &mut bwa_command);
+    let mut next_step = None;
+    let mut stream = None;
+    match output {
+        BwaOutput::File(_) => {}
+        BwaOutput::Pipe(step, command) => {
+            let bwa_stdout = Stdio::from(child_proc.stdout.take().unwrap());
+            next_step = Some((step, spawn_step(step, command.stdin(bwa_stdout))));
+        }
+        BwaOutput::Stream(read_output) => {
+            let bwa_stdout =
+                BufReader::with_capacity(IO_BUFFER_CAPACITY, child_proc.stdout.take().unwrap());
+            stream = Some((read_output, bwa_stdout));
+        }
+    };
+    let bwa_stderr = BufReader::new(child_proc.stderr.take().unwrap());
+
+    match stream {
+        // BWA blocks once either pipe is full, so its progress is followed on another thread
+        // while its output is read on this one
+        Some((read_output, mut bwa_stdout)) => std::thread::scope(|scope| {
+            scope.spawn(|| report_bwa_progress(bwa_stderr, estimated_total_bases));
+            read_output(&mut bwa_stdout);
+            // BWA stops at the closed pipe if its output wasn't read to the end
+            drop(bwa_stdout);
+        }),
+        None => report_bwa_progress(bwa_stderr, estimated_total_bases),
+    }
+    wait_for_step("bwa mem", &mut child_proc);
+    if let Some((step, next_proc)) = &mut next_step {
+        wait_for_step(step, next_proc);
+    }
+}
+
+// print BWA's stderr, and the number of aligned reads (with an ETA) after every batch
+fn report_bwa_progress<R: BufRead>(bwa_stderr: R, estimated_total_bases: Option<u64>) {
+    // BWA loads the next batch while it is still aligning the previous one,
+    // so keep the sizes of the loaded batches in a queue until they are processed
+    let mut loaded_batch_bases: VecDeque<u64> = VecDeque::new();
+    let mut processed_reads: u64 = 0;
+    let mut processed_bases: u64 = 0;
+    let start_time = Instant::now();
+
+    for line in bwa_stderr.lines() {
+        let line = line.expect("Something went wrong - unable to read BWA output");
+        // BWA's own lines become log events too in the JSON log format, so that stderr stays NDJSON
+        if log_json() {
+            print_json_event("info", &line, &[]);
+        } else {
+            eprintln!("{}", line);
+        }
+        if regexes::BWA_READ_BATCH_REGEX.is_match(&line[..]) {
+            loaded_batch_bases.push_back(regexes::get_capture(
+                regexes::BWA_READ_BATCH_REGEX.captures(&line[..]),
+                2,
+            ));
+        } else if regexes::BWA_PROCESSED_BATCH_REGEX.is_match(&line[..]) {
+            processed_reads +=
+                regexes::get_capture(regexes::BWA_PROCESSED_BATCH_REGEX.captures(&line[..]), 1);
+            processed_bases += loaded_batch_bases.pop_front().unwrap_or(0);
+            let elapsed_seconds = start_time.elapsed().as_secs_f64();
+            let reads_per_second = processed_reads as f64 / elapsed_seconds.max(1.0);
+            let eta = match estimated_total_bases {
+                Some(total_bases) if processed_bases > 0 && total_bases > processed_bases => {
+                    let remaining_fraction =
+                        (total_bases - processed_bases) as f64 / processed_bases as f64;
+                    format!(
+                        ", ETA ~{}",
+                        format_duration((elapsed_seconds * remaining_fraction) as u64)
+                    )
+                }
+                _ => String::new(),
+            };
+            progress!(
+                "BWA progress: {} reads aligned in {} ({:.0} reads/s){}",
+                processed_reads,
+                format_duration(elapsed_seconds as u64),
+                reads_per_second,
+                eta
             );
-            let mut child_proc = Command::new("bwa")
-                .args(&[
-                    "mem",
-                    "-t",
-                    &bwa_threads.to_string()[..],
-                    "-o",
-                    &result_file[..],
-                    ref_path_str,
-                    &filepath[..],
-                ])
-                .spawn()
-                .unwrap();
-            let _result = child_proc.wait().unwrap();
         }
-        Reads::PairedEnds(file1, file2) => {
-            let mut child_proc = Command::new("bwa")
-                .args(&[
-                    "mem",
-                    "-t",
-                    &bwa_threads.to_string()[..],
-                    "-o",
-                    &result_file[..],
-                    ref_path_str,
-                    &file1[..],
-                    &file2[..],
-                ])
-                .spawn()
-                .unwrap();
-            let _result = child_proc.wait().unwrap();
-        }
-    }
-    println!("Alignment complete");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // a reader that fails on every read
+    struct FailingReader;
+
+    impl std::io::Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            return Err(std::io::Error::other("disk error"));
+        }
+    }
+
+    #[test]
+    fn test_counting_reader() {
+        let mut reader = CountingReader::new(Cursor::new("@HD\tVN:1.6\nPa1\t0\t2L\n"));
+        assert_eq!(reader.bytes_read(), 0);
+        assert_eq!(
+            read_line_trimmed(&mut reader).unwrap().unwrap(),
+            "@HD\tVN:1.6"
+        );
+        assert_eq!(reader.bytes_read(), 11);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "Pa1\t0\t2L\n");
+        assert_eq!(reader.bytes_read(), 20);
+    }
+
+    #[test]
+    fn test_read_line_trimmed() {
+        let mut reader = Cursor::new("Pa1\t0\t2L\r\nPa2\t0\t3R\n\nPa3");
+        assert_eq!(
+            read_line_with_length(&mut reader).unwrap(),
+            Some(("Pa1\t0\t2L".to_owned(), 10))
+        );
+        assert_eq!(
+            read_line_trimmed(&mut reader).unwrap().unwrap(),
+            "Pa2\t0\t3R"
+        );
+        // an empty line isn't the end of the file, and the last line may have no line ending
+        assert_eq!(read_line_trimmed(&mut reader).unwrap().unwrap(), "");
+        assert_eq!(read_line_trimmed(&mut reader).unwrap().unwrap(), "Pa3");
+        assert_eq!(read_line_trimmed(&mut reader).unwrap(), None);
+        assert_eq!(read_line_trimmed(&mut Cursor::new("")).unwrap(), None);
+        // I/O errors are returned instead of being taken for the end of the file
+        let mut failing = BufReader::new(FailingReader);
+        assert!(matches!(
+            read_line_trimmed(&mut failing),
+            Err(StanexError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_json_event() {
+        assert_eq!(
+            json_event("info", "\n\nTE mapping done\n", &[], 1760000000.5),
+            r#"{"level":"info","message":"TE mapping done","timestamp":1760000000.5}"#
+        );
+        set_log_phase(4);
+        let event: serde_json::Value = serde_json::from_str(&json_event(
+            "warning",
+            "Skipped records",
+            &[("genome alignments: unmapped", 12034)],
+            1760000000.5,
+        ))
+        .unwrap();
+        set_log_phase(0);
+        assert_eq!(event["level"], "warning");
+        assert_eq!(event["phase"], 4);
+        assert_eq!(event["counts"]["genome alignments: unmapped"], 12034);
+        assert_eq!(LogFormat::parse("json"), Some(LogFormat::Json));
+        assert_eq!(LogFormat::parse("yaml"), None);
+    }
+
+    #[test]
+    fn test_bwa_index_files() {
+        let index_dir =
+            std::env::temp_dir().join(format!("sx_test_bwa_index_files_{}", std::process::id()));
+        fs::create_dir_all(&index_dir).unwrap();
+        let ref_name = index_dir.join("ref.fa");
+        fs::write(&ref_name, ">2L\nACGT\n").unwrap();
+        let ref_name = ref_name.to_str().unwrap();
+        assert!(bwa_index_files(ref_name).is_empty());
+        for extension in &["bwt", "sa", "fai"] {
+            fs::write(format!("{}.{}", ref_name, extension), "").unwrap();
+        }
+        assert_eq!(
+            bwa_index_files(ref_name),
+            vec![format!("{}.bwt", ref_name), format!("{}.sa", ref_name)]
+        );
+        fs::remove_dir_all(&index_dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_files() {
+        let result_dir =
+            std::env::temp_dir().join(format!("sx_test_existing_files_{}", std::process::id()));
+        fs::create_dir_all(&result_dir).unwrap();
+        fs::write(result_dir.join("te_mapper_output.tsv"), "").unwrap();
+        let file_names = vec![
+            "te_mapper_manifest.json".to_owned(),
+            "te_mapper_output.tsv".to_owned(),
+        ];
+        let existing = existing_files(result_dir.to_str().unwrap(), &file_names);
+        fs::remove_dir_all(&result_dir).unwrap();
+        assert_eq!(existing, vec!["te_mapper_output.tsv"]);
+    }
+
+    #[test]
+    fn test_check_step_status() {
+        let success = Command::new("sh").args(&["-c", "exit 0"]).status().unwrap();
+        assert!(check_step_status("sh", success).is_ok());
+        let failure = Command::new("sh").args(&["-c", "exit 3"]).status().unwrap();
+        match check_step_status("samtools sort", failure) {
+            Err(StanexError::SubprocessFailed { step, status }) => {
+                assert_eq!(step, "samtools sort");
+                assert_eq!(status.code(), Some(3));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_pair_name() {
+        assert_eq!(read_pair_name("@read1/1"), "read1");
+        assert_eq!(read_pair_name("@read1/2"), "read1");
+        assert_eq!(read_pair_name("@SRR1234.1 1 length=100"), "SRR1234.1");
+        assert_eq!(read_pair_name("@read1/3"), "read1/3");
+    }
+
+    #[test]
+    fn test_summarize_fastq() {
+        let fastq = "@read1/1\nACGT\n+\nIIII\n@read2/1\nACGT\n+\n@III\n";
+        assert_eq!(
+            summarize_fastq(Cursor::new(fastq), "reads_1.fastq").unwrap(),
+            FastqSummary {
+                num_records: 2,
+                first_name: "read1".to_owned(),
+                last_name: "read2".to_owned(),
+            }
+        );
+
+        let truncated = "@read1/1\nACGT\n+\nIIII\n@read2/1\nACGT\n";
+        assert!(matches!(
+            summarize_fastq(Cursor::new(truncated), "reads_1.fastq"),
+            Err(StanexError::TruncatedFastq(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_contig_renames() {
+        let renames_path =
+            std::env::temp_dir().join(format!("sx_test_contig_renames_{}.tsv", std::process::id()));
+        std::fs::write(&renames_path, "# UCSC to Ensembl\nchr2L\t2L\nchr2R\t2R\n").unwrap();
+        let contig_renames = parse_contig_renames(&[
+            "chrX=X".to_owned(),
+            renames_path.to_str().unwrap().to_owned(),
+        ])
+        .unwrap();
+        std::fs::remove_file(&renames_path).unwrap();
+        assert_eq!(contig_renames.len(), 3);
+        assert_eq!(rename_contig("chrX", &contig_renames), "X");
+        assert_eq!(rename_contig("chr2R", &contig_renames), "2R");
+        assert_eq!(rename_contig("3L", &contig_renames), "3L");
+        assert!(matches!(
+            parse_contig_renames(&["chrX=".to_owned()]),
+            Err(StanexError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_chromosome_list() {
+        let chromosomes_path =
+            std::env::temp_dir().join(format!("sx_test_chromosomes_{}.txt", std::process::id()));
+        std::fs::write(
+            &chromosomes_path,
+            "# major arms\n2L\n2R\n\n  3L  \n3R # the right arm\n\n#X\n2L\n",
+        )
+        .unwrap();
+        let chromosome_list = parse_chromosome_list(
+            &["X".to_owned(), "2L".to_owned()],
+            Some(chromosomes_path.to_str().unwrap()),
+        )
+        .unwrap();
+        std::fs::remove_file(&chromosomes_path).unwrap();
+        assert_eq!(chromosome_list, vec!["X", "2L", "2R", "3L", "3R"]);
+        assert!(parse_chromosome_list(&[], Some("/nonexistent/chromosomes.txt")).is_err());
+    }
+
+    #[test]
+    fn test_exclude_short_contigs() {
+        let chrom_lengths: HashMap<String, u64> = vec![
+            ("2L".to_owned(), 23513712),
+            ("scaffold_12".to_owned(), 4999),
+            ("scaffold_13".to_owned(), 5000),
+        ]
+        .into_iter()
+        .collect();
+        let chroms: Vec<String> = vec!["2L", "scaffold_12", "scaffold_13", "unplaced"]
+            .into_iter()
+            .map(|chrom| chrom.to_owned())
+            .collect();
+        let (kept, excluded) = exclude_short_contigs(chroms.clone(), &chrom_lengths, 5000);
+        assert_eq!(kept, vec!["2L", "scaffold_13", "unplaced"]);
+        assert_eq!(excluded, vec!["scaffold_12"]);
+        assert_eq!(
+            exclude_short_contigs(chroms.clone(), &chrom_lengths, 0).0,
+            chroms
+        );
+    }
+
+    #[test]
+    fn test_check_bwa_mem_args() {
+        let args =
+            |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+        assert!(check_bwa_mem_args(&args(&["-k", "15", "-T", "20", "-L", "5,5"])).is_ok());
+        assert!(check_bwa_mem_args(&args(&["-k15", "-M", "-Y"])).is_ok());
+        // extra input files, the options set by StanEx and missing values are rejected
+        assert!(check_bwa_mem_args(&args(&["-k", "15", "extra.fastq"])).is_err());
+        assert!(check_bwa_mem_args(&args(&["-t", "4"])).is_err());
+        assert!(check_bwa_mem_args(&args(&["-o", "other.sam"])).is_err());
+        assert!(check_bwa_mem_args(&args(&["-T"])).is_err());
+        assert!(check_bwa_mem_args(&args(&["-MY"])).is_err());
+        assert!(check_bwa_mem_args(&args(&["index"])).is_err());
+    }
+
+    #[test]
+    fn test_checked_thread_count() {
+        assert_eq!(checked_thread_count(4, 8, false, "BWA"), 4);
+        assert_eq!(checked_thread_count(8, 8, false, "BWA"), 8);
+        assert_eq!(checked_thread_count(100000, 8, false, "BWA"), 8);
+        assert_eq!(checked_thread_count(100000, 8, true, "BWA"), 100000);
+    }
 }
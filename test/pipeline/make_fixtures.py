@@ -0,0 +1,51 @@
+#!/usr/bin/env python3
+# generates the fixtures for the end-to-end test of the map pipeline (tests/map_pipeline.rs):
+# - ref.fa (and ref.fa.fai): a random 3 kb chromosome "2L"
+# - tes.fa: a random 600 bp transposon "roo"
+# - reads.fastq: error-free 100 bp reads tiled across a sample genome that has roo inserted
+#   (on the + strand) after position 1500 of 2L, with a 5 bp target site duplication (1496-1500)
+# the expected insertion is therefore 2L 1496 1500 +/+ roo (non-reference)
+
+import random
+
+random.seed(1861)
+
+CHROM_LENGTH = 3000
+TE_LENGTH = 600
+INSERTION_POS = 1500
+TSD_LENGTH = 5
+READ_LENGTH = 100
+READ_STEP = 7
+LINE_WIDTH = 60
+
+
+def random_seq(length):
+    return "".join(random.choice("ACGT") for _ in range(length))
+
+
+def write_fasta(path, name, seq):
+    with open(path, "w") as f:
+        f.write(">{}\n".format(name))
+        for i in range(0, len(seq), LINE_WIDTH):
+            f.write(seq[i : i + LINE_WIDTH] + "\n")
+
+
+chrom = random_seq(CHROM_LENGTH)
+te = random_seq(TE_LENGTH)
+
+write_fasta("ref.fa", "2L", chrom)
+with open("ref.fa.fai", "w") as f:
+    f.write("2L\t{}\t4\t{}\t{}\n".format(CHROM_LENGTH, LINE_WIDTH, LINE_WIDTH + 1))
+write_fasta("tes.fa", "roo", te)
+
+# the TE must not share a nucleotide with the genome at either junction,
+# otherwise BWA extends the match by that nucleotide and the breakpoints move
+assert te[-1] != chrom[INSERTION_POS - TSD_LENGTH - 1] and te[0] != chrom[INSERTION_POS]
+
+# the TSD is the last TSD_LENGTH nucleotides before the insertion (1-based, inclusive)
+tsd = chrom[INSERTION_POS - TSD_LENGTH : INSERTION_POS]
+sample = chrom[:INSERTION_POS] + te + tsd + chrom[INSERTION_POS:]
+
+with open("reads.fastq", "w") as f:
+    for read_num, start in enumerate(range(0, len(sample) - READ_LENGTH + 1, READ_STEP)):
+        f.write("@read_{}\n{}\n+\n{}\n".format(read_num, sample[start : start + READ_LENGTH], "I" * READ_LENGTH))
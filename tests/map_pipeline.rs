@@ -0,0 +1,70 @@
+// end-to-end test of the map pipeline (TE alignment -> select_reads -> genome alignment -> select_alignments)
+// on the tiny synthetic genome in test/pipeline (see test/pipeline/make_fixtures.py)
+// it needs bwa on the PATH, so it is ignored by default: run it with "cargo test -- --ignored"
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const FIXTURES: [&str; 4] = ["ref.fa", "ref.fa.fai", "tes.fa", "reads.fastq"];
+
+// copy the fixtures into a fresh directory, since bwa writes its index next to the FASTA files
+fn fixture_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for fixture in FIXTURES.iter() {
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test/pipeline")
+                .join(fixture),
+            dir.join(fixture),
+        )
+        .unwrap();
+    }
+    dir
+}
+
+#[test]
+#[ignore]
+fn test_map_pipeline() {
+    let dir = fixture_dir("map_pipeline");
+    let status = Command::new(env!("CARGO_BIN_EXE_sx"))
+        .current_dir(&dir)
+        .args([
+            "map",
+            "--ref",
+            "ref.fa",
+            "--reads",
+            "reads.fastq",
+            "--transposons",
+            "tes.fa",
+            "--result",
+            "result",
+            "--bwa-threads",
+            "1",
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = fs::read_to_string(dir.join("result/te_mapper_output.tsv")).unwrap();
+    let insertions: Vec<Vec<&str>> = output
+        .lines()
+        .skip(1)
+        .map(|line| line.split('\t').collect())
+        .collect();
+    // roo was inserted on the + strand after 2L:1500 with a TSD from 1496 to 1500
+    assert_eq!(insertions.len(), 1, "unexpected output:\n{}", output);
+    assert_eq!(
+        insertions[0][..5],
+        ["2L", "1496", "1500", "+/+", "roo"],
+        "unexpected output:\n{}",
+        output
+    );
+    assert_eq!(insertions[0][7], "non-reference");
+    // the reads are tiled every 7 nucleotides, so there are several split-reads on both ends
+    let upstream_reads: u64 = insertions[0][5].parse().unwrap();
+    let downstream_reads: u64 = insertions[0][6].parse().unwrap();
+    assert!(upstream_reads > 1 && downstream_reads > 1);
+}